@@ -0,0 +1,31 @@
+use roco_z21_driver::Z21Station;
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() -> roco_z21_driver::Result<()> {
+    // Ask every Z21 on the local network to identify itself
+    let stations = Z21Station::discover(Duration::from_secs(2)).await?;
+
+    if stations.is_empty() {
+        println!("No Z21 station found");
+    }
+    for (index, found) in stations.iter().enumerate() {
+        match &found.hardware_info {
+            Some(info) => println!(
+                "[{}] {} serial {} ({:?}, firmware {}.{:02})",
+                index,
+                found.address,
+                found.serial_number,
+                info.hardware_type,
+                info.firmware_major,
+                info.firmware_minor
+            ),
+            None => println!(
+                "[{}] {} serial {}",
+                index, found.address, found.serial_number
+            ),
+        }
+    }
+
+    Ok(())
+}
@@ -1,16 +1,15 @@
 use roco_z21_driver::{Loco, Z21Station};
 use std::sync::Arc;
-use tokio;
 
 #[tokio::main]
-async fn main() -> std::io::Result<()> {
+async fn main() -> roco_z21_driver::Result<()> {
     let station = Arc::new(Z21Station::new("192.168.0.111:21105").await?);
 
     // Control a locomotive with address 3
     let loco = Loco::control(station.clone(), 4).await?;
 
-    // Subscribe to locomotive state changes
-    loco.subscribe_loco_state(Box::new(|state| {
+    // Subscribe to locomotive state changes, until the guard or the loco is dropped
+    let _subscription = loco.subscribe_loco_state(Box::new(|state| {
         println!(
             "Locomotive speed: {}%",
             state.speed_percentage.unwrap_or(0.)
@@ -1,7 +1,7 @@
 use roco_z21_driver::Z21Station;
 use std::sync::Arc;
 #[tokio::main]
-async fn main() -> std::io::Result<()> {
+async fn main() -> roco_z21_driver::Result<()> {
     // Create a connection to the Z21 station
     let station = Arc::new(Z21Station::new("192.168.0.111:21105").await?);
 
@@ -12,8 +12,8 @@ async fn main() -> std::io::Result<()> {
     // Turn on track power
     station.voltage_on().await?;
 
-    // Subscribe to system state updates
-    station.subscribe_system_state(
+    // Subscribe to system state updates, until the guard is dropped
+    let _subscription = station.subscribe_system_state(
         1.0,
         Box::new(|state| {
             println!("Main track voltage: {:.2}V", state.vcc_voltage);
@@ -27,7 +27,8 @@ async fn main() -> std::io::Result<()> {
 
     // Turn off track power before exiting
     station.voltage_off().await?;
-    station.logout().await?;
+    // Log out and stop all background tasks
+    station.shutdown().await?;
 
     Ok(())
 
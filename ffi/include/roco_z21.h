@@ -0,0 +1,284 @@
+#ifndef ROCO_Z21_H
+#define ROCO_Z21_H
+
+/* Generated by cbindgen from src/lib.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Action of [`z21_loco_set_function`] which switches the function off.
+#define Z21_FUNCTION_OFF 0
+
+// Action of [`z21_loco_set_function`] which switches the function on.
+#define Z21_FUNCTION_ON 1
+
+// Action of [`z21_loco_set_function`] which toggles the function.
+#define Z21_FUNCTION_TOGGLE 2
+
+// Result of a call of the C API.
+typedef enum Z21Status {
+  // The call succeeded.
+  Z21_STATUS_OK = 0,
+  // A pointer is null or an argument is out of range.
+  Z21_STATUS_INVALID_ARGUMENT,
+  // The underlying socket failed.
+  Z21_STATUS_IO,
+  // The station did not answer in time, or no event arrived in time.
+  Z21_STATUS_TIMEOUT,
+  // A message could not be encoded or decoded.
+  Z21_STATUS_PROTOCOL,
+  // The station does not know the command.
+  Z21_STATUS_UNKNOWN_COMMAND,
+  // The decoder did not acknowledge a CV programming command.
+  Z21_STATUS_CV_NACK,
+  // A short circuit was detected on the track.
+  Z21_STATUS_SHORT_CIRCUIT,
+  // The connection to the station is closed.
+  Z21_STATUS_NOT_CONNECTED,
+  // The library panicked, the handles passed to the call may be unusable.
+  Z21_STATUS_PANIC,
+  // The function was called on a thread of the library, e.g. from an event callback.
+  Z21_STATUS_WRONG_THREAD,
+} Z21Status;
+
+// Kind of a [`Z21EventData`].
+typedef enum Z21EventKind {
+  // State of a loco: `address`, `speed_percent` and `functions`.
+  Z21_EVENT_KIND_LOCO_INFO,
+  // Position of a turnout: `address` and `value` (0 unknown, 1 P0, 2 P1, 3 invalid).
+  Z21_EVENT_KIND_TURNOUT_INFO,
+  // State of an extended accessory: `address` and `value`.
+  Z21_EVENT_KIND_ACCESSORY_INFO,
+  // Track power was switched off.
+  Z21_EVENT_KIND_TRACK_POWER_OFF,
+  // Track power was switched on.
+  Z21_EVENT_KIND_TRACK_POWER_ON,
+  // The station is in programming mode.
+  Z21_EVENT_KIND_PROGRAMMING_MODE,
+  // A short circuit was detected on the track.
+  Z21_EVENT_KIND_SHORT_CIRCUIT,
+  // All locos were stopped.
+  Z21_EVENT_KIND_EMERGENCY_STOP,
+  // System state of the station: `system_state`.
+  Z21_EVENT_KIND_SYSTEM_STATE,
+  // The connection state changed: `value` (0 connected, 1 disconnected,
+  // 2 reconnecting).
+  Z21_EVENT_KIND_CONNECTION_STATE,
+  // The queue fell behind, `value` events were lost.
+  Z21_EVENT_KIND_LAGGED,
+  // Any other event, not described further by the C API.
+  Z21_EVENT_KIND_OTHER,
+} Z21EventKind;
+
+// Queue of the events of a station.
+typedef struct Z21EventQueue Z21EventQueue;
+
+// A locomotive controlled through a station.
+typedef struct Z21Loco Z21Loco;
+
+// Connection to a Z21 station.
+typedef struct Z21Station Z21Station;
+
+// Registration of an event callback, the callback is called until it is freed.
+typedef struct Z21Subscription Z21Subscription;
+
+// System state of a station, see `SystemState` of the Rust API.
+typedef struct Z21SystemState {
+  // Current on the main track in mA.
+  int16_t main_current;
+  // Current on the programming track in mA.
+  int16_t prog_current;
+  // Smoothed current on the main track in mA.
+  int16_t filtered_main_current;
+  // Internal temperature in °C.
+  int16_t temperature;
+  // Supply voltage in mV.
+  uint16_t supply_voltage;
+  // Internal voltage, identical to the track voltage, in mV.
+  uint16_t vcc_voltage;
+  // Bit mask of the central state.
+  uint8_t central_state;
+  // Bit mask of the extended central state.
+  uint8_t central_state_ex;
+} Z21SystemState;
+
+// An event of a station, the fields set depend on `kind`.
+typedef struct Z21EventData {
+  // Kind of the event.
+  enum Z21EventKind kind;
+  // Address of the loco, turnout or accessory.
+  uint16_t address;
+  // Speed of the loco in percent, negative when driving backwards, NaN if unknown.
+  double speed_percent;
+  // Functions F0 to F31 of the loco, bit n is function n.
+  uint32_t functions;
+  // Value of the event, see [`Z21EventKind`].
+  uint64_t value;
+  // System state of the station.
+  struct Z21SystemState system_state;
+} Z21EventData;
+
+// Callback of [`z21_station_on_event`], called with the event and the user data.
+typedef void (*Z21EventCallback)(const struct Z21EventData *event, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Returns the message of the last failed call on this thread, or null if no call
+// failed yet.
+//
+// The string stays valid until the next failing call on the same thread.
+const char *z21_last_error_message(void);
+
+// Connects to the Z21 station at `address`, e.g. "192.168.0.111:21105".
+//
+// `timeout_ms` is the time to wait for each reply, 0 keeps the default. The station
+// must be released with [`z21_station_free`].
+//
+// # Safety
+//
+// `address` must be a null terminated string and `out` must be valid for writes.
+enum Z21Status z21_station_connect(const char *address,
+                                   uint32_t timeout_ms,
+                                   struct Z21Station **out);
+
+// Logs off from the station and releases it.
+//
+// Locos, queues and subscriptions of the station stop working but must still be freed.
+//
+// # Safety
+//
+// `station` must be null or a handle of [`z21_station_connect`] which is not used
+// afterwards.
+void z21_station_free(struct Z21Station *station);
+
+// Switches the track power on.
+//
+// # Safety
+//
+// `station` must be a live station handle.
+enum Z21Status z21_station_voltage_on(const struct Z21Station *station);
+
+// Switches the track power off.
+//
+// # Safety
+//
+// `station` must be a live station handle.
+enum Z21Status z21_station_voltage_off(const struct Z21Station *station);
+
+// Reads the serial number of the station into `serial_number`.
+//
+// # Safety
+//
+// `station` must be a live station handle and `serial_number` valid for writes.
+enum Z21Status z21_station_serial_number(const struct Z21Station *station, uint32_t *serial_number);
+
+// Creates a queue receiving the events of the station from now on.
+//
+// The queue must be released with [`z21_events_free`].
+//
+// # Safety
+//
+// `station` must be a live station handle and `out` valid for writes.
+enum Z21Status z21_station_events(const struct Z21Station *station, struct Z21EventQueue **out);
+
+// Waits at most `timeout_ms` for the next event of the queue.
+//
+// Returns [`Z21Status::Timeout`] if no event arrived in time and
+// [`Z21Status::NotConnected`] once the station shut down.
+//
+// # Safety
+//
+// `queue` must be a live queue handle and `event` valid for writes.
+enum Z21Status z21_events_next(struct Z21EventQueue *queue,
+                               uint32_t timeout_ms,
+                               struct Z21EventData *event);
+
+// Releases an event queue.
+//
+// # Safety
+//
+// `queue` must be null or a handle of [`z21_station_events`] which is not used
+// afterwards.
+void z21_events_free(struct Z21EventQueue *queue);
+
+// Calls `callback` with every event of the station until the subscription is freed.
+//
+// The callback runs on a thread of the library and must return quickly; the event is
+// only valid during the call. It must not call the blocking functions of this API,
+// such as [`z21_loco_drive`], which fail with [`Z21Status::WrongThread`] on that
+// thread. The subscription must be released with [`z21_subscription_free`].
+//
+// # Safety
+//
+// `station` must be a live station handle and `out` valid for writes. `callback` and
+// `user_data` must be usable from another thread until the subscription is freed.
+enum Z21Status z21_station_on_event(const struct Z21Station *station,
+                                    Z21EventCallback callback,
+                                    void *user_data,
+                                    struct Z21Subscription **out);
+
+// Stops calling the callback of a subscription and releases it.
+//
+// # Safety
+//
+// `subscription` must be null or a handle of [`z21_station_on_event`] which is not
+// used afterwards.
+void z21_subscription_free(struct Z21Subscription *subscription);
+
+// Takes control of the loco at `address` with 128 throttle steps.
+//
+// The loco must be released with [`z21_loco_free`].
+//
+// # Safety
+//
+// `station` must be a live station handle and `out` valid for writes.
+enum Z21Status z21_loco_control(const struct Z21Station *station,
+                                uint16_t address,
+                                struct Z21Loco **out);
+
+// Releases a loco, the loco keeps its last speed.
+//
+// # Safety
+//
+// `loco` must be null or a handle of [`z21_loco_control`] which is not used afterwards.
+void z21_loco_free(struct Z21Loco *loco);
+
+// Drives the loco at `speed_percent` (-100 to 100, negative drives backwards).
+//
+// # Safety
+//
+// `loco` must be a live loco handle.
+enum Z21Status z21_loco_drive(const struct Z21Loco *loco, double speed_percent);
+
+// Stops the loco with its braking curve.
+//
+// # Safety
+//
+// `loco` must be a live loco handle.
+enum Z21Status z21_loco_stop(const struct Z21Loco *loco);
+
+// Stops the loco immediately.
+//
+// # Safety
+//
+// `loco` must be a live loco handle.
+enum Z21Status z21_loco_halt(const struct Z21Loco *loco);
+
+// Switches function `function_index` (0 to 31) of the loco.
+//
+// # Safety
+//
+// `loco` must be a live loco handle.
+enum Z21Status z21_loco_set_function(const struct Z21Loco *loco,
+                                     uint8_t function_index,
+                                     uint8_t action);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* ROCO_Z21_H */
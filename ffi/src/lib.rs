@@ -0,0 +1,755 @@
+//! C API of the Roco Z21 driver.
+//!
+//! The library wraps [`roco_z21_driver::blocking`]: every station owns the runtime which
+//! runs its background tasks, and every function blocks until the command completed.
+//! Handles are opaque pointers, created by [`z21_station_connect`], [`z21_loco_control`],
+//! [`z21_station_events`] and [`z21_station_on_event`] and released with the matching
+//! `*_free` function.
+//!
+//! Functions which can fail return a [`Z21Status`]; [`z21_last_error_message`] describes
+//! the last failure on the calling thread.
+//!
+//! The header `include/roco_z21.h` is generated from this file by cbindgen, see
+//! `cbindgen.toml`.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+
+use roco_z21_driver::blocking;
+use roco_z21_driver::messages::{SystemState, TurnoutPosition};
+use roco_z21_driver::{ConnectionState, Error, Subscription, Z21Event, Z21StationBuilder};
+use tokio::runtime::Handle;
+
+/// Result of a call of the C API.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Z21Status {
+    /// The call succeeded.
+    Ok = 0,
+    /// A pointer is null or an argument is out of range.
+    InvalidArgument,
+    /// The underlying socket failed.
+    Io,
+    /// The station did not answer in time, or no event arrived in time.
+    Timeout,
+    /// A message could not be encoded or decoded.
+    Protocol,
+    /// The station does not know the command.
+    UnknownCommand,
+    /// The decoder did not acknowledge a CV programming command.
+    CvNack,
+    /// A short circuit was detected on the track.
+    ShortCircuit,
+    /// The connection to the station is closed.
+    NotConnected,
+    /// The library panicked, the handles passed to the call may be unusable.
+    Panic,
+    /// The function was called on a thread of the library, e.g. from an event callback.
+    WrongThread,
+}
+
+/// Connection to a Z21 station.
+pub struct Z21Station(blocking::Z21Station);
+
+/// A locomotive controlled through a station.
+pub struct Z21Loco(blocking::Loco);
+
+/// Queue of the events of a station.
+pub struct Z21EventQueue(blocking::Events);
+
+/// Registration of an event callback, the callback is called until it is freed.
+pub struct Z21Subscription(#[allow(dead_code)] Subscription);
+
+/// Action of [`z21_loco_set_function`] which switches the function off.
+pub const Z21_FUNCTION_OFF: u8 = 0;
+/// Action of [`z21_loco_set_function`] which switches the function on.
+pub const Z21_FUNCTION_ON: u8 = 1;
+/// Action of [`z21_loco_set_function`] which toggles the function.
+pub const Z21_FUNCTION_TOGGLE: u8 = 2;
+
+/// Kind of a [`Z21EventData`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Z21EventKind {
+    /// State of a loco: `address`, `speed_percent` and `functions`.
+    LocoInfo,
+    /// Position of a turnout: `address` and `value` (0 unknown, 1 P0, 2 P1, 3 invalid).
+    TurnoutInfo,
+    /// State of an extended accessory: `address` and `value`.
+    AccessoryInfo,
+    /// Track power was switched off.
+    TrackPowerOff,
+    /// Track power was switched on.
+    TrackPowerOn,
+    /// The station is in programming mode.
+    ProgrammingMode,
+    /// A short circuit was detected on the track.
+    ShortCircuit,
+    /// All locos were stopped.
+    EmergencyStop,
+    /// System state of the station: `system_state`.
+    SystemState,
+    /// The connection state changed: `value` (0 connected, 1 disconnected,
+    /// 2 reconnecting).
+    ConnectionState,
+    /// The queue fell behind, `value` events were lost.
+    Lagged,
+    /// Any other event, not described further by the C API.
+    Other,
+}
+
+/// System state of a station, see `SystemState` of the Rust API.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Z21SystemState {
+    /// Current on the main track in mA.
+    pub main_current: i16,
+    /// Current on the programming track in mA.
+    pub prog_current: i16,
+    /// Smoothed current on the main track in mA.
+    pub filtered_main_current: i16,
+    /// Internal temperature in °C.
+    pub temperature: i16,
+    /// Supply voltage in mV.
+    pub supply_voltage: u16,
+    /// Internal voltage, identical to the track voltage, in mV.
+    pub vcc_voltage: u16,
+    /// Bit mask of the central state.
+    pub central_state: u8,
+    /// Bit mask of the extended central state.
+    pub central_state_ex: u8,
+}
+
+impl From<&SystemState> for Z21SystemState {
+    fn from(state: &SystemState) -> Self {
+        Z21SystemState {
+            main_current: state.main_current,
+            prog_current: state.prog_current,
+            filtered_main_current: state.filtered_main_current,
+            temperature: state.temperature,
+            supply_voltage: state.supply_voltage,
+            vcc_voltage: state.vcc_voltage,
+            central_state: state.central_state,
+            central_state_ex: state.central_state_ex,
+        }
+    }
+}
+
+/// An event of a station, the fields set depend on `kind`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Z21EventData {
+    /// Kind of the event.
+    pub kind: Z21EventKind,
+    /// Address of the loco, turnout or accessory.
+    pub address: u16,
+    /// Speed of the loco in percent, negative when driving backwards, NaN if unknown.
+    pub speed_percent: f64,
+    /// Functions F0 to F31 of the loco, bit n is function n.
+    pub functions: u32,
+    /// Value of the event, see [`Z21EventKind`].
+    pub value: u64,
+    /// System state of the station.
+    pub system_state: Z21SystemState,
+}
+
+impl From<&Z21Event> for Z21EventData {
+    fn from(event: &Z21Event) -> Self {
+        let mut data = Z21EventData {
+            kind: Z21EventKind::Other,
+            address: 0,
+            speed_percent: f64::NAN,
+            functions: 0,
+            value: 0,
+            system_state: Z21SystemState::default(),
+        };
+        match event {
+            Z21Event::LocoInfo(state) => {
+                data.kind = Z21EventKind::LocoInfo;
+                data.address = state.address;
+                data.speed_percent = state.speed_percentage.unwrap_or(f64::NAN);
+                data.functions = state.functions.map_or(0, |functions| {
+                    functions
+                        .iter()
+                        .enumerate()
+                        .fold(0, |bits, (i, &on)| bits | (on as u32) << i)
+                });
+            }
+            Z21Event::TurnoutInfo(info) => {
+                data.kind = Z21EventKind::TurnoutInfo;
+                data.address = info.address;
+                data.value = match info.position {
+                    TurnoutPosition::Unknown => 0,
+                    TurnoutPosition::P0 => 1,
+                    TurnoutPosition::P1 => 2,
+                    TurnoutPosition::Invalid => 3,
+                };
+            }
+            Z21Event::AccessoryInfo(info) => {
+                data.kind = Z21EventKind::AccessoryInfo;
+                data.address = info.address;
+                data.value = info.state.into();
+            }
+            Z21Event::TrackPowerOff => data.kind = Z21EventKind::TrackPowerOff,
+            Z21Event::TrackPowerOn => data.kind = Z21EventKind::TrackPowerOn,
+            Z21Event::ProgrammingMode => data.kind = Z21EventKind::ProgrammingMode,
+            Z21Event::ShortCircuit => data.kind = Z21EventKind::ShortCircuit,
+            Z21Event::EmergencyStop => data.kind = Z21EventKind::EmergencyStop,
+            Z21Event::SystemState(state) => {
+                data.kind = Z21EventKind::SystemState;
+                data.system_state = state.into();
+            }
+            Z21Event::ConnectionState(state) => {
+                data.kind = Z21EventKind::ConnectionState;
+                data.value = match state {
+                    ConnectionState::Connected => 0,
+                    ConnectionState::Disconnected => 1,
+                    ConnectionState::Reconnecting => 2,
+                };
+            }
+            Z21Event::Lagged(skipped) => {
+                data.kind = Z21EventKind::Lagged;
+                data.value = *skipped;
+            }
+            _ => {}
+        }
+        data
+    }
+}
+
+/// Callback of [`z21_station_on_event`], called with the event and the user data.
+pub type Z21EventCallback =
+    Option<unsafe extern "C" fn(event: *const Z21EventData, user_data: *mut c_void)>;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Records `message` as the last error of the thread and returns `status`.
+fn fail(status: Z21Status, message: impl ToString) -> Z21Status {
+    let message = CString::new(message.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    status
+}
+
+/// Converts the result of the Rust API, recording the error.
+fn check<T>(result: roco_z21_driver::Result<T>) -> Result<T, Z21Status> {
+    result.map_err(|error| {
+        let status = match &error {
+            Error::Io(_) => Z21Status::Io,
+            Error::Timeout { .. } => Z21Status::Timeout,
+            Error::Checksum { .. } | Error::Protocol(_) | Error::MalformedPayload(_) => {
+                Z21Status::Protocol
+            }
+            Error::UnknownCommand => Z21Status::UnknownCommand,
+            Error::CvNack => Z21Status::CvNack,
+            Error::ShortCircuit => Z21Status::ShortCircuit,
+            Error::NotConnected => Z21Status::NotConnected,
+            Error::InvalidArgument(_) => Z21Status::InvalidArgument,
+            Error::InsideRuntime => Z21Status::WrongThread,
+        };
+        fail(status, error)
+    })
+}
+
+/// Runs the body of an API function, a panic is reported as [`Z21Status::Panic`]
+/// instead of unwinding into the caller. A call on a thread of the library, which
+/// cannot block, fails with [`Z21Status::WrongThread`] without running the body.
+fn call(body: impl FnOnce() -> Result<(), Z21Status>) -> Z21Status {
+    if Handle::try_current().is_ok() {
+        return fail(
+            Z21Status::WrongThread,
+            "Called on a thread of the library, e.g. from an event callback",
+        );
+    }
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(result) => result.err().unwrap_or(Z21Status::Ok),
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+            fail(Z21Status::Panic, format!("Panic: {}", message))
+        }
+    }
+}
+
+/// Runs the body of a `*_free` function, a panic is swallowed as the function has no
+/// way to report it.
+fn release(body: impl FnOnce()) {
+    let _ = panic::catch_unwind(AssertUnwindSafe(body));
+}
+
+/// Borrows the handle behind `ptr`.
+///
+/// # Safety
+///
+/// `ptr` must be null or point to a live handle.
+unsafe fn handle<'a, T>(ptr: *const T) -> Result<&'a T, Z21Status> {
+    ptr.as_ref()
+        .ok_or_else(|| fail(Z21Status::InvalidArgument, "Null handle"))
+}
+
+/// Stores a new handle in `out`.
+///
+/// # Safety
+///
+/// `out` must be null or valid for writes.
+unsafe fn store<T>(out: *mut *mut T, value: T) -> Result<(), Z21Status> {
+    if out.is_null() {
+        return Err(fail(Z21Status::InvalidArgument, "Null output pointer"));
+    }
+    *out = Box::into_raw(Box::new(value));
+    Ok(())
+}
+
+/// Returns the message of the last failed call on this thread, or null if no call
+/// failed yet.
+///
+/// The string stays valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn z21_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |m| m.as_ptr()))
+}
+
+/// Connects to the Z21 station at `address`, e.g. "192.168.0.111:21105".
+///
+/// `timeout_ms` is the time to wait for each reply, 0 keeps the default. The station
+/// must be released with [`z21_station_free`].
+///
+/// # Safety
+///
+/// `address` must be a null terminated string and `out` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn z21_station_connect(
+    address: *const c_char,
+    timeout_ms: u32,
+    out: *mut *mut Z21Station,
+) -> Z21Status {
+    call(|| {
+        if address.is_null() {
+            return Err(fail(Z21Status::InvalidArgument, "Null address"));
+        }
+        let address = CStr::from_ptr(address)
+            .to_str()
+            .map_err(|_| fail(Z21Status::InvalidArgument, "Address is not UTF-8"))?;
+        let mut builder = Z21StationBuilder::new(address);
+        if timeout_ms > 0 {
+            builder = builder.timeout(Duration::from_millis(timeout_ms.into()));
+        }
+        let station = check(blocking::Z21Station::connect(builder))?;
+        store(out, Z21Station(station))
+    })
+}
+
+/// Logs off from the station and releases it.
+///
+/// Locos, queues and subscriptions of the station stop working but must still be freed.
+///
+/// # Safety
+///
+/// `station` must be null or a handle of [`z21_station_connect`] which is not used
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn z21_station_free(station: *mut Z21Station) {
+    release(|| {
+        if !station.is_null() {
+            let station = Box::from_raw(station);
+            let _ = station.0.shutdown();
+        }
+    })
+}
+
+/// Switches the track power on.
+///
+/// # Safety
+///
+/// `station` must be a live station handle.
+#[no_mangle]
+pub unsafe extern "C" fn z21_station_voltage_on(station: *const Z21Station) -> Z21Status {
+    call(|| check(handle(station)?.0.voltage_on()))
+}
+
+/// Switches the track power off.
+///
+/// # Safety
+///
+/// `station` must be a live station handle.
+#[no_mangle]
+pub unsafe extern "C" fn z21_station_voltage_off(station: *const Z21Station) -> Z21Status {
+    call(|| check(handle(station)?.0.voltage_off()))
+}
+
+/// Reads the serial number of the station into `serial_number`.
+///
+/// # Safety
+///
+/// `station` must be a live station handle and `serial_number` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn z21_station_serial_number(
+    station: *const Z21Station,
+    serial_number: *mut u32,
+) -> Z21Status {
+    call(|| {
+        if serial_number.is_null() {
+            return Err(fail(Z21Status::InvalidArgument, "Null output pointer"));
+        }
+        *serial_number = check(handle(station)?.0.get_serial_number())?;
+        Ok(())
+    })
+}
+
+/// Creates a queue receiving the events of the station from now on.
+///
+/// The queue must be released with [`z21_events_free`].
+///
+/// # Safety
+///
+/// `station` must be a live station handle and `out` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn z21_station_events(
+    station: *const Z21Station,
+    out: *mut *mut Z21EventQueue,
+) -> Z21Status {
+    call(|| store(out, Z21EventQueue(handle(station)?.0.events())))
+}
+
+/// Waits at most `timeout_ms` for the next event of the queue.
+///
+/// Returns [`Z21Status::Timeout`] if no event arrived in time and
+/// [`Z21Status::NotConnected`] once the station shut down.
+///
+/// # Safety
+///
+/// `queue` must be a live queue handle and `event` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn z21_events_next(
+    queue: *mut Z21EventQueue,
+    timeout_ms: u32,
+    event: *mut Z21EventData,
+) -> Z21Status {
+    call(|| {
+        let queue = queue
+            .as_mut()
+            .ok_or_else(|| fail(Z21Status::InvalidArgument, "Null handle"))?;
+        if event.is_null() {
+            return Err(fail(Z21Status::InvalidArgument, "Null output pointer"));
+        }
+        match queue
+            .0
+            .recv_timeout(Duration::from_millis(timeout_ms.into()))
+        {
+            Ok(next) => {
+                *event = Z21EventData::from(&next);
+                Ok(())
+            }
+            Err(RecvTimeoutError::Timeout) => Err(fail(Z21Status::Timeout, "No event")),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(fail(Z21Status::NotConnected, "The station shut down"))
+            }
+        }
+    })
+}
+
+/// Releases an event queue.
+///
+/// # Safety
+///
+/// `queue` must be null or a handle of [`z21_station_events`] which is not used
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn z21_events_free(queue: *mut Z21EventQueue) {
+    release(|| {
+        if !queue.is_null() {
+            drop(Box::from_raw(queue));
+        }
+    })
+}
+
+/// User data of a callback, handed back to the caller untouched.
+struct UserData(*mut c_void);
+
+// The caller of `z21_station_on_event` guarantees that the user data may be used from
+// the thread of the runtime.
+unsafe impl Send for UserData {}
+unsafe impl Sync for UserData {}
+
+impl UserData {
+    fn get(&self) -> *mut c_void {
+        self.0
+    }
+}
+
+/// Calls `callback` with every event of the station until the subscription is freed.
+///
+/// The callback runs on a thread of the library and must return quickly; the event is
+/// only valid during the call. It must not call the blocking functions of this API,
+/// such as [`z21_loco_drive`], which fail with [`Z21Status::WrongThread`] on that
+/// thread. The subscription must be released with [`z21_subscription_free`].
+///
+/// # Safety
+///
+/// `station` must be a live station handle and `out` valid for writes. `callback` and
+/// `user_data` must be usable from another thread until the subscription is freed.
+#[no_mangle]
+pub unsafe extern "C" fn z21_station_on_event(
+    station: *const Z21Station,
+    callback: Z21EventCallback,
+    user_data: *mut c_void,
+    out: *mut *mut Z21Subscription,
+) -> Z21Status {
+    call(|| {
+        let station = handle(station)?;
+        let callback = callback.ok_or_else(|| fail(Z21Status::InvalidArgument, "Null callback"))?;
+        let user_data = UserData(user_data);
+        let subscription = station.0.on_event(move |event| {
+            let data = Z21EventData::from(&event);
+            unsafe { callback(&data, user_data.get()) };
+        });
+        store(out, Z21Subscription(subscription))
+    })
+}
+
+/// Stops calling the callback of a subscription and releases it.
+///
+/// # Safety
+///
+/// `subscription` must be null or a handle of [`z21_station_on_event`] which is not
+/// used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn z21_subscription_free(subscription: *mut Z21Subscription) {
+    release(|| {
+        if !subscription.is_null() {
+            drop(Box::from_raw(subscription));
+        }
+    })
+}
+
+/// Takes control of the loco at `address` with 128 throttle steps.
+///
+/// The loco must be released with [`z21_loco_free`].
+///
+/// # Safety
+///
+/// `station` must be a live station handle and `out` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn z21_loco_control(
+    station: *const Z21Station,
+    address: u16,
+    out: *mut *mut Z21Loco,
+) -> Z21Status {
+    call(|| {
+        let loco = check(blocking::Loco::control(&handle(station)?.0, address))?;
+        store(out, Z21Loco(loco))
+    })
+}
+
+/// Releases a loco, the loco keeps its last speed.
+///
+/// # Safety
+///
+/// `loco` must be null or a handle of [`z21_loco_control`] which is not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn z21_loco_free(loco: *mut Z21Loco) {
+    release(|| {
+        if !loco.is_null() {
+            drop(Box::from_raw(loco));
+        }
+    })
+}
+
+/// Drives the loco at `speed_percent` (-100 to 100, negative drives backwards).
+///
+/// # Safety
+///
+/// `loco` must be a live loco handle.
+#[no_mangle]
+pub unsafe extern "C" fn z21_loco_drive(loco: *const Z21Loco, speed_percent: f64) -> Z21Status {
+    call(|| check(handle(loco)?.0.drive(speed_percent)))
+}
+
+/// Stops the loco with its braking curve.
+///
+/// # Safety
+///
+/// `loco` must be a live loco handle.
+#[no_mangle]
+pub unsafe extern "C" fn z21_loco_stop(loco: *const Z21Loco) -> Z21Status {
+    call(|| check(handle(loco)?.0.stop()))
+}
+
+/// Stops the loco immediately.
+///
+/// # Safety
+///
+/// `loco` must be a live loco handle.
+#[no_mangle]
+pub unsafe extern "C" fn z21_loco_halt(loco: *const Z21Loco) -> Z21Status {
+    call(|| check(handle(loco)?.0.halt()))
+}
+
+/// Switches function `function_index` (0 to 31) of the loco.
+///
+/// # Safety
+///
+/// `loco` must be a live loco handle.
+#[no_mangle]
+pub unsafe extern "C" fn z21_loco_set_function(
+    loco: *const Z21Loco,
+    function_index: u8,
+    action: u8,
+) -> Z21Status {
+    call(|| {
+        let loco = handle(loco)?;
+        if action > Z21_FUNCTION_TOGGLE {
+            return Err(fail(
+                Z21Status::InvalidArgument,
+                format!("Invalid function action {}", action),
+            ));
+        }
+        check(loco.0.set_function(function_index, action))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use roco_z21_driver::Z21Simulator;
+    use tokio::runtime::Runtime;
+
+    fn connect(runtime: &Runtime) -> (Z21Simulator, *mut Z21Station) {
+        let simulator = runtime.block_on(Z21Simulator::bind("127.0.0.1:0")).unwrap();
+        let address = CString::new(simulator.local_addr().to_string()).unwrap();
+        let mut station = ptr::null_mut();
+        let status = unsafe { z21_station_connect(address.as_ptr(), 300, &mut station) };
+        assert_eq!(status, Z21Status::Ok);
+        (simulator, station)
+    }
+
+    #[test]
+    fn test_station_loco_and_event_queue() {
+        let runtime = Runtime::new().unwrap();
+        let (simulator, station) = connect(&runtime);
+        simulator.set_serial_number(4711);
+        unsafe {
+            let mut serial_number = 0;
+            assert_eq!(
+                z21_station_serial_number(station, &mut serial_number),
+                Z21Status::Ok
+            );
+            assert_eq!(serial_number, 4711);
+
+            let mut queue = ptr::null_mut();
+            assert_eq!(z21_station_events(station, &mut queue), Z21Status::Ok);
+            let mut loco = ptr::null_mut();
+            assert_eq!(z21_loco_control(station, 3, &mut loco), Z21Status::Ok);
+            assert_eq!(z21_loco_drive(loco, 50.0), Z21Status::Ok);
+            assert_eq!(
+                z21_loco_set_function(loco, 0, Z21_FUNCTION_ON),
+                Z21Status::Ok
+            );
+            assert_eq!(
+                z21_loco_set_function(loco, 0, 3),
+                Z21Status::InvalidArgument
+            );
+
+            let mut event = Z21EventData::from(&Z21Event::TrackPowerOn);
+            loop {
+                assert_eq!(z21_events_next(queue, 5000, &mut event), Z21Status::Ok);
+                if event.kind == Z21EventKind::LocoInfo && event.functions & 1 == 1 {
+                    break;
+                }
+            }
+            assert_eq!(event.address, 3);
+            assert!(event.speed_percent > 0.);
+
+            z21_loco_free(loco);
+            z21_station_free(station);
+            assert_eq!(
+                z21_events_next(queue, 1000, &mut event),
+                Z21Status::NotConnected
+            );
+            z21_events_free(queue);
+        }
+    }
+
+    unsafe extern "C" fn count_power_off(event: *const Z21EventData, user_data: *mut c_void) {
+        if (*event).kind == Z21EventKind::TrackPowerOff {
+            let sender = &*(user_data as *const std::sync::Mutex<std::sync::mpsc::Sender<()>>);
+            let _ = sender.lock().unwrap().send(());
+        }
+    }
+
+    #[test]
+    fn test_event_callback_and_errors() {
+        let runtime = Runtime::new().unwrap();
+        let (_simulator, station) = connect(&runtime);
+        let (sender, receiver) = std::sync::mpsc::channel::<()>();
+        let sender = std::sync::Mutex::new(sender);
+        unsafe {
+            let mut subscription = ptr::null_mut();
+            let status = z21_station_on_event(
+                station,
+                Some(count_power_off),
+                &sender as *const _ as *mut c_void,
+                &mut subscription,
+            );
+            assert_eq!(status, Z21Status::Ok);
+            assert_eq!(z21_station_voltage_off(station), Z21Status::Ok);
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+            z21_subscription_free(subscription);
+
+            assert_eq!(
+                z21_loco_drive(ptr::null(), 10.0),
+                Z21Status::InvalidArgument
+            );
+            let message = CStr::from_ptr(z21_last_error_message());
+            assert_eq!(message.to_str().unwrap(), "Null handle");
+            z21_station_free(station);
+        }
+    }
+
+    struct Reentry {
+        station: *const Z21Station,
+        sender: std::sync::Mutex<std::sync::mpsc::Sender<Z21Status>>,
+    }
+
+    unsafe extern "C" fn call_from_callback(event: *const Z21EventData, user_data: *mut c_void) {
+        if (*event).kind == Z21EventKind::TrackPowerOff {
+            let reentry = &*(user_data as *const Reentry);
+            let status = z21_station_voltage_on(reentry.station);
+            let _ = reentry.sender.lock().unwrap().send(status);
+        }
+    }
+
+    #[test]
+    fn test_call_from_callback_reports_wrong_thread() {
+        let runtime = Runtime::new().unwrap();
+        let (_simulator, station) = connect(&runtime);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let reentry = Reentry {
+            station,
+            sender: std::sync::Mutex::new(sender),
+        };
+        unsafe {
+            let mut subscription = ptr::null_mut();
+            let status = z21_station_on_event(
+                station,
+                Some(call_from_callback),
+                &reentry as *const _ as *mut c_void,
+                &mut subscription,
+            );
+            assert_eq!(status, Z21Status::Ok);
+            assert_eq!(z21_station_voltage_off(station), Z21Status::Ok);
+            let status = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(status, Z21Status::WrongThread);
+            z21_subscription_free(subscription);
+            z21_station_free(station);
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! Error types of the protocol codec.
+
+use alloc::string::String;
+use core::fmt;
+
+/// Errors raised while framing or decoding Z21 datasets.
+///
+/// Receiving a malformed datagram must never bring down the connection, so every
+/// decoding step reports its failure through this type instead of panicking.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// Fewer bytes are available than required to decode the dataset.
+    Truncated {
+        /// Number of bytes required.
+        needed: usize,
+        /// Number of bytes actually available.
+        available: usize,
+    },
+    /// The DataLen field is smaller than the minimal dataset length of 4 bytes.
+    InvalidLength(u16),
+    /// The DataLen field does not match the number of bytes of the dataset.
+    LengthMismatch {
+        /// Length declared in the DataLen field.
+        declared: usize,
+        /// Length of the supplied bytes.
+        actual: usize,
+    },
+    /// The payload does not fit into a single dataset.
+    PayloadTooLarge(usize),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Truncated { needed, available } => write!(
+                f,
+                "Truncated dataset: needed {} bytes, {} available",
+                needed, available
+            ),
+            ProtocolError::InvalidLength(len) => write!(f, "Invalid dataset length {}", len),
+            ProtocolError::LengthMismatch { declared, actual } => write!(
+                f,
+                "Dataset length mismatch: declared {} bytes, got {}",
+                declared, actual
+            ),
+            ProtocolError::PayloadTooLarge(len) => {
+                write!(f, "Packet payload of {} bytes is too big", len)
+            }
+        }
+    }
+}
+
+impl core::error::Error for ProtocolError {}
+
+/// A specialized `Result` type for encoding and decoding.
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Errors returned when encoding or decoding messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The XOR checksum of an X-Bus message is wrong.
+    Checksum {
+        /// Checksum calculated over the received bytes.
+        expected: u8,
+        /// Checksum contained in the message.
+        actual: u8,
+    },
+    /// A dataset could not be framed.
+    Protocol(ProtocolError),
+    /// The payload of a message is malformed.
+    MalformedPayload(String),
+    /// An argument of a message is out of range.
+    InvalidArgument(String),
+}
+
+impl Error {
+    pub(crate) fn malformed(message: impl Into<String>) -> Error {
+        Error::MalformedPayload(message.into())
+    }
+
+    pub(crate) fn invalid_argument(message: impl Into<String>) -> Error {
+        Error::InvalidArgument(message.into())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Checksum { expected, actual } => write!(
+                f,
+                "XBus message XOR is wrong: expected 0x{:02x}, got 0x{:02x}",
+                expected, actual
+            ),
+            Error::Protocol(e) => write!(f, "{}", e),
+            Error::MalformedPayload(message) => write!(f, "Malformed payload: {}", message),
+            Error::InvalidArgument(message) => write!(f, "Invalid argument: {}", message),
+        }
+    }
+}
+
+impl core::error::Error for Error {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            Error::Protocol(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ProtocolError> for Error {
+    fn from(error: ProtocolError) -> Self {
+        Error::Protocol(error)
+    }
+}
@@ -0,0 +1,18 @@
+//! Encoding and decoding of the Roco Z21 LAN protocol.
+//!
+//! This crate holds the runtime independent part of `roco_z21_driver`: framing of
+//! datasets into [`Packet`]s, X-Bus messages and the typed [`messages`] exchanged with
+//! a Z21 station. It does no I/O and only needs `core` and `alloc`, so it can be used
+//! on embedded targets or with any async runtime.
+
+#![no_std]
+
+extern crate alloc;
+#[cfg(test)]
+extern crate std;
+
+mod error;
+pub use error::{Error, ProtocolError, Result};
+pub mod messages;
+pub mod packet;
+pub use packet::{join_datagram, split_datagram, DatagramIter, Packet};
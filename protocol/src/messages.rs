@@ -0,0 +1,37 @@
+mod accessory;
+mod broadcast_flags;
+mod can_booster;
+mod decoder_mode;
+mod fast_clock;
+mod feedback;
+mod hardware_info;
+pub mod header;
+mod loco_state;
+mod railcom;
+mod reply;
+mod request;
+mod system_state;
+mod xbus_message;
+
+pub use accessory::AccessoryInfo;
+pub use accessory::TurnoutInfo;
+pub use accessory::TurnoutPosition;
+pub use broadcast_flags::BroadcastFlags;
+pub use can_booster::CanBoosterState;
+pub use decoder_mode::DecoderMode;
+pub use fast_clock::FastClockControl;
+pub use fast_clock::FastClockTime;
+pub use feedback::CanDetector;
+pub use feedback::LocoNetDetector;
+pub use feedback::RBusFeedback;
+pub use hardware_info::HardwareInfo;
+pub use hardware_info::HardwareType;
+pub use loco_state::DccThrottleSteps;
+pub use loco_state::LocoState;
+pub use railcom::RailComData;
+pub use reply::Reply;
+pub use request::FunctionAction;
+pub use request::Request;
+pub use system_state::SystemState;
+pub use xbus_message::XBusMessage;
+pub use xbus_message::XBUS_HEADER;
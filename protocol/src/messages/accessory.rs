@@ -0,0 +1,98 @@
+use crate::error::Error;
+use alloc::{vec, vec::Vec};
+
+/// Position of a turnout as reported in LAN_X_TURNOUT_INFO.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TurnoutPosition {
+    /// The turnout has not been switched yet.
+    Unknown = 0b00,
+    /// Output 1 is active (P = 0).
+    P0 = 0b01,
+    /// Output 2 is active (P = 1).
+    P1 = 0b10,
+    /// Invalid combination reported by the station.
+    Invalid = 0b11,
+}
+
+impl From<u8> for TurnoutPosition {
+    fn from(value: u8) -> Self {
+        match value & 0b11 {
+            0b00 => TurnoutPosition::Unknown,
+            0b01 => TurnoutPosition::P0,
+            0b10 => TurnoutPosition::P1,
+            _ => TurnoutPosition::Invalid,
+        }
+    }
+}
+
+/// State of a turnout as reported in LAN_X_TURNOUT_INFO.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TurnoutInfo {
+    /// Function address of the turnout (0 based, as transmitted on the wire).
+    pub address: u16,
+    /// Current position of the turnout.
+    pub position: TurnoutPosition,
+}
+
+impl TryFrom<&[u8]> for TurnoutInfo {
+    type Error = Error;
+
+    /// Attempts to parse a `TurnoutInfo` from the X-Bus data bytes `FAdr_MSB FAdr_LSB ZZ`.
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        if data.len() < 3 {
+            return Err(Error::malformed("Invalid TurnoutInfo data length"));
+        }
+        Ok(TurnoutInfo {
+            address: u16::from_be_bytes([data[0], data[1]]),
+            position: TurnoutPosition::from(data[2]),
+        })
+    }
+}
+
+impl From<TurnoutInfo> for Vec<u8> {
+    /// Converts a `TurnoutInfo` into its 3 X-Bus data bytes.
+    fn from(info: TurnoutInfo) -> Vec<u8> {
+        let addr = info.address.to_be_bytes();
+        vec![addr[0], addr[1], info.position as u8]
+    }
+}
+
+/// State of an extended accessory decoder as reported in LAN_X_EXT_ACCESSORY_INFO.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AccessoryInfo {
+    /// Raw address of the accessory decoder.
+    pub address: u16,
+    /// Last aspect (DDDDDDDD) sent to the decoder.
+    pub state: u8,
+    /// `true` if the station knows the state, `false` if it is unknown.
+    pub valid: bool,
+}
+
+impl TryFrom<&[u8]> for AccessoryInfo {
+    type Error = Error;
+
+    /// Attempts to parse an `AccessoryInfo` from the X-Bus data bytes
+    /// `Adr_MSB Adr_LSB DDDDDDDD Status`.
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        if data.len() < 4 {
+            return Err(Error::malformed("Invalid AccessoryInfo data length"));
+        }
+        Ok(AccessoryInfo {
+            address: u16::from_be_bytes([data[0], data[1]]),
+            state: data[2],
+            valid: data[3] == 0x00,
+        })
+    }
+}
+
+impl From<AccessoryInfo> for Vec<u8> {
+    /// Converts an `AccessoryInfo` into its 4 X-Bus data bytes.
+    fn from(info: AccessoryInfo) -> Vec<u8> {
+        let addr = info.address.to_be_bytes();
+        let status = if info.valid { 0x00 } else { 0xFF };
+        vec![addr[0], addr[1], info.state, status]
+    }
+}
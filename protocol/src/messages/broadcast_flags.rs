@@ -0,0 +1,90 @@
+use core::ops::{BitOr, BitOrAssign};
+
+/// Broadcast flags selecting which unsolicited messages the Z21 sends to a client.
+///
+/// The flags are set with LAN_SET_BROADCASTFLAGS and can be combined with `|`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+pub struct BroadcastFlags(u32);
+
+impl BroadcastFlags {
+    /// No broadcasts at all.
+    pub const NONE: BroadcastFlags = BroadcastFlags(0x0000_0000);
+    /// Driving and switching: track power, stop, short circuit, and loco/turnout info
+    /// of the locos this client has requested information for.
+    pub const DRIVING_SWITCHING: BroadcastFlags = BroadcastFlags(0x0000_0001);
+    /// R-BUS feedback changes (LAN_RMBUS_DATACHANGED).
+    pub const RBUS: BroadcastFlags = BroadcastFlags(0x0000_0002);
+    /// RailCom data of the locos this client has subscribed to.
+    pub const RAILCOM: BroadcastFlags = BroadcastFlags(0x0000_0004);
+    /// Fast clock time messages (LAN_FAST_CLOCK_DATA).
+    pub const FAST_CLOCK: BroadcastFlags = BroadcastFlags(0x0000_0010);
+    /// System state changes (LAN_SYSTEMSTATE_DATACHANGED).
+    pub const SYSTEM_STATE: BroadcastFlags = BroadcastFlags(0x0000_0100);
+    /// Loco info of every loco, not only the subscribed ones.
+    pub const ALL_LOCO_INFO: BroadcastFlags = BroadcastFlags(0x0001_0000);
+    /// CAN booster state changes (LAN_CAN_BOOSTER_SYSTEMSTATE_CHGD).
+    pub const CAN_BOOSTER: BroadcastFlags = BroadcastFlags(0x0002_0000);
+    /// RailCom data of every loco.
+    pub const ALL_RAILCOM: BroadcastFlags = BroadcastFlags(0x0004_0000);
+    /// CAN occupancy detector messages (LAN_CAN_DETECTOR).
+    pub const CAN_DETECTOR: BroadcastFlags = BroadcastFlags(0x0008_0000);
+    /// LocoNet messages, except loco and switch messages.
+    pub const LOCONET: BroadcastFlags = BroadcastFlags(0x0100_0000);
+    /// LocoNet loco specific messages.
+    pub const LOCONET_LOCOS: BroadcastFlags = BroadcastFlags(0x0200_0000);
+    /// LocoNet switch specific messages.
+    pub const LOCONET_SWITCHES: BroadcastFlags = BroadcastFlags(0x0400_0000);
+    /// LocoNet occupancy detector messages (LAN_LOCONET_DETECTOR).
+    pub const LOCONET_DETECTOR: BroadcastFlags = BroadcastFlags(0x0800_0000);
+
+    /// Creates flags from their raw bit representation.
+    pub const fn from_bits(bits: u32) -> BroadcastFlags {
+        BroadcastFlags(bits)
+    }
+
+    /// Returns the raw bit representation.
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Returns `true` if all flags of `other` are set.
+    pub const fn contains(self, other: BroadcastFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns `true` if any flag of `other` is set.
+    pub const fn intersects(self, other: BroadcastFlags) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl BitOr for BroadcastFlags {
+    type Output = BroadcastFlags;
+
+    fn bitor(self, rhs: BroadcastFlags) -> BroadcastFlags {
+        BroadcastFlags(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for BroadcastFlags {
+    fn bitor_assign(&mut self, rhs: BroadcastFlags) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl From<u32> for BroadcastFlags {
+    fn from(bits: u32) -> Self {
+        BroadcastFlags(bits)
+    }
+}
+
+impl From<BroadcastFlags> for u32 {
+    fn from(flags: BroadcastFlags) -> Self {
+        flags.0
+    }
+}
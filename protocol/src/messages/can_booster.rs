@@ -0,0 +1,49 @@
+use crate::error::Error;
+use alloc::vec::Vec;
+
+/// State of one output of a CAN booster (LAN_CAN_BOOSTER_SYSTEMSTATE_CHGD).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CanBoosterState {
+    /// CAN network ID of the booster.
+    pub network_id: u16,
+    /// Output port of the booster.
+    pub output_port: u16,
+    /// Booster state bitmask.
+    pub state: u16,
+    /// Output voltage in mV.
+    pub vcc_voltage: u16,
+    /// Output current in mA.
+    pub current: u16,
+}
+
+impl TryFrom<&[u8]> for CanBoosterState {
+    type Error = Error;
+
+    /// Attempts to parse a `CanBoosterState` from a 10-byte slice.
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        if data.len() < 10 {
+            return Err(Error::malformed("Invalid CanBoosterState data length"));
+        }
+        Ok(CanBoosterState {
+            network_id: u16::from_le_bytes([data[0], data[1]]),
+            output_port: u16::from_le_bytes([data[2], data[3]]),
+            state: u16::from_le_bytes([data[4], data[5]]),
+            vcc_voltage: u16::from_le_bytes([data[6], data[7]]),
+            current: u16::from_le_bytes([data[8], data[9]]),
+        })
+    }
+}
+
+impl From<CanBoosterState> for Vec<u8> {
+    /// Converts a `CanBoosterState` into a 10-byte vector.
+    fn from(state: CanBoosterState) -> Vec<u8> {
+        let mut result = Vec::with_capacity(10);
+        result.extend(&state.network_id.to_le_bytes());
+        result.extend(&state.output_port.to_le_bytes());
+        result.extend(&state.state.to_le_bytes());
+        result.extend(&state.vcc_voltage.to_le_bytes());
+        result.extend(&state.current.to_le_bytes());
+        result
+    }
+}
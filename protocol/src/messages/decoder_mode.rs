@@ -0,0 +1,24 @@
+use crate::error::Error;
+
+/// Output format of a loco or accessory decoder address (LAN_GET_LOCOMODE / LAN_GET_TURNOUTMODE).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DecoderMode {
+    /// Digital Command Control.
+    #[default]
+    Dcc = 0x00,
+    /// Märklin Motorola.
+    Motorola = 0x01,
+}
+
+impl TryFrom<u8> for DecoderMode {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(DecoderMode::Dcc),
+            0x01 => Ok(DecoderMode::Motorola),
+            _ => Err(Error::malformed("Invalid coding of decoder mode")),
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use crate::error::Error;
+use alloc::{vec, vec::Vec};
+
+/// Model time of the fast clock (LAN_FAST_CLOCK_DATA).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FastClockTime {
+    /// Day of the week, 0 is Monday.
+    pub day_of_week: u8,
+    /// Hour (0-23).
+    pub hour: u8,
+    /// Minute (0-59).
+    pub minute: u8,
+    /// Acceleration factor of the model time (0 stops the clock, 1-63).
+    pub rate: u8,
+    /// `true` if the clock is stopped.
+    pub stopped: bool,
+}
+
+impl TryFrom<&[u8]> for FastClockTime {
+    type Error = Error;
+
+    /// Attempts to parse a `FastClockTime` from the data bytes `DDDHHHHH 00MMMMMM S0RRRRRR`.
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        if data.len() < 3 {
+            return Err(Error::malformed("Invalid FastClockTime data length"));
+        }
+        Ok(FastClockTime {
+            day_of_week: data[0] >> 5,
+            hour: data[0] & 0b0001_1111,
+            minute: data[1] & 0b0011_1111,
+            rate: data[2] & 0b0011_1111,
+            stopped: data[2] & 0b1000_0000 != 0,
+        })
+    }
+}
+
+impl From<FastClockTime> for Vec<u8> {
+    /// Converts a `FastClockTime` into its 3 data bytes.
+    fn from(time: FastClockTime) -> Vec<u8> {
+        vec![
+            (time.day_of_week << 5) | (time.hour & 0b0001_1111),
+            time.minute & 0b0011_1111,
+            (time.rate & 0b0011_1111) | if time.stopped { 0b1000_0000 } else { 0 },
+        ]
+    }
+}
+
+/// Commands controlling the fast clock (LAN_FAST_CLOCK_CONTROL).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FastClockControl {
+    /// Requests the current model time.
+    Read,
+    /// Sets model time and rate.
+    Set {
+        /// Day of the week, 0 is Monday.
+        day_of_week: u8,
+        /// Hour (0-23).
+        hour: u8,
+        /// Minute (0-59).
+        minute: u8,
+        /// Acceleration factor (0-63).
+        rate: u8,
+    },
+    /// Starts the fast clock.
+    Start,
+    /// Stops the fast clock.
+    Stop,
+}
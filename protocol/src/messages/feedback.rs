@@ -0,0 +1,144 @@
+use crate::error::Error;
+use alloc::vec::Vec;
+
+/// Occupancy state of an R-BUS feedback group (LAN_RMBUS_DATACHANGED).
+///
+/// A group consists of 10 feedback modules with 8 inputs each.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RBusFeedback {
+    /// Group index: 0 for modules 1-10, 1 for modules 11-20.
+    pub group: u8,
+    /// Input states, one byte per module, bit 0 is input 1.
+    pub modules: [u8; 10],
+}
+
+impl RBusFeedback {
+    /// Returns `true` if `input` (1-8) of `module` (1-20) reports occupancy.
+    ///
+    /// Returns `false` for modules which are not part of this group.
+    pub fn is_occupied(&self, module: u8, input: u8) -> bool {
+        let first_module = self.group as usize * 10 + 1;
+        let module = module as usize;
+        if module < first_module || module >= first_module + 10 || !(1..=8).contains(&input) {
+            return false;
+        }
+        self.modules[module - first_module] & (1 << (input - 1)) != 0
+    }
+}
+
+impl TryFrom<&[u8]> for RBusFeedback {
+    type Error = Error;
+
+    /// Attempts to parse an `RBusFeedback` from an 11-byte slice.
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        if data.len() < 11 {
+            return Err(Error::malformed("Invalid RBusFeedback data length"));
+        }
+        let mut modules = [0u8; 10];
+        modules.copy_from_slice(&data[1..11]);
+        Ok(RBusFeedback {
+            group: data[0],
+            modules,
+        })
+    }
+}
+
+impl From<RBusFeedback> for Vec<u8> {
+    /// Converts an `RBusFeedback` into an 11-byte vector.
+    fn from(feedback: RBusFeedback) -> Vec<u8> {
+        let mut result = Vec::with_capacity(11);
+        result.push(feedback.group);
+        result.extend(&feedback.modules);
+        result
+    }
+}
+
+/// Occupancy report of a LocoNet detector (LAN_LOCONET_DETECTOR).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LocoNetDetector {
+    /// Kind of the report (e.g. 0x01 occupancy, 0x02 transponder enter).
+    pub kind: u8,
+    /// Feedback address of the detector.
+    pub address: u16,
+    /// Report specific information bytes.
+    pub info: Vec<u8>,
+}
+
+impl TryFrom<&[u8]> for LocoNetDetector {
+    type Error = Error;
+
+    /// Attempts to parse a `LocoNetDetector` from `Type FeedbackAddress Info...`.
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        if data.len() < 3 {
+            return Err(Error::malformed("Invalid LocoNetDetector data length"));
+        }
+        Ok(LocoNetDetector {
+            kind: data[0],
+            address: u16::from_le_bytes([data[1], data[2]]),
+            info: data[3..].to_vec(),
+        })
+    }
+}
+
+impl From<LocoNetDetector> for Vec<u8> {
+    fn from(detector: LocoNetDetector) -> Vec<u8> {
+        let mut result = Vec::with_capacity(3 + detector.info.len());
+        result.push(detector.kind);
+        result.extend(&detector.address.to_le_bytes());
+        result.extend(&detector.info);
+        result
+    }
+}
+
+/// Occupancy report of a CAN detector such as the 10808 (LAN_CAN_DETECTOR).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CanDetector {
+    /// CAN network ID of the detector.
+    pub network_id: u16,
+    /// Module address.
+    pub address: u16,
+    /// Input port of the module.
+    pub port: u8,
+    /// Kind of the report (0x01 occupancy, 0x11.. RailCom addresses, ...).
+    pub kind: u8,
+    /// First report value.
+    pub value1: u16,
+    /// Second report value.
+    pub value2: u16,
+}
+
+impl TryFrom<&[u8]> for CanDetector {
+    type Error = Error;
+
+    /// Attempts to parse a `CanDetector` from a 10-byte slice.
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        if data.len() < 10 {
+            return Err(Error::malformed("Invalid CanDetector data length"));
+        }
+        Ok(CanDetector {
+            network_id: u16::from_le_bytes([data[0], data[1]]),
+            address: u16::from_le_bytes([data[2], data[3]]),
+            port: data[4],
+            kind: data[5],
+            value1: u16::from_le_bytes([data[6], data[7]]),
+            value2: u16::from_le_bytes([data[8], data[9]]),
+        })
+    }
+}
+
+impl From<CanDetector> for Vec<u8> {
+    /// Converts a `CanDetector` into a 10-byte vector.
+    fn from(detector: CanDetector) -> Vec<u8> {
+        let mut result = Vec::with_capacity(10);
+        result.extend(&detector.network_id.to_le_bytes());
+        result.extend(&detector.address.to_le_bytes());
+        result.push(detector.port);
+        result.push(detector.kind);
+        result.extend(&detector.value1.to_le_bytes());
+        result.extend(&detector.value2.to_le_bytes());
+        result
+    }
+}
@@ -0,0 +1,128 @@
+use crate::error::Error;
+use alloc::vec::Vec;
+
+/// Hardware variant of a Z21 family device, as reported in LAN_GET_HWINFO.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum HardwareType {
+    /// Black Z21 (hardware variant from 2012).
+    Z21Old,
+    /// Black Z21 (hardware variant from 2013).
+    Z21New,
+    /// SmartRail (from 2012).
+    SmartRail,
+    /// White z21 starter set variant (from 2013).
+    Z21Small,
+    /// z21 start starter set variant (from 2016).
+    Z21Start,
+    /// Z21 XL Series (from 2020).
+    Z21Xl,
+    /// 10806 Z21 single booster.
+    SingleBooster,
+    /// 10807 Z21 dual booster.
+    DualBooster,
+    /// 10869 Z21 XL booster.
+    XlBooster,
+    /// 10836 Z21 switch decoder.
+    SwitchDecoder,
+    /// 10836 Z21 signal decoder.
+    SignalDecoder,
+    /// Any hardware type not known to this crate.
+    Unknown(u32),
+}
+
+impl From<u32> for HardwareType {
+    fn from(value: u32) -> Self {
+        match value {
+            0x0000_0200 => HardwareType::Z21Old,
+            0x0000_0201 => HardwareType::Z21New,
+            0x0000_0202 => HardwareType::SmartRail,
+            0x0000_0203 => HardwareType::Z21Small,
+            0x0000_0204 => HardwareType::Z21Start,
+            0x0000_0211 => HardwareType::Z21Xl,
+            0x0000_0205 => HardwareType::SingleBooster,
+            0x0000_0206 => HardwareType::DualBooster,
+            0x0000_0212 => HardwareType::XlBooster,
+            0x0000_0301 => HardwareType::SwitchDecoder,
+            0x0000_0302 => HardwareType::SignalDecoder,
+            other => HardwareType::Unknown(other),
+        }
+    }
+}
+
+impl From<HardwareType> for u32 {
+    fn from(value: HardwareType) -> Self {
+        match value {
+            HardwareType::Z21Old => 0x0000_0200,
+            HardwareType::Z21New => 0x0000_0201,
+            HardwareType::SmartRail => 0x0000_0202,
+            HardwareType::Z21Small => 0x0000_0203,
+            HardwareType::Z21Start => 0x0000_0204,
+            HardwareType::Z21Xl => 0x0000_0211,
+            HardwareType::SingleBooster => 0x0000_0205,
+            HardwareType::DualBooster => 0x0000_0206,
+            HardwareType::XlBooster => 0x0000_0212,
+            HardwareType::SwitchDecoder => 0x0000_0301,
+            HardwareType::SignalDecoder => 0x0000_0302,
+            HardwareType::Unknown(other) => other,
+        }
+    }
+}
+
+/// Hardware type and firmware version as reported by the Z21 station.
+///
+/// The structure corresponds to 8 bytes of data in the LAN_GET_HWINFO reply.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HardwareInfo {
+    /// Hardware variant of the device.
+    pub hardware_type: HardwareType,
+    /// Firmware major version (decoded from BCD).
+    pub firmware_major: u8,
+    /// Firmware minor version (decoded from BCD).
+    pub firmware_minor: u8,
+}
+
+/// Decodes a single BCD encoded byte, e.g. `0x42` into `42`.
+pub(crate) fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+/// Encodes a value below 100 into a single BCD byte, e.g. `42` into `0x42`.
+pub(crate) fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+impl TryFrom<&[u8]> for HardwareInfo {
+    type Error = Error;
+
+    /// Attempts to parse a `HardwareInfo` from an 8-byte slice.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the provided slice is shorter than 8 bytes.
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        if data.len() < 8 {
+            return Err(Error::malformed("Invalid HardwareInfo data length"));
+        }
+        let hardware_type = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        // Firmware version is BCD coded, e.g. 0x00000120 is V1.20
+        Ok(HardwareInfo {
+            hardware_type: HardwareType::from(hardware_type),
+            firmware_major: from_bcd(data[5]),
+            firmware_minor: from_bcd(data[4]),
+        })
+    }
+}
+
+impl From<HardwareInfo> for Vec<u8> {
+    /// Converts a `HardwareInfo` into an 8-byte vector.
+    fn from(info: HardwareInfo) -> Vec<u8> {
+        let mut result = Vec::with_capacity(8);
+        result.extend(&u32::from(info.hardware_type).to_le_bytes());
+        result.push(to_bcd(info.firmware_minor));
+        result.push(to_bcd(info.firmware_major));
+        result.extend(&[0, 0]);
+        result
+    }
+}
@@ -0,0 +1,99 @@
+//! Header and X-Header constants of the Z21 LAN protocol.
+//!
+//! The names follow the official "Z21 LAN Protocol Specification" so they can be
+//! looked up there directly.
+
+pub use super::xbus_message::XBUS_HEADER;
+
+// LAN headers (client to Z21 and Z21 to client).
+pub const LAN_GET_SERIAL_NUMBER: u16 = 0x10;
+pub const LAN_GET_CODE: u16 = 0x18;
+pub const LAN_GET_HWINFO: u16 = 0x1A;
+pub const LAN_LOGOFF: u16 = 0x30;
+pub const LAN_X: u16 = XBUS_HEADER;
+pub const LAN_SET_BROADCASTFLAGS: u16 = 0x50;
+pub const LAN_GET_BROADCASTFLAGS: u16 = 0x51;
+pub const LAN_GET_LOCOMODE: u16 = 0x60;
+pub const LAN_SET_LOCOMODE: u16 = 0x61;
+pub const LAN_GET_TURNOUTMODE: u16 = 0x70;
+pub const LAN_SET_TURNOUTMODE: u16 = 0x71;
+pub const LAN_RMBUS_DATACHANGED: u16 = 0x80;
+pub const LAN_RMBUS_GETDATA: u16 = 0x81;
+pub const LAN_RMBUS_PROGRAMMODULE: u16 = 0x82;
+pub const LAN_SYSTEMSTATE_DATACHANGED: u16 = 0x84;
+pub const LAN_SYSTEMSTATE_GETDATA: u16 = 0x85;
+pub const LAN_RAILCOM_DATACHANGED: u16 = 0x88;
+pub const LAN_RAILCOM_GETDATA: u16 = 0x89;
+pub const LAN_LOCONET_Z21_RX: u16 = 0xA0;
+pub const LAN_LOCONET_Z21_TX: u16 = 0xA1;
+pub const LAN_LOCONET_FROM_LAN: u16 = 0xA2;
+pub const LAN_LOCONET_DISPATCH_ADDR: u16 = 0xA3;
+pub const LAN_LOCONET_DETECTOR: u16 = 0xA4;
+pub const LAN_CAN_DETECTOR: u16 = 0xC4;
+pub const LAN_CAN_DEVICE_GET_DESCRIPTION: u16 = 0xC8;
+pub const LAN_CAN_DEVICE_SET_DESCRIPTION: u16 = 0xC9;
+pub const LAN_CAN_BOOSTER_SYSTEMSTATE_CHGD: u16 = 0xCA;
+pub const LAN_CAN_BOOSTER_SET_TRACKPOWER: u16 = 0xCB;
+pub const LAN_FAST_CLOCK_CONTROL: u16 = 0xCC;
+pub const LAN_FAST_CLOCK_DATA: u16 = 0xCD;
+
+// X-Headers carried inside LAN_X datasets.
+pub const X_GET: u8 = 0x21;
+pub const X_BC: u8 = 0x61;
+pub const X_STATUS_CHANGED: u8 = 0x62;
+pub const X_GET_VERSION_REPLY: u8 = 0x63;
+pub const X_CV_RESULT: u8 = 0x64;
+pub const X_SET_STOP: u8 = 0x80;
+pub const X_BC_STOPPED: u8 = 0x81;
+pub const X_SET_LOCO_E_STOP: u8 = 0x92;
+pub const X_CV_READ: u8 = 0x23;
+pub const X_CV_WRITE: u8 = 0x24;
+pub const X_TURNOUT_INFO: u8 = 0x43;
+pub const X_EXT_ACCESSORY_INFO: u8 = 0x44;
+pub const X_SET_TURNOUT: u8 = 0x53;
+pub const X_SET_EXT_ACCESSORY: u8 = 0x54;
+pub const X_LOCO_GET_INFO: u8 = 0xE3;
+pub const X_LOCO_SET: u8 = 0xE4;
+pub const X_LOCO_SET_BINARY_STATE: u8 = 0xE5;
+pub const X_CV_POM: u8 = 0xE6;
+pub const X_LOCO_INFO: u8 = 0xEF;
+pub const X_GET_FIRMWARE_VERSION: u8 = 0xF1;
+pub const X_GET_FIRMWARE_VERSION_REPLY: u8 = 0xF3;
+
+// DB0 values qualifying the X-Headers above.
+pub const DB0_GET_VERSION: u8 = 0x21;
+pub const DB0_GET_STATUS: u8 = 0x24;
+pub const DB0_SET_TRACK_POWER_OFF: u8 = 0x80;
+pub const DB0_SET_TRACK_POWER_ON: u8 = 0x81;
+pub const DB0_BC_TRACK_POWER_OFF: u8 = 0x00;
+pub const DB0_BC_TRACK_POWER_ON: u8 = 0x01;
+pub const DB0_BC_PROGRAMMING_MODE: u8 = 0x02;
+pub const DB0_BC_TRACK_SHORT_CIRCUIT: u8 = 0x08;
+pub const DB0_CV_NACK_SC: u8 = 0x12;
+pub const DB0_CV_NACK: u8 = 0x13;
+pub const DB0_UNKNOWN_COMMAND: u8 = 0x82;
+pub const DB0_STATUS_CHANGED: u8 = 0x22;
+pub const DB0_CV_READ: u8 = 0x11;
+pub const DB0_CV_WRITE: u8 = 0x12;
+pub const DB0_CV_RESULT: u8 = 0x14;
+pub const DB0_LOCO_GET_INFO: u8 = 0xF0;
+pub const DB0_LOCO_PURGE: u8 = 0x44;
+pub const DB0_LOCO_FUNCTION: u8 = 0xF8;
+pub const DB0_LOCO_BINARY_STATE: u8 = 0x5F;
+pub const DB0_CV_POM: u8 = 0x30;
+pub const DB0_FIRMWARE_VERSION: u8 = 0x0A;
+
+// Option bits of LAN_X_CV_POM_* commands (upper 6 bits of DB3).
+pub const POM_WRITE_BYTE: u8 = 0xEC;
+pub const POM_WRITE_BIT: u8 = 0xE8;
+pub const POM_READ_BYTE: u8 = 0xE4;
+
+// X-Headers and DB0 values carried inside LAN_FAST_CLOCK_* datasets.
+pub const X_FAST_CLOCK_GET: u8 = 0x21;
+pub const X_FAST_CLOCK_SET: u8 = 0x24;
+pub const X_FAST_CLOCK_DATA: u8 = 0x66;
+pub const DB0_FAST_CLOCK_READ: u8 = 0x2A;
+pub const DB0_FAST_CLOCK_SET: u8 = 0x2B;
+pub const DB0_FAST_CLOCK_START: u8 = 0x2C;
+pub const DB0_FAST_CLOCK_STOP: u8 = 0x2D;
+pub const DB0_FAST_CLOCK_DATA: u8 = 0x25;
@@ -1,15 +1,19 @@
-use tokio::io;
+use crate::error::Error;
+use alloc::{vec, vec::Vec};
 
 use super::XBusMessage;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DccThrottleSteps {
     Steps14 = 0x10,
     Steps28 = 0x12,
+    #[default]
     Steps128 = 0x13,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LocoState {
     /// Address of the locomotive.
     pub address: u16,
@@ -28,7 +32,7 @@ pub struct LocoState {
     pub functions: Option<[bool; 32]>,
 }
 impl TryFrom<&[u8]> for LocoState {
-    type Error = io::Error;
+    type Error = Error;
 
     /// Attempts to parse a `LocoState` from a n byte slice
     ///
@@ -41,7 +45,7 @@ impl TryFrom<&[u8]> for LocoState {
 }
 
 impl TryFrom<&XBusMessage> for LocoState {
-    type Error = io::Error;
+    type Error = Error;
 
     /// Attempts to parse a `LocoState` from a n byte slice
     ///
@@ -52,10 +56,7 @@ impl TryFrom<&XBusMessage> for LocoState {
         let data = data.get_dbs();
         let len = data.len();
         if len <= 1 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Invalid LocoState data length",
-            ));
+            return Err(Error::malformed("Invalid LocoState data length"));
         }
         // The two highest bits in Adr_MSB must be ignored
         let addr = [data[0] & 0b00111111, data[1]];
@@ -77,10 +78,7 @@ impl TryFrom<&XBusMessage> for LocoState {
             } else if (data[2] & STEPPING_MASK) == 4 {
                 Some(DccThrottleSteps::Steps128)
             } else {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Invalid coding of DCC Stepping",
-                ));
+                return Err(Error::malformed("Invalid coding of DCC Stepping"));
             };
         }
         if len >= 4 {
@@ -142,3 +140,75 @@ impl TryFrom<&XBusMessage> for LocoState {
         })
     }
 }
+
+/// Encodes a loco address into `Adr_MSB Adr_LSB` as used by the X-Bus loco commands.
+///
+/// Addresses from 128 upwards are long addresses, flagged by the two highest bits of Adr_MSB.
+pub(crate) fn loco_address_to_bytes(address: u16) -> [u8; 2] {
+    let mut bytes = address.to_be_bytes();
+    if address >= 128 {
+        bytes[0] |= 0b1100_0000;
+    }
+    bytes
+}
+
+/// Decodes `Adr_MSB Adr_LSB` of the X-Bus loco commands, ignoring the long address flag.
+pub(crate) fn loco_address_from_bytes(msb: u8, lsb: u8) -> u16 {
+    u16::from_be_bytes([msb & 0b0011_1111, lsb])
+}
+
+impl From<LocoState> for Vec<u8> {
+    /// Converts a `LocoState` into the X-Bus data bytes of LAN_X_LOCO_INFO.
+    ///
+    /// Fields which are `None` are encoded as zero.
+    fn from(state: LocoState) -> Vec<u8> {
+        let addr = loco_address_to_bytes(state.address);
+        let stepping = state.stepping.unwrap_or_default();
+        let mut db2 = match stepping {
+            DccThrottleSteps::Steps14 => 0,
+            DccThrottleSteps::Steps28 => 2,
+            DccThrottleSteps::Steps128 => 4,
+        };
+        if state.is_busy == Some(true) {
+            db2 |= 0b0000_1000;
+        }
+
+        let speed = state.speed_percentage.unwrap_or(0.);
+        let steps = match stepping {
+            DccThrottleSteps::Steps14 => 14.,
+            DccThrottleSteps::Steps28 => 28.,
+            DccThrottleSteps::Steps128 => 128.,
+        };
+        // Rounded by hand, `f64::round` needs std.
+        let raw_speed = ((speed.abs() / 100.) * steps + 0.5).min(127.) as u8;
+        let db3 = raw_speed | if speed.is_sign_positive() { 0x80 } else { 0 };
+
+        let functions = state.functions.unwrap_or([false; 32]);
+        let mut db4 = (functions[0] as u8) << 4
+            | (functions[4] as u8) << 3
+            | (functions[3] as u8) << 2
+            | (functions[2] as u8) << 1
+            | functions[1] as u8;
+        if state.double_traction == Some(true) {
+            db4 |= 0b0100_0000;
+        }
+        if state.smart_search == Some(true) {
+            db4 |= 0b0010_0000;
+        }
+        let pack = |first: usize, count: usize| {
+            (0..count).fold(0u8, |acc, i| acc | (functions[first + i] as u8) << i)
+        };
+
+        vec![
+            addr[0],
+            addr[1],
+            db2,
+            db3,
+            db4,
+            pack(5, 8),
+            pack(13, 8),
+            pack(21, 8),
+            pack(29, 3),
+        ]
+    }
+}
@@ -0,0 +1,55 @@
+use crate::error::Error;
+use alloc::vec::Vec;
+
+/// RailCom data of a loco as reported in LAN_RAILCOM_DATACHANGED.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RailComData {
+    /// Address of the loco.
+    pub address: u16,
+    /// Number of RailCom messages received from the decoder.
+    pub receive_counter: u32,
+    /// Number of RailCom receive errors.
+    pub error_counter: u16,
+    /// Option bits (speed 1/2 present, QoS present).
+    pub options: u8,
+    /// Speed reported by the decoder.
+    pub speed: u8,
+    /// Quality of service reported by the decoder.
+    pub qos: u8,
+}
+
+impl TryFrom<&[u8]> for RailComData {
+    type Error = Error;
+
+    /// Attempts to parse a `RailComData` from a 13-byte slice.
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        if data.len() < 13 {
+            return Err(Error::malformed("Invalid RailComData data length"));
+        }
+        Ok(RailComData {
+            address: u16::from_le_bytes([data[0], data[1]]),
+            receive_counter: u32::from_le_bytes([data[2], data[3], data[4], data[5]]),
+            error_counter: u16::from_le_bytes([data[6], data[7]]),
+            options: data[9],
+            speed: data[10],
+            qos: data[11],
+        })
+    }
+}
+
+impl From<RailComData> for Vec<u8> {
+    /// Converts a `RailComData` into a 13-byte vector.
+    fn from(data: RailComData) -> Vec<u8> {
+        let mut result = Vec::with_capacity(13);
+        result.extend(&data.address.to_le_bytes());
+        result.extend(&data.receive_counter.to_le_bytes());
+        result.extend(&data.error_counter.to_le_bytes());
+        result.push(0);
+        result.push(data.options);
+        result.push(data.speed);
+        result.push(data.qos);
+        result.push(0);
+        result
+    }
+}
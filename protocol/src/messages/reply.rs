@@ -0,0 +1,446 @@
+use super::hardware_info::{from_bcd, to_bcd};
+use super::header::*;
+use super::request::{xbus_packet, xbus_payload};
+use super::{
+    AccessoryInfo, BroadcastFlags, CanBoosterState, CanDetector, DecoderMode, FastClockTime,
+    HardwareInfo, LocoNetDetector, LocoState, RBusFeedback, RailComData, SystemState, TurnoutInfo,
+    XBusMessage,
+};
+use crate::error::{Error, Result};
+use crate::packet::Packet;
+use alloc::{format, string::String, vec, vec::Vec};
+
+/// A message sent from the Z21 station to a LAN client.
+///
+/// This covers both direct replies to a [`Request`](super::Request) and unsolicited
+/// broadcasts. Messages which are not modelled explicitly are kept as [`Reply::XBus`]
+/// and [`Reply::Other`].
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Reply {
+    /// Reply to LAN_GET_SERIAL_NUMBER.
+    SerialNumber(u32),
+    /// Reply to LAN_GET_CODE (0x00 all features, 0x01 z21 start locked, 0x02 unlocked).
+    Code(u8),
+    /// Reply to LAN_GET_HWINFO.
+    HardwareInfo(HardwareInfo),
+    /// Reply to LAN_GET_BROADCASTFLAGS.
+    BroadcastFlags(BroadcastFlags),
+    /// Reply to LAN_GET_LOCOMODE.
+    LocoMode { address: u16, mode: DecoderMode },
+    /// Reply to LAN_GET_TURNOUTMODE.
+    TurnoutMode { address: u16, mode: DecoderMode },
+    /// LAN_X_GET_VERSION reply.
+    XBusVersion { version: u8, station_id: u8 },
+    /// LAN_X_STATUS_CHANGED with the central state bitmask.
+    Status(u8),
+    /// LAN_X_BC_TRACK_POWER_OFF
+    TrackPowerOff,
+    /// LAN_X_BC_TRACK_POWER_ON
+    TrackPowerOn,
+    /// LAN_X_BC_PROGRAMMING_MODE
+    ProgrammingMode,
+    /// LAN_X_BC_TRACK_SHORT_CIRCUIT
+    TrackShortCircuit,
+    /// LAN_X_CV_NACK_SC
+    CvNackShortCircuit,
+    /// LAN_X_CV_NACK
+    CvNack,
+    /// LAN_X_UNKNOWN_COMMAND
+    UnknownCommand,
+    /// LAN_X_BC_STOPPED
+    Stopped,
+    /// LAN_X_GET_FIRMWARE_VERSION reply.
+    FirmwareVersion { major: u8, minor: u8 },
+    /// LAN_X_LOCO_INFO
+    LocoInfo(LocoState),
+    /// LAN_X_TURNOUT_INFO
+    TurnoutInfo(TurnoutInfo),
+    /// LAN_X_EXT_ACCESSORY_INFO
+    ExtAccessoryInfo(AccessoryInfo),
+    /// LAN_X_CV_RESULT, `cv` is 1 based.
+    CvResult { cv: u16, value: u8 },
+    /// LAN_RMBUS_DATACHANGED
+    RBusDataChanged(RBusFeedback),
+    /// LAN_SYSTEMSTATE_DATACHANGED
+    SystemStateDataChanged(SystemState),
+    /// LAN_RAILCOM_DATACHANGED
+    RailComDataChanged(RailComData),
+    /// LAN_LOCONET_Z21_RX, a LocoNet message received by the Z21.
+    LocoNetRx(Vec<u8>),
+    /// LAN_LOCONET_Z21_TX, a LocoNet message sent by the Z21.
+    LocoNetTx(Vec<u8>),
+    /// LAN_LOCONET_FROM_LAN, a LocoNet message sent by another LAN client.
+    LocoNetFromLan(Vec<u8>),
+    /// Reply to LAN_LOCONET_DISPATCH_ADDR.
+    LocoNetDispatchAddr { address: u16, result: u8 },
+    /// LAN_LOCONET_DETECTOR
+    LocoNetDetector(LocoNetDetector),
+    /// LAN_CAN_DETECTOR
+    CanDetector(CanDetector),
+    /// Reply to LAN_CAN_DEVICE_GET_DESCRIPTION.
+    CanDeviceDescription { network_id: u16, name: String },
+    /// LAN_CAN_BOOSTER_SYSTEMSTATE_CHGD
+    CanBoosterSystemState(CanBoosterState),
+    /// LAN_FAST_CLOCK_DATA
+    FastClockData(FastClockTime),
+    /// Any other X-Bus message.
+    XBus(XBusMessage),
+    /// Any other LAN message.
+    Other(Packet),
+}
+
+fn require(data: &[u8], len: usize, what: &str) -> Result<()> {
+    if data.len() < len {
+        return Err(Error::malformed(format!("Invalid {} data length", what)));
+    }
+    Ok(())
+}
+
+impl Reply {
+    /// Encodes the reply into a [`Packet`] as the Z21 station would send it.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Protocol`] if the payload does not fit into a dataset.
+    pub fn encode(&self) -> Result<Packet> {
+        let packet = match self {
+            Reply::SerialNumber(serial) => {
+                Packet::with_header_and_data(LAN_GET_SERIAL_NUMBER, &serial.to_le_bytes())?
+            }
+            Reply::Code(code) => Packet::with_header_and_data(LAN_GET_CODE, &[*code])?,
+            Reply::HardwareInfo(info) => {
+                let data: Vec<u8> = info.clone().into();
+                Packet::with_header_and_data(LAN_GET_HWINFO, &data)?
+            }
+            Reply::BroadcastFlags(flags) => {
+                Packet::with_header_and_data(LAN_GET_BROADCASTFLAGS, &flags.bits().to_le_bytes())?
+            }
+            Reply::LocoMode { address, mode } => {
+                let addr = address.to_be_bytes();
+                Packet::with_header_and_data(LAN_GET_LOCOMODE, &[addr[0], addr[1], *mode as u8])?
+            }
+            Reply::TurnoutMode { address, mode } => {
+                let addr = address.to_be_bytes();
+                Packet::with_header_and_data(LAN_GET_TURNOUTMODE, &[addr[0], addr[1], *mode as u8])?
+            }
+            Reply::XBusVersion {
+                version,
+                station_id,
+            } => xbus_packet(
+                X_GET_VERSION_REPLY,
+                vec![DB0_GET_VERSION, *version, *station_id],
+            )?,
+            Reply::Status(status) => {
+                xbus_packet(X_STATUS_CHANGED, vec![DB0_STATUS_CHANGED, *status])?
+            }
+            Reply::TrackPowerOff => xbus_packet(X_BC, vec![DB0_BC_TRACK_POWER_OFF])?,
+            Reply::TrackPowerOn => xbus_packet(X_BC, vec![DB0_BC_TRACK_POWER_ON])?,
+            Reply::ProgrammingMode => xbus_packet(X_BC, vec![DB0_BC_PROGRAMMING_MODE])?,
+            Reply::TrackShortCircuit => xbus_packet(X_BC, vec![DB0_BC_TRACK_SHORT_CIRCUIT])?,
+            Reply::CvNackShortCircuit => xbus_packet(X_BC, vec![DB0_CV_NACK_SC])?,
+            Reply::CvNack => xbus_packet(X_BC, vec![DB0_CV_NACK])?,
+            Reply::UnknownCommand => xbus_packet(X_BC, vec![DB0_UNKNOWN_COMMAND])?,
+            Reply::Stopped => xbus_packet(X_BC_STOPPED, vec![0x00])?,
+            Reply::FirmwareVersion { major, minor } => xbus_packet(
+                X_GET_FIRMWARE_VERSION_REPLY,
+                vec![DB0_FIRMWARE_VERSION, to_bcd(*major), to_bcd(*minor)],
+            )?,
+            Reply::LocoInfo(state) => xbus_packet(X_LOCO_INFO, state.clone().into())?,
+            Reply::TurnoutInfo(info) => xbus_packet(X_TURNOUT_INFO, info.clone().into())?,
+            Reply::ExtAccessoryInfo(info) => {
+                xbus_packet(X_EXT_ACCESSORY_INFO, info.clone().into())?
+            }
+            Reply::CvResult { cv, value } => {
+                let cv = cv.saturating_sub(1).to_be_bytes();
+                xbus_packet(X_CV_RESULT, vec![DB0_CV_RESULT, cv[0], cv[1], *value])?
+            }
+            Reply::RBusDataChanged(feedback) => {
+                let data: Vec<u8> = feedback.clone().into();
+                Packet::with_header_and_data(LAN_RMBUS_DATACHANGED, &data)?
+            }
+            Reply::SystemStateDataChanged(state) => {
+                let data: Vec<u8> = state.clone().into();
+                Packet::with_header_and_data(LAN_SYSTEMSTATE_DATACHANGED, &data)?
+            }
+            Reply::RailComDataChanged(data) => {
+                let data: Vec<u8> = data.clone().into();
+                Packet::with_header_and_data(LAN_RAILCOM_DATACHANGED, &data)?
+            }
+            Reply::LocoNetRx(message) => Packet::with_header_and_data(LAN_LOCONET_Z21_RX, message)?,
+            Reply::LocoNetTx(message) => Packet::with_header_and_data(LAN_LOCONET_Z21_TX, message)?,
+            Reply::LocoNetFromLan(message) => {
+                Packet::with_header_and_data(LAN_LOCONET_FROM_LAN, message)?
+            }
+            Reply::LocoNetDispatchAddr { address, result } => {
+                let addr = address.to_le_bytes();
+                Packet::with_header_and_data(
+                    LAN_LOCONET_DISPATCH_ADDR,
+                    &[addr[0], addr[1], *result],
+                )?
+            }
+            Reply::LocoNetDetector(detector) => {
+                let data: Vec<u8> = detector.clone().into();
+                Packet::with_header_and_data(LAN_LOCONET_DETECTOR, &data)?
+            }
+            Reply::CanDetector(detector) => {
+                let data: Vec<u8> = detector.clone().into();
+                Packet::with_header_and_data(LAN_CAN_DETECTOR, &data)?
+            }
+            Reply::CanDeviceDescription { network_id, name } => {
+                let mut data = network_id.to_le_bytes().to_vec();
+                let mut name_bytes = [0u8; 16];
+                for (dst, src) in name_bytes.iter_mut().zip(name.bytes().take(15)) {
+                    *dst = src;
+                }
+                data.extend(&name_bytes);
+                Packet::with_header_and_data(LAN_CAN_DEVICE_GET_DESCRIPTION, &data)?
+            }
+            Reply::CanBoosterSystemState(state) => {
+                let data: Vec<u8> = state.clone().into();
+                Packet::with_header_and_data(LAN_CAN_BOOSTER_SYSTEMSTATE_CHGD, &data)?
+            }
+            Reply::FastClockData(time) => {
+                let mut dbs = vec![DB0_FAST_CLOCK_DATA];
+                dbs.extend(Vec::<u8>::from(time.clone()));
+                dbs.push(0x00);
+                let data: Vec<u8> = XBusMessage::new_dbs_vec(X_FAST_CLOCK_DATA, dbs).into();
+                Packet::with_header_and_data(LAN_FAST_CLOCK_DATA, &data)?
+            }
+            Reply::XBus(msg) => {
+                let data: Vec<u8> = msg.clone().into();
+                Packet::with_header_and_data(LAN_X, &data)?
+            }
+            Reply::Other(packet) => packet.clone(),
+        };
+        Ok(packet)
+    }
+
+    /// Decodes a [`Packet`] received from the Z21 station into a reply.
+    ///
+    /// Packets with unknown headers are returned as [`Reply::Other`], unknown X-Bus
+    /// messages as [`Reply::XBus`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`Error`] if the payload of a known reply is malformed or the X-Bus
+    /// checksum is wrong.
+    pub fn decode(packet: &Packet) -> Result<Reply> {
+        let data = packet.get_data();
+        let reply = match packet.get_header() {
+            LAN_GET_SERIAL_NUMBER => {
+                require(&data, 4, "LAN_GET_SERIAL_NUMBER")?;
+                Reply::SerialNumber(u32::from_le_bytes([data[0], data[1], data[2], data[3]]))
+            }
+            LAN_GET_CODE => {
+                require(&data, 1, "LAN_GET_CODE")?;
+                Reply::Code(data[0])
+            }
+            LAN_GET_HWINFO => Reply::HardwareInfo(HardwareInfo::try_from(&data[..])?),
+            LAN_GET_BROADCASTFLAGS => {
+                require(&data, 4, "LAN_GET_BROADCASTFLAGS")?;
+                let bits = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                Reply::BroadcastFlags(BroadcastFlags::from_bits(bits))
+            }
+            LAN_GET_LOCOMODE => {
+                require(&data, 3, "LAN_GET_LOCOMODE")?;
+                Reply::LocoMode {
+                    address: u16::from_be_bytes([data[0], data[1]]),
+                    mode: DecoderMode::try_from(data[2])?,
+                }
+            }
+            LAN_GET_TURNOUTMODE => {
+                require(&data, 3, "LAN_GET_TURNOUTMODE")?;
+                Reply::TurnoutMode {
+                    address: u16::from_be_bytes([data[0], data[1]]),
+                    mode: DecoderMode::try_from(data[2])?,
+                }
+            }
+            LAN_X => Self::decode_xbus(xbus_payload(packet)?)?,
+            LAN_RMBUS_DATACHANGED => Reply::RBusDataChanged(RBusFeedback::try_from(&data[..])?),
+            LAN_SYSTEMSTATE_DATACHANGED => {
+                Reply::SystemStateDataChanged(SystemState::try_from(&data[..])?)
+            }
+            LAN_RAILCOM_DATACHANGED => Reply::RailComDataChanged(RailComData::try_from(&data[..])?),
+            LAN_LOCONET_Z21_RX => Reply::LocoNetRx(data),
+            LAN_LOCONET_Z21_TX => Reply::LocoNetTx(data),
+            LAN_LOCONET_FROM_LAN => Reply::LocoNetFromLan(data),
+            LAN_LOCONET_DISPATCH_ADDR => {
+                require(&data, 3, "LAN_LOCONET_DISPATCH_ADDR")?;
+                Reply::LocoNetDispatchAddr {
+                    address: u16::from_le_bytes([data[0], data[1]]),
+                    result: data[2],
+                }
+            }
+            LAN_LOCONET_DETECTOR => Reply::LocoNetDetector(LocoNetDetector::try_from(&data[..])?),
+            LAN_CAN_DETECTOR => Reply::CanDetector(CanDetector::try_from(&data[..])?),
+            LAN_CAN_DEVICE_GET_DESCRIPTION => {
+                require(&data, 2, "LAN_CAN_DEVICE_GET_DESCRIPTION")?;
+                let name = data[2..]
+                    .iter()
+                    .take_while(|&&b| b != 0)
+                    .map(|&b| b as char)
+                    .collect();
+                Reply::CanDeviceDescription {
+                    network_id: u16::from_le_bytes([data[0], data[1]]),
+                    name,
+                }
+            }
+            LAN_CAN_BOOSTER_SYSTEMSTATE_CHGD => {
+                Reply::CanBoosterSystemState(CanBoosterState::try_from(&data[..])?)
+            }
+            LAN_FAST_CLOCK_DATA => {
+                let msg = XBusMessage::try_from(&data[..])?;
+                let dbs = msg.get_dbs();
+                if msg.get_x_header() != X_FAST_CLOCK_DATA
+                    || dbs.first() != Some(&DB0_FAST_CLOCK_DATA)
+                {
+                    return Ok(Reply::Other(packet.clone()));
+                }
+                Reply::FastClockData(FastClockTime::try_from(&dbs[1..])?)
+            }
+            _ => Reply::Other(packet.clone()),
+        };
+        Ok(reply)
+    }
+
+    /// Returns the broadcast flags of which a client needs at least one to receive this
+    /// message as an unsolicited broadcast, or `None` if it is only sent as a direct reply.
+    ///
+    /// LAN_X_LOCO_INFO is sent to clients with [`BroadcastFlags::DRIVING_SWITCHING`] only
+    /// for the locos they asked for; [`BroadcastFlags::ALL_LOCO_INFO`] covers every loco.
+    pub fn broadcast_flag(&self) -> Option<BroadcastFlags> {
+        let flags = match self {
+            Reply::TrackPowerOff
+            | Reply::TrackPowerOn
+            | Reply::ProgrammingMode
+            | Reply::TrackShortCircuit
+            | Reply::Stopped
+            | Reply::TurnoutInfo(_)
+            | Reply::ExtAccessoryInfo(_) => BroadcastFlags::DRIVING_SWITCHING,
+            Reply::LocoInfo(_) => BroadcastFlags::DRIVING_SWITCHING | BroadcastFlags::ALL_LOCO_INFO,
+            Reply::RBusDataChanged(_) => BroadcastFlags::RBUS,
+            Reply::RailComDataChanged(_) => BroadcastFlags::RAILCOM | BroadcastFlags::ALL_RAILCOM,
+            Reply::SystemStateDataChanged(_) => BroadcastFlags::SYSTEM_STATE,
+            Reply::FastClockData(_) => BroadcastFlags::FAST_CLOCK,
+            Reply::CanBoosterSystemState(_) => BroadcastFlags::CAN_BOOSTER,
+            Reply::CanDetector(_) => BroadcastFlags::CAN_DETECTOR,
+            Reply::LocoNetRx(_) | Reply::LocoNetTx(_) | Reply::LocoNetFromLan(_) => {
+                BroadcastFlags::LOCONET
+            }
+            Reply::LocoNetDetector(_) => BroadcastFlags::LOCONET_DETECTOR,
+            _ => return None,
+        };
+        Some(flags)
+    }
+
+    fn decode_xbus(msg: XBusMessage) -> Result<Reply> {
+        let dbs = msg.get_dbs();
+        let reply = match (msg.get_x_header(), dbs.first().copied()) {
+            (X_BC, Some(DB0_BC_TRACK_POWER_OFF)) => Reply::TrackPowerOff,
+            (X_BC, Some(DB0_BC_TRACK_POWER_ON)) => Reply::TrackPowerOn,
+            (X_BC, Some(DB0_BC_PROGRAMMING_MODE)) => Reply::ProgrammingMode,
+            (X_BC, Some(DB0_BC_TRACK_SHORT_CIRCUIT)) => Reply::TrackShortCircuit,
+            (X_BC, Some(DB0_CV_NACK_SC)) => Reply::CvNackShortCircuit,
+            (X_BC, Some(DB0_CV_NACK)) => Reply::CvNack,
+            (X_BC, Some(DB0_UNKNOWN_COMMAND)) => Reply::UnknownCommand,
+            (X_BC_STOPPED, Some(0x00)) => Reply::Stopped,
+            (X_STATUS_CHANGED, Some(DB0_STATUS_CHANGED)) => {
+                require(dbs, 2, "LAN_X_STATUS_CHANGED")?;
+                Reply::Status(dbs[1])
+            }
+            (X_GET_VERSION_REPLY, Some(DB0_GET_VERSION)) => {
+                require(dbs, 3, "LAN_X_GET_VERSION")?;
+                Reply::XBusVersion {
+                    version: dbs[1],
+                    station_id: dbs[2],
+                }
+            }
+            (X_GET_FIRMWARE_VERSION_REPLY, Some(DB0_FIRMWARE_VERSION)) => {
+                require(dbs, 3, "LAN_X_GET_FIRMWARE_VERSION")?;
+                Reply::FirmwareVersion {
+                    major: from_bcd(dbs[1]),
+                    minor: from_bcd(dbs[2]),
+                }
+            }
+            (X_LOCO_INFO, Some(_)) => Reply::LocoInfo(LocoState::try_from(&msg)?),
+            (X_TURNOUT_INFO, Some(_)) => Reply::TurnoutInfo(TurnoutInfo::try_from(&dbs[..])?),
+            (X_EXT_ACCESSORY_INFO, Some(_)) => {
+                Reply::ExtAccessoryInfo(AccessoryInfo::try_from(&dbs[..])?)
+            }
+            (X_CV_RESULT, Some(DB0_CV_RESULT)) => {
+                require(dbs, 4, "LAN_X_CV_RESULT")?;
+                Reply::CvResult {
+                    cv: (u16::from_be_bytes([dbs[1], dbs[2]]) & 0x3FF) + 1,
+                    value: dbs[3],
+                }
+            }
+            _ => Reply::XBus(msg),
+        };
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_bytes(bytes: &[u8]) -> Reply {
+        Reply::decode(&Packet::try_from(bytes).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_decode_track_power() {
+        let reply = decode_bytes(&[0x07, 0x00, 0x40, 0x00, 0x61, 0x01, 0x60]);
+        assert!(matches!(reply, Reply::TrackPowerOn));
+    }
+
+    #[test]
+    fn test_decode_serial_number() {
+        let reply = decode_bytes(&[0x08, 0x00, 0x10, 0x00, 0x4A, 0x01, 0x00, 0x00]);
+        assert!(matches!(reply, Reply::SerialNumber(0x014A)));
+    }
+
+    #[test]
+    fn test_decode_bad_checksum() {
+        let packet = Packet::try_from(&[0x07, 0x00, 0x40, 0x00, 0x61, 0x01, 0x00][..]).unwrap();
+        assert!(matches!(
+            Reply::decode(&packet),
+            Err(Error::Checksum { .. })
+        ));
+    }
+
+    #[test]
+    fn test_roundtrip_loco_info() {
+        let mut functions = [false; 32];
+        functions[0] = true;
+        functions[7] = true;
+        let state = LocoState {
+            address: 3,
+            is_busy: Some(false),
+            stepping: Some(crate::messages::DccThrottleSteps::Steps28),
+            speed_percentage: Some(-50.),
+            double_traction: Some(false),
+            smart_search: Some(false),
+            functions: Some(functions),
+        };
+        let packet = Reply::LocoInfo(state).encode().unwrap();
+        match Reply::decode(&packet).unwrap() {
+            Reply::LocoInfo(decoded) => {
+                assert_eq!(decoded.address, 3);
+                assert_eq!(decoded.speed_percentage, Some(-50.));
+                assert_eq!(decoded.functions, Some(functions));
+            }
+            other => panic!("Unexpected reply {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_cv_result() {
+        let packet = Reply::CvResult { cv: 8, value: 145 }.encode().unwrap();
+        assert!(matches!(
+            Reply::decode(&packet).unwrap(),
+            Reply::CvResult { cv: 8, value: 145 }
+        ));
+    }
+}
@@ -0,0 +1,861 @@
+use super::header::*;
+use super::loco_state::{loco_address_from_bytes, loco_address_to_bytes};
+use super::{BroadcastFlags, DccThrottleSteps, DecoderMode, FastClockControl, Reply, XBusMessage};
+use crate::error::{Error, Result};
+use crate::packet::Packet;
+use alloc::{format, vec, vec::Vec};
+
+/// Action applied to a loco function by [`Request::SetLocoFunction`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FunctionAction {
+    Off = 0b00,
+    On = 0b01,
+    Toggle = 0b10,
+}
+
+impl TryFrom<u8> for FunctionAction {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0b00 => Ok(FunctionAction::Off),
+            0b01 => Ok(FunctionAction::On),
+            0b10 => Ok(FunctionAction::Toggle),
+            _ => Err(Error::invalid_argument(
+                "Action must be 0 (off), 1 (on), or 2 (toggle)",
+            )),
+        }
+    }
+}
+
+/// A message sent from a LAN client to the Z21 station.
+///
+/// Every variant corresponds to one request of the Z21 LAN protocol. Requests which are
+/// not modelled explicitly can still be expressed with [`Request::XBus`] and
+/// [`Request::Other`].
+///
+/// Addresses of locos are DCC addresses, CV numbers are 1 based (CV1 is transmitted as 0),
+/// turnout and accessory addresses are transmitted as given.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Request {
+    /// LAN_GET_SERIAL_NUMBER
+    GetSerialNumber,
+    /// LAN_GET_CODE
+    GetCode,
+    /// LAN_GET_HWINFO
+    GetHardwareInfo,
+    /// LAN_LOGOFF
+    Logoff,
+    /// LAN_X_GET_VERSION
+    GetXBusVersion,
+    /// LAN_X_GET_STATUS
+    GetStatus,
+    /// LAN_X_SET_TRACK_POWER_OFF
+    SetTrackPowerOff,
+    /// LAN_X_SET_TRACK_POWER_ON
+    SetTrackPowerOn,
+    /// LAN_X_SET_STOP
+    SetStop,
+    /// LAN_X_GET_FIRMWARE_VERSION
+    GetFirmwareVersion,
+    /// LAN_SET_BROADCASTFLAGS
+    SetBroadcastFlags(BroadcastFlags),
+    /// LAN_GET_BROADCASTFLAGS
+    GetBroadcastFlags,
+    /// LAN_GET_LOCOMODE
+    GetLocoMode { address: u16 },
+    /// LAN_SET_LOCOMODE
+    SetLocoMode { address: u16, mode: DecoderMode },
+    /// LAN_GET_TURNOUTMODE
+    GetTurnoutMode { address: u16 },
+    /// LAN_SET_TURNOUTMODE
+    SetTurnoutMode { address: u16, mode: DecoderMode },
+    /// LAN_X_GET_LOCO_INFO
+    GetLocoInfo { address: u16 },
+    /// LAN_X_SET_LOCO_DRIVE, `speed` is the raw `RVVVVVVV` byte.
+    SetLocoDrive {
+        address: u16,
+        steps: DccThrottleSteps,
+        speed: u8,
+    },
+    /// LAN_X_SET_LOCO_FUNCTION
+    SetLocoFunction {
+        address: u16,
+        function: u8,
+        action: FunctionAction,
+    },
+    /// LAN_X_SET_LOCO_FUNCTION_GROUP, `group` is the raw DB0 (e.g. 0x20 for F0-F4).
+    SetLocoFunctionGroup {
+        address: u16,
+        group: u8,
+        functions: u8,
+    },
+    /// LAN_X_SET_LOCO_BINARY_STATE
+    SetLocoBinaryState { address: u16, state: u16, on: bool },
+    /// LAN_X_SET_LOCO_E_STOP
+    SetLocoEmergencyStop { address: u16 },
+    /// LAN_X_PURGE_LOCO
+    PurgeLoco { address: u16 },
+    /// LAN_X_GET_TURNOUT_INFO
+    GetTurnoutInfo { address: u16 },
+    /// LAN_X_SET_TURNOUT
+    SetTurnout {
+        address: u16,
+        /// Output to switch: `false` for P = 0, `true` for P = 1.
+        output: bool,
+        /// `true` activates the output, `false` deactivates it.
+        activate: bool,
+        /// `true` queues the command instead of executing it immediately.
+        queue: bool,
+    },
+    /// LAN_X_GET_EXT_ACCESSORY_INFO
+    GetExtAccessoryInfo { address: u16 },
+    /// LAN_X_SET_EXT_ACCESSORY
+    SetExtAccessory { address: u16, state: u8 },
+    /// LAN_X_CV_READ on the programming track.
+    CvRead { cv: u16 },
+    /// LAN_X_CV_WRITE on the programming track.
+    CvWrite { cv: u16, value: u8 },
+    /// LAN_X_CV_POM_WRITE_BYTE on the main track.
+    CvPomWriteByte { address: u16, cv: u16, value: u8 },
+    /// LAN_X_CV_POM_WRITE_BIT on the main track.
+    CvPomWriteBit {
+        address: u16,
+        cv: u16,
+        bit: u8,
+        value: bool,
+    },
+    /// LAN_X_CV_POM_READ_BYTE on the main track.
+    CvPomReadByte { address: u16, cv: u16 },
+    /// LAN_RMBUS_GETDATA
+    RBusGetData { group: u8 },
+    /// LAN_RMBUS_PROGRAMMODULE
+    RBusProgramModule { address: u8 },
+    /// LAN_SYSTEMSTATE_GETDATA
+    SystemStateGetData,
+    /// LAN_RAILCOM_GETDATA
+    RailComGetData { address: u16 },
+    /// LAN_LOCONET_FROM_LAN, the raw LocoNet message including checksum.
+    LocoNetFromLan(Vec<u8>),
+    /// LAN_LOCONET_DISPATCH_ADDR
+    LocoNetDispatchAddr { address: u16 },
+    /// LAN_LOCONET_DETECTOR
+    LocoNetDetector { kind: u8, address: u16 },
+    /// LAN_CAN_DETECTOR
+    CanDetector { network_id: u16 },
+    /// LAN_CAN_DEVICE_GET_DESCRIPTION
+    CanDeviceGetDescription { network_id: u16 },
+    /// LAN_CAN_BOOSTER_SET_TRACKPOWER
+    CanBoosterSetTrackPower { network_id: u16, power: u8 },
+    /// LAN_FAST_CLOCK_CONTROL
+    FastClockControl(FastClockControl),
+    /// Any other X-Bus request.
+    XBus(XBusMessage),
+    /// Any other LAN request.
+    Other(Packet),
+}
+
+/// Builds a LAN_X packet out of an X-Header and its data bytes.
+pub(crate) fn xbus_packet(x_header: u8, dbs: Vec<u8>) -> Result<Packet> {
+    let data: Vec<u8> = XBusMessage::new_dbs_vec(x_header, dbs).into();
+    Ok(Packet::with_header_and_data(LAN_X, &data)?)
+}
+
+/// Decodes the X-Bus message carried by a LAN_X packet.
+pub(crate) fn xbus_payload(packet: &Packet) -> Result<XBusMessage> {
+    XBusMessage::try_from(&packet.get_data()[..])
+}
+
+fn cv_bytes(cv: u16) -> Result<[u8; 2]> {
+    if !(1..=1024).contains(&cv) {
+        return Err(Error::invalid_argument(
+            "CV number must be between 1 and 1024",
+        ));
+    }
+    Ok((cv - 1).to_be_bytes())
+}
+
+fn require(dbs: &[u8], len: usize, what: &str) -> Result<()> {
+    if dbs.len() < len {
+        return Err(Error::malformed(format!("Invalid {} data length", what)));
+    }
+    Ok(())
+}
+
+impl Request {
+    /// Encodes the request into a [`Packet`] ready to be sent to the Z21 station.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidArgument`] if a field is out of range (e.g. a CV number of 0)
+    /// and [`Error::Protocol`] if the payload does not fit into a dataset.
+    pub fn encode(&self) -> Result<Packet> {
+        let packet = match self {
+            Request::GetSerialNumber => Packet::with_header(LAN_GET_SERIAL_NUMBER),
+            Request::GetCode => Packet::with_header(LAN_GET_CODE),
+            Request::GetHardwareInfo => Packet::with_header(LAN_GET_HWINFO),
+            Request::Logoff => Packet::with_header(LAN_LOGOFF),
+            Request::GetXBusVersion => xbus_packet(X_GET, vec![DB0_GET_VERSION])?,
+            Request::GetStatus => xbus_packet(X_GET, vec![DB0_GET_STATUS])?,
+            Request::SetTrackPowerOff => xbus_packet(X_GET, vec![DB0_SET_TRACK_POWER_OFF])?,
+            Request::SetTrackPowerOn => xbus_packet(X_GET, vec![DB0_SET_TRACK_POWER_ON])?,
+            Request::SetStop => xbus_packet(X_SET_STOP, vec![])?,
+            Request::GetFirmwareVersion => {
+                xbus_packet(X_GET_FIRMWARE_VERSION, vec![DB0_FIRMWARE_VERSION])?
+            }
+            Request::SetBroadcastFlags(flags) => {
+                Packet::with_header_and_data(LAN_SET_BROADCASTFLAGS, &flags.bits().to_le_bytes())?
+            }
+            Request::GetBroadcastFlags => Packet::with_header(LAN_GET_BROADCASTFLAGS),
+            Request::GetLocoMode { address } => {
+                Packet::with_header_and_data(LAN_GET_LOCOMODE, &address.to_be_bytes())?
+            }
+            Request::SetLocoMode { address, mode } => {
+                let addr = address.to_be_bytes();
+                Packet::with_header_and_data(LAN_SET_LOCOMODE, &[addr[0], addr[1], *mode as u8])?
+            }
+            Request::GetTurnoutMode { address } => {
+                Packet::with_header_and_data(LAN_GET_TURNOUTMODE, &address.to_be_bytes())?
+            }
+            Request::SetTurnoutMode { address, mode } => {
+                let addr = address.to_be_bytes();
+                Packet::with_header_and_data(LAN_SET_TURNOUTMODE, &[addr[0], addr[1], *mode as u8])?
+            }
+            Request::GetLocoInfo { address } => {
+                let addr = loco_address_to_bytes(*address);
+                xbus_packet(X_LOCO_GET_INFO, vec![DB0_LOCO_GET_INFO, addr[0], addr[1]])?
+            }
+            Request::SetLocoDrive {
+                address,
+                steps,
+                speed,
+            } => {
+                let addr = loco_address_to_bytes(*address);
+                xbus_packet(X_LOCO_SET, vec![*steps as u8, addr[0], addr[1], *speed])?
+            }
+            Request::SetLocoFunction {
+                address,
+                function,
+                action,
+            } => {
+                if *function > 31 {
+                    return Err(Error::invalid_argument(
+                        "Function index must be between 0 and 31",
+                    ));
+                }
+                let addr = loco_address_to_bytes(*address);
+                // TTNNNNNN: TT is the action type, NNNNNN is the function index
+                let function_byte = ((*action as u8) << 6) | (function & 0x3F);
+                xbus_packet(
+                    X_LOCO_SET,
+                    vec![DB0_LOCO_FUNCTION, addr[0], addr[1], function_byte],
+                )?
+            }
+            Request::SetLocoFunctionGroup {
+                address,
+                group,
+                functions,
+            } => {
+                let addr = loco_address_to_bytes(*address);
+                xbus_packet(X_LOCO_SET, vec![*group, addr[0], addr[1], *functions])?
+            }
+            Request::SetLocoBinaryState { address, state, on } => {
+                if *state > 0x7FFF {
+                    return Err(Error::invalid_argument(
+                        "Binary state must be between 0 and 32767",
+                    ));
+                }
+                let addr = loco_address_to_bytes(*address);
+                let low = (*state as u8 & 0x7F) | if *on { 0x80 } else { 0 };
+                let high = (*state >> 7) as u8;
+                xbus_packet(
+                    X_LOCO_SET_BINARY_STATE,
+                    vec![DB0_LOCO_BINARY_STATE, addr[0], addr[1], low, high],
+                )?
+            }
+            Request::SetLocoEmergencyStop { address } => {
+                let addr = loco_address_to_bytes(*address);
+                xbus_packet(X_SET_LOCO_E_STOP, vec![addr[0], addr[1]])?
+            }
+            Request::PurgeLoco { address } => {
+                let addr = loco_address_to_bytes(*address);
+                xbus_packet(X_LOCO_GET_INFO, vec![DB0_LOCO_PURGE, addr[0], addr[1]])?
+            }
+            Request::GetTurnoutInfo { address } => {
+                xbus_packet(X_TURNOUT_INFO, address.to_be_bytes().to_vec())?
+            }
+            Request::SetTurnout {
+                address,
+                output,
+                activate,
+                queue,
+            } => {
+                let addr = address.to_be_bytes();
+                // 10Q0A00P
+                let db2 = 0x80 | (*queue as u8) << 5 | (*activate as u8) << 3 | *output as u8;
+                xbus_packet(X_SET_TURNOUT, vec![addr[0], addr[1], db2])?
+            }
+            Request::GetExtAccessoryInfo { address } => {
+                let addr = address.to_be_bytes();
+                xbus_packet(X_EXT_ACCESSORY_INFO, vec![addr[0], addr[1], 0x00])?
+            }
+            Request::SetExtAccessory { address, state } => {
+                let addr = address.to_be_bytes();
+                xbus_packet(X_SET_EXT_ACCESSORY, vec![addr[0], addr[1], *state, 0x00])?
+            }
+            Request::CvRead { cv } => {
+                let cv = cv_bytes(*cv)?;
+                xbus_packet(X_CV_READ, vec![DB0_CV_READ, cv[0], cv[1]])?
+            }
+            Request::CvWrite { cv, value } => {
+                let cv = cv_bytes(*cv)?;
+                xbus_packet(X_CV_WRITE, vec![DB0_CV_WRITE, cv[0], cv[1], *value])?
+            }
+            Request::CvPomWriteByte { address, cv, value } => {
+                Self::pom_packet(*address, *cv, POM_WRITE_BYTE, *value)?
+            }
+            Request::CvPomWriteBit {
+                address,
+                cv,
+                bit,
+                value,
+            } => {
+                if *bit > 7 {
+                    return Err(Error::invalid_argument(
+                        "Bit position must be between 0 and 7",
+                    ));
+                }
+                let data = (*value as u8) << 3 | bit;
+                Self::pom_packet(*address, *cv, POM_WRITE_BIT, data)?
+            }
+            Request::CvPomReadByte { address, cv } => {
+                Self::pom_packet(*address, *cv, POM_READ_BYTE, 0)?
+            }
+            Request::RBusGetData { group } => {
+                Packet::with_header_and_data(LAN_RMBUS_GETDATA, &[*group])?
+            }
+            Request::RBusProgramModule { address } => {
+                Packet::with_header_and_data(LAN_RMBUS_PROGRAMMODULE, &[*address])?
+            }
+            Request::SystemStateGetData => Packet::with_header(LAN_SYSTEMSTATE_GETDATA),
+            Request::RailComGetData { address } => {
+                let addr = address.to_le_bytes();
+                Packet::with_header_and_data(LAN_RAILCOM_GETDATA, &[0x01, addr[0], addr[1]])?
+            }
+            Request::LocoNetFromLan(message) => {
+                Packet::with_header_and_data(LAN_LOCONET_FROM_LAN, message)?
+            }
+            Request::LocoNetDispatchAddr { address } => {
+                Packet::with_header_and_data(LAN_LOCONET_DISPATCH_ADDR, &address.to_le_bytes())?
+            }
+            Request::LocoNetDetector { kind, address } => {
+                let addr = address.to_le_bytes();
+                Packet::with_header_and_data(LAN_LOCONET_DETECTOR, &[*kind, addr[0], addr[1]])?
+            }
+            Request::CanDetector { network_id } => {
+                let id = network_id.to_le_bytes();
+                Packet::with_header_and_data(LAN_CAN_DETECTOR, &[0x00, id[0], id[1]])?
+            }
+            Request::CanDeviceGetDescription { network_id } => Packet::with_header_and_data(
+                LAN_CAN_DEVICE_GET_DESCRIPTION,
+                &network_id.to_le_bytes(),
+            )?,
+            Request::CanBoosterSetTrackPower { network_id, power } => {
+                let id = network_id.to_le_bytes();
+                Packet::with_header_and_data(
+                    LAN_CAN_BOOSTER_SET_TRACKPOWER,
+                    &[id[0], id[1], *power],
+                )?
+            }
+            Request::FastClockControl(control) => {
+                let msg = match control {
+                    FastClockControl::Read => {
+                        XBusMessage::new_single(X_FAST_CLOCK_GET, DB0_FAST_CLOCK_READ)
+                    }
+                    FastClockControl::Start => {
+                        XBusMessage::new_single(X_FAST_CLOCK_GET, DB0_FAST_CLOCK_START)
+                    }
+                    FastClockControl::Stop => {
+                        XBusMessage::new_single(X_FAST_CLOCK_GET, DB0_FAST_CLOCK_STOP)
+                    }
+                    FastClockControl::Set {
+                        day_of_week,
+                        hour,
+                        minute,
+                        rate,
+                    } => XBusMessage::new_dbs_vec(
+                        X_FAST_CLOCK_SET,
+                        vec![
+                            DB0_FAST_CLOCK_SET,
+                            (day_of_week << 5) | (hour & 0x1F),
+                            minute & 0x3F,
+                            rate & 0x3F,
+                        ],
+                    ),
+                };
+                let data: Vec<u8> = msg.into();
+                Packet::with_header_and_data(LAN_FAST_CLOCK_CONTROL, &data)?
+            }
+            Request::XBus(msg) => {
+                let data: Vec<u8> = msg.clone().into();
+                Packet::with_header_and_data(LAN_X, &data)?
+            }
+            Request::Other(packet) => packet.clone(),
+        };
+        Ok(packet)
+    }
+
+    fn pom_packet(address: u16, cv: u16, option: u8, value: u8) -> Result<Packet> {
+        let addr = loco_address_to_bytes(address);
+        let cv = cv_bytes(cv)?;
+        let db3 = option | (cv[0] & 0x03);
+        xbus_packet(
+            X_CV_POM,
+            vec![DB0_CV_POM, addr[0] & 0x3F, addr[1], db3, cv[1], value],
+        )
+    }
+
+    /// Returns `true` if `reply` is the answer of the Z21 station to this request.
+    ///
+    /// Replies are matched on their header and, where the protocol allows it, on the
+    /// key fields of the request (loco or accessory address, CV number, R-BUS group),
+    /// so a broadcast about another loco is never taken as the answer. Negative
+    /// answers ([`Reply::CvNack`], [`Reply::CvNackShortCircuit`] and
+    /// [`Reply::UnknownCommand`]) match the requests they can be sent for.
+    ///
+    /// Requests which the station does not answer never match.
+    pub fn matches_reply(&self, reply: &Reply) -> bool {
+        if matches!(reply, Reply::UnknownCommand) {
+            return self.is_xbus();
+        }
+        match (self, reply) {
+            (Request::GetSerialNumber, Reply::SerialNumber(_))
+            | (Request::GetCode, Reply::Code(_))
+            | (Request::GetHardwareInfo, Reply::HardwareInfo(_))
+            | (Request::GetXBusVersion, Reply::XBusVersion { .. })
+            | (Request::GetStatus, Reply::Status(_))
+            | (Request::SetTrackPowerOff, Reply::TrackPowerOff)
+            | (Request::SetTrackPowerOn, Reply::TrackPowerOn)
+            | (Request::SetStop, Reply::Stopped)
+            | (Request::GetFirmwareVersion, Reply::FirmwareVersion { .. })
+            | (Request::GetBroadcastFlags, Reply::BroadcastFlags(_))
+            | (Request::SystemStateGetData, Reply::SystemStateDataChanged(_))
+            | (Request::CanDetector { .. }, Reply::CanDetector(_))
+            | (Request::FastClockControl(FastClockControl::Read), Reply::FastClockData(_)) => true,
+            (Request::GetLocoMode { address }, Reply::LocoMode { address: a, .. })
+            | (Request::GetTurnoutMode { address }, Reply::TurnoutMode { address: a, .. })
+            | (
+                Request::LocoNetDispatchAddr { address },
+                Reply::LocoNetDispatchAddr { address: a, .. },
+            ) => address == a,
+            (
+                Request::GetLocoInfo { address }
+                | Request::SetLocoDrive { address, .. }
+                | Request::SetLocoFunction { address, .. }
+                | Request::SetLocoFunctionGroup { address, .. }
+                | Request::SetLocoBinaryState { address, .. }
+                | Request::SetLocoEmergencyStop { address },
+                Reply::LocoInfo(state),
+            ) => *address == state.address,
+            (
+                Request::GetTurnoutInfo { address } | Request::SetTurnout { address, .. },
+                Reply::TurnoutInfo(info),
+            ) => *address == info.address,
+            (
+                Request::GetExtAccessoryInfo { address } | Request::SetExtAccessory { address, .. },
+                Reply::ExtAccessoryInfo(info),
+            ) => *address == info.address,
+            (
+                Request::CvRead { cv }
+                | Request::CvWrite { cv, .. }
+                | Request::CvPomReadByte { cv, .. },
+                Reply::CvResult { cv: result_cv, .. },
+            ) => cv == result_cv,
+            (
+                Request::CvRead { .. } | Request::CvWrite { .. } | Request::CvPomReadByte { .. },
+                Reply::CvNack | Reply::CvNackShortCircuit,
+            ) => true,
+            (Request::RBusGetData { group }, Reply::RBusDataChanged(feedback)) => {
+                *group == feedback.group
+            }
+            (Request::RailComGetData { address }, Reply::RailComDataChanged(data)) => {
+                *address == data.address
+            }
+            (Request::LocoNetDetector { address, .. }, Reply::LocoNetDetector(detector)) => {
+                *address == detector.address
+            }
+            (
+                Request::CanDeviceGetDescription { network_id },
+                Reply::CanDeviceDescription { network_id: id, .. },
+            ) => network_id == id,
+            _ => false,
+        }
+    }
+
+    /// Returns `true` if sending the request twice has the same effect as sending it once.
+    ///
+    /// Such requests can safely be resent when their reply got lost. Toggling a function,
+    /// CV programming and raw messages are not idempotent.
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            Request::SetLocoFunction { action, .. } => *action != FunctionAction::Toggle,
+            Request::FastClockControl(control) => *control == FastClockControl::Read,
+            Request::GetSerialNumber
+            | Request::GetCode
+            | Request::GetHardwareInfo
+            | Request::GetXBusVersion
+            | Request::GetStatus
+            | Request::SetTrackPowerOff
+            | Request::SetTrackPowerOn
+            | Request::SetStop
+            | Request::GetFirmwareVersion
+            | Request::SetBroadcastFlags(_)
+            | Request::GetBroadcastFlags
+            | Request::GetLocoMode { .. }
+            | Request::GetTurnoutMode { .. }
+            | Request::GetLocoInfo { .. }
+            | Request::SetLocoDrive { .. }
+            | Request::SetLocoFunctionGroup { .. }
+            | Request::SetLocoBinaryState { .. }
+            | Request::SetLocoEmergencyStop { .. }
+            | Request::GetTurnoutInfo { .. }
+            | Request::SetTurnout { .. }
+            | Request::GetExtAccessoryInfo { .. }
+            | Request::SetExtAccessory { .. }
+            | Request::RBusGetData { .. }
+            | Request::SystemStateGetData
+            | Request::RailComGetData { .. }
+            | Request::CanDeviceGetDescription { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Returns `true` if the request is sent as a LAN_X (X-Bus) message.
+    fn is_xbus(&self) -> bool {
+        matches!(
+            self,
+            Request::GetXBusVersion
+                | Request::GetStatus
+                | Request::SetTrackPowerOff
+                | Request::SetTrackPowerOn
+                | Request::SetStop
+                | Request::GetFirmwareVersion
+                | Request::GetLocoInfo { .. }
+                | Request::SetLocoDrive { .. }
+                | Request::SetLocoFunction { .. }
+                | Request::SetLocoFunctionGroup { .. }
+                | Request::SetLocoBinaryState { .. }
+                | Request::SetLocoEmergencyStop { .. }
+                | Request::PurgeLoco { .. }
+                | Request::GetTurnoutInfo { .. }
+                | Request::SetTurnout { .. }
+                | Request::GetExtAccessoryInfo { .. }
+                | Request::SetExtAccessory { .. }
+                | Request::CvRead { .. }
+                | Request::CvWrite { .. }
+                | Request::CvPomWriteByte { .. }
+                | Request::CvPomWriteBit { .. }
+                | Request::CvPomReadByte { .. }
+                | Request::XBus(_)
+        )
+    }
+
+    /// Decodes a [`Packet`] received from a LAN client into a request.
+    ///
+    /// Packets with unknown headers are returned as [`Request::Other`], unknown X-Bus
+    /// messages as [`Request::XBus`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`Error`] if the payload of a known request is malformed or the
+    /// X-Bus checksum is wrong.
+    pub fn decode(packet: &Packet) -> Result<Request> {
+        let data = packet.get_data();
+        let u16_be = |what: &str| -> Result<u16> {
+            require(&data, 2, what)?;
+            Ok(u16::from_be_bytes([data[0], data[1]]))
+        };
+        let u16_le = |offset: usize, what: &str| -> Result<u16> {
+            require(&data, offset + 2, what)?;
+            Ok(u16::from_le_bytes([data[offset], data[offset + 1]]))
+        };
+        let request = match packet.get_header() {
+            LAN_GET_SERIAL_NUMBER => Request::GetSerialNumber,
+            LAN_GET_CODE => Request::GetCode,
+            LAN_GET_HWINFO => Request::GetHardwareInfo,
+            LAN_LOGOFF => Request::Logoff,
+            LAN_X => Self::decode_xbus(xbus_payload(packet)?)?,
+            LAN_SET_BROADCASTFLAGS => {
+                require(&data, 4, "LAN_SET_BROADCASTFLAGS")?;
+                let bits = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                Request::SetBroadcastFlags(BroadcastFlags::from_bits(bits))
+            }
+            LAN_GET_BROADCASTFLAGS => Request::GetBroadcastFlags,
+            LAN_GET_LOCOMODE => Request::GetLocoMode {
+                address: u16_be("LAN_GET_LOCOMODE")?,
+            },
+            LAN_SET_LOCOMODE => {
+                require(&data, 3, "LAN_SET_LOCOMODE")?;
+                Request::SetLocoMode {
+                    address: u16_be("LAN_SET_LOCOMODE")?,
+                    mode: DecoderMode::try_from(data[2])?,
+                }
+            }
+            LAN_GET_TURNOUTMODE => Request::GetTurnoutMode {
+                address: u16_be("LAN_GET_TURNOUTMODE")?,
+            },
+            LAN_SET_TURNOUTMODE => {
+                require(&data, 3, "LAN_SET_TURNOUTMODE")?;
+                Request::SetTurnoutMode {
+                    address: u16_be("LAN_SET_TURNOUTMODE")?,
+                    mode: DecoderMode::try_from(data[2])?,
+                }
+            }
+            LAN_RMBUS_GETDATA => {
+                require(&data, 1, "LAN_RMBUS_GETDATA")?;
+                Request::RBusGetData { group: data[0] }
+            }
+            LAN_RMBUS_PROGRAMMODULE => {
+                require(&data, 1, "LAN_RMBUS_PROGRAMMODULE")?;
+                Request::RBusProgramModule { address: data[0] }
+            }
+            LAN_SYSTEMSTATE_GETDATA => Request::SystemStateGetData,
+            LAN_RAILCOM_GETDATA => Request::RailComGetData {
+                address: u16_le(1, "LAN_RAILCOM_GETDATA")?,
+            },
+            LAN_LOCONET_FROM_LAN => Request::LocoNetFromLan(data.clone()),
+            LAN_LOCONET_DISPATCH_ADDR => Request::LocoNetDispatchAddr {
+                address: u16_le(0, "LAN_LOCONET_DISPATCH_ADDR")?,
+            },
+            LAN_LOCONET_DETECTOR => Request::LocoNetDetector {
+                address: u16_le(1, "LAN_LOCONET_DETECTOR")?,
+                kind: data[0],
+            },
+            LAN_CAN_DETECTOR => Request::CanDetector {
+                network_id: u16_le(1, "LAN_CAN_DETECTOR")?,
+            },
+            LAN_CAN_DEVICE_GET_DESCRIPTION => Request::CanDeviceGetDescription {
+                network_id: u16_le(0, "LAN_CAN_DEVICE_GET_DESCRIPTION")?,
+            },
+            LAN_CAN_BOOSTER_SET_TRACKPOWER => {
+                require(&data, 3, "LAN_CAN_BOOSTER_SET_TRACKPOWER")?;
+                Request::CanBoosterSetTrackPower {
+                    network_id: u16_le(0, "LAN_CAN_BOOSTER_SET_TRACKPOWER")?,
+                    power: data[2],
+                }
+            }
+            LAN_FAST_CLOCK_CONTROL => {
+                let msg = XBusMessage::try_from(&data[..])?;
+                let dbs = msg.get_dbs();
+                let control = match (msg.get_x_header(), dbs.first()) {
+                    (X_FAST_CLOCK_GET, Some(&DB0_FAST_CLOCK_READ)) => FastClockControl::Read,
+                    (X_FAST_CLOCK_GET, Some(&DB0_FAST_CLOCK_START)) => FastClockControl::Start,
+                    (X_FAST_CLOCK_GET, Some(&DB0_FAST_CLOCK_STOP)) => FastClockControl::Stop,
+                    (X_FAST_CLOCK_SET, Some(&DB0_FAST_CLOCK_SET)) if dbs.len() >= 4 => {
+                        FastClockControl::Set {
+                            day_of_week: dbs[1] >> 5,
+                            hour: dbs[1] & 0x1F,
+                            minute: dbs[2] & 0x3F,
+                            rate: dbs[3] & 0x3F,
+                        }
+                    }
+                    _ => return Ok(Request::Other(packet.clone())),
+                };
+                Request::FastClockControl(control)
+            }
+            _ => Request::Other(packet.clone()),
+        };
+        Ok(request)
+    }
+
+    fn decode_xbus(msg: XBusMessage) -> Result<Request> {
+        let dbs = msg.get_dbs();
+        let loco_address = |offset: usize| -> Result<u16> {
+            require(dbs, offset + 2, "X-Bus loco")?;
+            Ok(loco_address_from_bytes(dbs[offset], dbs[offset + 1]))
+        };
+        let cv_number = |offset: usize| -> Result<u16> {
+            require(dbs, offset + 2, "X-Bus CV")?;
+            Ok((u16::from_be_bytes([dbs[offset], dbs[offset + 1]]) & 0x3FF) + 1)
+        };
+        let request = match (msg.get_x_header(), dbs.first().copied()) {
+            (X_GET, Some(DB0_GET_VERSION)) => Request::GetXBusVersion,
+            (X_GET, Some(DB0_GET_STATUS)) => Request::GetStatus,
+            (X_GET, Some(DB0_SET_TRACK_POWER_OFF)) => Request::SetTrackPowerOff,
+            (X_GET, Some(DB0_SET_TRACK_POWER_ON)) => Request::SetTrackPowerOn,
+            (X_SET_STOP, None) => Request::SetStop,
+            (X_GET_FIRMWARE_VERSION, Some(DB0_FIRMWARE_VERSION)) => Request::GetFirmwareVersion,
+            (X_LOCO_GET_INFO, Some(DB0_LOCO_GET_INFO)) => Request::GetLocoInfo {
+                address: loco_address(1)?,
+            },
+            (X_LOCO_GET_INFO, Some(DB0_LOCO_PURGE)) => Request::PurgeLoco {
+                address: loco_address(1)?,
+            },
+            (X_LOCO_SET, Some(db0 @ (0x10 | 0x12 | 0x13))) => {
+                require(dbs, 4, "LAN_X_SET_LOCO_DRIVE")?;
+                let steps = match db0 {
+                    0x10 => DccThrottleSteps::Steps14,
+                    0x12 => DccThrottleSteps::Steps28,
+                    _ => DccThrottleSteps::Steps128,
+                };
+                Request::SetLocoDrive {
+                    address: loco_address(1)?,
+                    steps,
+                    speed: dbs[3],
+                }
+            }
+            (X_LOCO_SET, Some(DB0_LOCO_FUNCTION)) => {
+                require(dbs, 4, "LAN_X_SET_LOCO_FUNCTION")?;
+                Request::SetLocoFunction {
+                    address: loco_address(1)?,
+                    function: dbs[3] & 0x3F,
+                    action: FunctionAction::try_from(dbs[3] >> 6)?,
+                }
+            }
+            (X_LOCO_SET, Some(group @ (0x20..=0x2F | 0x50..=0x51))) => {
+                require(dbs, 4, "LAN_X_SET_LOCO_FUNCTION_GROUP")?;
+                Request::SetLocoFunctionGroup {
+                    address: loco_address(1)?,
+                    group,
+                    functions: dbs[3],
+                }
+            }
+            (X_LOCO_SET_BINARY_STATE, Some(DB0_LOCO_BINARY_STATE)) => {
+                require(dbs, 5, "LAN_X_SET_LOCO_BINARY_STATE")?;
+                Request::SetLocoBinaryState {
+                    address: loco_address(1)?,
+                    state: (dbs[3] & 0x7F) as u16 | (dbs[4] as u16) << 7,
+                    on: dbs[3] & 0x80 != 0,
+                }
+            }
+            (X_SET_LOCO_E_STOP, Some(_)) => Request::SetLocoEmergencyStop {
+                address: loco_address(0)?,
+            },
+            (X_TURNOUT_INFO, Some(_)) => {
+                require(dbs, 2, "LAN_X_GET_TURNOUT_INFO")?;
+                Request::GetTurnoutInfo {
+                    address: u16::from_be_bytes([dbs[0], dbs[1]]),
+                }
+            }
+            (X_SET_TURNOUT, Some(_)) => {
+                require(dbs, 3, "LAN_X_SET_TURNOUT")?;
+                Request::SetTurnout {
+                    address: u16::from_be_bytes([dbs[0], dbs[1]]),
+                    output: dbs[2] & 0b0000_0001 != 0,
+                    activate: dbs[2] & 0b0000_1000 != 0,
+                    queue: dbs[2] & 0b0010_0000 != 0,
+                }
+            }
+            (X_EXT_ACCESSORY_INFO, Some(_)) => {
+                require(dbs, 2, "LAN_X_GET_EXT_ACCESSORY_INFO")?;
+                Request::GetExtAccessoryInfo {
+                    address: u16::from_be_bytes([dbs[0], dbs[1]]),
+                }
+            }
+            (X_SET_EXT_ACCESSORY, Some(_)) => {
+                require(dbs, 3, "LAN_X_SET_EXT_ACCESSORY")?;
+                Request::SetExtAccessory {
+                    address: u16::from_be_bytes([dbs[0], dbs[1]]),
+                    state: dbs[2],
+                }
+            }
+            (X_CV_READ, Some(DB0_CV_READ)) => Request::CvRead { cv: cv_number(1)? },
+            (X_CV_WRITE, Some(DB0_CV_WRITE)) => {
+                require(dbs, 4, "LAN_X_CV_WRITE")?;
+                Request::CvWrite {
+                    cv: cv_number(1)?,
+                    value: dbs[3],
+                }
+            }
+            (X_CV_POM, Some(DB0_CV_POM)) => {
+                require(dbs, 6, "LAN_X_CV_POM")?;
+                let address = loco_address(1)?;
+                let cv = (u16::from_be_bytes([dbs[3] & 0x03, dbs[4]])) + 1;
+                match dbs[3] & 0xFC {
+                    POM_WRITE_BYTE => Request::CvPomWriteByte {
+                        address,
+                        cv,
+                        value: dbs[5],
+                    },
+                    POM_WRITE_BIT => Request::CvPomWriteBit {
+                        address,
+                        cv,
+                        bit: dbs[5] & 0x07,
+                        value: dbs[5] & 0x08 != 0,
+                    },
+                    POM_READ_BYTE => Request::CvPomReadByte { address, cv },
+                    _ => Request::XBus(msg),
+                }
+            }
+            _ => Request::XBus(msg),
+        };
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(request: Request) -> Request {
+        Request::decode(&request.encode().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_encode_track_power() {
+        let data: Vec<u8> = Request::SetTrackPowerOn.encode().unwrap().into();
+        assert_eq!(data, vec![0x07, 0x00, 0x40, 0x00, 0x21, 0x81, 0xA0]);
+    }
+
+    #[test]
+    fn test_encode_loco_drive_long_address() {
+        let request = Request::SetLocoDrive {
+            address: 1234,
+            steps: DccThrottleSteps::Steps128,
+            speed: 0x80 | 50,
+        };
+        let data = request.encode().unwrap().get_data();
+        assert_eq!(data[..5], [0xE4, 0x13, 0xC4, 0xD2, 0xB2]);
+        match roundtrip(request) {
+            Request::SetLocoDrive { address, speed, .. } => {
+                assert_eq!(address, 1234);
+                assert_eq!(speed, 0xB2);
+            }
+            other => panic!("Unexpected request {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_cv_and_turnout() {
+        match roundtrip(Request::CvWrite { cv: 29, value: 6 }) {
+            Request::CvWrite { cv, value } => assert_eq!((cv, value), (29, 6)),
+            other => panic!("Unexpected request {:?}", other),
+        }
+        match roundtrip(Request::SetTurnout {
+            address: 9,
+            output: true,
+            activate: true,
+            queue: false,
+        }) {
+            Request::SetTurnout {
+                address,
+                output,
+                activate,
+                queue,
+            } => assert_eq!((address, output, activate, queue), (9, true, true, false)),
+            other => panic!("Unexpected request {:?}", other),
+        }
+        assert!(Request::CvRead { cv: 0 }.encode().is_err());
+    }
+
+    #[test]
+    fn test_decode_unknown() {
+        let packet = Packet::with_header(0x1234);
+        assert!(matches!(
+            Request::decode(&packet).unwrap(),
+            Request::Other(_)
+        ));
+    }
+}
@@ -1,9 +1,11 @@
-use tokio::io;
+use crate::error::Error;
+use alloc::vec::Vec;
 
 /// Represents the system state as reported by the Z21 station.
 ///
 /// The structure corresponds to 16 bytes of data in the LAN_SYSTEMSTATE_DATACHANGED event.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SystemState {
     /// Current on the main track in mA.
     pub main_current: i16,
@@ -27,7 +29,7 @@ pub struct SystemState {
     pub capabilities: u8,
 }
 impl TryFrom<&[u8]> for SystemState {
-    type Error = io::Error;
+    type Error = Error;
 
     /// Attempts to parse a `SystemState` from a 16-byte slice.
     ///
@@ -36,10 +38,7 @@ impl TryFrom<&[u8]> for SystemState {
     /// Returns an error if the provided slice is not exactly 16 bytes long.
     fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
         if data.len() != 16 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Invalid SystemState data length",
-            ));
+            return Err(Error::malformed("Invalid SystemState data length"));
         }
         Ok(SystemState {
             main_current: i16::from_le_bytes([data[0], data[1]]),
@@ -55,20 +54,20 @@ impl TryFrom<&[u8]> for SystemState {
         })
     }
 }
-impl Into<Vec<u8>> for SystemState {
+impl From<SystemState> for Vec<u8> {
     /// Converts a `SystemState` into a 16-byte vector.
-    fn into(self) -> Vec<u8> {
+    fn from(state: SystemState) -> Vec<u8> {
         let mut result = Vec::with_capacity(16);
-        result.extend(&self.main_current.to_le_bytes());
-        result.extend(&self.prog_current.to_le_bytes());
-        result.extend(&self.filtered_main_current.to_le_bytes());
-        result.extend(&self.temperature.to_le_bytes());
-        result.extend(&self.supply_voltage.to_le_bytes());
-        result.extend(&self.vcc_voltage.to_le_bytes());
-        result.push(self.central_state);
-        result.push(self.central_state_ex);
-        result.push(self.reserved);
-        result.push(self.capabilities);
+        result.extend(&state.main_current.to_le_bytes());
+        result.extend(&state.prog_current.to_le_bytes());
+        result.extend(&state.filtered_main_current.to_le_bytes());
+        result.extend(&state.temperature.to_le_bytes());
+        result.extend(&state.supply_voltage.to_le_bytes());
+        result.extend(&state.vcc_voltage.to_le_bytes());
+        result.push(state.central_state);
+        result.push(state.central_state_ex);
+        result.push(state.reserved);
+        result.push(state.capabilities);
         result
     }
 }
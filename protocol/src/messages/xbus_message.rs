@@ -1,7 +1,13 @@
-use tokio::io;
+use crate::error::Error;
+use alloc::{vec, vec::Vec};
 
 pub const XBUS_HEADER: u16 = 0x40;
 #[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "XBusMessageFields")
+)]
 pub struct XBusMessage {
     x_header: u8,
     dbs: Vec<u8>,
@@ -51,18 +57,18 @@ impl XBusMessage {
     }
 }
 
-impl Into<Vec<u8>> for XBusMessage {
-    fn into(self) -> Vec<u8> {
+impl From<XBusMessage> for Vec<u8> {
+    fn from(msg: XBusMessage) -> Vec<u8> {
         let mut vec = Vec::new();
-        vec.push(self.x_header);
-        vec.extend_from_slice(&self.dbs);
-        vec.push(self.xor);
+        vec.push(msg.x_header);
+        vec.extend_from_slice(&msg.dbs);
+        vec.push(msg.xor);
         vec
     }
 }
 
 impl TryFrom<&[u8]> for XBusMessage {
-    type Error = io::Error;
+    type Error = Error;
 
     fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
         let counts = data.len() as i64 - 2;
@@ -74,10 +80,10 @@ impl TryFrom<&[u8]> for XBusMessage {
             vec.extend_from_slice(&data[1..=counts]);
             let calculated_xor = vec.iter().fold(x_header, |acc, x| acc ^ x);
             if data_xor != calculated_xor {
-                return Err(io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "XBus message XOR is wrong",
-                ));
+                return Err(Error::Checksum {
+                    expected: calculated_xor,
+                    actual: data_xor,
+                });
             }
             Ok(XBusMessage {
                 x_header,
@@ -85,14 +91,34 @@ impl TryFrom<&[u8]> for XBusMessage {
                 xor: calculated_xor,
             })
         } else {
-            Err(io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "XBus message is too short",
-            ))
+            Err(Error::malformed("XBus message is too short"))
         }
     }
 }
 
+/// Serialized form of an [`XBusMessage`], checked like a received message when
+/// deserialized.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct XBusMessageFields {
+    x_header: u8,
+    dbs: Vec<u8>,
+    xor: u8,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<XBusMessageFields> for XBusMessage {
+    type Error = Error;
+
+    fn try_from(fields: XBusMessageFields) -> Result<Self, Self::Error> {
+        let mut data = Vec::with_capacity(fields.dbs.len() + 2);
+        data.push(fields.x_header);
+        data.extend_from_slice(&fields.dbs);
+        data.push(fields.xor);
+        XBusMessage::try_from(data.as_slice())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -150,7 +176,13 @@ mod tests {
     fn test_try_from_invalid_xor() {
         let data = vec![0x21, 0x34, 0x56, 0xFF]; // Wrong XOR
         let result = XBusMessage::try_from(data.as_slice());
-        assert!(result.is_err());
+        assert!(matches!(
+            result,
+            Err(Error::Checksum {
+                expected: 0x43,
+                actual: 0xFF
+            })
+        ));
     }
 
     #[test]
@@ -0,0 +1,297 @@
+use crate::error::ProtocolError;
+use alloc::vec::Vec;
+
+/// Length of the DataLen and Header fields which precede the payload of every dataset.
+pub const PACKET_HEADER_LEN: usize = 4;
+
+/// Largest datagram sent to the Z21 in one go (Ethernet MTU minus IP and UDP headers).
+pub const MAX_DATAGRAM_LEN: usize = 1472;
+
+/// A single Z21 dataset consisting of DataLen, Header and payload.
+///
+/// One UDP datagram may carry several datasets, use [`split_datagram`] and
+/// [`join_datagram`] to convert between datagrams and packets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(into = "PacketFields", try_from = "PacketFields")
+)]
+pub struct Packet {
+    data_len: u16,
+    header: u16,
+    data: Vec<u8>,
+}
+
+impl Packet {
+    /// Creates a packet without payload.
+    pub fn with_header(header: u16) -> Packet {
+        Packet {
+            header,
+            data_len: PACKET_HEADER_LEN as u16,
+            data: Vec::new(),
+        }
+    }
+
+    /// Creates a packet with the given header and payload.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ProtocolError::PayloadTooLarge`] if the dataset would exceed 65535 bytes.
+    pub fn with_header_and_data(header: u16, data: &[u8]) -> Result<Packet, ProtocolError> {
+        if data.len() + PACKET_HEADER_LEN > u16::MAX as usize {
+            return Err(ProtocolError::PayloadTooLarge(data.len()));
+        }
+
+        let calculated_len = (data.len() + PACKET_HEADER_LEN) as u16;
+        Ok(Packet {
+            header,
+            data_len: calculated_len,
+            data: Vec::from(data),
+        })
+    }
+    pub fn get_data(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+    pub fn get_header(&self) -> u16 {
+        self.header
+    }
+    pub fn get_data_len(&self) -> u16 {
+        self.data_len
+    }
+}
+
+impl From<Packet> for Vec<u8> {
+    fn from(val: Packet) -> Self {
+        let mut result = Vec::new();
+        result.extend(&val.data_len.to_le_bytes());
+        result.extend(&val.header.to_le_bytes());
+        result.extend(&val.data);
+        result
+    }
+}
+
+impl TryFrom<&[u8]> for Packet {
+    type Error = ProtocolError;
+
+    /// Attempts to parse a single dataset.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the slice is shorter than the dataset header, or if the
+    /// DataLen field does not match the length of the slice.
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        if data.len() < PACKET_HEADER_LEN {
+            return Err(ProtocolError::Truncated {
+                needed: PACKET_HEADER_LEN,
+                available: data.len(),
+            });
+        }
+        let data_len = u16::from_le_bytes([data[0], data[1]]);
+        if (data_len as usize) < PACKET_HEADER_LEN {
+            return Err(ProtocolError::InvalidLength(data_len));
+        }
+        if data_len as usize != data.len() {
+            return Err(ProtocolError::LengthMismatch {
+                declared: data_len as usize,
+                actual: data.len(),
+            });
+        }
+        let header = u16::from_le_bytes([data[2], data[3]]);
+        Ok(Packet {
+            data_len,
+            header,
+            data: data[PACKET_HEADER_LEN..].to_vec(),
+        })
+    }
+}
+
+impl TryFrom<Vec<u8>> for Packet {
+    type Error = ProtocolError;
+
+    /// Attempts to parse a single dataset, see [`Packet::try_from`] for `&[u8]`.
+    fn try_from(data: Vec<u8>) -> Result<Self, Self::Error> {
+        Packet::try_from(data.as_slice())
+    }
+}
+
+/// Serialized form of a [`Packet`]. DataLen is implied by the payload.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct PacketFields {
+    header: u16,
+    data: Vec<u8>,
+}
+
+#[cfg(feature = "serde")]
+impl From<Packet> for PacketFields {
+    fn from(packet: Packet) -> Self {
+        PacketFields {
+            header: packet.header,
+            data: packet.data,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<PacketFields> for Packet {
+    type Error = ProtocolError;
+
+    fn try_from(fields: PacketFields) -> Result<Self, Self::Error> {
+        Packet::with_header_and_data(fields.header, &fields.data)
+    }
+}
+
+/// Iterator over the datasets contained in a single UDP datagram.
+///
+/// Yields every dataset in order. After the first malformed dataset an error is
+/// yielded and the iteration stops, as the remaining bytes can no longer be framed.
+pub struct DatagramIter<'a> {
+    remaining: &'a [u8],
+}
+
+impl<'a> DatagramIter<'a> {
+    pub fn new(datagram: &'a [u8]) -> DatagramIter<'a> {
+        DatagramIter {
+            remaining: datagram,
+        }
+    }
+}
+
+impl Iterator for DatagramIter<'_> {
+    type Item = Result<Packet, ProtocolError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining.is_empty() {
+            return None;
+        }
+        let rest = core::mem::take(&mut self.remaining);
+        if rest.len() < PACKET_HEADER_LEN {
+            return Some(Err(ProtocolError::Truncated {
+                needed: PACKET_HEADER_LEN,
+                available: rest.len(),
+            }));
+        }
+        let data_len = u16::from_le_bytes([rest[0], rest[1]]);
+        if (data_len as usize) < PACKET_HEADER_LEN {
+            return Some(Err(ProtocolError::InvalidLength(data_len)));
+        }
+        if data_len as usize > rest.len() {
+            return Some(Err(ProtocolError::Truncated {
+                needed: data_len as usize,
+                available: rest.len(),
+            }));
+        }
+        let (dataset, rest) = rest.split_at(data_len as usize);
+        self.remaining = rest;
+        Some(Packet::try_from(dataset))
+    }
+}
+
+/// Splits a UDP datagram into all datasets it contains.
+///
+/// # Errors
+///
+/// Returns an error if any dataset is truncated or declares an invalid length.
+pub fn split_datagram(datagram: &[u8]) -> Result<Vec<Packet>, ProtocolError> {
+    DatagramIter::new(datagram).collect()
+}
+
+/// Concatenates several packets into a single UDP datagram.
+pub fn join_datagram<I>(packets: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = Packet>,
+{
+    let mut datagram = Vec::new();
+    for packet in packets {
+        datagram.extend(Vec::<u8>::from(packet));
+    }
+    datagram
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_split_single() {
+        let packets = split_datagram(&[0x08, 0x00, 0x10, 0x00, 1, 2, 3, 4]).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].get_header(), 0x10);
+        assert_eq!(packets[0].get_data(), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_split_multiple() {
+        let data = [
+            0x05, 0x00, 0x40, 0x00, 0x61, // first
+            0x04, 0x00, 0x30, 0x00, // second
+            0x06, 0x00, 0x51, 0x00, 0xAA, 0xBB, // third
+        ];
+        let packets = split_datagram(&data).unwrap();
+        assert_eq!(packets.len(), 3);
+        assert_eq!(packets[0].get_data(), vec![0x61]);
+        assert_eq!(packets[1].get_header(), 0x30);
+        assert!(packets[1].get_data().is_empty());
+        assert_eq!(packets[2].get_data(), vec![0xAA, 0xBB]);
+    }
+
+    #[test]
+    fn test_split_invalid_lengths() {
+        assert_eq!(
+            split_datagram(&[0x05, 0x00, 0x40]).unwrap_err(),
+            ProtocolError::Truncated {
+                needed: 4,
+                available: 3
+            }
+        );
+        assert!(split_datagram(&[0x09, 0x00, 0x40, 0x00, 0x61]).is_err());
+        assert_eq!(
+            split_datagram(&[0x02, 0x00, 0x40, 0x00]).unwrap_err(),
+            ProtocolError::InvalidLength(2)
+        );
+
+        // A valid dataset before a malformed one is still delivered
+        let mut iter = DatagramIter::new(&[0x04, 0x00, 0x30, 0x00, 0xFF]);
+        assert!(iter.next().unwrap().is_ok());
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_try_from_single() {
+        assert!(Packet::try_from(&[0x04, 0x00, 0x30, 0x00][..]).is_ok());
+        assert!(Packet::try_from(&[0x04, 0x00][..]).is_err());
+        assert_eq!(
+            Packet::try_from(vec![0x04, 0x00, 0x30, 0x00, 0x01]).unwrap_err(),
+            ProtocolError::LengthMismatch {
+                declared: 4,
+                actual: 5
+            }
+        );
+    }
+
+    #[test]
+    fn test_payload_too_large() {
+        let payload = vec![0u8; u16::MAX as usize];
+        assert_eq!(
+            Packet::with_header_and_data(0x40, &payload).unwrap_err(),
+            ProtocolError::PayloadTooLarge(u16::MAX as usize)
+        );
+    }
+
+    #[test]
+    fn test_join_roundtrip() {
+        let packets = vec![
+            Packet::with_header_and_data(0x40, &[0x21, 0x81, 0xA0]).unwrap(),
+            Packet::with_header(0x85),
+        ];
+        let datagram = join_datagram(packets);
+        assert_eq!(datagram.len(), 7 + 4);
+        let split = split_datagram(&datagram).unwrap();
+        assert_eq!(split[0].get_header(), 0x40);
+        assert_eq!(split[0].get_data_len(), 7);
+        assert_eq!(split[1].get_header(), 0x85);
+    }
+}
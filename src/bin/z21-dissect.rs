@@ -0,0 +1,128 @@
+//! Renders Z21 traffic from capture files or hex dumps as human-readable lines.
+//!
+//! ```text
+//! z21-dissect session.pcap
+//! echo "rx 08 00 10 00 40 e2 01 00" | z21-dissect
+//! ```
+//!
+//! Input which is not a capture is read as one datagram per line in hex, optionally
+//! prefixed with `tx` or `rx`. Spaces, commas, colons and `0x` prefixes are ignored.
+
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::UNIX_EPOCH;
+
+use clap::Parser;
+use roco_z21_driver::capture::{parse_capture, CaptureFormat, Direction};
+use roco_z21_driver::dissect::dissect_datagram;
+
+/// Dissects Roco Z21 LAN protocol traffic.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Capture files (native or pcap) or hex dumps. Reads standard input if omitted.
+    files: Vec<PathBuf>,
+    /// Treat hex dump lines without a direction as received from the Z21.
+    #[arg(long)]
+    rx: bool,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let default_direction = if args.rx {
+        Direction::Rx
+    } else {
+        Direction::Tx
+    };
+    let inputs = if args.files.is_empty() {
+        vec![None]
+    } else {
+        args.files.into_iter().map(Some).collect()
+    };
+
+    let mut result = ExitCode::SUCCESS;
+    for input in inputs {
+        let name = input
+            .as_ref()
+            .map_or("<stdin>".to_string(), |path| path.display().to_string());
+        let bytes = match &input {
+            Some(path) => std::fs::read(path),
+            None => {
+                let mut bytes = Vec::new();
+                io::stdin().read_to_end(&mut bytes).map(|_| bytes)
+            }
+        };
+        let dissected = bytes.and_then(|bytes| dissect(&bytes, default_direction));
+        if let Err(e) = dissected {
+            eprintln!("{}: {}", name, e);
+            result = ExitCode::FAILURE;
+        }
+    }
+    result
+}
+
+/// Prints every datagram of a capture or hex dump.
+fn dissect(bytes: &[u8], default_direction: Direction) -> io::Result<()> {
+    // A malformed capture is reported as such instead of as malformed hex.
+    if CaptureFormat::detect(bytes).is_some() {
+        for record in parse_capture(bytes)? {
+            let micros = record
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros();
+            println!("{} {} {}", micros, record.direction, record.peer);
+            print_datagram(&record.data, record.direction);
+        }
+        return Ok(());
+    }
+
+    let text = std::str::from_utf8(bytes)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Neither capture nor hex"))?;
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (direction, hex) = match line.split_once(char::is_whitespace) {
+            Some((prefix, rest)) => match prefix.parse::<Direction>() {
+                Ok(direction) => (direction, rest),
+                Err(_) => (default_direction, line),
+            },
+            None => (default_direction, line),
+        };
+        let datagram = parse_hex(hex).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid hex on line {}", number + 1),
+            )
+        })?;
+        println!("{}", direction);
+        print_datagram(&datagram, direction);
+    }
+    Ok(())
+}
+
+fn print_datagram(datagram: &[u8], direction: Direction) {
+    for line in dissect_datagram(datagram, direction) {
+        println!("    {}", line);
+    }
+}
+
+/// Parses a hex dump like `08 00 10 00`, `0x08,0x00` or `08:00:10:00`.
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    let digits: String = text
+        .replace("0x", "")
+        .replace("0X", "")
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != ',' && *c != ':')
+        .collect();
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(digits.get(i..i + 2)?, 16).ok())
+        .collect()
+}
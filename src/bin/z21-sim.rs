@@ -0,0 +1,43 @@
+//! Runs a simulated Z21 station, e.g. to develop against without hardware.
+//!
+//! ```text
+//! z21-sim --listen 0.0.0.0:21105 --drop-rate 0.05 --reply-delay-ms 20
+//! ```
+
+use std::time::Duration;
+
+use clap::Parser;
+use roco_z21_driver::Z21Simulator;
+
+/// Simulated Roco Z21 station answering LAN clients over UDP.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Address to listen on.
+    #[arg(long, default_value = "0.0.0.0:21105")]
+    listen: String,
+    /// Serial number reported to clients.
+    #[arg(long)]
+    serial: Option<u32>,
+    /// Probability (0.0 to 1.0) of dropping a received datagram.
+    #[arg(long, default_value_t = 0.0)]
+    drop_rate: f64,
+    /// Delay of every reply in milliseconds.
+    #[arg(long, default_value_t = 0)]
+    reply_delay_ms: u64,
+}
+
+#[tokio::main]
+async fn main() -> roco_z21_driver::Result<()> {
+    let args = Args::parse();
+    let simulator = Z21Simulator::bind(&args.listen).await?;
+    if let Some(serial) = args.serial {
+        simulator.set_serial_number(serial);
+    }
+    simulator.set_drop_rate(args.drop_rate);
+    simulator.set_reply_delay(Duration::from_millis(args.reply_delay_ms));
+
+    println!("Simulated Z21 listening on {}", simulator.local_addr());
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
@@ -0,0 +1,373 @@
+//! A blocking API for programs which do not run an async runtime.
+//!
+//! [`Z21Station`] and [`Loco`] wrap their async counterparts with synchronous methods.
+//! The station owns a small Tokio runtime which keeps running the background tasks
+//! (receiving, keep-alive, subscriptions) between calls; every method blocks the calling
+//! thread until the async method it wraps completes.
+//!
+//! Events are consumed either by iterating over [`Z21Station::events`] or by
+//! registering a callback with [`Z21Station::on_event`].
+//!
+//! The blocking types must not be used from within an async runtime, including the
+//! callbacks of [`Z21Station::on_event`]: their methods return
+//! [`Error::InsideRuntime`](crate::Error::InsideRuntime) there instead of blocking.
+//! An [`Events`] iterator ends right away.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use roco_z21_driver::blocking::{Loco, Z21Station};
+//! use roco_z21_driver::Z21Event;
+//!
+//! fn main() -> roco_z21_driver::Result<()> {
+//!     let station = Z21Station::new("192.168.0.111:21105")?;
+//!     station.voltage_on()?;
+//!
+//!     let loco = Loco::control(&station, 3)?;
+//!     loco.drive(50.0)?;
+//!
+//!     for event in station.events() {
+//!         if let Z21Event::LocoInfo(state) = event {
+//!             println!("Loco {}: {:?}%", state.address, state.speed_percentage);
+//!         }
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+use std::future::Future;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::runtime::{Handle, Runtime};
+use tokio_stream::StreamExt;
+
+use crate::error::{Error, Result};
+use crate::messages::{
+    BroadcastFlags, DccThrottleSteps, HardwareInfo, LocoState, Reply, Request, SystemState,
+};
+use crate::{ConnectionState, EventStream, Metrics, Subscription, Z21Event, Z21StationBuilder};
+
+/// Blocking connection to a Z21 station, see [`crate::Z21Station`].
+pub struct Z21Station {
+    // Dropped before the runtime, which stops the remaining tasks.
+    station: Arc<crate::Z21Station>,
+    runtime: Arc<Runtime>,
+}
+
+impl Z21Station {
+    /// Connects to the Z21 station at `station_addr` (typically "192.168.0.111:21105").
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`Error`](crate::Error) if the runtime cannot be started, the UDP
+    /// socket cannot be bound or the station does not answer the handshake.
+    pub fn new(station_addr: &str) -> Result<Self> {
+        Self::connect(Z21StationBuilder::new(station_addr))
+    }
+
+    /// Connects to the Z21 station with the settings of `builder`.
+    ///
+    /// # Errors
+    ///
+    /// See [`Z21Station::new`].
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use roco_z21_driver::blocking::Z21Station;
+    /// # use std::time::Duration;
+    /// # fn example() -> roco_z21_driver::Result<()> {
+    /// let builder = roco_z21_driver::Z21Station::builder("192.168.0.111:21105")
+    ///     .timeout(Duration::from_secs(4));
+    /// let station = Z21Station::connect(builder)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn connect(builder: Z21StationBuilder) -> Result<Self> {
+        if inside_runtime() {
+            return Err(Error::InsideRuntime);
+        }
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("z21-blocking")
+            .enable_all()
+            .build()?;
+        let station = runtime.block_on(builder.connect())?;
+        Ok(Z21Station {
+            station: Arc::new(station),
+            runtime: Arc::new(runtime),
+        })
+    }
+
+    /// Returns the async station, e.g. to hand it to async code running elsewhere.
+    pub fn as_async(&self) -> &Arc<crate::Z21Station> {
+        &self.station
+    }
+
+    fn block_on<T>(&self, future: impl Future<Output = Result<T>>) -> Result<T> {
+        block_on(&self.runtime, future)
+    }
+
+    /// See [`crate::Z21Station::voltage_off`].
+    pub fn voltage_off(&self) -> Result<()> {
+        self.block_on(self.station.voltage_off())
+    }
+
+    /// See [`crate::Z21Station::voltage_on`].
+    pub fn voltage_on(&self) -> Result<()> {
+        self.block_on(self.station.voltage_on())
+    }
+
+    /// See [`crate::Z21Station::get_serial_number`].
+    pub fn get_serial_number(&self) -> Result<u32> {
+        self.block_on(self.station.get_serial_number())
+    }
+
+    /// See [`crate::Z21Station::get_hardware_info`].
+    pub fn get_hardware_info(&self) -> Result<HardwareInfo> {
+        self.block_on(self.station.get_hardware_info())
+    }
+
+    /// See [`crate::Z21Station::request`].
+    pub fn request(&self, request: &Request) -> Result<Reply> {
+        self.block_on(self.station.request(request))
+    }
+
+    /// See [`crate::Z21Station::request_with_timeout`].
+    pub fn request_with_timeout(&self, request: &Request, timeout: Duration) -> Result<Reply> {
+        self.block_on(self.station.request_with_timeout(request, timeout))
+    }
+
+    /// See [`crate::Z21Station::send_request`].
+    pub fn send_request(&self, request: &Request) -> Result<()> {
+        self.block_on(self.station.send_request(request))
+    }
+
+    /// See [`crate::Z21Station::broadcast_flags`].
+    pub fn broadcast_flags(&self) -> BroadcastFlags {
+        self.station.broadcast_flags()
+    }
+
+    /// See [`crate::Z21Station::set_broadcast_flags`].
+    pub fn set_broadcast_flags(&self, flags: BroadcastFlags) -> Result<()> {
+        self.block_on(self.station.set_broadcast_flags(flags))
+    }
+
+    /// See [`crate::Z21Station::connection_state`].
+    pub fn connection_state(&self) -> ConnectionState {
+        self.station.connection_state()
+    }
+
+    /// See [`crate::Z21Station::metrics`].
+    pub fn metrics(&self) -> Metrics {
+        self.station.metrics()
+    }
+
+    /// Returns an iterator over the events of the station.
+    ///
+    /// The iterator blocks until the next event arrives and ends when the station shuts
+    /// down. Events are buffered from the moment it is created.
+    pub fn events(&self) -> Events {
+        Events {
+            stream: self.station.events(),
+            runtime: Arc::clone(&self.runtime),
+        }
+    }
+
+    /// Calls `callback` with every event of the station, on a thread of the internal
+    /// runtime, until the returned [`Subscription`] is dropped.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use roco_z21_driver::blocking::Z21Station;
+    /// # use roco_z21_driver::Z21Event;
+    /// # fn example(station: &Z21Station) {
+    /// let _subscription = station.on_event(|event| {
+    ///     if let Z21Event::TrackPowerOff = event {
+    ///         println!("Track power off");
+    ///     }
+    /// });
+    /// # }
+    /// ```
+    pub fn on_event(&self, callback: impl Fn(Z21Event) + Send + Sync + 'static) -> Subscription {
+        let _runtime = self.runtime.enter();
+        let mut events = self.station.events();
+        self.station
+            .subscribe(&self.station.child_token(), async move {
+                while let Some(event) = events.next().await {
+                    callback(event);
+                }
+            })
+    }
+
+    /// See [`crate::Z21Station::subscribe_system_state`].
+    pub fn subscribe_system_state(
+        &self,
+        freq_in_sec: f64,
+        subscriber: Box<dyn Fn(SystemState) + Send + Sync>,
+    ) -> Subscription {
+        let _runtime = self.runtime.enter();
+        self.station.subscribe_system_state(freq_in_sec, subscriber)
+    }
+
+    /// See [`crate::Z21Station::logout`].
+    pub fn logout(&self) -> Result<()> {
+        self.block_on(self.station.logout())
+    }
+
+    /// See [`crate::Z21Station::shutdown`].
+    pub fn shutdown(&self) -> Result<()> {
+        self.block_on(self.station.shutdown())
+    }
+}
+
+/// Blocking iterator over the events of a station, created by [`Z21Station::events`].
+pub struct Events {
+    stream: EventStream,
+    runtime: Arc<Runtime>,
+}
+
+impl Events {
+    /// Waits at most `timeout` for the next event.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RecvTimeoutError::Timeout`] if no event arrived in time, and
+    /// [`RecvTimeoutError::Disconnected`] once the station shut down or when called from
+    /// within an async runtime.
+    pub fn recv_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> std::result::Result<Z21Event, RecvTimeoutError> {
+        if inside_runtime() {
+            return Err(RecvTimeoutError::Disconnected);
+        }
+        let stream = &mut self.stream;
+        match self
+            .runtime
+            .block_on(async { tokio::time::timeout(timeout, stream.next()).await })
+        {
+            Ok(Some(event)) => Ok(event),
+            Ok(None) => Err(RecvTimeoutError::Disconnected),
+            Err(_) => Err(RecvTimeoutError::Timeout),
+        }
+    }
+}
+
+impl Iterator for Events {
+    type Item = Z21Event;
+
+    fn next(&mut self) -> Option<Z21Event> {
+        if inside_runtime() {
+            return None;
+        }
+        self.runtime.block_on(self.stream.next())
+    }
+}
+
+/// Blocking control of a locomotive, see [`crate::Loco`].
+pub struct Loco {
+    // Dropped before the runtime, which stops the remaining tasks.
+    loco: crate::Loco,
+    runtime: Arc<Runtime>,
+}
+
+impl Loco {
+    /// Controls the loco at `address` with 128 throttle steps.
+    ///
+    /// # Errors
+    ///
+    /// See [`crate::Loco::control`].
+    pub fn control(station: &Z21Station, address: u16) -> Result<Loco> {
+        Self::control_with_steps(station, address, DccThrottleSteps::default())
+    }
+
+    /// Controls the loco at `address` with `steps`.
+    ///
+    /// # Errors
+    ///
+    /// See [`crate::Loco::control_with_steps`].
+    pub fn control_with_steps(
+        station: &Z21Station,
+        address: u16,
+        steps: DccThrottleSteps,
+    ) -> Result<Loco> {
+        let loco = station.block_on(crate::Loco::control_with_steps(
+            Arc::clone(&station.station),
+            address,
+            steps,
+        ))?;
+        Ok(Loco {
+            loco,
+            runtime: Arc::clone(&station.runtime),
+        })
+    }
+
+    /// See [`crate::Loco::drive`].
+    pub fn drive(&self, speed_percent: f64) -> Result<()> {
+        block_on(&self.runtime, self.loco.drive(speed_percent))
+    }
+
+    /// See [`crate::Loco::stop`].
+    pub fn stop(&self) -> Result<()> {
+        block_on(&self.runtime, self.loco.stop())
+    }
+
+    /// See [`crate::Loco::halt`].
+    pub fn halt(&self) -> Result<()> {
+        block_on(&self.runtime, self.loco.halt())
+    }
+
+    /// See [`crate::Loco::set_function`].
+    pub fn set_function(&self, function_index: u8, action: u8) -> Result<()> {
+        block_on(
+            &self.runtime,
+            self.loco.set_function(function_index, action),
+        )
+    }
+
+    /// See [`crate::Loco::function_on`].
+    pub fn function_on(&self, function_index: u8) -> Result<()> {
+        block_on(&self.runtime, self.loco.function_on(function_index))
+    }
+
+    /// See [`crate::Loco::function_off`].
+    pub fn function_off(&self, function_index: u8) -> Result<()> {
+        block_on(&self.runtime, self.loco.function_off(function_index))
+    }
+
+    /// See [`crate::Loco::function_toggle`].
+    pub fn function_toggle(&self, function_index: u8) -> Result<()> {
+        block_on(&self.runtime, self.loco.function_toggle(function_index))
+    }
+
+    /// See [`crate::Loco::set_headlights`].
+    pub fn set_headlights(&self, on: bool) -> Result<()> {
+        block_on(&self.runtime, self.loco.set_headlights(on))
+    }
+
+    /// See [`crate::Loco::subscribe_loco_state`].
+    pub fn subscribe_loco_state(
+        &self,
+        subscriber: Box<dyn Fn(LocoState) + Send + Sync>,
+    ) -> Subscription {
+        let _runtime = self.runtime.enter();
+        self.loco.subscribe_loco_state(subscriber)
+    }
+}
+
+/// Returns whether the calling thread runs an async runtime, where blocking would panic.
+fn inside_runtime() -> bool {
+    Handle::try_current().is_ok()
+}
+
+/// Runs `future` on `runtime` and blocks until it completes.
+fn block_on<T>(runtime: &Runtime, future: impl Future<Output = Result<T>>) -> Result<T> {
+    if inside_runtime() {
+        return Err(Error::InsideRuntime);
+    }
+    runtime.block_on(future)
+}
@@ -0,0 +1,608 @@
+//! Recording and replaying the traffic between a client and a Z21.
+//!
+//! A capture is a list of [`CaptureRecord`]s, each a datagram with its timestamp,
+//! [`Direction`] and addresses. Captures are written by a [`CaptureWriter`], either in
+//! the native text format or as pcap file for Wireshark, and read back with
+//! [`read_capture`].
+//!
+//! - [`CaptureTransport`] wraps any [`Transport`] and records everything sent and
+//!   received over it. [`Z21StationBuilder::capture`](crate::Z21StationBuilder::capture)
+//!   installs it on a station.
+//! - [`ReplayTransport`] feeds a capture back into a station as if the Z21 was
+//!   answering live, e.g. to reproduce a bug without the hardware.
+//!
+//! # Native format
+//!
+//! One datagram per line: the timestamp in microseconds since the UNIX epoch, `tx` for
+//! datagrams sent to the Z21 or `rx` for datagrams received from it, the local and the
+//! peer address and the datagram as hex:
+//!
+//! ```text
+//! 1700000000000000 tx 192.168.0.10:51000 192.168.0.111:21105 04001000
+//! 1700000000001532 rx 192.168.0.10:51000 192.168.0.111:21105 0800100040e20100
+//! ```
+//!
+//! Empty lines and lines starting with `#` are ignored.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # use roco_z21_driver::capture::{CaptureFormat, CaptureWriter, ReplayTransport};
+//! # use roco_z21_driver::Z21Station;
+//! # async fn example() -> roco_z21_driver::Result<()> {
+//! // At the layout
+//! let writer = CaptureWriter::create("session.pcap", CaptureFormat::Pcap)?;
+//! let station = Z21Station::builder("192.168.0.111:21105")
+//!     .capture(writer)
+//!     .connect()
+//!     .await?;
+//!
+//! // Later, without the hardware
+//! let replay = ReplayTransport::open("session.pcap")?;
+//! let station = Z21Station::builder("replay")
+//!     .connect_with_transport(replay)
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod pcap;
+mod replay;
+
+pub use replay::ReplayTransport;
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::transport::{BoxFuture, Transport};
+use crate::Z21_DEFAULT_PORT;
+
+/// Direction of a captured datagram, seen from the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// Sent to the Z21.
+    Tx,
+    /// Received from the Z21.
+    Rx,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Direction::Tx => write!(f, "tx"),
+            Direction::Rx => write!(f, "rx"),
+        }
+    }
+}
+
+impl FromStr for Direction {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s {
+            "tx" => Ok(Direction::Tx),
+            "rx" => Ok(Direction::Rx),
+            other => Err(invalid_data(format!("Invalid direction {:?}", other))),
+        }
+    }
+}
+
+/// A captured datagram.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureRecord {
+    /// When the datagram was sent or received.
+    pub timestamp: SystemTime,
+    /// Whether the datagram was sent or received.
+    pub direction: Direction,
+    /// Address of the client.
+    pub local: SocketAddr,
+    /// Address of the Z21.
+    pub peer: SocketAddr,
+    /// The datagram, one or more packets.
+    pub data: Vec<u8>,
+}
+
+impl CaptureRecord {
+    /// Formats the record as a line of the native format, without line break.
+    pub fn to_line(&self) -> String {
+        let micros = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros();
+        let hex: String = self
+            .data
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        format!(
+            "{} {} {} {} {}",
+            micros, self.direction, self.local, self.peer, hex
+        )
+    }
+
+    /// Parses a line of the native format.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`io::Error`] of kind [`InvalidData`](io::ErrorKind::InvalidData) if
+    /// the line is malformed.
+    pub fn from_line(line: &str) -> io::Result<Self> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [micros, direction, local, peer, hex] = fields[..] else {
+            return Err(invalid_data(format!("Malformed capture line {:?}", line)));
+        };
+        let micros: u64 = micros
+            .parse()
+            .map_err(|_| invalid_data(format!("Invalid timestamp {:?}", micros)))?;
+        let address = |addr: &str| {
+            addr.parse::<SocketAddr>()
+                .map_err(|_| invalid_data(format!("Invalid address {:?}", addr)))
+        };
+        if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid_data(format!("Invalid hex {:?}", hex)));
+        }
+        if hex.len() % 2 != 0 {
+            return Err(invalid_data(format!("Odd number of hex digits {:?}", hex)));
+        }
+        // Only ASCII hex digits are left, so the pairs are valid slices without signs.
+        let data = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| invalid_data(format!("Invalid hex {:?}", hex)))?;
+        Ok(CaptureRecord {
+            timestamp: UNIX_EPOCH + Duration::from_micros(micros),
+            direction: direction.parse()?,
+            local: address(local)?,
+            peer: address(peer)?,
+            data,
+        })
+    }
+}
+
+/// File format of a capture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureFormat {
+    /// One line of text per datagram, see the [module documentation](self).
+    Native,
+    /// pcap with raw IP and UDP headers, readable by Wireshark and tcpdump. Reading also
+    /// accepts pcapng, the default format of Wireshark.
+    Pcap,
+}
+
+impl CaptureFormat {
+    /// Guesses the format of a capture from its contents.
+    ///
+    /// Returns `None` if `bytes` look like neither format, e.g. a plain hex dump. The
+    /// capture may still be malformed, see [`parse_capture`].
+    pub fn detect(bytes: &[u8]) -> Option<CaptureFormat> {
+        if pcap::is_pcap(bytes) {
+            return Some(CaptureFormat::Pcap);
+        }
+        let text = std::str::from_utf8(bytes).ok()?;
+        let line = text
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))?;
+        (line.split_whitespace().count() == 5).then_some(CaptureFormat::Native)
+    }
+}
+
+/// Writes [`CaptureRecord`]s to a file or any other [`Write`].
+///
+/// Records are written by a background thread, so capturing never blocks the tasks of
+/// a station. The thread flushes the output whenever it has caught up, so a capture is
+/// complete up to the last few datagrams even if the program crashes. Clones write to
+/// the same output; dropping the last clone waits until every record is written.
+#[derive(Clone)]
+pub struct CaptureWriter {
+    inner: Arc<WriterThread>,
+}
+
+struct WriterThread {
+    commands: Option<mpsc::Sender<Command>>,
+    thread: Option<JoinHandle<()>>,
+    format: CaptureFormat,
+}
+
+enum Command {
+    Write(CaptureRecord),
+    Flush(mpsc::Sender<()>),
+}
+
+impl CaptureWriter {
+    /// Creates a writer to `out`, writing the file header of `format` if it has one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`io::Error`] if the header cannot be written or the writer thread
+    /// cannot be started.
+    pub fn new(out: impl Write + Send + 'static, format: CaptureFormat) -> io::Result<Self> {
+        let mut out: Box<dyn Write + Send> = Box::new(out);
+        if format == CaptureFormat::Pcap {
+            pcap::write_header(&mut out)?;
+            out.flush()?;
+        }
+        let (commands, receiver) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("z21-capture".into())
+            .spawn(move || {
+                if let Err(e) = write_commands(out, format, receiver) {
+                    crate::log::error!("Failed to write capture: {}", e);
+                }
+            })?;
+        Ok(CaptureWriter {
+            inner: Arc::new(WriterThread {
+                commands: Some(commands),
+                thread: Some(thread),
+                format,
+            }),
+        })
+    }
+
+    /// Creates (or truncates) the file at `path` and writes the capture to it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`io::Error`] if the file cannot be created.
+    pub fn create(path: impl AsRef<Path>, format: CaptureFormat) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), format)
+    }
+
+    /// Queues `record` to be appended to the capture.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`io::Error`] if writing an earlier record failed, which stops the
+    /// capture.
+    pub fn write(&self, record: &CaptureRecord) -> io::Result<()> {
+        self.send(Command::Write(record.clone()))
+    }
+
+    /// Waits until every queued record is written and the output is flushed.
+    ///
+    /// This blocks the calling thread, it is meant for the end of a capture.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`io::Error`] if a record or the flush failed.
+    pub fn flush(&self) -> io::Result<()> {
+        let (done, flushed) = mpsc::channel();
+        self.send(Command::Flush(done))?;
+        flushed.recv().map_err(|_| stopped())
+    }
+
+    fn send(&self, command: Command) -> io::Result<()> {
+        let commands = self.inner.commands.as_ref().ok_or_else(stopped)?;
+        commands.send(command).map_err(|_| stopped())
+    }
+}
+
+impl Drop for WriterThread {
+    fn drop(&mut self) {
+        // Closing the channel ends the thread once it has written the queued records.
+        self.commands.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl fmt::Debug for CaptureWriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CaptureWriter")
+            .field("format", &self.inner.format)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Body of the writer thread, runs until the last [`CaptureWriter`] is dropped.
+fn write_commands(
+    mut out: Box<dyn Write + Send>,
+    format: CaptureFormat,
+    commands: mpsc::Receiver<Command>,
+) -> io::Result<()> {
+    while let Ok(mut command) = commands.recv() {
+        // Everything queued is written before a single flush.
+        let mut waiting = Vec::new();
+        loop {
+            match command {
+                Command::Write(record) => match format {
+                    CaptureFormat::Native => writeln!(out, "{}", record.to_line())?,
+                    CaptureFormat::Pcap => pcap::write_record(&mut out, &record)?,
+                },
+                Command::Flush(done) => waiting.push(done),
+            }
+            match commands.try_recv() {
+                Ok(next) => command = next,
+                Err(_) => break,
+            }
+        }
+        out.flush()?;
+        for done in waiting {
+            let _ = done.send(());
+        }
+    }
+    Ok(())
+}
+
+/// Reads a capture file in any of the [`CaptureFormat`]s.
+///
+/// # Errors
+///
+/// Returns an [`io::Error`] if the file cannot be read or is malformed.
+pub fn read_capture(path: impl AsRef<Path>) -> io::Result<Vec<CaptureRecord>> {
+    parse_capture(&std::fs::read(path)?)
+}
+
+/// Parses a capture in any of the [`CaptureFormat`]s.
+///
+/// pcap and pcapng files do not tell which side is the client: the sender of the first
+/// datagram is taken as the client, as the client always speaks first. Datagrams cut
+/// short by the snapshot length of the capture are skipped with a warning.
+///
+/// # Errors
+///
+/// Returns an [`io::Error`] of kind [`InvalidData`](io::ErrorKind::InvalidData) if the
+/// capture is malformed.
+pub fn parse_capture(bytes: &[u8]) -> io::Result<Vec<CaptureRecord>> {
+    if pcap::is_pcap(bytes) {
+        return pcap::parse(bytes);
+    }
+    let text = std::str::from_utf8(bytes).map_err(|_| invalid_data("Capture is not text"))?;
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(CaptureRecord::from_line)
+        .collect()
+}
+
+/// A [`Transport`] recording every datagram sent and received over another transport.
+#[derive(Debug)]
+pub struct CaptureTransport<T> {
+    transport: T,
+    writer: CaptureWriter,
+    local: SocketAddr,
+    peer: SocketAddr,
+}
+
+impl<T: Transport> CaptureTransport<T> {
+    /// Records the traffic of `transport` to `writer`.
+    ///
+    /// Transports without addresses are recorded as `0.0.0.0:0` talking to
+    /// `0.0.0.0:21105`.
+    pub fn new(transport: T, writer: CaptureWriter) -> Self {
+        let local = transport
+            .local_addr()
+            .unwrap_or(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+        let peer = transport
+            .peer_addr()
+            .unwrap_or(SocketAddr::from((Ipv4Addr::UNSPECIFIED, Z21_DEFAULT_PORT)));
+        CaptureTransport {
+            transport,
+            writer,
+            local,
+            peer,
+        }
+    }
+
+    fn record(&self, direction: Direction, data: &[u8]) {
+        let record = CaptureRecord {
+            timestamp: SystemTime::now(),
+            direction,
+            local: self.local,
+            peer: self.peer,
+            data: data.to_vec(),
+        };
+        // A broken capture must not break the connection.
+        if let Err(e) = self.writer.write(&record) {
+            crate::log::error!("Failed to write capture: {}", e);
+        }
+    }
+}
+
+impl<T: Transport> Transport for CaptureTransport<T> {
+    fn send<'a>(&'a self, datagram: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            self.transport.send(datagram).await?;
+            self.record(Direction::Tx, datagram);
+            Ok(())
+        })
+    }
+
+    fn recv<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>> {
+        Box::pin(async move {
+            let len = self.transport.recv(buf).await?;
+            self.record(Direction::Rx, &buf[..len]);
+            Ok(len)
+        })
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.transport.local_addr()
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.transport.peer_addr()
+    }
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn stopped() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "Capture writer stopped after an error",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// A [`Write`] whose contents stay readable after it was moved into a writer.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn records() -> Vec<CaptureRecord> {
+        let local = "192.168.0.10:51000".parse().unwrap();
+        let peer = "192.168.0.111:21105".parse().unwrap();
+        let start = UNIX_EPOCH + Duration::from_micros(1_700_000_000_000_000);
+        vec![
+            CaptureRecord {
+                timestamp: start,
+                direction: Direction::Tx,
+                local,
+                peer,
+                data: vec![0x04, 0x00, 0x10, 0x00],
+            },
+            CaptureRecord {
+                timestamp: start + Duration::from_micros(1532),
+                direction: Direction::Rx,
+                local,
+                peer,
+                data: vec![0x08, 0x00, 0x10, 0x00, 0x40, 0xE2, 0x01, 0x00],
+            },
+        ]
+    }
+
+    fn roundtrip(format: CaptureFormat) -> Vec<CaptureRecord> {
+        let buffer = SharedBuffer::default();
+        let writer = CaptureWriter::new(buffer.clone(), format).unwrap();
+        for record in records() {
+            writer.write(&record).unwrap();
+        }
+        writer.flush().unwrap();
+        let bytes = buffer.0.lock().unwrap().clone();
+        parse_capture(&bytes).unwrap()
+    }
+
+    #[test]
+    fn test_native_roundtrip() {
+        let line = records()[1].to_line();
+        assert_eq!(
+            line,
+            "1700000000001532 rx 192.168.0.10:51000 192.168.0.111:21105 0800100040e20100"
+        );
+        assert_eq!(roundtrip(CaptureFormat::Native), records());
+    }
+
+    #[test]
+    fn test_pcap_roundtrip() {
+        assert_eq!(roundtrip(CaptureFormat::Pcap), records());
+    }
+
+    /// Appends a pcapng block with `body`, padded to 32 bits.
+    fn pcapng_block(out: &mut Vec<u8>, block_type: u32, body: &[u8]) {
+        let len = (12 + body.len().next_multiple_of(4)) as u32;
+        out.extend_from_slice(&block_type.to_le_bytes());
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(body);
+        out.resize(out.len() + body.len().next_multiple_of(4) - body.len(), 0);
+        out.extend_from_slice(&len.to_le_bytes());
+    }
+
+    /// Converts a pcap file as written by [`CaptureWriter`] to pcapng with nanosecond
+    /// timestamps, cutting the last byte off every frame for which `truncate` is true.
+    fn to_pcapng(pcap: &[u8], truncate: impl Fn(usize) -> bool) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut section_header = 0x1A2B_3C4Du32.to_le_bytes().to_vec();
+        section_header.extend_from_slice(&[1, 0, 0, 0]);
+        section_header.extend_from_slice(&(-1i64).to_le_bytes());
+        pcapng_block(&mut out, 0x0A0D_0D0A, &section_header);
+        // LINKTYPE_RAW, snaplen and if_tsresol 9 followed by opt_endofopt.
+        let interface = [
+            101, 0, 0, 0, 0xFF, 0xFF, 0, 0, 9, 0, 1, 0, 9, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        pcapng_block(&mut out, 1, &interface);
+
+        let mut rest = &pcap[24..];
+        let mut index = 0;
+        while !rest.is_empty() {
+            let seconds = u32::from_le_bytes(rest[0..4].try_into().unwrap()) as u64;
+            let micros = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as u64;
+            let len = u32::from_le_bytes(rest[8..12].try_into().unwrap()) as usize;
+            let frame = &rest[16..16 + len];
+            rest = &rest[16 + len..];
+
+            let nanos = seconds * 1_000_000_000 + micros * 1000;
+            let captured = if truncate(index) { len - 1 } else { len };
+            let mut packet = Vec::new();
+            packet.extend_from_slice(&0u32.to_le_bytes());
+            packet.extend_from_slice(&((nanos >> 32) as u32).to_le_bytes());
+            packet.extend_from_slice(&(nanos as u32).to_le_bytes());
+            packet.extend_from_slice(&(captured as u32).to_le_bytes());
+            packet.extend_from_slice(&(len as u32).to_le_bytes());
+            packet.extend_from_slice(&frame[..captured]);
+            pcapng_block(&mut out, 6, &packet);
+            index += 1;
+        }
+        out
+    }
+
+    fn pcap_bytes() -> Vec<u8> {
+        let mut pcap = Vec::new();
+        pcap::write_header(&mut pcap).unwrap();
+        for record in records() {
+            pcap::write_record(&mut pcap, &record).unwrap();
+        }
+        pcap
+    }
+
+    #[test]
+    fn test_parse_pcapng() {
+        let pcapng = to_pcapng(&pcap_bytes(), |_| false);
+        assert_eq!(CaptureFormat::detect(&pcapng), Some(CaptureFormat::Pcap));
+        assert_eq!(parse_capture(&pcapng).unwrap(), records());
+    }
+
+    #[test]
+    fn test_parse_skips_truncated_datagrams() {
+        let pcapng = to_pcapng(&pcap_bytes(), |index| index == 1);
+        assert_eq!(parse_capture(&pcapng).unwrap(), records()[..1]);
+        assert!(parse_capture(&pcapng[..pcapng.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_detect_format() {
+        let native = records()[0].to_line();
+        assert_eq!(
+            CaptureFormat::detect(native.as_bytes()),
+            Some(CaptureFormat::Native)
+        );
+        let mut pcap = Vec::new();
+        pcap::write_header(&mut pcap).unwrap();
+        assert_eq!(CaptureFormat::detect(&pcap), Some(CaptureFormat::Pcap));
+        assert_eq!(CaptureFormat::detect(b"tx 04001000"), None);
+    }
+
+    #[test]
+    fn test_parse_rejects_garbage() {
+        assert!(parse_capture(b"1 up 1.2.3.4:5 1.2.3.4:6 00").is_err());
+        assert!(parse_capture(b"1 tx 1.2.3.4:5 1.2.3.4:6 0").is_err());
+        assert!(parse_capture(b"1 tx 1.2.3.4:5 1.2.3.4:6 +f").is_err());
+        assert!(parse_capture("1 tx 1.2.3.4:5 1.2.3.4:6 0é0".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,403 @@
+//! The pcap file format, with every datagram wrapped in IP and UDP headers.
+//!
+//! Records are written with link type `LINKTYPE_RAW`, i.e. starting with the IPv4 or
+//! IPv6 header. Reading also accepts Ethernet, IPv4 and IPv6 link types, nanosecond
+//! timestamps and pcapng files, as written by tcpdump and Wireshark.
+
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{invalid_data, CaptureRecord, Direction};
+use crate::log;
+
+const MAGIC_MICROS: u32 = 0xA1B2_C3D4;
+const MAGIC_NANOS: u32 = 0xA1B2_3C4D;
+const SNAPLEN: u32 = 65535;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 1;
+const BLOCK_ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const OPTION_END: u16 = 0;
+const OPTION_IF_TSRESOL: u16 = 9;
+
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+const IP_PROTO_UDP: u8 = 17;
+const UDP_HEADER_LEN: usize = 8;
+
+/// Returns `true` if `bytes` start with a pcap file header or a pcapng section header.
+pub(super) fn is_pcap(bytes: &[u8]) -> bool {
+    bytes.len() >= 4 && {
+        let magic = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        [u32::from_le_bytes(magic), u32::from_be_bytes(magic)]
+            .iter()
+            .any(|magic| *magic == MAGIC_MICROS || *magic == MAGIC_NANOS)
+    } || is_pcapng(bytes)
+}
+
+fn is_pcapng(bytes: &[u8]) -> bool {
+    bytes.len() >= 12
+        && read_u32(&bytes[0..4], true) == BLOCK_SECTION_HEADER
+        && byte_order(&bytes[8..12]).is_some()
+}
+
+/// Endianness of a pcapng section from its byte-order magic, `true` for little endian.
+fn byte_order(magic: &[u8]) -> Option<bool> {
+    if read_u32(magic, true) == BYTE_ORDER_MAGIC {
+        Some(true)
+    } else if read_u32(magic, false) == BYTE_ORDER_MAGIC {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+fn read_u32(bytes: &[u8], little_endian: bool) -> u32 {
+    let word = [bytes[0], bytes[1], bytes[2], bytes[3]];
+    if little_endian {
+        u32::from_le_bytes(word)
+    } else {
+        u32::from_be_bytes(word)
+    }
+}
+
+fn read_u16(bytes: &[u8], little_endian: bool) -> u16 {
+    let word = [bytes[0], bytes[1]];
+    if little_endian {
+        u16::from_le_bytes(word)
+    } else {
+        u16::from_be_bytes(word)
+    }
+}
+
+/// Writes the pcap file header.
+pub(super) fn write_header(out: &mut dyn Write) -> io::Result<()> {
+    out.write_all(&MAGIC_MICROS.to_le_bytes())?;
+    out.write_all(&2u16.to_le_bytes())?;
+    out.write_all(&4u16.to_le_bytes())?;
+    // Time zone and accuracy of the timestamps, always 0.
+    out.write_all(&0i32.to_le_bytes())?;
+    out.write_all(&0u32.to_le_bytes())?;
+    out.write_all(&SNAPLEN.to_le_bytes())?;
+    out.write_all(&LINKTYPE_RAW.to_le_bytes())
+}
+
+/// Writes `record` as a UDP datagram between its local and peer address.
+pub(super) fn write_record(out: &mut dyn Write, record: &CaptureRecord) -> io::Result<()> {
+    let (src, dst) = match record.direction {
+        Direction::Tx => (record.local, record.peer),
+        Direction::Rx => (record.peer, record.local),
+    };
+    let packet = ip_packet(src, dst, &record.data)?;
+    let since_epoch = record
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    out.write_all(&(since_epoch.as_secs() as u32).to_le_bytes())?;
+    out.write_all(&since_epoch.subsec_micros().to_le_bytes())?;
+    out.write_all(&(packet.len() as u32).to_le_bytes())?;
+    out.write_all(&(packet.len() as u32).to_le_bytes())?;
+    out.write_all(&packet)
+}
+
+/// Builds an IPv4 (or, if any address is IPv6, IPv6) packet carrying `data` over UDP.
+fn ip_packet(src: SocketAddr, dst: SocketAddr, data: &[u8]) -> io::Result<Vec<u8>> {
+    let udp_len = u16::try_from(UDP_HEADER_LEN + data.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Datagram too long"))?;
+    let mut udp = Vec::with_capacity(udp_len as usize);
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&udp_len.to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(data);
+
+    let mut packet = Vec::with_capacity(40 + udp.len());
+    let pseudo_header = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let total_len = (20 + udp.len()) as u16;
+            packet.extend_from_slice(&[0x45, 0x00]);
+            packet.extend_from_slice(&total_len.to_be_bytes());
+            // Identification, don't fragment, TTL 64, UDP, checksum filled in below.
+            packet.extend_from_slice(&[0x00, 0x00, 0x40, 0x00, 64, IP_PROTO_UDP, 0, 0]);
+            packet.extend_from_slice(&src_ip.octets());
+            packet.extend_from_slice(&dst_ip.octets());
+            let header_checksum = checksum(&packet, 0);
+            packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+
+            let mut pseudo_header = Vec::with_capacity(12);
+            pseudo_header.extend_from_slice(&src_ip.octets());
+            pseudo_header.extend_from_slice(&dst_ip.octets());
+            pseudo_header.extend_from_slice(&[0, IP_PROTO_UDP]);
+            pseudo_header.extend_from_slice(&udp_len.to_be_bytes());
+            pseudo_header
+        }
+        (src_ip, dst_ip) => {
+            let (src_ip, dst_ip) = (to_ipv6(src_ip), to_ipv6(dst_ip));
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&udp_len.to_be_bytes());
+            packet.extend_from_slice(&[IP_PROTO_UDP, 64]);
+            packet.extend_from_slice(&src_ip.octets());
+            packet.extend_from_slice(&dst_ip.octets());
+
+            let mut pseudo_header = Vec::with_capacity(40);
+            pseudo_header.extend_from_slice(&src_ip.octets());
+            pseudo_header.extend_from_slice(&dst_ip.octets());
+            pseudo_header.extend_from_slice(&(udp_len as u32).to_be_bytes());
+            pseudo_header.extend_from_slice(&[0, 0, 0, IP_PROTO_UDP]);
+            pseudo_header
+        }
+    };
+    // A computed checksum of 0 is sent as all ones, 0 means "no checksum".
+    let udp_checksum = match checksum(&udp, sum(&pseudo_header)) {
+        0 => 0xFFFF,
+        checksum => checksum,
+    };
+    udp[6..8].copy_from_slice(&udp_checksum.to_be_bytes());
+    packet.extend_from_slice(&udp);
+    Ok(packet)
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Sum of the big-endian 16-bit words of `bytes`.
+fn sum(bytes: &[u8]) -> u32 {
+    bytes
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32)
+        .sum()
+}
+
+/// Internet checksum of `bytes`, continuing the sum `initial`.
+fn checksum(bytes: &[u8], initial: u32) -> u16 {
+    let mut sum = initial + sum(bytes);
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// A captured frame of a pcap or pcapng file.
+struct Frame<'a> {
+    timestamp: SystemTime,
+    link_type: u32,
+    data: &'a [u8],
+}
+
+/// Reads the UDP datagrams of a pcap or pcapng file. Other packets are skipped.
+pub(super) fn parse(bytes: &[u8]) -> io::Result<Vec<CaptureRecord>> {
+    let frames = if is_pcapng(bytes) {
+        pcapng_frames(bytes)?
+    } else {
+        pcap_frames(bytes)?
+    };
+    let mut records = Vec::new();
+    let mut client = None;
+    for frame in frames {
+        let Some((src, dst, data)) = udp_datagram(frame.link_type, frame.data) else {
+            continue;
+        };
+        let client = *client.get_or_insert(src);
+        let (direction, local, peer) = if src == client {
+            (Direction::Tx, src, dst)
+        } else {
+            (Direction::Rx, dst, src)
+        };
+        records.push(CaptureRecord {
+            timestamp: frame.timestamp,
+            direction,
+            local,
+            peer,
+            data: data.to_vec(),
+        });
+    }
+    Ok(records)
+}
+
+fn pcap_frames(bytes: &[u8]) -> io::Result<Vec<Frame<'_>>> {
+    let truncated = || invalid_data("Truncated pcap file");
+    let header = bytes.get(..24).ok_or_else(truncated)?;
+    let magic = [header[0], header[1], header[2], header[3]];
+    let little_endian = matches!(u32::from_le_bytes(magic), MAGIC_MICROS | MAGIC_NANOS);
+    let nanos = read_u32(&header[0..4], little_endian) == MAGIC_NANOS;
+    let link_type = read_u32(&header[20..24], little_endian);
+
+    let mut frames = Vec::new();
+    let mut rest = &bytes[24..];
+    while !rest.is_empty() {
+        let record_header = rest.get(..16).ok_or_else(truncated)?;
+        let seconds = read_u32(&record_header[0..4], little_endian) as u64;
+        let fraction = read_u32(&record_header[4..8], little_endian) as u64;
+        let len = read_u32(&record_header[8..12], little_endian) as usize;
+        let data = rest.get(16..16 + len).ok_or_else(truncated)?;
+        rest = &rest[16 + len..];
+
+        let fraction = if nanos {
+            Duration::from_nanos(fraction)
+        } else {
+            Duration::from_micros(fraction)
+        };
+        frames.push(Frame {
+            timestamp: UNIX_EPOCH + Duration::from_secs(seconds) + fraction,
+            link_type,
+            data,
+        });
+    }
+    Ok(frames)
+}
+
+/// An interface of a pcapng section.
+struct Interface {
+    link_type: u32,
+    /// Timestamp units per second.
+    units_per_second: u128,
+}
+
+/// Reads the Enhanced Packet Blocks of a pcapng file. Other blocks are skipped.
+fn pcapng_frames(bytes: &[u8]) -> io::Result<Vec<Frame<'_>>> {
+    let truncated = || invalid_data("Truncated pcapng file");
+    let mut frames = Vec::new();
+    let mut interfaces = Vec::new();
+    let mut little_endian = true;
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let block_type = read_u32(rest.get(..4).ok_or_else(truncated)?, little_endian);
+        if block_type == BLOCK_SECTION_HEADER {
+            // Every section has its own byte order and interfaces.
+            let magic = rest.get(8..12).ok_or_else(truncated)?;
+            little_endian =
+                byte_order(magic).ok_or_else(|| invalid_data("Invalid pcapng byte order"))?;
+            interfaces.clear();
+        }
+        let len = read_u32(rest.get(4..8).ok_or_else(truncated)?, little_endian) as usize;
+        if len < 12 || !len.is_multiple_of(4) {
+            return Err(invalid_data("Invalid pcapng block length"));
+        }
+        let block = rest.get(..len).ok_or_else(truncated)?;
+        let body = &block[8..len - 4];
+        rest = &rest[len..];
+
+        match block_type {
+            BLOCK_INTERFACE_DESCRIPTION => {
+                let header = body.get(..8).ok_or_else(truncated)?;
+                interfaces.push(Interface {
+                    link_type: read_u16(&header[0..2], little_endian) as u32,
+                    units_per_second: units_per_second(&body[8..], little_endian)?,
+                });
+            }
+            BLOCK_ENHANCED_PACKET => {
+                let header = body.get(..20).ok_or_else(truncated)?;
+                let interface = interfaces
+                    .get(read_u32(&header[0..4], little_endian) as usize)
+                    .ok_or_else(|| invalid_data("Packet of an undescribed pcapng interface"))?;
+                let units = (read_u32(&header[4..8], little_endian) as u64) << 32
+                    | read_u32(&header[8..12], little_endian) as u64;
+                let captured_len = read_u32(&header[12..16], little_endian) as usize;
+                let data = body.get(20..20 + captured_len).ok_or_else(truncated)?;
+                let nanos = units as u128 * 1_000_000_000 / interface.units_per_second;
+                let nanos = u64::try_from(nanos).unwrap_or(u64::MAX);
+                frames.push(Frame {
+                    timestamp: UNIX_EPOCH + Duration::from_nanos(nanos),
+                    link_type: interface.link_type,
+                    data,
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(frames)
+}
+
+/// Resolution of the timestamps of an interface, from its `if_tsresol` option.
+fn units_per_second(mut options: &[u8], little_endian: bool) -> io::Result<u128> {
+    let truncated = || invalid_data("Truncated pcapng options");
+    while options.len() >= 4 {
+        let code = read_u16(&options[0..2], little_endian);
+        let len = read_u16(&options[2..4], little_endian) as usize;
+        if code == OPTION_END {
+            break;
+        }
+        let value = options.get(4..4 + len).ok_or_else(truncated)?;
+        if code == OPTION_IF_TSRESOL {
+            let resolution = *value.first().ok_or_else(truncated)?;
+            // The most significant bit selects a power of two instead of ten.
+            let units = if resolution & 0x80 == 0 {
+                10u128.checked_pow(resolution.into())
+            } else {
+                2u128.checked_pow((resolution & 0x7F).into())
+            };
+            return units.ok_or_else(|| invalid_data("Unsupported pcapng timestamp resolution"));
+        }
+        options = options
+            .get(4 + len.next_multiple_of(4)..)
+            .unwrap_or_default();
+    }
+    Ok(1_000_000)
+}
+
+/// Extracts source, destination and payload of a UDP datagram from a captured frame.
+fn udp_datagram(link_type: u32, frame: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let ip = match link_type {
+        LINKTYPE_ETHERNET => {
+            let ether_type = u16::from_be_bytes([*frame.get(12)?, *frame.get(13)?]);
+            if ether_type != ETHERTYPE_IPV4 && ether_type != ETHERTYPE_IPV6 {
+                return None;
+            }
+            &frame[14..]
+        }
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => frame,
+        _ => return None,
+    };
+    let (src_ip, dst_ip, udp): (IpAddr, IpAddr, &[u8]) = match ip.first()? >> 4 {
+        4 => {
+            let header_len = ((ip[0] & 0x0F) as usize) * 4;
+            if *ip.get(9)? != IP_PROTO_UDP {
+                return None;
+            }
+            let src: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = ip.get(16..20)?.try_into().ok()?;
+            (src.into(), dst.into(), ip.get(header_len..)?)
+        }
+        6 => {
+            if *ip.get(6)? != IP_PROTO_UDP {
+                return None;
+            }
+            let src: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = ip.get(24..40)?.try_into().ok()?;
+            (src.into(), dst.into(), ip.get(40..)?)
+        }
+        _ => return None,
+    };
+    let src_port = u16::from_be_bytes([*udp.first()?, *udp.get(1)?]);
+    let dst_port = u16::from_be_bytes([*udp.get(2)?, *udp.get(3)?]);
+    let udp_len = u16::from_be_bytes([*udp.get(4)?, *udp.get(5)?]) as usize;
+    if udp_len < UDP_HEADER_LEN {
+        return None;
+    }
+    let Some(data) = udp.get(UDP_HEADER_LEN..udp_len) else {
+        log::warning!(
+            "Skipping UDP datagram from {}:{} truncated to {} of {} bytes",
+            src_ip,
+            src_port,
+            udp.len(),
+            udp_len
+        );
+        return None;
+    };
+    Some((
+        SocketAddr::new(src_ip, src_port),
+        SocketAddr::new(dst_ip, dst_port),
+        data,
+    ))
+}
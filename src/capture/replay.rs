@@ -0,0 +1,119 @@
+//! Replaying a capture into a station.
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+use super::{read_capture, CaptureRecord, Direction};
+use crate::transport::{BoxFuture, Transport};
+
+/// A [`Transport`] answering with the datagrams received in a capture.
+///
+/// The received (`rx`) datagrams of the capture are handed to the station in their
+/// original order. Each one is held back until the station sent as many datagrams as
+/// had been sent before it in the capture, so a reply never arrives before the request
+/// it answers. What the station sends is not compared with the capture, but kept for
+/// inspection with [`ReplayTransport::sent`].
+///
+/// Once the capture is exhausted the transport stays silent, like a Z21 which was
+/// switched off.
+#[derive(Debug)]
+pub struct ReplayTransport {
+    records: Vec<CaptureRecord>,
+    /// Index of the next record to replay.
+    position: tokio::sync::Mutex<usize>,
+    /// Number of datagrams sent by the station.
+    sent_count: watch::Sender<usize>,
+    sent: Mutex<Vec<Vec<u8>>>,
+    realtime: bool,
+}
+
+impl ReplayTransport {
+    /// Creates a transport replaying `records`.
+    pub fn new(records: Vec<CaptureRecord>) -> Self {
+        ReplayTransport {
+            records,
+            position: tokio::sync::Mutex::new(0),
+            sent_count: watch::Sender::new(0),
+            sent: Mutex::new(Vec::new()),
+            realtime: false,
+        }
+    }
+
+    /// Creates a transport replaying the capture file at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`io::Error`] if the file cannot be read or is malformed.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(read_capture(path)?))
+    }
+
+    /// Keeps the original gaps between the datagrams, instead of replaying as fast as
+    /// the station sends. Disabled by default.
+    pub fn realtime(mut self, realtime: bool) -> Self {
+        self.realtime = realtime;
+        self
+    }
+
+    /// Returns the datagrams the station sent so far.
+    pub fn sent(&self) -> Vec<Vec<u8>> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn send<'a>(&'a self, datagram: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            self.sent.lock().unwrap().push(datagram.to_vec());
+            self.sent_count.send_modify(|count| *count += 1);
+            Ok(())
+        })
+    }
+
+    fn recv<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>> {
+        Box::pin(async move {
+            let mut position = self.position.lock().await;
+            let mut sent_before = self.records[..*position]
+                .iter()
+                .filter(|record| record.direction == Direction::Tx)
+                .count();
+            // The position only advances once a record is returned, a cancelled call
+            // loses nothing.
+            let mut next = *position;
+            while let Some(record) = self.records.get(next) {
+                next += 1;
+                if record.direction == Direction::Tx {
+                    sent_before += 1;
+                    continue;
+                }
+                let mut sent_count = self.sent_count.subscribe();
+                // The sender lives as long as the transport, waiting cannot fail.
+                let _ = sent_count.wait_for(|count| *count >= sent_before).await;
+                if self.realtime {
+                    let index = next - 1;
+                    let previous = &self.records[index.saturating_sub(1)];
+                    let gap = record
+                        .timestamp
+                        .duration_since(previous.timestamp)
+                        .unwrap_or(Duration::ZERO);
+                    tokio::time::sleep(gap).await;
+                }
+                let len = record.data.len().min(buf.len());
+                buf[..len].copy_from_slice(&record.data[..len]);
+                *position = next;
+                return Ok(len);
+            }
+            drop(position);
+            std::future::pending().await
+        })
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.records.first().map(|record| record.peer)
+    }
+}
@@ -0,0 +1,234 @@
+//! Human-readable rendering of Z21 traffic.
+//!
+//! [`dissect_packet`] renders a [`Packet`] as one line: the message name from the
+//! "Z21 LAN Protocol Specification", the decoded fields and, for X-Bus messages, the
+//! checksum status. The direction decides whether the packet is decoded as
+//! [`Request`] or [`Reply`], as both use the same headers.
+//!
+//! The renderer is used by the `z21-dissect` command line tool and can be hooked into
+//! a station with [`Z21StationBuilder::on_packet`](crate::Z21StationBuilder::on_packet).
+//!
+//! # Example
+//!
+//! ```rust
+//! # use roco_z21_driver::capture::Direction;
+//! # use roco_z21_driver::dissect::dissect_datagram;
+//! let lines = dissect_datagram(&[0x08, 0x00, 0x10, 0x00, 0x40, 0xE2, 0x01, 0x00], Direction::Rx);
+//! assert_eq!(lines, ["LAN_GET_SERIAL_NUMBER SerialNumber(123456)"]);
+//! ```
+
+use crate::capture::Direction;
+use crate::messages::header::*;
+use crate::messages::{Reply, Request, XBusMessage};
+use crate::packet::{DatagramIter, Packet};
+use roco_z21_protocol::Error;
+
+/// Renders `packet`, sent in `direction`, as a single line.
+pub fn dissect_packet(packet: &Packet, direction: Direction) -> String {
+    let header = packet.get_header();
+    let data = packet.get_data();
+    let name = match header {
+        LAN_X | LAN_FAST_CLOCK_CONTROL | LAN_FAST_CLOCK_DATA if !data.is_empty() => {
+            x_name(header, data[0], data.get(1).copied(), direction)
+        }
+        header => lan_name(header),
+    };
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => format!("unknown header 0x{:02X}", header),
+    };
+    let fields = match direction {
+        Direction::Tx => Request::decode(packet).map(|request| format!("{:?}", request)),
+        Direction::Rx => Reply::decode(packet).map(|reply| format!("{:?}", reply)),
+    };
+    let mut line = match fields {
+        Ok(fields) => format!("{} {}", name, fields),
+        Err(e) => format!("{} undecodable ({}): {}", name, e, hex(&data)),
+    };
+    if matches!(header, LAN_X | LAN_FAST_CLOCK_CONTROL | LAN_FAST_CLOCK_DATA) {
+        line.push_str(", ");
+        line.push_str(&checksum_status(&data));
+    }
+    line
+}
+
+/// Renders an X-Bus message, sent in `direction`, as a single line.
+pub fn dissect_xbus(message: &XBusMessage, direction: Direction) -> String {
+    let x_header = message.get_x_header();
+    let dbs = message.get_dbs();
+    let name = x_name(LAN_X, x_header, dbs.first().copied(), direction).unwrap_or("LAN_X");
+    let calculated = dbs.iter().fold(x_header, |xor, db| xor ^ db);
+    let status = if calculated == message.get_xor() {
+        "checksum ok".to_string()
+    } else {
+        format!(
+            "checksum bad (expected 0x{:02X}, got 0x{:02X})",
+            calculated,
+            message.get_xor()
+        )
+    };
+    format!(
+        "{} X-Header 0x{:02X} DB [{}], {}",
+        name,
+        x_header,
+        hex(dbs),
+        status
+    )
+}
+
+/// Renders every packet of a datagram, sent in `direction`, one line per packet.
+///
+/// A malformed remainder of the datagram is rendered as a last line.
+pub fn dissect_datagram(datagram: &[u8], direction: Direction) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    for packet in DatagramIter::new(datagram) {
+        match packet {
+            Ok(packet) => {
+                offset += packet.get_data_len() as usize;
+                lines.push(dissect_packet(&packet, direction));
+            }
+            Err(e) => {
+                lines.push(format!("malformed ({}): {}", e, hex(&datagram[offset..])));
+                break;
+            }
+        }
+    }
+    lines
+}
+
+/// Checksum status of the X-Bus message in `data`.
+fn checksum_status(data: &[u8]) -> String {
+    match XBusMessage::try_from(data) {
+        Ok(_) => "checksum ok".to_string(),
+        Err(Error::Checksum { expected, actual }) => format!(
+            "checksum bad (expected 0x{:02X}, got 0x{:02X})",
+            expected, actual
+        ),
+        Err(_) => "checksum missing".to_string(),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Name of a dataset which is not an X-Bus message.
+fn lan_name(header: u16) -> Option<&'static str> {
+    let name = match header {
+        LAN_GET_SERIAL_NUMBER => "LAN_GET_SERIAL_NUMBER",
+        LAN_GET_CODE => "LAN_GET_CODE",
+        LAN_GET_HWINFO => "LAN_GET_HWINFO",
+        LAN_LOGOFF => "LAN_LOGOFF",
+        LAN_X => "LAN_X",
+        LAN_SET_BROADCASTFLAGS => "LAN_SET_BROADCASTFLAGS",
+        LAN_GET_BROADCASTFLAGS => "LAN_GET_BROADCASTFLAGS",
+        LAN_GET_LOCOMODE => "LAN_GET_LOCOMODE",
+        LAN_SET_LOCOMODE => "LAN_SET_LOCOMODE",
+        LAN_GET_TURNOUTMODE => "LAN_GET_TURNOUTMODE",
+        LAN_SET_TURNOUTMODE => "LAN_SET_TURNOUTMODE",
+        LAN_RMBUS_DATACHANGED => "LAN_RMBUS_DATACHANGED",
+        LAN_RMBUS_GETDATA => "LAN_RMBUS_GETDATA",
+        LAN_RMBUS_PROGRAMMODULE => "LAN_RMBUS_PROGRAMMODULE",
+        LAN_SYSTEMSTATE_DATACHANGED => "LAN_SYSTEMSTATE_DATACHANGED",
+        LAN_SYSTEMSTATE_GETDATA => "LAN_SYSTEMSTATE_GETDATA",
+        LAN_RAILCOM_DATACHANGED => "LAN_RAILCOM_DATACHANGED",
+        LAN_RAILCOM_GETDATA => "LAN_RAILCOM_GETDATA",
+        LAN_LOCONET_Z21_RX => "LAN_LOCONET_Z21_RX",
+        LAN_LOCONET_Z21_TX => "LAN_LOCONET_Z21_TX",
+        LAN_LOCONET_FROM_LAN => "LAN_LOCONET_FROM_LAN",
+        LAN_LOCONET_DISPATCH_ADDR => "LAN_LOCONET_DISPATCH_ADDR",
+        LAN_LOCONET_DETECTOR => "LAN_LOCONET_DETECTOR",
+        LAN_CAN_DETECTOR => "LAN_CAN_DETECTOR",
+        LAN_CAN_DEVICE_GET_DESCRIPTION => "LAN_CAN_DEVICE_GET_DESCRIPTION",
+        LAN_CAN_DEVICE_SET_DESCRIPTION => "LAN_CAN_DEVICE_SET_DESCRIPTION",
+        LAN_CAN_BOOSTER_SYSTEMSTATE_CHGD => "LAN_CAN_BOOSTER_SYSTEMSTATE_CHGD",
+        LAN_CAN_BOOSTER_SET_TRACKPOWER => "LAN_CAN_BOOSTER_SET_TRACKPOWER",
+        LAN_FAST_CLOCK_CONTROL => "LAN_FAST_CLOCK_CONTROL",
+        LAN_FAST_CLOCK_DATA => "LAN_FAST_CLOCK_DATA",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Name of an X-Bus message, carried in a dataset with `header`.
+fn x_name(
+    header: u16,
+    x_header: u8,
+    db0: Option<u8>,
+    direction: Direction,
+) -> Option<&'static str> {
+    if header != LAN_X {
+        return lan_name(header);
+    }
+    let name = match (direction, x_header, db0) {
+        (Direction::Tx, X_GET, Some(DB0_GET_VERSION)) => "LAN_X_GET_VERSION",
+        (Direction::Tx, X_GET, Some(DB0_GET_STATUS)) => "LAN_X_GET_STATUS",
+        (Direction::Tx, X_GET, Some(DB0_SET_TRACK_POWER_OFF)) => "LAN_X_SET_TRACK_POWER_OFF",
+        (Direction::Tx, X_GET, Some(DB0_SET_TRACK_POWER_ON)) => "LAN_X_SET_TRACK_POWER_ON",
+        (Direction::Tx, X_SET_STOP, _) => "LAN_X_SET_STOP",
+        (Direction::Tx, X_LOCO_GET_INFO, Some(DB0_LOCO_GET_INFO)) => "LAN_X_GET_LOCO_INFO",
+        (Direction::Tx, X_LOCO_GET_INFO, Some(DB0_LOCO_PURGE)) => "LAN_X_PURGE_LOCO",
+        (Direction::Tx, X_LOCO_SET, Some(0x10..=0x13)) => "LAN_X_SET_LOCO_DRIVE",
+        (Direction::Tx, X_LOCO_SET, Some(DB0_LOCO_FUNCTION)) => "LAN_X_SET_LOCO_FUNCTION",
+        (Direction::Tx, X_LOCO_SET, Some(DB0_LOCO_BINARY_STATE)) => "LAN_X_SET_LOCO_BINARY_STATE",
+        (Direction::Tx, X_LOCO_SET, Some(_)) => "LAN_X_SET_LOCO_FUNCTION_GROUP",
+        (Direction::Tx, X_SET_LOCO_E_STOP, _) => "LAN_X_SET_LOCO_E_STOP",
+        (Direction::Tx, X_TURNOUT_INFO, _) => "LAN_X_GET_TURNOUT_INFO",
+        (Direction::Tx, X_SET_TURNOUT, _) => "LAN_X_SET_TURNOUT",
+        (Direction::Tx, X_EXT_ACCESSORY_INFO, _) => "LAN_X_GET_EXT_ACCESSORY_INFO",
+        (Direction::Tx, X_SET_EXT_ACCESSORY, _) => "LAN_X_SET_EXT_ACCESSORY",
+        (Direction::Tx, X_CV_READ, _) => "LAN_X_CV_READ",
+        (Direction::Tx, X_CV_WRITE, _) => "LAN_X_CV_WRITE",
+        (Direction::Tx, X_CV_POM, _) => "LAN_X_CV_POM",
+        (Direction::Tx, X_GET_FIRMWARE_VERSION, _) => "LAN_X_GET_FIRMWARE_VERSION",
+        (Direction::Rx, X_BC, Some(DB0_BC_TRACK_POWER_OFF)) => "LAN_X_BC_TRACK_POWER_OFF",
+        (Direction::Rx, X_BC, Some(DB0_BC_TRACK_POWER_ON)) => "LAN_X_BC_TRACK_POWER_ON",
+        (Direction::Rx, X_BC, Some(DB0_BC_PROGRAMMING_MODE)) => "LAN_X_BC_PROGRAMMING_MODE",
+        (Direction::Rx, X_BC, Some(DB0_BC_TRACK_SHORT_CIRCUIT)) => "LAN_X_BC_TRACK_SHORT_CIRCUIT",
+        (Direction::Rx, X_BC, Some(DB0_CV_NACK_SC)) => "LAN_X_CV_NACK_SC",
+        (Direction::Rx, X_BC, Some(DB0_CV_NACK)) => "LAN_X_CV_NACK",
+        (Direction::Rx, X_BC, Some(DB0_UNKNOWN_COMMAND)) => "LAN_X_UNKNOWN_COMMAND",
+        (Direction::Rx, X_STATUS_CHANGED, _) => "LAN_X_STATUS_CHANGED",
+        (Direction::Rx, X_GET_VERSION_REPLY, _) => "LAN_X_GET_VERSION",
+        (Direction::Rx, X_CV_RESULT, _) => "LAN_X_CV_RESULT",
+        (Direction::Rx, X_BC_STOPPED, _) => "LAN_X_BC_STOPPED",
+        (Direction::Rx, X_LOCO_INFO, _) => "LAN_X_LOCO_INFO",
+        (Direction::Rx, X_TURNOUT_INFO, _) => "LAN_X_TURNOUT_INFO",
+        (Direction::Rx, X_EXT_ACCESSORY_INFO, _) => "LAN_X_EXT_ACCESSORY_INFO",
+        (Direction::Rx, X_GET_FIRMWARE_VERSION_REPLY, _) => "LAN_X_GET_FIRMWARE_VERSION",
+        _ => "LAN_X",
+    };
+    Some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dissect_xbus_packets() {
+        // LAN_X_SET_LOCO_DRIVE of loco 3, 128 steps, forward at step 0
+        let drive = [0x0A, 0x00, 0x40, 0x00, 0xE4, 0x13, 0x00, 0x03, 0x80, 0x74];
+        let line = &dissect_datagram(&drive, Direction::Tx)[0];
+        assert!(line.starts_with("LAN_X_SET_LOCO_DRIVE SetLocoDrive { address: 3"));
+        assert!(line.ends_with("checksum ok"));
+
+        let mut corrupt = drive;
+        corrupt[9] = 0x00;
+        let line = &dissect_datagram(&corrupt, Direction::Tx)[0];
+        assert!(line.starts_with("LAN_X_SET_LOCO_DRIVE undecodable"));
+        assert!(line.ends_with("checksum bad (expected 0x74, got 0x00)"));
+    }
+
+    #[test]
+    fn test_dissect_malformed_remainder() {
+        let lines = dissect_datagram(&[0x04, 0x00, 0x30, 0x00, 0x09, 0x00], Direction::Tx);
+        assert_eq!(lines[0], "LAN_LOGOFF Logoff");
+        assert!(lines[1].starts_with("malformed"));
+    }
+}
@@ -0,0 +1,143 @@
+//! Error types of the crate.
+
+use std::fmt;
+
+use tokio::io;
+
+pub use roco_z21_protocol::ProtocolError;
+
+/// A specialized `Result` type for Z21 operations.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors returned by [`Z21Station`](crate::Z21Station), [`Loco`](crate::Loco) and the
+/// message parsers.
+#[derive(Debug)]
+pub enum Error {
+    /// The underlying socket failed.
+    Io(io::Error),
+    /// The Z21 station did not answer within the timeout period.
+    Timeout {
+        /// Header of the awaited packet.
+        header: u16,
+        /// X-Header of the awaited X-Bus message, if an X-Bus reply was awaited.
+        x_header: Option<u8>,
+    },
+    /// The XOR checksum of an X-Bus message is wrong.
+    Checksum {
+        /// Checksum calculated over the received bytes.
+        expected: u8,
+        /// Checksum contained in the message.
+        actual: u8,
+    },
+    /// A dataset could not be framed.
+    Protocol(ProtocolError),
+    /// The payload of a message is malformed.
+    MalformedPayload(String),
+    /// The Z21 station replied with LAN_X_UNKNOWN_COMMAND.
+    UnknownCommand,
+    /// The decoder did not acknowledge a CV programming command (LAN_X_CV_NACK).
+    CvNack,
+    /// A short circuit was detected on the track.
+    ShortCircuit,
+    /// The connection to the Z21 station is closed.
+    NotConnected,
+    /// An argument passed to the API is out of range.
+    InvalidArgument(String),
+    /// A function of the [`blocking`](crate::blocking) API was called from within an
+    /// async runtime, e.g. from an event callback.
+    InsideRuntime,
+}
+
+impl Error {
+    pub(crate) fn malformed(message: impl Into<String>) -> Error {
+        Error::MalformedPayload(message.into())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Timeout {
+                header,
+                x_header: Some(x_header),
+            } => write!(
+                f,
+                "Timeout waiting for packet with header 0x{:04x} and X-Header 0x{:02x}",
+                header, x_header
+            ),
+            Error::Timeout {
+                header,
+                x_header: None,
+            } => write!(f, "Timeout waiting for packet with header 0x{:04x}", header),
+            Error::Checksum { expected, actual } => write!(
+                f,
+                "XBus message XOR is wrong: expected 0x{:02x}, got 0x{:02x}",
+                expected, actual
+            ),
+            Error::Protocol(e) => write!(f, "{}", e),
+            Error::MalformedPayload(message) => write!(f, "Malformed payload: {}", message),
+            Error::UnknownCommand => write!(f, "The Z21 station does not know the command"),
+            Error::CvNack => write!(f, "The decoder did not acknowledge the CV command"),
+            Error::ShortCircuit => write!(f, "Short circuit detected"),
+            Error::NotConnected => write!(f, "Not connected to the Z21 station"),
+            Error::InvalidArgument(message) => write!(f, "Invalid argument: {}", message),
+            Error::InsideRuntime => write!(f, "Blocking call from within an async runtime"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Protocol(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<ProtocolError> for Error {
+    fn from(error: ProtocolError) -> Self {
+        Error::Protocol(error)
+    }
+}
+
+impl From<roco_z21_protocol::Error> for Error {
+    fn from(error: roco_z21_protocol::Error) -> Self {
+        match error {
+            roco_z21_protocol::Error::Checksum { expected, actual } => {
+                Error::Checksum { expected, actual }
+            }
+            roco_z21_protocol::Error::Protocol(e) => Error::Protocol(e),
+            roco_z21_protocol::Error::MalformedPayload(message) => Error::MalformedPayload(message),
+            roco_z21_protocol::Error::InvalidArgument(message) => Error::InvalidArgument(message),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    /// Allows using `?` on Z21 results in functions returning `io::Result`.
+    fn from(error: Error) -> Self {
+        let kind = match &error {
+            Error::Io(e) => e.kind(),
+            Error::Timeout { .. } => io::ErrorKind::TimedOut,
+            Error::Checksum { .. } | Error::Protocol(_) | Error::MalformedPayload(_) => {
+                io::ErrorKind::InvalidData
+            }
+            Error::InvalidArgument(_) | Error::InsideRuntime => io::ErrorKind::InvalidInput,
+            Error::NotConnected => io::ErrorKind::NotConnected,
+            Error::UnknownCommand | Error::CvNack | Error::ShortCircuit => io::ErrorKind::Other,
+        };
+        match error {
+            Error::Io(e) => e,
+            error => io::Error::new(kind, error),
+        }
+    }
+}
@@ -3,15 +3,60 @@
 //! The crate is based on the Tokio runtime.
 //!
 //! ## Features
+//! - Discovery of Z21 stations on the local network
 //! - Interacting with system state of Z21
 //! - Loco and peripheral control.
 //! - CV programming.
 //! - Asynchronous, subscription-based event handling.
 //! - Error handling.
+//! - A Z21 LAN server to emulate a Z21 towards apps and handhelds.
+//! - A proxy sharing one Z21 station between many LAN clients.
+//! - A simulated Z21 station for development and tests without hardware.
+//! - Pluggable transports: UDP, in-memory channels for tests, TCP tunnels.
+//! - Traffic capture (native text format and pcap) and offline replay.
+//! - A dissector rendering Z21 traffic as human-readable lines.
+//! - Spans and events through `tracing` (feature `tracing`).
+//! - Link health metrics, optionally served in the Prometheus text format.
+//! - A `no_std` protocol codec in the `roco_z21_protocol` crate, re-exported as [`messages`].
+//! - Serde support for states, messages, packets and events (feature `serde`).
+//! - A blocking API for programs without an async runtime (feature `blocking`).
+//! - Python bindings with asyncio coroutines (feature `python`).
 //! - Ready to use driver for integration into other projects.
 
-mod packet;
+mod error;
+mod log;
+pub use error::{Error, ProtocolError, Result};
+use roco_z21_protocol::packet;
+pub use roco_z21_protocol::{join_datagram, split_datagram, DatagramIter, Packet};
 mod station;
+pub use station::CommandClass;
+pub use station::ConnectionState;
+pub use station::DiscoveredStation;
+pub use station::EventStream;
 pub use station::Loco;
+pub use station::Metrics;
+#[cfg(feature = "prometheus")]
+pub use station::MetricsServer;
+pub use station::QueueConfig;
+pub use station::RttHistogram;
+pub use station::Subscription;
+pub use station::Z21Event;
 pub use station::Z21Station;
-pub mod messages;
+pub use station::Z21StationBuilder;
+pub use station::RTT_BUCKETS;
+pub use station::Z21_DEFAULT_PORT;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod capture;
+pub mod dissect;
+pub use roco_z21_protocol::messages;
+pub mod proxy;
+#[cfg(feature = "python")]
+pub mod python;
+pub use proxy::Z21Proxy;
+pub mod server;
+pub use server::{ServerEvent, Z21Server};
+pub mod simulator;
+pub use simulator::Z21Simulator;
+pub mod transport;
+pub use transport::Transport;
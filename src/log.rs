@@ -0,0 +1,56 @@
+//! Diagnostics of the driver.
+//!
+//! With the `tracing` feature, warnings, errors and debug events go through the
+//! [`tracing`](https://docs.rs/tracing) crate, and commands are wrapped in spans.
+//! Without it, warnings and errors are written to stderr and debug events are dropped.
+
+use crate::packet::Packet;
+
+/// Logs an error, e.g. a failed send.
+macro_rules! error {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        ::tracing::error!($($arg)+);
+        #[cfg(not(feature = "tracing"))]
+        eprintln!($($arg)+);
+    }};
+}
+
+/// Logs a warning, e.g. a dropped datagram or a lagging event receiver.
+macro_rules! warning {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        ::tracing::warn!($($arg)+);
+        #[cfg(not(feature = "tracing"))]
+        eprintln!($($arg)+);
+    }};
+}
+
+pub(crate) use error;
+pub(crate) use warning;
+
+/// Records header and X-Header of `packet` in the span of the current command.
+#[cfg(feature = "tracing")]
+pub(crate) fn record_packet(packet: &Packet) {
+    let span = tracing::Span::current();
+    span.record("header", format_args!("0x{:02X}", packet.get_header()));
+    if packet.get_header() == crate::messages::header::LAN_X {
+        if let Some(x_header) = packet.get_data().first() {
+            span.record("x_header", format_args!("0x{:02X}", x_header));
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn record_packet(_packet: &Packet) {}
+
+/// Hook logging every packet of a station at debug level.
+#[cfg(feature = "tracing")]
+pub(crate) fn trace_packet(direction: crate::capture::Direction, packet: &Packet) {
+    tracing::debug!(
+        %direction,
+        header = format_args!("0x{:02X}", packet.get_header()),
+        "{}",
+        crate::dissect::dissect_packet(packet, direction)
+    );
+}
@@ -1,9 +0,0 @@
-mod loco_state;
-mod system_state;
-mod xbus_message;
-
-pub use loco_state::DccThrottleSteps;
-pub use loco_state::LocoState;
-pub use system_state::SystemState;
-pub use xbus_message::XBusMessage;
-pub use xbus_message::XBUS_HEADER;
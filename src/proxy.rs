@@ -0,0 +1,228 @@
+//! Sharing one Z21 station between several LAN clients.
+//!
+//! The Z21 accepts a limited number of LAN clients. [`Z21Proxy`] connects to the station
+//! once, through a [`Z21Station`], and accepts any number of clients itself, e.g. the
+//! Z21 app, a WLANmaus and other programs on the same PC:
+//!
+//! - Requests of the clients are forwarded to the station, replies are routed back to
+//!   the client which sent the request.
+//! - Broadcasts of the station are fanned out to every client, according to its
+//!   broadcast flags and the locos it asked for.
+//! - The station is subscribed to the broadcasts any of the clients asked for.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # use std::sync::Arc;
+//! # use roco_z21_driver::{Z21Proxy, Z21Station};
+//! # async fn example() -> roco_z21_driver::Result<()> {
+//! let station = Arc::new(Z21Station::new("192.168.0.111:21105").await?);
+//! let proxy = Z21Proxy::bind("0.0.0.0:21105", station).await?;
+//! tokio::signal::ctrl_c().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::net::ToSocketAddrs;
+use tokio::task::JoinSet;
+use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
+
+use crate::error::{Error, Result};
+use crate::messages::{BroadcastFlags, FastClockControl, Reply, Request};
+use crate::server::{ServerEvent, Z21Server};
+use crate::station::{Z21Event, Z21Station};
+
+/// A Z21 LAN server forwarding the requests of its clients to one [`Z21Station`].
+///
+/// The proxy runs in background tasks until it is dropped. It manages the broadcast
+/// flags of the station: they are the flags the station was connected with, plus the
+/// flags of every logged in client.
+pub struct Z21Proxy {
+    inner: Arc<Inner>,
+    cancel: CancellationToken,
+}
+
+/// State shared between a [`Z21Proxy`] and its background tasks.
+struct Inner {
+    server: Z21Server,
+    station: Arc<Z21Station>,
+    /// Broadcast flags of the station when the proxy was started.
+    base_flags: BroadcastFlags,
+}
+
+impl Z21Proxy {
+    /// Starts a proxy listening on `addr`, typically "0.0.0.0:21105", in front of
+    /// `station`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`Error`] if the UDP socket cannot be bound.
+    pub async fn bind(addr: impl ToSocketAddrs, station: Arc<Z21Station>) -> Result<Self> {
+        let inner = Arc::new(Inner {
+            server: Z21Server::bind(addr).await?,
+            base_flags: station.broadcast_flags(),
+            station,
+        });
+        let cancel = CancellationToken::new();
+        let (serve, fan_out) = (Arc::clone(&inner), Arc::clone(&inner));
+        let token = cancel.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = token.cancelled() => {}
+                _ = serve.serve() => {}
+                _ = fan_out.fan_out() => {}
+            }
+        });
+        Ok(Z21Proxy { inner, cancel })
+    }
+
+    /// Returns the address the proxy listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.inner.server.local_addr()
+    }
+
+    /// Returns the addresses of the logged in clients.
+    pub fn clients(&self) -> Vec<SocketAddr> {
+        self.inner.server.clients()
+    }
+}
+
+impl Drop for Z21Proxy {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+impl Inner {
+    /// Forwards the requests of the clients until the task is cancelled.
+    async fn serve(self: Arc<Self>) {
+        // Dropping the set when the task is cancelled aborts the forwards in flight.
+        let mut forwards = JoinSet::new();
+        while let Some(event) = self.server.recv().await {
+            while forwards.try_join_next().is_some() {}
+            match event {
+                ServerEvent::Login(_) => {}
+                ServerEvent::Logout(_) => self.update_flags().await,
+                ServerEvent::Request {
+                    request: Request::SetBroadcastFlags(_),
+                    ..
+                } => self.update_flags().await,
+                ServerEvent::Request { client, request } if is_answered(&request) => {
+                    // Waiting for the reply must not hold up the other clients.
+                    let inner = Arc::clone(&self);
+                    forwards.spawn(async move { inner.forward(client, request).await });
+                }
+                ServerEvent::Request { request, .. } => {
+                    // Replies, if any, arrive as broadcasts.
+                    let _ = self.station.send_request(&request).await;
+                }
+            }
+        }
+    }
+
+    /// Sends the broadcasts of the station to the clients until the task is cancelled.
+    async fn fan_out(self: Arc<Self>) {
+        let mut events = self.station.events();
+        while let Some(event) = events.next().await {
+            if let Some(reply) = into_reply(event) {
+                let _ = self.server.broadcast(&reply).await;
+            }
+        }
+    }
+
+    /// Sends `request` to the station and its reply to `client`.
+    async fn forward(&self, client: SocketAddr, request: Request) {
+        let reply = match self.station.request(&request).await {
+            Ok(reply) => reply,
+            Err(Error::CvNack) => Reply::CvNack,
+            Err(Error::ShortCircuit) => Reply::CvNackShortCircuit,
+            Err(Error::UnknownCommand) => Reply::UnknownCommand,
+            // The station did not answer, neither does the proxy.
+            Err(_) => return,
+        };
+        // A client subscribed to the reply already got it from the fan out.
+        if !self.server.receives_broadcast(client, &reply) {
+            let _ = self.server.reply(client, &reply).await;
+        }
+    }
+
+    /// Subscribes the station to the broadcasts of all clients.
+    async fn update_flags(&self) {
+        let flags = self
+            .server
+            .clients()
+            .into_iter()
+            .filter_map(|client| self.server.broadcast_flags(client))
+            .fold(self.base_flags, |flags, client_flags| flags | client_flags);
+        if flags != self.station.broadcast_flags() {
+            let _ = self.station.set_broadcast_flags(flags).await;
+        }
+    }
+}
+
+/// Returns `true` if the Z21 answers `request` to the client which sent it.
+///
+/// Commands such as driving a loco or switching a turnout are only answered by a
+/// broadcast.
+fn is_answered(request: &Request) -> bool {
+    matches!(
+        request,
+        Request::GetSerialNumber
+            | Request::GetCode
+            | Request::GetHardwareInfo
+            | Request::GetXBusVersion
+            | Request::GetStatus
+            | Request::SetTrackPowerOff
+            | Request::SetTrackPowerOn
+            | Request::SetStop
+            | Request::GetFirmwareVersion
+            | Request::GetLocoMode { .. }
+            | Request::GetTurnoutMode { .. }
+            | Request::GetLocoInfo { .. }
+            | Request::GetTurnoutInfo { .. }
+            | Request::GetExtAccessoryInfo { .. }
+            | Request::CvRead { .. }
+            | Request::CvWrite { .. }
+            | Request::CvPomReadByte { .. }
+            | Request::RBusGetData { .. }
+            | Request::SystemStateGetData
+            | Request::RailComGetData { .. }
+            | Request::LocoNetDispatchAddr { .. }
+            | Request::LocoNetDetector { .. }
+            | Request::CanDetector { .. }
+            | Request::CanDeviceGetDescription { .. }
+            | Request::FastClockControl(FastClockControl::Read)
+    )
+}
+
+/// Turns an event of the station back into the message it was received as.
+fn into_reply(event: Z21Event) -> Option<Reply> {
+    let reply = match event {
+        Z21Event::LocoInfo(state) => Reply::LocoInfo(state),
+        Z21Event::TurnoutInfo(info) => Reply::TurnoutInfo(info),
+        Z21Event::AccessoryInfo(info) => Reply::ExtAccessoryInfo(info),
+        Z21Event::TrackPowerOff => Reply::TrackPowerOff,
+        Z21Event::TrackPowerOn => Reply::TrackPowerOn,
+        Z21Event::ProgrammingMode => Reply::ProgrammingMode,
+        Z21Event::ShortCircuit => Reply::TrackShortCircuit,
+        Z21Event::EmergencyStop => Reply::Stopped,
+        Z21Event::Status(status) => Reply::Status(status),
+        Z21Event::SystemState(state) => Reply::SystemStateDataChanged(state),
+        Z21Event::Feedback(feedback) => Reply::RBusDataChanged(feedback),
+        Z21Event::RailCom(data) => Reply::RailComDataChanged(data),
+        Z21Event::LocoNetRx(message) => Reply::LocoNetRx(message),
+        Z21Event::LocoNetTx(message) => Reply::LocoNetTx(message),
+        Z21Event::LocoNetFromLan(message) => Reply::LocoNetFromLan(message),
+        Z21Event::LocoNetDetector(detector) => Reply::LocoNetDetector(detector),
+        Z21Event::CanDetector(detector) => Reply::CanDetector(detector),
+        Z21Event::CanBooster(state) => Reply::CanBoosterSystemState(state),
+        Z21Event::FastClock(time) => Reply::FastClockData(time),
+        Z21Event::Reply(reply) => reply,
+        Z21Event::ConnectionState(_) | Z21Event::Lagged(_) => return None,
+    };
+    Some(reply)
+}
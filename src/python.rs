@@ -0,0 +1,481 @@
+//! Python bindings of the station and loco API.
+//!
+//! The module `roco_z21_driver` exposes [`Z21Station`], [`Loco`], [`SystemState`] and
+//! [`LocoState`] to Python. Every command is a coroutine to be awaited in an asyncio
+//! event loop, the commands themselves run on a Tokio runtime owned by the module.
+//! Events are read with `async for event in station.events()`.
+//!
+//! Build the extension with [maturin](https://www.maturin.rs), which picks up the
+//! features listed in `pyproject.toml`:
+//!
+//! ```sh
+//! maturin develop --release
+//! ```
+//!
+//! ```python
+//! import asyncio
+//! from roco_z21_driver import Loco, Z21Station
+//!
+//! async def main():
+//!     station = await Z21Station.connect("192.168.0.111:21105")
+//!     await station.voltage_on()
+//!     loco = await Loco.control(station, 3)
+//!     await loco.drive(50.0)
+//!     async for event in station.events():
+//!         if event.kind == "LocoInfo":
+//!             print(event.loco_state)
+//!
+//! asyncio.run(main())
+//! ```
+//!
+//! Errors are raised as `TimeoutError` when the station does not answer, as
+//! `ConnectionError` when the connection is closed, as `ValueError` for arguments out
+//! of range and as `Z21Error` otherwise.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use pyo3::create_exception;
+use pyo3::exceptions::{
+    PyConnectionError, PyException, PyStopAsyncIteration, PyTimeoutError, PyValueError,
+};
+use pyo3::prelude::*;
+use pyo3_async_runtimes::tokio::future_into_py;
+use tokio::sync::Mutex;
+use tokio_stream::StreamExt;
+
+use crate::messages::{self, DccThrottleSteps, Reply, Request};
+use crate::station::unexpected_reply;
+use crate::{ConnectionState, Error, EventStream, Z21Event, Z21StationBuilder};
+
+create_exception!(
+    roco_z21_driver,
+    Z21Error,
+    PyException,
+    "Error reported by the Z21 station or raised while talking to it."
+);
+
+fn to_py_err(error: Error) -> PyErr {
+    match error {
+        Error::Timeout { .. } => PyTimeoutError::new_err(error.to_string()),
+        Error::NotConnected => PyConnectionError::new_err(error.to_string()),
+        Error::InvalidArgument(_) => PyValueError::new_err(error.to_string()),
+        error => Z21Error::new_err(error.to_string()),
+    }
+}
+
+fn throttle_steps(steps: u8) -> PyResult<DccThrottleSteps> {
+    match steps {
+        14 => Ok(DccThrottleSteps::Steps14),
+        28 => Ok(DccThrottleSteps::Steps28),
+        128 => Ok(DccThrottleSteps::Steps128),
+        steps => Err(PyValueError::new_err(format!(
+            "Invalid throttle steps {}, expected 14, 28 or 128",
+            steps
+        ))),
+    }
+}
+
+fn connection_state_name(state: ConnectionState) -> &'static str {
+    match state {
+        ConnectionState::Connected => "Connected",
+        ConnectionState::Disconnected => "Disconnected",
+        ConnectionState::Reconnecting => "Reconnecting",
+    }
+}
+
+/// System state of the station, see [`messages::SystemState`].
+#[pyclass(name = "SystemState", module = "roco_z21_driver", frozen, get_all)]
+#[derive(Debug, Clone)]
+pub struct SystemState {
+    main_current: i16,
+    prog_current: i16,
+    filtered_main_current: i16,
+    temperature: i16,
+    supply_voltage: u16,
+    vcc_voltage: u16,
+    central_state: u8,
+    central_state_ex: u8,
+    capabilities: u8,
+}
+
+impl From<messages::SystemState> for SystemState {
+    fn from(state: messages::SystemState) -> Self {
+        SystemState {
+            main_current: state.main_current,
+            prog_current: state.prog_current,
+            filtered_main_current: state.filtered_main_current,
+            temperature: state.temperature,
+            supply_voltage: state.supply_voltage,
+            vcc_voltage: state.vcc_voltage,
+            central_state: state.central_state,
+            central_state_ex: state.central_state_ex,
+            capabilities: state.capabilities,
+        }
+    }
+}
+
+#[pymethods]
+impl SystemState {
+    fn __repr__(&self) -> String {
+        format!(
+            "SystemState(main_current={}, prog_current={}, temperature={}, supply_voltage={}, vcc_voltage={})",
+            self.main_current,
+            self.prog_current,
+            self.temperature,
+            self.supply_voltage,
+            self.vcc_voltage
+        )
+    }
+}
+
+/// State of a loco, see [`messages::LocoState`]. Fields the station did not report
+/// are `None`.
+#[pyclass(name = "LocoState", module = "roco_z21_driver", frozen, get_all)]
+#[derive(Debug, Clone)]
+pub struct LocoState {
+    address: u16,
+    is_busy: Option<bool>,
+    steps: Option<u8>,
+    speed_percentage: Option<f64>,
+    double_traction: Option<bool>,
+    smart_search: Option<bool>,
+    functions: Option<Vec<bool>>,
+}
+
+impl From<messages::LocoState> for LocoState {
+    fn from(state: messages::LocoState) -> Self {
+        LocoState {
+            address: state.address,
+            is_busy: state.is_busy,
+            steps: state.stepping.map(|steps| match steps {
+                DccThrottleSteps::Steps14 => 14,
+                DccThrottleSteps::Steps28 => 28,
+                DccThrottleSteps::Steps128 => 128,
+            }),
+            speed_percentage: state.speed_percentage,
+            double_traction: state.double_traction,
+            smart_search: state.smart_search,
+            functions: state.functions.map(Vec::from),
+        }
+    }
+}
+
+#[pymethods]
+impl LocoState {
+    fn __repr__(&self) -> String {
+        format!(
+            "LocoState(address={}, speed_percentage={:?}, steps={:?})",
+            self.address, self.speed_percentage, self.steps
+        )
+    }
+}
+
+/// An event of the station, see [`Z21Event`].
+///
+/// `kind` is the name of the event variant, e.g. `"LocoInfo"` or `"TrackPowerOff"`.
+/// Depending on the kind `loco_state`, `system_state` or `value` (status byte, number
+/// of lost events of `"Lagged"`) is set, `connection_state` holds the new state of
+/// `"ConnectionState"` events.
+#[pyclass(name = "Event", module = "roco_z21_driver", frozen, get_all)]
+#[derive(Debug, Clone)]
+pub struct Event {
+    kind: &'static str,
+    loco_state: Option<LocoState>,
+    system_state: Option<SystemState>,
+    value: Option<u64>,
+    connection_state: Option<&'static str>,
+}
+
+impl From<Z21Event> for Event {
+    fn from(event: Z21Event) -> Self {
+        let kind = match &event {
+            Z21Event::LocoInfo(_) => "LocoInfo",
+            Z21Event::TurnoutInfo(_) => "TurnoutInfo",
+            Z21Event::AccessoryInfo(_) => "AccessoryInfo",
+            Z21Event::TrackPowerOff => "TrackPowerOff",
+            Z21Event::TrackPowerOn => "TrackPowerOn",
+            Z21Event::ProgrammingMode => "ProgrammingMode",
+            Z21Event::ShortCircuit => "ShortCircuit",
+            Z21Event::EmergencyStop => "EmergencyStop",
+            Z21Event::Status(_) => "Status",
+            Z21Event::SystemState(_) => "SystemState",
+            Z21Event::Feedback(_) => "Feedback",
+            Z21Event::RailCom(_) => "RailCom",
+            Z21Event::LocoNetRx(_) => "LocoNetRx",
+            Z21Event::LocoNetTx(_) => "LocoNetTx",
+            Z21Event::LocoNetFromLan(_) => "LocoNetFromLan",
+            Z21Event::LocoNetDetector(_) => "LocoNetDetector",
+            Z21Event::CanDetector(_) => "CanDetector",
+            Z21Event::CanBooster(_) => "CanBooster",
+            Z21Event::FastClock(_) => "FastClock",
+            Z21Event::ConnectionState(_) => "ConnectionState",
+            Z21Event::Lagged(_) => "Lagged",
+            Z21Event::Reply(_) => "Reply",
+        };
+        let mut result = Event {
+            kind,
+            loco_state: None,
+            system_state: None,
+            value: None,
+            connection_state: None,
+        };
+        match event {
+            Z21Event::LocoInfo(state) => result.loco_state = Some(state.into()),
+            Z21Event::SystemState(state) => result.system_state = Some(state.into()),
+            Z21Event::Status(status) => result.value = Some(status.into()),
+            Z21Event::Lagged(skipped) => result.value = Some(skipped),
+            Z21Event::ConnectionState(state) => {
+                result.connection_state = Some(connection_state_name(state))
+            }
+            _ => {}
+        }
+        result
+    }
+}
+
+#[pymethods]
+impl Event {
+    fn __repr__(&self) -> String {
+        format!("Event(kind={:?})", self.kind)
+    }
+}
+
+/// Connection to a Z21 station, see [`crate::Z21Station`].
+#[pyclass(name = "Z21Station", module = "roco_z21_driver", frozen)]
+pub struct Z21Station {
+    station: Arc<crate::Z21Station>,
+}
+
+#[pymethods]
+impl Z21Station {
+    /// Connects to the station at `address`, `timeout` is the reply timeout in seconds.
+    #[staticmethod]
+    #[pyo3(signature = (address, timeout = None))]
+    fn connect(
+        py: Python<'_>,
+        address: String,
+        timeout: Option<f64>,
+    ) -> PyResult<Bound<'_, PyAny>> {
+        let mut builder = Z21StationBuilder::new(&address);
+        if let Some(timeout) = timeout {
+            let timeout = Duration::try_from_secs_f64(timeout)
+                .map_err(|e| PyValueError::new_err(e.to_string()))?;
+            builder = builder.timeout(timeout);
+        }
+        future_into_py(py, async move {
+            let station = builder.connect().await.map_err(to_py_err)?;
+            Ok(Z21Station {
+                station: Arc::new(station),
+            })
+        })
+    }
+
+    fn voltage_on<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let station = Arc::clone(&self.station);
+        future_into_py(
+            py,
+            async move { station.voltage_on().await.map_err(to_py_err) },
+        )
+    }
+
+    fn voltage_off<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let station = Arc::clone(&self.station);
+        future_into_py(
+            py,
+            async move { station.voltage_off().await.map_err(to_py_err) },
+        )
+    }
+
+    fn get_serial_number<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let station = Arc::clone(&self.station);
+        future_into_py(py, async move {
+            station.get_serial_number().await.map_err(to_py_err)
+        })
+    }
+
+    /// Requests the current system state.
+    fn get_system_state<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let station = Arc::clone(&self.station);
+        future_into_py(py, async move {
+            match station.request(&Request::SystemStateGetData).await {
+                Ok(Reply::SystemStateDataChanged(state)) => Ok(SystemState::from(state)),
+                Ok(reply) => Err(to_py_err(unexpected_reply(reply))),
+                Err(error) => Err(to_py_err(error)),
+            }
+        })
+    }
+
+    /// Returns an async iterator over the events of the station, buffered from now on.
+    fn events(&self) -> EventIterator {
+        EventIterator {
+            stream: Arc::new(Mutex::new(self.station.events())),
+        }
+    }
+
+    #[getter]
+    fn connection_state(&self) -> &'static str {
+        connection_state_name(self.station.connection_state())
+    }
+
+    fn logout<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let station = Arc::clone(&self.station);
+        future_into_py(py, async move { station.logout().await.map_err(to_py_err) })
+    }
+
+    fn shutdown<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let station = Arc::clone(&self.station);
+        future_into_py(
+            py,
+            async move { station.shutdown().await.map_err(to_py_err) },
+        )
+    }
+}
+
+/// Async iterator over the events of a station, created by `Z21Station.events()`.
+#[pyclass(module = "roco_z21_driver", frozen)]
+pub struct EventIterator {
+    stream: Arc<Mutex<EventStream>>,
+}
+
+#[pymethods]
+impl EventIterator {
+    fn __aiter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    fn __anext__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let stream = Arc::clone(&self.stream);
+        future_into_py(py, async move {
+            match stream.lock().await.next().await {
+                Some(event) => Ok(Event::from(event)),
+                None => Err(PyStopAsyncIteration::new_err(())),
+            }
+        })
+    }
+}
+
+/// A locomotive controlled through a station, see [`crate::Loco`].
+#[pyclass(name = "Loco", module = "roco_z21_driver", frozen)]
+pub struct Loco {
+    loco: Arc<crate::Loco>,
+    station: Arc<crate::Z21Station>,
+    address: u16,
+}
+
+#[pymethods]
+impl Loco {
+    /// Takes control of the loco at `address` with `steps` (14, 28 or 128) throttle steps.
+    #[staticmethod]
+    #[pyo3(signature = (station, address, steps = 128))]
+    fn control<'py>(
+        py: Python<'py>,
+        station: &Z21Station,
+        address: u16,
+        steps: u8,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let steps = throttle_steps(steps)?;
+        let station = Arc::clone(&station.station);
+        future_into_py(py, async move {
+            let loco = crate::Loco::control_with_steps(Arc::clone(&station), address, steps)
+                .await
+                .map_err(to_py_err)?;
+            Ok(Loco {
+                loco: Arc::new(loco),
+                station,
+                address,
+            })
+        })
+    }
+
+    #[getter]
+    fn address(&self) -> u16 {
+        self.address
+    }
+
+    fn drive<'py>(&self, py: Python<'py>, speed_percent: f64) -> PyResult<Bound<'py, PyAny>> {
+        let loco = Arc::clone(&self.loco);
+        future_into_py(py, async move {
+            loco.drive(speed_percent).await.map_err(to_py_err)
+        })
+    }
+
+    fn stop<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let loco = Arc::clone(&self.loco);
+        future_into_py(py, async move { loco.stop().await.map_err(to_py_err) })
+    }
+
+    fn halt<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let loco = Arc::clone(&self.loco);
+        future_into_py(py, async move { loco.halt().await.map_err(to_py_err) })
+    }
+
+    /// Switches a function: `action` 0 switches it off, 1 on and 2 toggles it.
+    fn set_function<'py>(
+        &self,
+        py: Python<'py>,
+        function_index: u8,
+        action: u8,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let loco = Arc::clone(&self.loco);
+        future_into_py(py, async move {
+            loco.set_function(function_index, action)
+                .await
+                .map_err(to_py_err)
+        })
+    }
+
+    fn function_on<'py>(&self, py: Python<'py>, function_index: u8) -> PyResult<Bound<'py, PyAny>> {
+        self.set_function(py, function_index, 1)
+    }
+
+    fn function_off<'py>(
+        &self,
+        py: Python<'py>,
+        function_index: u8,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        self.set_function(py, function_index, 0)
+    }
+
+    fn function_toggle<'py>(
+        &self,
+        py: Python<'py>,
+        function_index: u8,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        self.set_function(py, function_index, 2)
+    }
+
+    fn set_headlights<'py>(&self, py: Python<'py>, on: bool) -> PyResult<Bound<'py, PyAny>> {
+        let loco = Arc::clone(&self.loco);
+        future_into_py(py, async move {
+            loco.set_headlights(on).await.map_err(to_py_err)
+        })
+    }
+
+    /// Requests the current state of the loco.
+    fn get_state<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let station = Arc::clone(&self.station);
+        let address = self.address;
+        future_into_py(py, async move {
+            match station.request(&Request::GetLocoInfo { address }).await {
+                Ok(Reply::LocoInfo(state)) => Ok(LocoState::from(state)),
+                Ok(reply) => Err(to_py_err(unexpected_reply(reply))),
+                Err(error) => Err(to_py_err(error)),
+            }
+        })
+    }
+}
+
+/// The `roco_z21_driver` Python module.
+#[pymodule]
+#[pyo3(name = "roco_z21_driver")]
+pub fn python_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("Z21Error", m.py().get_type::<Z21Error>())?;
+    m.add_class::<Z21Station>()?;
+    m.add_class::<Loco>()?;
+    m.add_class::<SystemState>()?;
+    m.add_class::<LocoState>()?;
+    m.add_class::<Event>()?;
+    m.add_class::<EventIterator>()?;
+    Ok(())
+}
@@ -0,0 +1,519 @@
+//! Server side of the Z21 LAN protocol.
+//!
+//! [`Z21Server`] accepts LAN clients such as the official Z21 app or a WLANmaus, as if
+//! it was a Z21 station. It keeps track of the logged in clients and their broadcast
+//! flags, decodes their requests into [`Request`]s and hands them to the application,
+//! which decides how to answer: from its own state, by forwarding the request to a real
+//! Z21, or not at all to veto a command.
+//!
+//! The server answers LAN_GET_BROADCASTFLAGS itself and handles LAN_LOGOFF. Every other
+//! request is delivered as [`ServerEvent::Request`]. Like the Z21, the server logs out
+//! clients which stay silent for a minute, see [`Z21Server::set_client_timeout`].
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # use roco_z21_driver::{messages::{Reply, Request}, ServerEvent, Z21Server, Z21Station};
+//! # async fn example() -> roco_z21_driver::Result<()> {
+//! let station = Z21Station::new("192.168.0.111:21105").await?;
+//! let server = Z21Server::bind("0.0.0.0:21105").await?;
+//! while let Some(event) = server.recv().await {
+//!     match event {
+//!         // Nobody may switch off the track power
+//!         ServerEvent::Request { request: Request::SetTrackPowerOff, .. } => {}
+//!         ServerEvent::Request { client, request } => {
+//!             println!("{} sent {:?}", client, request);
+//!             if let Ok(reply) = station.request(&request).await {
+//!                 server.reply(client, &reply).await?;
+//!             }
+//!         }
+//!         _ => {}
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::{self, Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::error::Result;
+use crate::log;
+use crate::messages::header::LAN_X;
+use crate::messages::{BroadcastFlags, Reply, Request};
+use crate::packet::{DatagramIter, MAX_DATAGRAM_LEN};
+
+/// Number of events buffered until the application receives them, further events are
+/// dropped.
+const EVENT_CAPACITY: usize = 256;
+
+/// Silence after which a client is logged out, as on the Z21.
+const DEFAULT_CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often the server looks for silent clients.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An event of a [`Z21Server`].
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    /// A client sent its first request.
+    Login(SocketAddr),
+    /// A client logged off with LAN_LOGOFF, or was logged out after it stayed silent
+    /// for the client timeout.
+    Logout(SocketAddr),
+    /// A client sent a request.
+    Request {
+        /// Address of the client.
+        client: SocketAddr,
+        /// The decoded request.
+        request: Request,
+    },
+}
+
+/// A Z21 LAN server, listening on UDP.
+///
+/// The server runs in a background task until it is dropped.
+pub struct Z21Server {
+    inner: Arc<Inner>,
+    events: tokio::sync::Mutex<mpsc::Receiver<ServerEvent>>,
+    local_addr: SocketAddr,
+    cancel: CancellationToken,
+}
+
+/// State shared between a [`Z21Server`] and its background task.
+struct Inner {
+    socket: UdpSocket,
+    clients: Mutex<HashMap<SocketAddr, Client>>,
+    client_timeout: Mutex<Duration>,
+    dropped_events: AtomicU64,
+}
+
+/// A LAN client logged in at the server.
+struct Client {
+    flags: BroadcastFlags,
+    /// Locos the client asked for or controlled, it receives their LAN_X_LOCO_INFO.
+    locos: HashSet<u16>,
+    /// When the client sent its last request.
+    last_seen: Instant,
+}
+
+impl Z21Server {
+    /// Starts a server listening on `addr`, typically "0.0.0.0:21105".
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`Error`](crate::Error) if the UDP socket cannot be bound.
+    pub async fn bind(addr: impl ToSocketAddrs) -> Result<Self> {
+        let socket = UdpSocket::bind(addr).await?;
+        let local_addr = socket.local_addr()?;
+        let inner = Arc::new(Inner {
+            socket,
+            clients: Mutex::new(HashMap::new()),
+            client_timeout: Mutex::new(DEFAULT_CLIENT_TIMEOUT),
+            dropped_events: AtomicU64::new(0),
+        });
+        let (sender, receiver) = mpsc::channel(EVENT_CAPACITY);
+        let cancel = CancellationToken::new();
+        let task_inner = Arc::clone(&inner);
+        let token = cancel.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = token.cancelled() => {}
+                _ = task_inner.serve(sender) => {}
+            }
+        });
+        Ok(Z21Server {
+            inner,
+            events: tokio::sync::Mutex::new(receiver),
+            local_addr,
+            cancel,
+        })
+    }
+
+    /// Returns the address the server listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Receives the next event, or `None` once the server stopped.
+    ///
+    /// The server does not wait for the application: while more than 256 events are
+    /// waiting to be received, new events are dropped, see
+    /// [`dropped_events`](Z21Server::dropped_events).
+    pub async fn recv(&self) -> Option<ServerEvent> {
+        self.events.lock().await.recv().await
+    }
+
+    /// Returns the number of events dropped because the application did not receive
+    /// them fast enough.
+    pub fn dropped_events(&self) -> u64 {
+        self.inner.dropped_events.load(Ordering::Relaxed)
+    }
+
+    /// Logs out clients which sent no request for `timeout`, 60 seconds by default.
+    ///
+    /// Every client logged out this way is reported as [`ServerEvent::Logout`].
+    pub fn set_client_timeout(&self, timeout: Duration) {
+        *self.inner.client_timeout.lock().unwrap() = timeout;
+    }
+
+    /// Returns the addresses of the logged in clients.
+    pub fn clients(&self) -> Vec<SocketAddr> {
+        self.inner.clients.lock().unwrap().keys().copied().collect()
+    }
+
+    /// Returns the broadcast flags of `client`, or `None` if it is not logged in.
+    pub fn broadcast_flags(&self, client: SocketAddr) -> Option<BroadcastFlags> {
+        let clients = self.inner.clients.lock().unwrap();
+        clients.get(&client).map(|client| client.flags)
+    }
+
+    /// Returns whether `client` receives `reply` when it is broadcast.
+    pub fn receives_broadcast(&self, client: SocketAddr, reply: &Reply) -> bool {
+        let clients = self.inner.clients.lock().unwrap();
+        clients
+            .get(&client)
+            .is_some_and(|client| client.receives(reply))
+    }
+
+    /// Sends `reply` to `client` only.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`Error`](crate::Error) if the reply cannot be encoded or fails to send.
+    pub async fn reply(&self, client: SocketAddr, reply: &Reply) -> Result<()> {
+        let data: Vec<u8> = reply.encode()?.into();
+        self.inner.socket.send_to(&data, client).await?;
+        Ok(())
+    }
+
+    /// Sends `reply` to every client which receives it as broadcast, according to its
+    /// broadcast flags (see [`Reply::broadcast_flag`]).
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`Error`](crate::Error) if the reply cannot be encoded.
+    pub async fn broadcast(&self, reply: &Reply) -> Result<()> {
+        self.send_to_all(self.inner.recipients(reply, None), reply)
+            .await
+    }
+
+    /// Sends `reply` to `client` and as broadcast to every other client which receives
+    /// it, like the Z21 answers e.g. LAN_X_SET_TRACK_POWER_OFF.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`Error`](crate::Error) if the reply cannot be encoded.
+    pub async fn reply_and_broadcast(&self, client: SocketAddr, reply: &Reply) -> Result<()> {
+        self.send_to_all(self.inner.recipients(reply, Some(client)), reply)
+            .await
+    }
+
+    async fn send_to_all(&self, recipients: Vec<SocketAddr>, reply: &Reply) -> Result<()> {
+        let data: Vec<u8> = reply.encode()?.into();
+        for recipient in recipients {
+            // A client which went away is logged out by the real Z21 after a while,
+            // failures are ignored as well.
+            let _ = self.inner.socket.send_to(&data, recipient).await;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Z21Server {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+impl Inner {
+    /// Receives requests and delivers them to `events` until the task is cancelled.
+    async fn serve(&self, events: mpsc::Sender<ServerEvent>) {
+        let mut buf = [0u8; MAX_DATAGRAM_LEN];
+        let mut next_eviction = Instant::now() + EVICTION_INTERVAL;
+        loop {
+            let received = tokio::select! {
+                received = self.socket.recv_from(&mut buf) => received,
+                _ = time::sleep_until(next_eviction) => {
+                    next_eviction = Instant::now() + EVICTION_INTERVAL;
+                    let timeout = *self.client_timeout.lock().unwrap();
+                    for client in self.evict_idle(timeout) {
+                        if !self.deliver(&events, ServerEvent::Logout(client)) {
+                            return;
+                        }
+                    }
+                    continue;
+                }
+            };
+            let Ok((size, client)) = received else {
+                // E.g. ICMP port unreachable of a client which went away.
+                continue;
+            };
+            for packet in DatagramIter::new(&buf[..size]) {
+                let Ok(packet) = packet else {
+                    break;
+                };
+                let event = match Request::decode(&packet) {
+                    Ok(request) => self.handle(client, request, &events).await,
+                    // Like the Z21, only malformed X-Bus commands are answered, other
+                    // datasets are ignored.
+                    Err(_) if packet.get_header() != LAN_X => None,
+                    Err(_) => {
+                        if let Ok(packet) = Reply::UnknownCommand.encode() {
+                            let data: Vec<u8> = packet.into();
+                            let _ = self.socket.send_to(&data, client).await;
+                        }
+                        None
+                    }
+                };
+                if let Some(event) = event {
+                    if !self.deliver(&events, event) {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Updates the session of `client` and returns the event to deliver, if any.
+    async fn handle(
+        &self,
+        client: SocketAddr,
+        request: Request,
+        events: &mpsc::Sender<ServerEvent>,
+    ) -> Option<ServerEvent> {
+        if let Request::Logoff = request {
+            let removed = self.clients.lock().unwrap().remove(&client).is_some();
+            return removed.then_some(ServerEvent::Logout(client));
+        }
+
+        let (login, flags) = {
+            let mut clients = self.clients.lock().unwrap();
+            let login = !clients.contains_key(&client);
+            let session = clients.entry(client).or_insert_with(Client::new);
+            session.last_seen = Instant::now();
+            match &request {
+                Request::SetBroadcastFlags(flags) => session.flags = *flags,
+                Request::GetLocoInfo { address }
+                | Request::SetLocoDrive { address, .. }
+                | Request::SetLocoFunction { address, .. }
+                | Request::SetLocoFunctionGroup { address, .. }
+                | Request::SetLocoBinaryState { address, .. }
+                | Request::SetLocoEmergencyStop { address } => {
+                    session.locos.insert(*address);
+                }
+                _ => {}
+            }
+            (login, session.flags)
+        };
+        if login && !self.deliver(events, ServerEvent::Login(client)) {
+            return None;
+        }
+
+        if let Request::GetBroadcastFlags = request {
+            if let Ok(packet) = Reply::BroadcastFlags(flags).encode() {
+                let data: Vec<u8> = packet.into();
+                let _ = self.socket.send_to(&data, client).await;
+            }
+            return None;
+        }
+        Some(ServerEvent::Request { client, request })
+    }
+
+    /// Hands `event` to the application without waiting, so a slow application cannot
+    /// hold up the clients. Returns `false` once the server was dropped.
+    fn deliver(&self, events: &mpsc::Sender<ServerEvent>, event: ServerEvent) -> bool {
+        match events.try_send(event) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                let dropped = self.dropped_events.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped == 1 || dropped.is_multiple_of(100) {
+                    log::warning!("Server events not received in time, dropped {}", dropped);
+                }
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// Removes the clients silent for longer than `timeout` and returns them.
+    fn evict_idle(&self, timeout: Duration) -> Vec<SocketAddr> {
+        let mut clients = self.clients.lock().unwrap();
+        let idle: Vec<SocketAddr> = clients
+            .iter()
+            .filter(|(_, client)| client.last_seen.elapsed() > timeout)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in &idle {
+            clients.remove(addr);
+        }
+        idle
+    }
+
+    /// Returns the clients which receive `reply`, including `requester`.
+    fn recipients(&self, reply: &Reply, requester: Option<SocketAddr>) -> Vec<SocketAddr> {
+        let clients = self.clients.lock().unwrap();
+        clients
+            .iter()
+            .filter(|(addr, client)| client.receives(reply) || requester == Some(**addr))
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+}
+
+impl Client {
+    fn new() -> Self {
+        Client {
+            flags: BroadcastFlags::default(),
+            locos: HashSet::new(),
+            last_seen: Instant::now(),
+        }
+    }
+
+    /// Returns whether the client receives `reply` as broadcast.
+    fn receives(&self, reply: &Reply) -> bool {
+        match reply {
+            Reply::LocoInfo(state) => {
+                self.flags.intersects(BroadcastFlags::ALL_LOCO_INFO)
+                    || (self.flags.intersects(BroadcastFlags::DRIVING_SWITCHING)
+                        && self.locos.contains(&state.address))
+            }
+            _ => reply
+                .broadcast_flag()
+                .is_some_and(|flags| self.flags.intersects(flags)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::LocoState;
+    use crate::packet::{join_datagram, Packet};
+
+    async fn send(socket: &UdpSocket, request: Request) {
+        let data: Vec<u8> = request.encode().unwrap().into();
+        socket.send(&data).await.unwrap();
+    }
+
+    async fn receive(socket: &UdpSocket) -> Reply {
+        let mut buf = [0u8; MAX_DATAGRAM_LEN];
+        let len = socket.recv(&mut buf).await.unwrap();
+        Reply::decode(&Packet::try_from(&buf[..len]).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_session_and_loco_broadcast() {
+        let server = Z21Server::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(server.local_addr()).await.unwrap();
+        let addr = client.local_addr().unwrap();
+
+        send(
+            &client,
+            Request::SetBroadcastFlags(BroadcastFlags::DRIVING_SWITCHING),
+        )
+        .await;
+        send(&client, Request::GetLocoInfo { address: 3 }).await;
+        assert!(matches!(server.recv().await, Some(ServerEvent::Login(a)) if a == addr));
+        assert!(matches!(
+            server.recv().await,
+            Some(ServerEvent::Request {
+                request: Request::SetBroadcastFlags(_),
+                ..
+            })
+        ));
+        assert!(matches!(
+            server.recv().await,
+            Some(ServerEvent::Request {
+                request: Request::GetLocoInfo { address: 3 },
+                ..
+            })
+        ));
+        assert_eq!(
+            server.broadcast_flags(addr),
+            Some(BroadcastFlags::DRIVING_SWITCHING)
+        );
+
+        let loco_info = |address| {
+            Reply::LocoInfo(LocoState {
+                address,
+                is_busy: None,
+                stepping: None,
+                speed_percentage: None,
+                double_traction: None,
+                smart_search: None,
+                functions: None,
+            })
+        };
+        // Only the loco the client asked for is broadcast to it.
+        server.broadcast(&loco_info(4)).await.unwrap();
+        server.broadcast(&loco_info(3)).await.unwrap();
+        assert!(matches!(receive(&client).await, Reply::LocoInfo(state) if state.address == 3));
+
+        send(&client, Request::Logoff).await;
+        assert!(matches!(server.recv().await, Some(ServerEvent::Logout(a)) if a == addr));
+        assert!(server.clients().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_idle_client_is_logged_out() {
+        let server = Z21Server::bind("127.0.0.1:0").await.unwrap();
+        server.set_client_timeout(Duration::from_millis(200));
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(server.local_addr()).await.unwrap();
+        let addr = client.local_addr().unwrap();
+
+        send(&client, Request::GetSerialNumber).await;
+        assert!(matches!(server.recv().await, Some(ServerEvent::Login(a)) if a == addr));
+        assert!(matches!(
+            server.recv().await,
+            Some(ServerEvent::Request { .. })
+        ));
+        let logout = time::timeout(Duration::from_secs(5), server.recv()).await;
+        assert!(matches!(logout, Ok(Some(ServerEvent::Logout(a))) if a == addr));
+        assert!(server.clients().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_slow_application_does_not_stall_clients() {
+        let server = Z21Server::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(server.local_addr()).await.unwrap();
+
+        // More requests than events are buffered, and nobody receives them.
+        let requests = (0..300).map(|_| Request::GetSerialNumber.encode().unwrap());
+        client.send(&join_datagram(requests)).await.unwrap();
+        send(&client, Request::GetBroadcastFlags).await;
+        let reply = time::timeout(Duration::from_secs(5), receive(&client)).await;
+        assert!(matches!(reply, Ok(Reply::BroadcastFlags(_))));
+        assert!(server.dropped_events() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_only_malformed_xbus_commands_are_answered() {
+        let server = Z21Server::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(server.local_addr()).await.unwrap();
+
+        // A malformed dataset is ignored, the next reply answers the request after it.
+        client.send(&[0x05, 0x00, 0x50, 0x00, 0x01]).await.unwrap();
+        send(&client, Request::GetBroadcastFlags).await;
+        assert!(matches!(receive(&client).await, Reply::BroadcastFlags(_)));
+
+        // An X-Bus command with a wrong checksum.
+        client
+            .send(&[0x07, 0x00, 0x40, 0x00, 0x21, 0x21, 0xFF])
+            .await
+            .unwrap();
+        assert!(matches!(receive(&client).await, Reply::UnknownCommand));
+    }
+}
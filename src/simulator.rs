@@ -0,0 +1,548 @@
+//! A simulated Z21 station for offline development and tests.
+//!
+//! [`Z21Simulator`] answers LAN clients like a real Z21: it keeps the state of the track
+//! power, locos, turnouts and the decoder on the programming track, and sends
+//! broadcasts to every logged in client according to its broadcast flags. It is built
+//! on a [`Z21Server`], which takes care of the client sessions. Faults such as lost
+//! datagrams, slow replies and short circuits can be injected.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # use roco_z21_driver::{Z21Simulator, Z21Station};
+//! # async fn example() -> roco_z21_driver::Result<()> {
+//! let simulator = Z21Simulator::bind("127.0.0.1:0").await?;
+//! let station = Z21Station::new(&simulator.local_addr().to_string()).await?;
+//!
+//! station.voltage_on().await?;
+//! assert!(simulator.track_power());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::net::ToSocketAddrs;
+use tokio_util::sync::CancellationToken;
+
+use crate::error::Result;
+use crate::messages::{
+    DccThrottleSteps, DecoderMode, FunctionAction, HardwareInfo, HardwareType, LocoState, Reply,
+    Request, SystemState, TurnoutInfo, TurnoutPosition,
+};
+use crate::server::{ServerEvent, Z21Server};
+
+/// Central state bits of LAN_X_STATUS_CHANGED and LAN_SYSTEMSTATE_DATACHANGED.
+const CS_EMERGENCY_STOP: u8 = 0x01;
+const CS_TRACK_VOLTAGE_OFF: u8 = 0x02;
+const CS_SHORT_CIRCUIT: u8 = 0x04;
+const CS_PROGRAMMING_MODE_ACTIVE: u8 = 0x20;
+
+/// A simulated Z21 station listening on UDP.
+///
+/// The simulator runs in a background task until it is dropped.
+pub struct Z21Simulator {
+    inner: Arc<Inner>,
+    cancel: CancellationToken,
+}
+
+/// State shared between a [`Z21Simulator`] and its background task.
+struct Inner {
+    server: Z21Server,
+    state: Mutex<State>,
+    faults: Mutex<Faults>,
+}
+
+/// How the simulator answers a request.
+enum Answer {
+    /// Sent to the requesting client only.
+    Reply(Reply),
+    /// Sent to the clients which receive it as broadcast.
+    Broadcast(Reply),
+    /// Sent to the requesting client and as broadcast.
+    ReplyAndBroadcast(Reply),
+}
+
+/// State of a simulated loco.
+#[derive(Clone, Copy)]
+struct Loco {
+    steps: DccThrottleSteps,
+    /// Raw `RVVVVVVV` speed byte.
+    speed: u8,
+    functions: [bool; 32],
+}
+
+/// State of the simulated station.
+struct State {
+    serial_number: u32,
+    hardware_info: HardwareInfo,
+    track_power: bool,
+    emergency_stop: bool,
+    short_circuit: bool,
+    programming_mode: bool,
+    locos: HashMap<u16, Loco>,
+    turnouts: HashMap<u16, TurnoutPosition>,
+    /// CVs of the decoder on the programming track, 1 based.
+    cvs: HashMap<u16, u8>,
+}
+
+/// Injected faults.
+struct Faults {
+    offline: bool,
+    drop_rate: f64,
+    reply_delay: Duration,
+    /// State of the xorshift generator deciding which requests are dropped.
+    rng: u64,
+}
+
+impl Z21Simulator {
+    /// Starts a simulator listening on `addr`, e.g. "127.0.0.1:0" for a free port.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`Error`](crate::Error) if the UDP socket cannot be bound.
+    pub async fn bind(addr: impl ToSocketAddrs) -> Result<Self> {
+        let inner = Arc::new(Inner {
+            server: Z21Server::bind(addr).await?,
+            state: Mutex::new(State::default()),
+            faults: Mutex::new(Faults::default()),
+        });
+        let cancel = CancellationToken::new();
+        let task_inner = Arc::clone(&inner);
+        let token = cancel.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = token.cancelled() => {}
+                _ = task_inner.serve() => {}
+            }
+        });
+        Ok(Z21Simulator { inner, cancel })
+    }
+
+    /// Returns the address the simulator listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.inner.server.local_addr()
+    }
+
+    /// Sets the serial number reported by the simulator.
+    pub fn set_serial_number(&self, serial_number: u32) {
+        self.inner.state.lock().unwrap().serial_number = serial_number;
+    }
+
+    /// Returns the addresses of the logged in clients.
+    pub fn clients(&self) -> Vec<SocketAddr> {
+        self.inner.server.clients()
+    }
+
+    /// Returns whether the track power is on.
+    pub fn track_power(&self) -> bool {
+        self.inner.state.lock().unwrap().track_power
+    }
+
+    /// Returns the state of the loco with `address`, if it was ever addressed.
+    pub fn loco(&self, address: u16) -> Option<LocoState> {
+        let state = self.inner.state.lock().unwrap();
+        state.locos.get(&address).map(|loco| loco.state(address))
+    }
+
+    /// Returns the position of the turnout with `address`.
+    pub fn turnout(&self, address: u16) -> TurnoutPosition {
+        let state = self.inner.state.lock().unwrap();
+        state.turnout(address)
+    }
+
+    /// Returns the value of a CV of the decoder on the programming track (1 based).
+    pub fn cv(&self, cv: u16) -> Option<u8> {
+        self.inner.state.lock().unwrap().cvs.get(&cv).copied()
+    }
+
+    /// Sets the value of a CV of the decoder on the programming track (1 based).
+    pub fn set_cv(&self, cv: u16, value: u8) {
+        self.inner.state.lock().unwrap().cvs.insert(cv, value);
+    }
+
+    /// Removes all CVs, as if no decoder was on the programming track. CV commands are
+    /// then answered with LAN_X_CV_NACK.
+    pub fn remove_decoder(&self) {
+        self.inner.state.lock().unwrap().cvs.clear();
+    }
+
+    /// Stops answering, as if the station was switched off. Requests received while
+    /// offline are dropped.
+    pub fn set_offline(&self, offline: bool) {
+        self.inner.faults.lock().unwrap().offline = offline;
+    }
+
+    /// Drops received requests with probability `rate` (0.0 to 1.0).
+    ///
+    /// The requests to drop are chosen by a pseudo random generator with a fixed seed,
+    /// so a test sees the same losses on every run.
+    pub fn set_drop_rate(&self, rate: f64) {
+        self.inner.faults.lock().unwrap().drop_rate = rate.clamp(0., 1.);
+    }
+
+    /// Delays every reply and broadcast by `delay`.
+    pub fn set_reply_delay(&self, delay: Duration) {
+        self.inner.faults.lock().unwrap().reply_delay = delay;
+    }
+
+    /// Simulates a short circuit on the track.
+    ///
+    /// The track power is switched off and LAN_X_BC_TRACK_SHORT_CIRCUIT is broadcast.
+    /// Switching the track power on again clears the short circuit.
+    pub async fn short_circuit(&self) {
+        let answers = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.track_power = false;
+            state.short_circuit = true;
+            vec![
+                Answer::Broadcast(Reply::TrackShortCircuit),
+                state.system_state_broadcast(),
+            ]
+        };
+        self.inner.send(None, answers).await;
+    }
+}
+
+impl Drop for Z21Simulator {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+impl Inner {
+    /// Answers the requests of the clients until the task is cancelled.
+    async fn serve(&self) {
+        while let Some(event) = self.server.recv().await {
+            let ServerEvent::Request { client, request } = event else {
+                continue;
+            };
+            if self.faults.lock().unwrap().drops() {
+                continue;
+            }
+            let answers = self.state.lock().unwrap().handle(request);
+            self.send(Some(client), answers).await;
+        }
+    }
+
+    /// Sends `answers` to `client` and the other clients.
+    async fn send(&self, client: Option<SocketAddr>, answers: Vec<Answer>) {
+        let delay = self.faults.lock().unwrap().reply_delay;
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        for answer in answers {
+            // Sending only fails if the answer cannot be encoded, or the client went
+            // away, which the real Z21 ignores as well.
+            let _ = match (answer, client) {
+                (Answer::Reply(reply), Some(client)) => self.server.reply(client, &reply).await,
+                (Answer::ReplyAndBroadcast(reply), Some(client)) => {
+                    self.server.reply_and_broadcast(client, &reply).await
+                }
+                (Answer::Reply(_), None) => Ok(()),
+                (Answer::Broadcast(reply) | Answer::ReplyAndBroadcast(reply), _) => {
+                    self.server.broadcast(&reply).await
+                }
+            };
+        }
+    }
+}
+
+impl Faults {
+    /// Decides whether the next request is dropped.
+    fn drops(&mut self) -> bool {
+        if self.offline {
+            return true;
+        }
+        if self.drop_rate <= 0. {
+            return false;
+        }
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng as f64 / u64::MAX as f64) < self.drop_rate
+    }
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Faults {
+            offline: false,
+            drop_rate: 0.,
+            reply_delay: Duration::ZERO,
+            rng: 0x2545_F491_4F6C_DD1D,
+        }
+    }
+}
+
+impl Loco {
+    fn state(&self, address: u16) -> LocoState {
+        let steps = match self.steps {
+            DccThrottleSteps::Steps14 => 14.,
+            DccThrottleSteps::Steps28 => 28.,
+            DccThrottleSteps::Steps128 => 128.,
+        };
+        // Speed step 1 is the emergency stop.
+        let speed = match self.speed & 0x7F {
+            1 => 0.,
+            speed => speed as f64 / steps * 100.,
+        };
+        LocoState {
+            address,
+            is_busy: Some(false),
+            stepping: Some(self.steps),
+            speed_percentage: Some(if self.speed & 0x80 != 0 {
+                speed
+            } else {
+                -speed
+            }),
+            double_traction: Some(false),
+            smart_search: Some(false),
+            functions: Some(self.functions),
+        }
+    }
+}
+
+impl Default for Loco {
+    fn default() -> Self {
+        Loco {
+            steps: DccThrottleSteps::Steps128,
+            speed: 0x80,
+            functions: [false; 32],
+        }
+    }
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            serial_number: 123456,
+            hardware_info: HardwareInfo {
+                hardware_type: HardwareType::Z21New,
+                firmware_major: 1,
+                firmware_minor: 43,
+            },
+            track_power: true,
+            emergency_stop: false,
+            short_circuit: false,
+            programming_mode: false,
+            locos: HashMap::new(),
+            turnouts: HashMap::new(),
+            // A typical decoder with short address 3.
+            cvs: HashMap::from([(1, 3), (7, 42), (8, 145), (29, 6)]),
+        }
+    }
+}
+
+impl State {
+    /// Handles `request` and returns the answers to send.
+    fn handle(&mut self, request: Request) -> Vec<Answer> {
+        let reply = |reply| vec![Answer::Reply(reply)];
+        match request {
+            Request::GetSerialNumber => reply(Reply::SerialNumber(self.serial_number)),
+            Request::GetCode => reply(Reply::Code(0x00)),
+            Request::GetHardwareInfo => reply(Reply::HardwareInfo(self.hardware_info.clone())),
+            Request::GetFirmwareVersion => reply(Reply::FirmwareVersion {
+                major: self.hardware_info.firmware_major,
+                minor: self.hardware_info.firmware_minor,
+            }),
+            Request::GetXBusVersion => reply(Reply::XBusVersion {
+                version: 0x30,
+                station_id: 0x12,
+            }),
+            Request::GetStatus => reply(Reply::Status(self.central_state())),
+            Request::SystemStateGetData => {
+                reply(Reply::SystemStateDataChanged(self.system_state()))
+            }
+            Request::SetTrackPowerOn => {
+                self.track_power = true;
+                self.emergency_stop = false;
+                self.short_circuit = false;
+                self.programming_mode = false;
+                self.power_broadcast(Reply::TrackPowerOn)
+            }
+            Request::SetTrackPowerOff => {
+                self.track_power = false;
+                self.power_broadcast(Reply::TrackPowerOff)
+            }
+            Request::SetStop => {
+                self.emergency_stop = true;
+                for loco in self.locos.values_mut() {
+                    loco.speed &= 0x80;
+                }
+                self.power_broadcast(Reply::Stopped)
+            }
+            Request::GetLocoMode { address } => reply(Reply::LocoMode {
+                address,
+                mode: DecoderMode::Dcc,
+            }),
+            Request::GetTurnoutMode { address } => reply(Reply::TurnoutMode {
+                address,
+                mode: DecoderMode::Dcc,
+            }),
+            Request::GetLocoInfo { address } => {
+                let state = self.locos.entry(address).or_default().state(address);
+                reply(Reply::LocoInfo(state))
+            }
+            Request::SetLocoDrive {
+                address,
+                steps,
+                speed,
+            } => {
+                let loco = self.locos.entry(address).or_default();
+                loco.steps = steps;
+                loco.speed = speed;
+                self.loco_broadcast(address)
+            }
+            Request::SetLocoFunction {
+                address,
+                function,
+                action,
+            } => {
+                let loco = self.locos.entry(address).or_default();
+                if let Some(on) = loco.functions.get_mut(function as usize) {
+                    *on = match action {
+                        FunctionAction::Off => false,
+                        FunctionAction::On => true,
+                        FunctionAction::Toggle => !*on,
+                    };
+                }
+                self.loco_broadcast(address)
+            }
+            Request::SetLocoEmergencyStop { address } => {
+                let loco = self.locos.entry(address).or_default();
+                loco.speed = (loco.speed & 0x80) | 0x01;
+                self.loco_broadcast(address)
+            }
+            Request::PurgeLoco { address } => {
+                self.locos.remove(&address);
+                Vec::new()
+            }
+            Request::GetTurnoutInfo { address } => reply(Reply::TurnoutInfo(TurnoutInfo {
+                address,
+                position: self.turnout(address),
+            })),
+            Request::SetTurnout {
+                address,
+                output,
+                activate,
+                ..
+            } => {
+                if !activate {
+                    return Vec::new();
+                }
+                let position = if output {
+                    TurnoutPosition::P1
+                } else {
+                    TurnoutPosition::P0
+                };
+                self.turnouts.insert(address, position);
+                let info = TurnoutInfo { address, position };
+                vec![Answer::ReplyAndBroadcast(Reply::TurnoutInfo(info))]
+            }
+            Request::CvRead { cv } => self.program(cv, None),
+            Request::CvWrite { cv, value } => self.program(cv, Some(value)),
+            // The simulated locos have no decoder CVs, POM reads are not acknowledged.
+            Request::CvPomReadByte { .. } => reply(Reply::CvNack),
+            Request::XBus(_) => reply(Reply::UnknownCommand),
+            _ => Vec::new(),
+        }
+    }
+
+    fn turnout(&self, address: u16) -> TurnoutPosition {
+        self.turnouts
+            .get(&address)
+            .copied()
+            .unwrap_or(TurnoutPosition::Unknown)
+    }
+
+    /// Reads or writes a CV on the programming track.
+    fn program(&mut self, cv: u16, value: Option<u8>) -> Vec<Answer> {
+        self.programming_mode = true;
+        let result = if self.short_circuit {
+            Reply::CvNackShortCircuit
+        } else {
+            match (self.cvs.get_mut(&cv), value) {
+                (Some(current), Some(value)) => {
+                    *current = value;
+                    Reply::CvResult { cv, value }
+                }
+                (Some(current), None) => Reply::CvResult {
+                    cv,
+                    value: *current,
+                },
+                // An empty programming track or a CV the decoder does not have.
+                (None, _) => Reply::CvNack,
+            }
+        };
+        vec![
+            Answer::Broadcast(Reply::ProgrammingMode),
+            Answer::Reply(result),
+        ]
+    }
+
+    fn central_state(&self) -> u8 {
+        let mut central_state = 0;
+        if self.emergency_stop {
+            central_state |= CS_EMERGENCY_STOP;
+        }
+        if !self.track_power {
+            central_state |= CS_TRACK_VOLTAGE_OFF;
+        }
+        if self.short_circuit {
+            central_state |= CS_SHORT_CIRCUIT;
+        }
+        if self.programming_mode {
+            central_state |= CS_PROGRAMMING_MODE_ACTIVE;
+        }
+        central_state
+    }
+
+    fn system_state(&self) -> SystemState {
+        let driving = self
+            .locos
+            .values()
+            .filter(|loco| loco.speed & 0x7F > 1)
+            .count() as i16;
+        let main_current = if self.track_power {
+            80 + 150 * driving
+        } else {
+            0
+        };
+        SystemState {
+            main_current,
+            prog_current: 0,
+            filtered_main_current: main_current,
+            temperature: 35,
+            supply_voltage: 19000,
+            vcc_voltage: if self.track_power { 18000 } else { 0 },
+            central_state: self.central_state(),
+            central_state_ex: 0,
+            reserved: 0,
+            capabilities: 0,
+        }
+    }
+
+    /// LAN_SYSTEMSTATE_DATACHANGED for the clients which asked for it.
+    fn system_state_broadcast(&self) -> Answer {
+        Answer::Broadcast(Reply::SystemStateDataChanged(self.system_state()))
+    }
+
+    fn power_broadcast(&self, reply: Reply) -> Vec<Answer> {
+        vec![
+            Answer::ReplyAndBroadcast(reply),
+            self.system_state_broadcast(),
+        ]
+    }
+
+    /// LAN_X_LOCO_INFO of `address` for the requester and every client interested in
+    /// the loco.
+    fn loco_broadcast(&mut self, address: u16) -> Vec<Answer> {
+        let state = self.locos.entry(address).or_default().state(address);
+        vec![Answer::ReplyAndBroadcast(Reply::LocoInfo(state))]
+    }
+}
@@ -9,54 +9,91 @@
 //! handling command transmission and event reception through an asynchronous architecture.
 //! It supports:
 //!
-//! - Automatic connection management with keep-alive functionality
+//! - Automatic connection management with keep-alive functionality and reconnection
 //! - Broadcast message handling for system state changes and locomotive information
 //! - DCC command transmission for controlling locomotives and accessories
 //! - XBus protocol implementation for low-level communication
 //!
 
-use crate::messages::{self, SystemState, XBusMessage};
-use crate::packet::Packet;
-use std::cell::OnceCell;
-use std::convert::TryFrom;
-use std::io;
-use std::net::SocketAddr;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::net::UdpSocket;
+use crate::capture::CaptureTransport;
+use crate::error::{Error, Result};
+use crate::log;
+use crate::messages::header::*;
+use crate::messages::{BroadcastFlags, HardwareInfo, Reply, Request, SystemState};
+use crate::packet::{join_datagram, DatagramIter, Packet, MAX_DATAGRAM_LEN};
+use crate::transport::{self, Transport, UdpTransport};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::broadcast;
-use tokio::time::{self, timeout};
-
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
+
+mod builder;
+mod discovery;
+mod dispatch;
+mod event;
+mod inspect;
 mod loco;
+mod metrics;
+mod queue;
+mod subscription;
+mod supervisor;
+use builder::CommandTimeouts;
+pub use builder::{CommandClass, Z21StationBuilder};
+pub use discovery::DiscoveredStation;
+use dispatch::Dispatcher;
+pub use event::{EventStream, Z21Event};
+use inspect::InspectTransport;
 pub use loco::Loco;
+use metrics::MetricsRecorder;
+#[cfg(feature = "prometheus")]
+pub use metrics::MetricsServer;
+pub use metrics::{Metrics, RttHistogram, RTT_BUCKETS};
+use queue::CommandQueue;
+pub use queue::QueueConfig;
+pub use subscription::Subscription;
+pub use supervisor::ConnectionState;
 
-/// The header value for the LAN_SYSTEMSTATE_DATACHANGED event.
-const LAN_SYSTEMSTATE_DATACHANGED: u16 = 0x84;
-const LAN_SET_BROADCASTFLAGS: u16 = 0x50;
-const LAN_SYSTEMSTATE_GETDATA: u16 = 0x85;
-const X_SET_TRACK_POWER_OFF: (u8, u8) = (0x21, 0x80);
-const X_SET_TRACK_POWER_ON: (u8, u8) = (0x21, 0x81);
-const X_BC_TRACK_POWER: u8 = 0x61;
+/// Default UDP port the Z21 station listens on.
+pub const Z21_DEFAULT_PORT: u16 = 21105;
 
-/// Default timeout in milliseconds for awaiting responses.
-const DEFAULT_TIMEOUT_MS: u64 = 2000;
+/// Delay before receiving again after the transport reported an error.
+const RECEIVE_ERROR_BACKOFF: Duration = Duration::from_millis(100);
 
-/// Default broadcast flags for the Z21 station.(Default is ONLY LOCO_INFO & TURNOUT_INFO)
-const DEFAULT_BROADCAST_FLAGS: u32 = 0x00000001;
+/// Minimal interval between two warnings about transient receive errors.
+const RECEIVE_ERROR_WARNING_INTERVAL: Duration = Duration::from_secs(10);
 
 /// Represents an asynchronous connection to a Z21 station.
 ///
-/// The `Z21Station` manages a UDP socket for communication with a Z21 station. It spawns a
+/// The `Z21Station` manages a UDP socket (or another [`Transport`]) for communication
+/// with a Z21 station. It spawns a
 /// background task to continuously listen for incoming packets and proceed these packets
 /// over an internal logic.
 pub struct Z21Station {
-    socket: Arc<UdpSocket>,
-    message_sender: broadcast::Sender<Packet>,
-    message_receiver: broadcast::Receiver<Packet>,
-    timeout: Duration,
-    keep_alive: Arc<AtomicBool>,
-    broadcast_flags: u32,
+    inner: Arc<Inner>,
+}
+
+/// State shared between a [`Z21Station`] and its background tasks.
+struct Inner {
+    transport: Arc<dyn Transport>,
+    dispatcher: Dispatcher,
+    queue: Arc<CommandQueue>,
+    event_sender: broadcast::Sender<Z21Event>,
+    timeouts: CommandTimeouts,
+    /// Cancelled when the station shuts down, stops every background task.
+    cancel: CancellationToken,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+    keep_alive_interval: Duration,
+    /// Broadcast flags sent on every keep-alive and after a reconnection.
+    broadcast_flags: Mutex<BroadcastFlags>,
+    /// Addresses of the locos controlled through [`Loco`], with the number of handles.
+    controlled_locos: Mutex<HashMap<u16, usize>>,
+    connection_state: Mutex<ConnectionState>,
+    metrics: Arc<MetricsRecorder>,
 }
 
 impl Z21Station {
@@ -75,299 +112,332 @@ impl Z21Station {
     ///
     /// # Errors
     ///
-    /// Returns an `io::Error` if:
+    /// Returns an [`Error`] if:
     /// - The UDP socket cannot be bound or connected
     /// - The initial handshake with the Z21 station fails
     /// - The station does not respond within the timeout period
     ///
     /// # Example
     ///
-    /// ```rust
+    /// ```rust,no_run
+    /// # use roco_z21_driver::Z21Station;
+    /// # async fn example() -> roco_z21_driver::Result<()> {
     /// let station = Z21Station::new("192.168.0.111:21105").await?;
+    /// # Ok(())
+    /// # }
     /// ```
-    pub async fn new(bind_addr: &str) -> io::Result<Self> {
-        // Bind the socket to an available local port on all interfaces.
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        // Enable broadcast on the socket to allow sending messages to a broadcast address.
-        socket.set_broadcast(true)?;
-        // Connect the socket to the Z21 station address.
-        socket.connect(bind_addr).await?;
-        let socket = Arc::new(socket);
-
-        // Create a broadcast channel for propagating incoming packets.
-        let (tx, rx) = broadcast::channel(100);
-        let station = Z21Station {
-            socket,
-            message_sender: tx,
-            message_receiver: rx,
-            keep_alive: Arc::new(AtomicBool::new(true)),
-            broadcast_flags: DEFAULT_BROADCAST_FLAGS,
-            timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS),
+    pub async fn new(bind_addr: &str) -> Result<Self> {
+        Self::builder(bind_addr).connect().await
+    }
+
+    /// Returns a [`Z21StationBuilder`] to connect to the Z21 station at the specified
+    /// address with custom settings.
+    ///
+    /// # Arguments
+    ///
+    /// * `station_addr` - Network address of the Z21 station (typically "192.168.0.111:21105")
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use roco_z21_driver::Z21Station;
+    /// # use std::time::Duration;
+    /// # async fn example() -> roco_z21_driver::Result<()> {
+    /// let station = Z21Station::builder("192.168.0.111:21105")
+    ///     .timeout(Duration::from_secs(5))
+    ///     .connect()
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn builder(station_addr: &str) -> Z21StationBuilder {
+        Z21StationBuilder::new(station_addr)
+    }
+
+    /// Connects to a Z21 station over UDP with the settings of `builder`.
+    async fn connect(builder: Z21StationBuilder) -> Result<Self> {
+        // Bind the socket to the configured local address (any interface by default).
+        let transport = UdpTransport::connect(builder.bind_addr, &builder.station_addr).await?;
+        Self::connect_with_transport(builder, Arc::new(transport)).await
+    }
+
+    /// Connects to a Z21 station over `transport` with the settings of `builder`.
+    async fn connect_with_transport(
+        builder: Z21StationBuilder,
+        transport: Arc<dyn Transport>,
+    ) -> Result<Self> {
+        let transport: Arc<dyn Transport> = match &builder.capture {
+            Some(writer) => Arc::new(CaptureTransport::new(transport, writer.clone())),
+            None => transport,
         };
-        // Start the background receiver task.
+        let transport: Arc<dyn Transport> = match &builder.packet_hook {
+            Some(hook) => Arc::new(InspectTransport::new(transport, hook.clone())),
+            None => transport,
+        };
+        #[cfg(feature = "tracing")]
+        let transport: Arc<dyn Transport> = Arc::new(InspectTransport::new(
+            transport,
+            inspect::PacketHook::new(log::trace_packet),
+        ));
+        // Create a broadcast channel for propagating incoming events.
+        let (event_sender, _) = broadcast::channel(builder.channel_capacity.max(1));
+        let inner = Arc::new(Inner {
+            transport,
+            dispatcher: Dispatcher::default(),
+            queue: Arc::new(CommandQueue::new(builder.queue_config)),
+            event_sender,
+            cancel: CancellationToken::new(),
+            tasks: Mutex::new(Vec::new()),
+            keep_alive_interval: builder.keep_alive_interval,
+            broadcast_flags: Mutex::new(builder.broadcast_flags),
+            timeouts: builder.timeouts,
+            controlled_locos: Mutex::new(HashMap::new()),
+            connection_state: Mutex::new(ConnectionState::Connected),
+            metrics: Arc::new(MetricsRecorder::default()),
+        });
+        let station = Z21Station { inner };
+        // Start the background receiver and sender tasks.
         station.start_receiver();
+        let queue = Arc::clone(&station.inner.queue);
+        let transport = Arc::clone(&station.inner.transport);
+        let metrics = Arc::clone(&station.inner.metrics);
+        station
+            .inner
+            .spawn(station.inner.cancel.clone(), async move {
+                queue.run_sender(transport.as_ref(), &metrics).await
+            });
 
         // Perform the initial handshake with the Z21 station.
-        let result = station.initial_handshake().await;
-        if let Err(e) = result {
-            eprintln!(
-                "There is no connection to the Z21 station, on the specified address: {}",
-                bind_addr
-            );
-            return Err(e);
+        if builder.handshake {
+            let result = station.inner.handshake().await;
+            if let Err(e) = result {
+                log::error!(
+                    "There is no connection to the Z21 station, on the specified address: {}",
+                    builder.station_addr
+                );
+                return Err(e);
+            }
         }
 
-        // Start the keep-alive thread.
-        station.start_keep_alive_setup_broadcast_task();
+        // Start the keep-alive and reconnection task.
+        supervisor::start(&station.inner);
         Ok(station)
     }
 
     /// Starts a background asynchronous task that continuously listens for incoming UDP packets.
     ///
-    /// The task reads data from the socket, converts it into a [`Packet`], and then sends it through
-    /// the internal broadcast channel so that subscribers can process the packet.
+    /// The task reads data from the transport, splits every datagram into the [`Packet`]s it
+    /// contains and decodes them. Every reply is handed to the pending request it answers
+    /// and published as a [`Z21Event`] for the streams returned by [`Z21Station::events`].
     fn start_receiver(&self) {
-        let socket = Arc::clone(&self.socket);
-        let message_sender = self.message_sender.clone();
+        let inner = Arc::clone(&self.inner);
 
-        tokio::spawn(async move {
-            let mut buf = [0u8; 1024];
+        self.inner.spawn(self.inner.cancel.clone(), async move {
+            let mut buf = [0u8; MAX_DATAGRAM_LEN];
+            let mut last_warning: Option<Instant> = None;
             loop {
-                match socket.recv(&mut buf).await {
+                match inner.transport.recv(&mut buf).await {
                     Ok(size) => {
-                        // Copy the received data into a vector.
-                        let data = buf[..size].to_vec();
-                        // Convert the raw data into a Packet.
-                        let packet = Packet::from(data);
-                        //println!("Received packet with header: {:?}", packet.get_header());
-                        // if packet.get_header() == 64 {
-                        //     let xbus_msg = XBusMessage::try_from(
-                        //         &packet.get_data()[0..packet.get_data_len() as usize - 4],
-                        //     );
-                        //     if let Ok(msg) = xbus_msg {
-                        //         println!(
-                        //             "Received XBus message with header: {:02x}",
-                        //             msg.get_x_header()
-                        //         );
-                        //     } else {
-                        //         eprintln!("Failed to parse XBus message");
-                        //     }
-                        // }
-
-                        // Broadcast the packet to all subscribers.
-                        if let Err(e) = message_sender.send(packet) {
-                            eprintln!("Failed to send packet via broadcast channel: {:?}", e);
+                        last_warning = None;
+                        // A single datagram may carry several datasets.
+                        for packet in DatagramIter::new(&buf[..size]) {
+                            let packet = match packet {
+                                Ok(packet) => packet,
+                                Err(e) => {
+                                    log::warning!("Dropping malformed datagram remainder: {}", e);
+                                    break;
+                                }
+                            };
+                            match Reply::decode(&packet) {
+                                Ok(reply) => {
+                                    MetricsRecorder::add(&inner.metrics.replies_received, 1);
+                                    inner.dispatcher.dispatch(&reply);
+                                    inner.emit(Z21Event::from(reply));
+                                }
+                                Err(e) => {
+                                    if let roco_z21_protocol::Error::Checksum { .. } = e {
+                                        MetricsRecorder::add(&inner.metrics.checksum_failures, 1);
+                                    }
+                                    log::warning!("Failed to decode packet: {}", e);
+                                }
+                            }
                         }
                     }
+                    Err(e) if transport::is_closed(&e) => {
+                        log::error!("Transport to the Z21 station closed: {}", e);
+                        inner.set_connection_state(ConnectionState::Disconnected);
+                        return;
+                    }
                     Err(e) => {
-                        eprintln!("Error receiving packet: {:?}", e);
-                        break;
+                        // E.g. ICMP port unreachable while the station reboots, the
+                        // supervisor takes care of the connection.
+                        if last_warning
+                            .is_none_or(|at| at.elapsed() >= RECEIVE_ERROR_WARNING_INTERVAL)
+                        {
+                            log::warning!("Error receiving packet: {:?}", e);
+                            last_warning = Some(Instant::now());
+                        }
+                        time::sleep(RECEIVE_ERROR_BACKOFF).await;
                     }
                 }
             }
         });
     }
 
-    async fn initial_handshake(&self) -> io::Result<()> {
-        let packet = Packet::with_header_and_data(LAN_SYSTEMSTATE_GETDATA, &[]);
-        self.send_packet(packet).await?;
-        let _ = self
-            .receive_packet_with_header(LAN_SYSTEMSTATE_DATACHANGED)
-            .await?;
-        Ok(())
-    }
-
-    async fn send_set_broadcast_flags(socket: &Arc<UdpSocket>, flags: u32) -> io::Result<()> {
-        let flags = flags.to_le_bytes();
-        let broadcast_packet = Packet::with_header_and_data(LAN_SET_BROADCASTFLAGS, &flags);
-        let broadcast_packet: Vec<_> = broadcast_packet.into();
-        socket.send(&broadcast_packet).await?;
-        Ok(())
-    }
-
-    /// Keeps connection alive by sending a broadcast packet to the Z21 station.
-    fn start_keep_alive_setup_broadcast_task(&self) {
-        let socket = Arc::clone(&self.socket);
-        let flags = self.broadcast_flags;
-        let keep_alive = Arc::clone(&self.keep_alive);
-        tokio::spawn(async move {
-            loop {
-                let _result = Self::send_set_broadcast_flags(&socket, flags).await;
-                tokio::time::sleep(Duration::from_secs(10)).await;
-
-                if !keep_alive.load(Ordering::Relaxed) {
-                    break;
-                }
-            }
-        });
-    }
-
-    /// Sends a [`Packet`] asynchronously to the connected Z21 station.
+    /// Sends several [`Packet`]s to the connected Z21 station, batched into as few
+    /// datagrams as possible.
     ///
-    /// The packet is serialized into a byte vector and sent through the UDP socket.
+    /// The Z21 accepts multiple datasets in one UDP datagram, which reduces the number of
+    /// datagrams on a busy network. Packets are kept in order; a new datagram is started
+    /// whenever the next packet would exceed the maximum datagram size. The datagrams are
+    /// sent through the command queue, so this returns as soon as they are queued.
     ///
     /// # Arguments
     ///
-    /// * `packet` - The [`Packet`] to be transmitted.
+    /// * `packets` - The [`Packet`]s to be transmitted.
     ///
     /// # Errors
     ///
-    /// Returns an `io::Error` if the packet fails to send.
-    async fn send_packet(&self, packet: Packet) -> io::Result<()> {
-        let data: Vec<u8> = packet.into();
-        // Send the serialized packet through the connected UDP socket.
-        self.socket.send(&data).await?;
+    /// Returns [`Error::NotConnected`] if the station was shut down.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use roco_z21_driver::{Packet, Z21Station};
+    /// # async fn example(station: &Z21Station) -> roco_z21_driver::Result<()> {
+    /// // Request serial number and hardware info in a single datagram
+    /// station
+    ///     .send_packets(vec![
+    ///         Packet::with_header(0x10),
+    ///         Packet::with_header(0x1A),
+    ///     ])
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn send_packets(&self, packets: Vec<Packet>) -> Result<()> {
+        if self.inner.cancel.is_cancelled() {
+            return Err(Error::NotConnected);
+        }
+        let mut batch = Vec::new();
+        let mut batch_len = 0;
+        for packet in packets {
+            let len = packet.get_data_len() as usize;
+            if !batch.is_empty() && batch_len + len > MAX_DATAGRAM_LEN {
+                self.inner
+                    .queue
+                    .push_datagram(join_datagram(batch.drain(..)));
+                batch_len = 0;
+            }
+            batch_len += len;
+            batch.push(packet);
+        }
+        if !batch.is_empty() {
+            self.inner.queue.push_datagram(join_datagram(batch));
+        }
         Ok(())
     }
-    async fn send_packet_external(socket: &Arc<UdpSocket>, packet: Packet) -> io::Result<()> {
+
+    async fn send_packet_external(transport: &dyn Transport, packet: Packet) -> Result<()> {
         let data: Vec<u8> = packet.into();
-        // Send the serialized packet through the connected UDP socket.
-        socket.send(&data).await?;
+        // Send the serialized packet through the transport.
+        transport.send(&data).await?;
         Ok(())
     }
 
-    /// Sends an XBus packet without waiting for a response
+    /// Sends a [`Request`] without waiting for a response.
+    ///
+    /// This is the low-level entry point for requests which have no dedicated method on
+    /// `Z21Station` yet. Replies and broadcasts are delivered like any other message.
+    /// The request is sent through the command queue, so this returns as soon as it is
+    /// queued.
     ///
     /// # Arguments
     ///
-    /// * `xbus_message` - The XBus message to send
+    /// * `request` - The request to send
     ///
     /// # Errors
     ///
-    /// Returns an `io::Error` if the packet fails to send
-    async fn send_xbus_packet(&self, xbus_message: XBusMessage) -> io::Result<()> {
-        let data: Vec<u8> = xbus_message.into();
-        let packet = Packet::with_header_and_data(messages::XBUS_HEADER, &data);
-        self.send_packet(packet).await
-    }
-
-    /// Sends an XBus command and waits for the expected response
-    ///
-    /// # Arguments
+    /// Returns an [`Error`] if the request cannot be encoded or fails to send.
     ///
-    /// * `xbus_message` - The XBus message to send
-    /// * `expected_response_xbus_header` - Optional expected response header. If None, uses the sent message header
-    ///
-    /// # Errors
+    /// # Example
     ///
-    /// Returns an `io::Error` if:
-    /// - The packet fails to send
-    /// - No response is received within the timeout period
-    /// - The response has an invalid format
-    async fn send_xbus_command(
-        &self,
-        xbus_message: XBusMessage,
-        expected_response_xbus_header: Option<u8>,
-    ) -> io::Result<XBusMessage> {
-        let x_header = xbus_message.get_x_header();
-        self.send_xbus_packet(xbus_message).await?;
-
-        let expected_header = expected_response_xbus_header.unwrap_or(x_header);
-        let xbus_return = self.receive_xbus_packet(expected_header).await?;
-        Ok(xbus_return)
+    /// ```rust,no_run
+    /// # use roco_z21_driver::{messages::Request, Z21Station};
+    /// # async fn example(station: &Z21Station) -> roco_z21_driver::Result<()> {
+    /// // Switch turnout 5 to position 1
+    /// station
+    ///     .send_request(&Request::SetTurnout {
+    ///         address: 5,
+    ///         output: true,
+    ///         activate: true,
+    ///         queue: false,
+    ///     })
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn send_request(&self, request: &Request) -> Result<()> {
+        self.inner.send_request(request)
     }
 
-    /// Asynchronously waits for a packet with the specified header.
+    /// Sends a [`Request`] and waits for the reply of the Z21 station.
     ///
-    /// This function listens on the internal broadcast channel and filters incoming packets,
-    /// returning the first packet that matches the given header value.
+    /// The request is registered before it is sent, so even an immediate reply is not
+    /// missed. Idempotent requests (see [`Request::is_idempotent`]) are resent when their
+    /// reply times out, as often as configured in [`QueueConfig::retries`]. Only a reply
+    /// answering this very request is accepted, e.g. the LAN_X_LOCO_INFO of the same loco
+    /// address or the LAN_X_CV_RESULT of the same CV (see [`Request::matches_reply`]).
+    /// The timeout configured for the [`CommandClass`] of the request applies.
     ///
     /// # Arguments
     ///
-    /// * `header` - The header value to filter for.
+    /// * `request` - The request to send
+    ///
+    /// # Returns
+    ///
+    /// The [`Reply`] answering the request.
     ///
     /// # Errors
     ///
-    /// Returns an `io::Error` if the broadcast channel is closed or an error occurs while receiving.
-    async fn receive_packet_with_header(&self, header: u16) -> io::Result<Packet> {
-        let mut msg_rcv = self.message_receiver.resubscribe();
-        match timeout(self.timeout, async {
-            loop {
-                match msg_rcv.recv().await {
-                    Ok(packet) => {
-                        if packet.get_header() == header {
-                            return Ok(packet);
-                        }
-                    }
-                    Err(_) => {
-                        return Err(io::Error::new(io::ErrorKind::Other, "Channel closed"));
-                    }
-                }
-            }
-        })
-        .await
-        {
-            Ok(result) => result,
-            Err(_) => Err(io::Error::new(
-                io::ErrorKind::TimedOut,
-                format!("Timeout waiting for packet with header 0x{:04x}", header),
-            )),
-        }
-    }
-
-    async fn receive_xbus_packet(&self, expected_xbus_header: u8) -> io::Result<XBusMessage> {
-        let mut msg_rcv = self.message_receiver.resubscribe();
-        match timeout(self.timeout, async {
-            loop {
-                match msg_rcv.recv().await {
-                    Ok(packet) => {
-                        if packet.get_header() == messages::XBUS_HEADER {
-                            let end_payload = packet.get_data_len() as isize - 4;
-                            if end_payload <= 0 {
-                                continue;
-                            }
-                            let end_payload = end_payload as usize;
-                            let payload = &packet.get_data()[0..end_payload];
-                            let xbus_msg = XBusMessage::try_from(payload);
-                            if let Ok(msg) = xbus_msg {
-                                if msg.get_x_header() == expected_xbus_header {
-                                    return Ok(msg);
-                                }
-                            }
-                        }
-                    }
-                    Err(_) => {
-                        return Err(io::Error::new(io::ErrorKind::Other, "Channel closed"));
-                    }
-                }
-            }
-        })
-        .await
-        {
-            Ok(result) => result,
-            Err(_) => Err(io::Error::new(
-                io::ErrorKind::TimedOut,
-                format!(
-                    "Timeout waiting for XBus message with header 0x{:02x}",
-                    expected_xbus_header
-                ),
-            )),
-        }
+    /// Returns an [`Error`] if:
+    /// - The request fails to send
+    /// - No reply is received within the timeout period
+    /// - The station answers with a negative reply ([`Error::CvNack`],
+    ///   [`Error::ShortCircuit`] or [`Error::UnknownCommand`])
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use roco_z21_driver::{messages::{Reply, Request}, Z21Station};
+    /// # async fn example(station: &Z21Station) -> roco_z21_driver::Result<()> {
+    /// if let Reply::FirmwareVersion { major, minor } =
+    ///     station.request(&Request::GetFirmwareVersion).await?
+    /// {
+    ///     println!("Firmware {}.{}", major, minor);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn request(&self, request: &Request) -> Result<Reply> {
+        self.inner.request(request).await
     }
 
-    /// Receives a single packet from the internal broadcast channel.
+    /// Sends a [`Request`] and waits at most `timeout` for the reply of the Z21 station.
     ///
-    /// This method awaits the next available packet regardless of its header.
+    /// Same as [`Z21Station::request`], but with an explicit timeout, e.g. for CV
+    /// programming which takes longer than other commands. The timeout applies to every
+    /// attempt.
     ///
     /// # Errors
     ///
-    /// Returns an `io::Error` if the broadcast channel is closed.
-    async fn receive_packet(&self) -> io::Result<Packet> {
-        let mut msg_rcv = self.message_receiver.resubscribe();
-        match timeout(self.timeout, async {
-            match msg_rcv.recv().await {
-                Ok(packet) => Ok(packet),
-                Err(_) => Err(io::Error::new(io::ErrorKind::Other, "Channel closed")),
-            }
-        })
-        .await
-        {
-            Ok(result) => result,
-            Err(_) => Err(io::Error::new(
-                io::ErrorKind::TimedOut,
-                "Timeout waiting for packet",
-            )),
-        }
+    /// See [`Z21Station::request`].
+    pub async fn request_with_timeout(
+        &self,
+        request: &Request,
+        timeout: Duration,
+    ) -> Result<Reply> {
+        self.inner.request_with_timeout(request, timeout).await
     }
 
     /// Turns off the track voltage.
@@ -381,20 +451,20 @@ impl Z21Station {
     ///
     /// # Errors
     ///
-    /// Returns an `io::Error` if the command fails to send or no acknowledgment is received.
+    /// Returns an [`Error`] if the command fails to send or no acknowledgment is received.
     ///
     /// # Example
     ///
-    /// ```rust
+    /// ```rust,no_run
+    /// # use roco_z21_driver::Z21Station;
+    /// # async fn example(station: &Z21Station) -> roco_z21_driver::Result<()> {
     /// // Emergency stop all locomotives by cutting track power
     /// station.voltage_off().await?;
+    /// # Ok(())
+    /// # }
     /// ```
-    pub async fn voltage_off(&self) -> io::Result<()> {
-        self.send_xbus_command(
-            XBusMessage::new_single(X_SET_TRACK_POWER_OFF.0, X_SET_TRACK_POWER_OFF.1),
-            Some(X_BC_TRACK_POWER),
-        )
-        .await?;
+    pub async fn voltage_off(&self) -> Result<()> {
+        self.request(&Request::SetTrackPowerOff).await?;
         Ok(())
     }
 
@@ -409,20 +479,20 @@ impl Z21Station {
     ///
     /// # Errors
     ///
-    /// Returns an `io::Error` if the command fails to send or no acknowledgment is received.
+    /// Returns an [`Error`] if the command fails to send or no acknowledgment is received.
     ///
     /// # Example
     ///
-    /// ```rust
+    /// ```rust,no_run
+    /// # use roco_z21_driver::Z21Station;
+    /// # async fn example(station: &Z21Station) -> roco_z21_driver::Result<()> {
     /// // Restore power to the tracks
     /// station.voltage_on().await?;
+    /// # Ok(())
+    /// # }
     /// ```
-    pub async fn voltage_on(&self) -> io::Result<()> {
-        self.send_xbus_command(
-            XBusMessage::new_single(X_SET_TRACK_POWER_ON.0, X_SET_TRACK_POWER_ON.1),
-            Some(X_BC_TRACK_POWER),
-        )
-        .await?;
+    pub async fn voltage_on(&self) -> Result<()> {
+        self.request(&Request::SetTrackPowerOn).await?;
         Ok(())
     }
 
@@ -434,84 +504,146 @@ impl Z21Station {
     ///
     /// # Errors
     ///
-    /// Returns an `io::Error` if:
+    /// Returns an [`Error`] if:
     /// - Sending the request fails
     /// - The response times out
     /// - The response data is invalid (e.g., too short)
     ///
     /// # Example
     ///
-    /// ```rust
+    /// ```rust,no_run
+    /// # use roco_z21_driver::Z21Station;
+    /// # async fn example(station: &Z21Station) -> roco_z21_driver::Result<()> {
     /// let serial = station.get_serial_number().await?;
     /// println!("Z21 station serial number: {}", serial);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn get_serial_number(&self) -> Result<u32> {
+        match self.request(&Request::GetSerialNumber).await? {
+            Reply::SerialNumber(serial) => Ok(serial),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Retrieves the hardware type and firmware version of the Z21 station.
+    ///
+    /// # Returns
+    ///
+    /// The [`HardwareInfo`] reported by the station.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`Error`] if:
+    /// - Sending the request fails
+    /// - The response times out
+    /// - The response data is invalid (e.g., too short)
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use roco_z21_driver::Z21Station;
+    /// # async fn example(station: &Z21Station) -> roco_z21_driver::Result<()> {
+    /// let info = station.get_hardware_info().await?;
+    /// println!("Firmware: {}.{}", info.firmware_major, info.firmware_minor);
+    /// # Ok(())
+    /// # }
     /// ```
-    pub async fn get_serial_number(&self) -> io::Result<u32> {
-        let packet = Packet::with_header_and_data(0x10, &[]);
-        self.send_packet(packet).await?;
-        let response = self.receive_packet_with_header(0x10).await?;
-        let data = response.get_data();
-        if data.len() < 4 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Response data too short",
-            ));
+    pub async fn get_hardware_info(&self) -> Result<HardwareInfo> {
+        match self.request(&Request::GetHardwareInfo).await? {
+            Reply::HardwareInfo(info) => Ok(info),
+            reply => Err(unexpected_reply(reply)),
         }
-        Ok(u32::from_le_bytes([data[0], data[1], data[2], data[3]]))
+    }
+
+    /// Returns a stream of all events received from the Z21 station.
+    ///
+    /// Every call creates an independent stream which sees all events received after the
+    /// call. Events can be filtered with the combinators of
+    /// [`StreamExt`](tokio_stream::StreamExt) and consumed in `tokio::select!`.
+    /// Dropping the stream stops the subscription.
+    ///
+    /// Which unsolicited events the station sends depends on the broadcast flags.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use roco_z21_driver::{Z21Event, Z21Station};
+    /// # use tokio_stream::StreamExt;
+    /// # async fn example(station: &Z21Station) -> roco_z21_driver::Result<()> {
+    /// let mut events = station.events();
+    /// while let Some(event) = events.next().await {
+    ///     match event {
+    ///         Z21Event::LocoInfo(state) => println!("Loco {}: {:?}%", state.address, state.speed_percentage),
+    ///         Z21Event::TrackPowerOff => println!("Track power off"),
+    ///         _ => {}
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn events(&self) -> EventStream {
+        EventStream::new(
+            self.inner.event_sender.subscribe(),
+            self.inner.cancel.clone(),
+            Arc::clone(&self.inner.metrics),
+        )
     }
 
     /// Subscribes to system state updates from the Z21 station.
     ///
     /// This method sets up a polling mechanism to regularly request system state updates
     /// and calls the provided callback function whenever new state information is received.
+    /// Polling stops when the returned [`Subscription`] is dropped.
     ///
     /// # Arguments
     ///
     /// * `freq_in_sec` - Polling frequency in Hz (updates per second)
     /// * `subscriber` - Callback function that receives `SystemState` updates
     ///
+    /// # Returns
+    ///
+    /// The guard of the subscription.
+    ///
     /// # Example
     ///
-    /// ```rust
-    /// station.subscribe_system_state(1.0, Box::new(|state| {
-    ///     println!("Main track voltage: {:.2}V", state.main_track_voltage);
+    /// ```rust,no_run
+    /// # use roco_z21_driver::Z21Station;
+    /// # fn example(station: &Z21Station) {
+    /// let subscription = station.subscribe_system_state(1.0, Box::new(|state| {
+    ///     println!("Main track voltage: {}mV", state.vcc_voltage);
     ///     println!("Temperature: {}°C", state.temperature);
-    ///     println!("Current: {}mA", state.current);
+    ///     println!("Current: {}mA", state.main_current);
     /// }));
+    /// // ...
+    /// subscription.unsubscribe();
+    /// # }
     /// ```
-
     pub fn subscribe_system_state(
         &self,
         freq_in_sec: f64,
         subscriber: Box<dyn Fn(SystemState) + Send + Sync>,
-    ) {
-        let mut receiver = self.message_receiver.resubscribe();
-        let socket = Arc::clone(&self.socket);
-        let keep_alive = Arc::clone(&self.keep_alive);
-        let packet = Packet::with_header_and_data(LAN_SYSTEMSTATE_GETDATA, &[]);
-        tokio::spawn(async move {
+    ) -> Subscription {
+        let token = self.inner.cancel.child_token();
+        let mut events = self.events();
+        let inner = Arc::clone(&self.inner);
+        self.inner.spawn(token.clone(), async move {
             loop {
-                let result = Self::send_packet_external(&socket, packet.clone()).await;
-                if result.is_err() {
+                if inner.send_request(&Request::SystemStateGetData).is_err() {
                     break;
                 }
 
                 time::sleep(Duration::from_millis((1000. / freq_in_sec) as u64)).await;
-
-                if !keep_alive.load(Ordering::Relaxed) {
-                    break;
-                }
             }
         });
-        tokio::spawn(async move {
-            while let Ok(packet) = receiver.recv().await {
-                if packet.get_header() == LAN_SYSTEMSTATE_DATACHANGED {
-                    let state = SystemState::try_from(&packet.get_data()[..]);
-                    if let Ok(state) = state {
-                        subscriber(state);
-                    }
+        self.inner.spawn(token.clone(), async move {
+            while let Some(event) = events.next().await {
+                if let Z21Event::SystemState(state) = event {
+                    subscriber(state);
                 }
             }
         });
+        Subscription::new(token)
     }
 
     /// Logs out from the Z21 station.
@@ -525,22 +657,267 @@ impl Z21Station {
     ///
     /// # Errors
     ///
-    /// Returns an `io::Error` if the logout command fails to send.
+    /// Returns an [`Error`] if the logout command fails to send.
     ///
     /// # Example
     ///
-    /// ```rust
+    /// ```rust,no_run
+    /// # use roco_z21_driver::Z21Station;
+    /// # async fn example(station: &Z21Station) -> roco_z21_driver::Result<()> {
     /// // Clean up and disconnect from the Z21 station
     /// station.logout().await?;
+    /// # Ok(())
+    /// # }
     /// ```
-    pub async fn logout(&self) -> io::Result<()> {
-        let packet = Packet::with_header_and_data(0x30, &[]);
-        self.send_packet(packet).await
+    pub async fn logout(&self) -> Result<()> {
+        self.send_request(&Request::Logoff).await
     }
+
+    /// Logs out from the Z21 station and stops all background tasks.
+    ///
+    /// Every subscription ends and every event stream is closed. The returned future
+    /// completes once all tasks have finished. Afterwards, requests fail with
+    /// [`Error::NotConnected`].
+    ///
+    /// Dropping the station stops the tasks as well, but neither logs out nor waits for
+    /// the tasks.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`Error`] if the logout command fails to send. The tasks are stopped
+    /// regardless.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use roco_z21_driver::Z21Station;
+    /// # async fn example(station: Z21Station) -> roco_z21_driver::Result<()> {
+    /// station.shutdown().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn shutdown(&self) -> Result<()> {
+        // Sent directly, the sender task of the queue is stopped right away.
+        let result = match Request::Logoff.encode() {
+            Ok(packet) => Self::send_packet_external(self.inner.transport.as_ref(), packet).await,
+            Err(e) => Err(e.into()),
+        };
+        self.inner.cancel.cancel();
+        let tasks = std::mem::take(&mut *self.inner.tasks.lock().unwrap());
+        for task in tasks {
+            // A task which panicked has stopped as well.
+            let _ = task.await;
+        }
+        result
+    }
+
+    /// Returns a snapshot of the [`Metrics`] of the connection, e.g. to judge the health
+    /// of a Wi-Fi link: commands sent, replies received, timeouts, retries, checksum
+    /// failures, events skipped by lagging event streams and round-trip times per
+    /// [`CommandClass`].
+    pub fn metrics(&self) -> Metrics {
+        self.inner.metrics.snapshot()
+    }
+
+    /// Returns the current state of the connection to the Z21 station.
+    ///
+    /// Changes of the state are also reported as [`Z21Event::ConnectionState`].
+    pub fn connection_state(&self) -> ConnectionState {
+        *self.inner.connection_state.lock().unwrap()
+    }
+
+    /// Returns the broadcast flags the station is subscribed to.
+    pub fn broadcast_flags(&self) -> BroadcastFlags {
+        *self.inner.broadcast_flags.lock().unwrap()
+    }
+
+    /// Changes the broadcast flags, i.e. which broadcasts the Z21 station sends.
+    ///
+    /// The flags are kept across reconnections, like the ones set with
+    /// [`Z21StationBuilder::broadcast_flags`].
+    ///
+    /// # Arguments
+    ///
+    /// * `flags` - The broadcasts to receive
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`Error`] if the request fails to send.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use roco_z21_driver::{messages::BroadcastFlags, Z21Station};
+    /// # async fn example(station: &Z21Station) -> roco_z21_driver::Result<()> {
+    /// station
+    ///     .set_broadcast_flags(BroadcastFlags::DRIVING_SWITCHING | BroadcastFlags::RBUS)
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn set_broadcast_flags(&self, flags: BroadcastFlags) -> Result<()> {
+        *self.inner.broadcast_flags.lock().unwrap() = flags;
+        self.inner.send_request(&Request::SetBroadcastFlags(flags))
+    }
+
+    /// Registers a loco controlled through a [`Loco`] handle, so its state is polled
+    /// again after a reconnection.
+    pub(crate) fn control_loco(&self, address: u16) {
+        let mut locos = self.inner.controlled_locos.lock().unwrap();
+        *locos.entry(address).or_insert(0) += 1;
+    }
+
+    /// Returns a token which is cancelled when the station shuts down.
+    pub(crate) fn child_token(&self) -> CancellationToken {
+        self.inner.cancel.child_token()
+    }
+
+    /// Runs `task` in the background until it completes or the returned
+    /// [`Subscription`] ends. The subscription also ends when `parent` is cancelled.
+    pub(crate) fn subscribe<F>(&self, parent: &CancellationToken, task: F) -> Subscription
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let token = parent.child_token();
+        self.inner.spawn(token.clone(), task);
+        Subscription::new(token)
+    }
+
+    /// Unregisters a loco when its [`Loco`] handle is dropped.
+    pub(crate) fn release_loco(&self, address: u16) {
+        let mut locos = self.inner.controlled_locos.lock().unwrap();
+        if let Some(count) = locos.get_mut(&address) {
+            *count -= 1;
+            if *count == 0 {
+                locos.remove(&address);
+            }
+        }
+    }
+}
+
+impl Inner {
+    /// Publishes `event` to all event streams.
+    fn emit(&self, event: Z21Event) {
+        // Sending only fails if nobody listens for events.
+        let _ = self.event_sender.send(event);
+    }
+
+    /// Spawns `task` as a background task which stops when `token` is cancelled.
+    ///
+    /// The task is awaited by [`Z21Station::shutdown`].
+    fn spawn<F>(&self, token: CancellationToken, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let handle = tokio::spawn(async move {
+            tokio::select! {
+                _ = token.cancelled() => {}
+                _ = task => {}
+            }
+        });
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(handle);
+    }
+
+    /// Queues `request` without waiting for a reply.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "command",
+            level = "debug",
+            skip(self),
+            fields(header = tracing::field::Empty, x_header = tracing::field::Empty)
+        )
+    )]
+    fn send_request(&self, request: &Request) -> Result<()> {
+        if self.cancel.is_cancelled() {
+            return Err(Error::NotConnected);
+        }
+        if let Ok(packet) = request.encode() {
+            log::record_packet(&packet);
+        }
+        self.queue.push(request, None, &self.dispatcher)
+    }
+
+    /// Sends `request` and waits for its reply with the timeout of its [`CommandClass`].
+    async fn request(&self, request: &Request) -> Result<Reply> {
+        let timeout = self.timeouts.get(CommandClass::of(request));
+        self.request_with_timeout(request, timeout).await
+    }
+
+    /// See [`Z21Station::request_with_timeout`].
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "command",
+            level = "debug",
+            skip(self),
+            fields(header = tracing::field::Empty, x_header = tracing::field::Empty)
+        )
+    )]
+    async fn request_with_timeout(&self, request: &Request, timeout: Duration) -> Result<Reply> {
+        if self.cancel.is_cancelled() {
+            return Err(Error::NotConnected);
+        }
+        let packet = request.encode()?;
+        log::record_packet(&packet);
+        let timeout_error = Error::Timeout {
+            header: packet.get_header(),
+            x_header: if packet.get_header() == LAN_X {
+                packet.get_data().first().copied()
+            } else {
+                None
+            },
+        };
+        let retries = if request.is_idempotent() {
+            self.queue.config().retries
+        } else {
+            0
+        };
+
+        // Cancels the registration however this future ends, including being dropped.
+        let (registration, mut reply) = self.dispatcher.register(request.clone());
+        for attempt in 0..=retries {
+            if attempt > 0 {
+                MetricsRecorder::add(&self.metrics.retries, 1);
+            }
+            let sent = Instant::now();
+            self.queue
+                .push(request, Some(registration.id()), &self.dispatcher)?;
+            match time::timeout(timeout, &mut reply).await {
+                Ok(Ok(result)) => {
+                    // The reply of a resent request may answer any of the attempts, so
+                    // its round trip is unknown and it only counts as a retry.
+                    if attempt == 0 {
+                        self.metrics
+                            .record_rtt(CommandClass::of(request), sent.elapsed());
+                    }
+                    return result;
+                }
+                Ok(Err(_)) => return Err(Error::NotConnected),
+                // Lost request or reply, resend if allowed.
+                Err(_) => continue,
+            }
+        }
+        MetricsRecorder::add(&self.metrics.timeouts, 1);
+        Err(timeout_error)
+    }
+
+    /// Requests the system state, which the station only answers once it is reachable.
+    async fn handshake(&self) -> Result<()> {
+        self.request(&Request::SystemStateGetData).await?;
+        Ok(())
+    }
+}
+
+/// Error for a reply which matched a request but has an unexpected type.
+pub(crate) fn unexpected_reply(reply: Reply) -> Error {
+    Error::malformed(format!("Unexpected reply {:?}", reply))
 }
 
 impl Drop for Z21Station {
     fn drop(&mut self) {
-        self.keep_alive.store(false, Ordering::Relaxed);
+        self.inner.cancel.cancel();
     }
 }
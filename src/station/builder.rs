@@ -0,0 +1,328 @@
+//! Configuration of a [`Z21Station`] connection.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! # use roco_z21_driver::{messages::BroadcastFlags, CommandClass, Z21Station};
+//! # use std::time::Duration;
+//! # async fn example() -> roco_z21_driver::Result<()> {
+//! // Tuned for a lossy Wi-Fi bridge
+//! let station = Z21Station::builder("192.168.0.111:21105")
+//!     .bind_addr("192.168.0.10:0".parse().unwrap())
+//!     .timeout(Duration::from_secs(4))
+//!     .command_timeout(CommandClass::Programming, Duration::from_secs(20))
+//!     .keep_alive_interval(Duration::from_secs(5))
+//!     .broadcast_flags(BroadcastFlags::DRIVING_SWITCHING | BroadcastFlags::RBUS)
+//!     .connect()
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::inspect::PacketHook;
+use super::{QueueConfig, Z21Station};
+use crate::capture::{CaptureWriter, Direction};
+use crate::error::Result;
+use crate::messages::{BroadcastFlags, Request};
+use crate::packet::Packet;
+use crate::transport::Transport;
+
+/// Default timeout for awaiting responses.
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(2000);
+
+/// Default timeout for CV programming, which takes considerably longer than other commands.
+const DEFAULT_PROGRAMMING_TIMEOUT: Duration = Duration::from_millis(10000);
+
+/// Default interval of the keep-alive messages.
+const DEFAULT_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Number of events buffered for each [`EventStream`](crate::EventStream) before the
+/// oldest are dropped.
+const DEFAULT_CHANNEL_CAPACITY: usize = 256;
+
+/// Broadcast flags sent to the station unless configured otherwise.
+const DEFAULT_BROADCAST_FLAGS: BroadcastFlags = BroadcastFlags::DRIVING_SWITCHING;
+
+/// Class of a command, used to configure separate reply timeouts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CommandClass {
+    /// Queries of information, e.g. serial number, loco info or system state.
+    Query,
+    /// Loco commands: speed, functions, emergency stop.
+    Drive,
+    /// Turnout and accessory commands.
+    Accessory,
+    /// CV reading and writing on the programming track and on the main track.
+    Programming,
+    /// Everything else, e.g. track power and broadcast flags.
+    System,
+}
+
+impl CommandClass {
+    /// Returns the class of `request`.
+    pub fn of(request: &Request) -> CommandClass {
+        match request {
+            Request::SetLocoDrive { .. }
+            | Request::SetLocoFunction { .. }
+            | Request::SetLocoFunctionGroup { .. }
+            | Request::SetLocoBinaryState { .. }
+            | Request::SetLocoEmergencyStop { .. }
+            | Request::PurgeLoco { .. } => CommandClass::Drive,
+            Request::SetTurnout { .. } | Request::SetExtAccessory { .. } => CommandClass::Accessory,
+            Request::CvRead { .. }
+            | Request::CvWrite { .. }
+            | Request::CvPomWriteByte { .. }
+            | Request::CvPomWriteBit { .. }
+            | Request::CvPomReadByte { .. } => CommandClass::Programming,
+            Request::GetSerialNumber
+            | Request::GetCode
+            | Request::GetHardwareInfo
+            | Request::GetXBusVersion
+            | Request::GetStatus
+            | Request::GetFirmwareVersion
+            | Request::GetBroadcastFlags
+            | Request::GetLocoMode { .. }
+            | Request::GetTurnoutMode { .. }
+            | Request::GetLocoInfo { .. }
+            | Request::GetTurnoutInfo { .. }
+            | Request::GetExtAccessoryInfo { .. }
+            | Request::RBusGetData { .. }
+            | Request::SystemStateGetData
+            | Request::RailComGetData { .. }
+            | Request::CanDeviceGetDescription { .. } => CommandClass::Query,
+            _ => CommandClass::System,
+        }
+    }
+}
+
+/// Reply timeouts of every [`CommandClass`].
+#[derive(Debug, Clone)]
+pub(crate) struct CommandTimeouts {
+    query: Duration,
+    drive: Duration,
+    accessory: Duration,
+    programming: Duration,
+    system: Duration,
+}
+
+impl CommandTimeouts {
+    /// Returns the timeout of `class`.
+    pub(crate) fn get(&self, class: CommandClass) -> Duration {
+        match class {
+            CommandClass::Query => self.query,
+            CommandClass::Drive => self.drive,
+            CommandClass::Accessory => self.accessory,
+            CommandClass::Programming => self.programming,
+            CommandClass::System => self.system,
+        }
+    }
+
+    fn set(&mut self, class: CommandClass, timeout: Duration) {
+        let slot = match class {
+            CommandClass::Query => &mut self.query,
+            CommandClass::Drive => &mut self.drive,
+            CommandClass::Accessory => &mut self.accessory,
+            CommandClass::Programming => &mut self.programming,
+            CommandClass::System => &mut self.system,
+        };
+        *slot = timeout;
+    }
+}
+
+impl Default for CommandTimeouts {
+    fn default() -> Self {
+        CommandTimeouts {
+            query: DEFAULT_TIMEOUT,
+            drive: DEFAULT_TIMEOUT,
+            accessory: DEFAULT_TIMEOUT,
+            programming: DEFAULT_PROGRAMMING_TIMEOUT,
+            system: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+/// Builder for a [`Z21Station`] connection with custom settings.
+///
+/// Created with [`Z21Station::builder`]. Every setting has the default used by
+/// [`Z21Station::new`].
+#[derive(Debug, Clone)]
+pub struct Z21StationBuilder {
+    pub(crate) station_addr: String,
+    pub(crate) bind_addr: SocketAddr,
+    pub(crate) timeouts: CommandTimeouts,
+    pub(crate) keep_alive_interval: Duration,
+    pub(crate) channel_capacity: usize,
+    pub(crate) broadcast_flags: BroadcastFlags,
+    pub(crate) handshake: bool,
+    pub(crate) queue_config: QueueConfig,
+    pub(crate) capture: Option<CaptureWriter>,
+    pub(crate) packet_hook: Option<PacketHook>,
+}
+
+impl Z21StationBuilder {
+    /// Creates a builder for a connection to the Z21 station at `station_addr`
+    /// (typically "192.168.0.111:21105").
+    pub fn new(station_addr: &str) -> Self {
+        Z21StationBuilder {
+            station_addr: station_addr.to_string(),
+            bind_addr: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            timeouts: CommandTimeouts::default(),
+            keep_alive_interval: DEFAULT_KEEP_ALIVE_INTERVAL,
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            broadcast_flags: DEFAULT_BROADCAST_FLAGS,
+            handshake: true,
+            queue_config: QueueConfig::default(),
+            capture: None,
+            packet_hook: None,
+        }
+    }
+
+    /// Sets the local address the UDP socket is bound to.
+    ///
+    /// Use the address of a network interface to talk to the station over that
+    /// interface only. Defaults to `0.0.0.0:0`, i.e. any interface and a free port.
+    pub fn bind_addr(mut self, bind_addr: SocketAddr) -> Self {
+        self.bind_addr = bind_addr;
+        self
+    }
+
+    /// Sets the reply timeout of all command classes.
+    ///
+    /// Defaults to 2 s, and to 10 s for [`CommandClass::Programming`].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        for class in [
+            CommandClass::Query,
+            CommandClass::Drive,
+            CommandClass::Accessory,
+            CommandClass::Programming,
+            CommandClass::System,
+        ] {
+            self.timeouts.set(class, timeout);
+        }
+        self
+    }
+
+    /// Sets the reply timeout of a single command class.
+    pub fn command_timeout(mut self, class: CommandClass, timeout: Duration) -> Self {
+        self.timeouts.set(class, timeout);
+        self
+    }
+
+    /// Sets the interval of the keep-alive messages. Defaults to 10 s.
+    ///
+    /// The Z21 drops clients it has not heard from for about a minute.
+    pub fn keep_alive_interval(mut self, interval: Duration) -> Self {
+        self.keep_alive_interval = interval;
+        self
+    }
+
+    /// Sets how many events are buffered for each event stream before the oldest are
+    /// dropped. Defaults to 256.
+    ///
+    /// A stream which falls behind reports the dropped events with a
+    /// [`Z21Event::Lagged`](crate::Z21Event::Lagged) and continues; the total is counted in
+    /// [`Metrics::lag_drops`](crate::Metrics::lag_drops).
+    pub fn channel_capacity(mut self, capacity: usize) -> Self {
+        self.channel_capacity = capacity;
+        self
+    }
+
+    /// Sets the broadcast flags sent to the station. Defaults to
+    /// [`BroadcastFlags::DRIVING_SWITCHING`].
+    pub fn broadcast_flags(mut self, flags: BroadcastFlags) -> Self {
+        self.broadcast_flags = flags;
+        self
+    }
+
+    /// Sets whether [`connect`](Z21StationBuilder::connect) waits for the station to
+    /// answer a system state request. Defaults to `true`.
+    ///
+    /// Without the handshake, connecting succeeds even if the station is unreachable.
+    pub fn handshake(mut self, handshake: bool) -> Self {
+        self.handshake = handshake;
+        self
+    }
+
+    /// Sets the configuration of the command queue.
+    pub fn queue_config(mut self, config: QueueConfig) -> Self {
+        self.queue_config = config;
+        self
+    }
+
+    /// Connects to the Z21 station with the configured settings.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`Error`](crate::Error) if:
+    /// - The UDP socket cannot be bound or connected
+    /// - The initial handshake with the Z21 station fails
+    /// - The station does not respond within the timeout period
+    pub async fn connect(self) -> Result<Z21Station> {
+        Z21Station::connect(self).await
+    }
+
+    /// Records every datagram sent to and received from the station to `writer`.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use roco_z21_driver::capture::{CaptureFormat, CaptureWriter};
+    /// # use roco_z21_driver::Z21Station;
+    /// # async fn example() -> roco_z21_driver::Result<()> {
+    /// let station = Z21Station::builder("192.168.0.111:21105")
+    ///     .capture(CaptureWriter::create("z21.pcap", CaptureFormat::Pcap)?)
+    ///     .connect()
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn capture(mut self, writer: CaptureWriter) -> Self {
+        self.capture = Some(writer);
+        self
+    }
+
+    /// Calls `hook` with every packet sent to and received from the station, e.g. to log
+    /// the traffic with [`dissect_packet`](crate::dissect::dissect_packet).
+    ///
+    /// The hook runs on the send and receive paths of the station and should return
+    /// quickly.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use roco_z21_driver::dissect::dissect_packet;
+    /// # use roco_z21_driver::Z21Station;
+    /// # async fn example() -> roco_z21_driver::Result<()> {
+    /// let station = Z21Station::builder("192.168.0.111:21105")
+    ///     .on_packet(|direction, packet| {
+    ///         println!("{} {}", direction, dissect_packet(packet, direction))
+    ///     })
+    ///     .connect()
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn on_packet(mut self, hook: impl Fn(Direction, &Packet) + Send + Sync + 'static) -> Self {
+        self.packet_hook = Some(PacketHook::new(hook));
+        self
+    }
+
+    /// Connects to the Z21 station over `transport` instead of UDP, e.g. a
+    /// [`TcpTransport`](crate::transport::TcpTransport) tunnel or one end of a
+    /// [`ChannelTransport`](crate::transport::ChannelTransport) pair.
+    ///
+    /// The station address and the bind address of the builder are not used to connect.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`Error`](crate::Error) if the initial handshake with the Z21 station
+    /// fails.
+    pub async fn connect_with_transport(self, transport: impl Transport) -> Result<Z21Station> {
+        Z21Station::connect_with_transport(self, Arc::new(transport)).await
+    }
+}
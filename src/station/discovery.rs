@@ -0,0 +1,159 @@
+//! Discovery of Z21 stations on the local network.
+//!
+//! The Z21 answers LAN_GET_SERIAL_NUMBER and LAN_GET_HWINFO requests sent to the
+//! broadcast address, which makes it possible to find every station on a network
+//! segment without knowing its IP address in advance.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! # use roco_z21_driver::Z21Station;
+//! # use std::time::Duration;
+//! # async fn example() -> roco_z21_driver::Result<()> {
+//! let stations = Z21Station::discover(Duration::from_secs(1)).await?;
+//! for found in &stations {
+//!     println!("Z21 #{} at {}", found.serial_number, found.address);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::time::{self, timeout_at, Instant};
+
+use super::{
+    LAN_GET_HWINFO, LAN_GET_SERIAL_NUMBER, RECEIVE_ERROR_BACKOFF, RECEIVE_ERROR_WARNING_INTERVAL,
+    Z21_DEFAULT_PORT,
+};
+use crate::error::Result;
+use crate::log;
+use crate::messages::{HardwareInfo, Reply};
+use crate::packet::{DatagramIter, Packet, MAX_DATAGRAM_LEN};
+use crate::Z21Station;
+
+/// A Z21 station that answered a discovery request.
+#[derive(Debug, Clone)]
+pub struct DiscoveredStation {
+    /// Address the station answered from, usable with [`Z21Station::new`].
+    pub address: SocketAddr,
+    /// Serial number of the station.
+    pub serial_number: u32,
+    /// Hardware type and firmware version, if the station answered LAN_GET_HWINFO in time.
+    pub hardware_info: Option<HardwareInfo>,
+}
+
+impl Z21Station {
+    /// Discovers Z21 stations on the local network.
+    ///
+    /// Broadcasts LAN_GET_SERIAL_NUMBER and LAN_GET_HWINFO to `255.255.255.255:21105`
+    /// and collects all replies that arrive within `wait`.
+    ///
+    /// # Arguments
+    ///
+    /// * `wait` - How long to collect replies for
+    ///
+    /// # Returns
+    ///
+    /// Every station which reported its serial number, ordered by address.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`Error`] if the UDP socket cannot be bound or the request fails to send.
+    pub async fn discover(wait: Duration) -> Result<Vec<DiscoveredStation>> {
+        let target = SocketAddr::from((Ipv4Addr::BROADCAST, Z21_DEFAULT_PORT));
+        Self::discover_on(target, wait).await
+    }
+
+    /// Discovers Z21 stations reachable through the given address.
+    ///
+    /// Same as [`Z21Station::discover`], but sends the requests to `target`, which may be
+    /// the directed broadcast address of a specific interface (e.g. `192.168.0.255:21105`)
+    /// or the unicast address of a single station.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - Address the discovery requests are sent to
+    /// * `wait` - How long to collect replies for
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`Error`] if the UDP socket cannot be bound or the request fails to send.
+    pub async fn discover_on(target: SocketAddr, wait: Duration) -> Result<Vec<DiscoveredStation>> {
+        let bind = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        Self::discover_from(bind, target, wait).await
+    }
+
+    /// Discovers Z21 stations from a given local address.
+    ///
+    /// Same as [`Z21Station::discover_on`], but binds the socket to `bind`, e.g. the
+    /// address of the interface facing the layout on a host with several networks.
+    ///
+    /// # Arguments
+    ///
+    /// * `bind` - Local address of the socket, port 0 picks a free port
+    /// * `target` - Address the discovery requests are sent to
+    /// * `wait` - How long to collect replies for
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`Error`] if the UDP socket cannot be bound or the request fails to send.
+    pub async fn discover_from(
+        bind: SocketAddr,
+        target: SocketAddr,
+        wait: Duration,
+    ) -> Result<Vec<DiscoveredStation>> {
+        let socket = UdpSocket::bind(bind).await?;
+        socket.set_broadcast(true)?;
+
+        for header in [LAN_GET_SERIAL_NUMBER, LAN_GET_HWINFO] {
+            let data: Vec<u8> = Packet::with_header(header).into();
+            socket.send_to(&data, target).await?;
+        }
+
+        let mut serials = BTreeMap::new();
+        let mut hardware = BTreeMap::new();
+        let deadline = Instant::now() + wait;
+        let mut buf = [0u8; MAX_DATAGRAM_LEN];
+        let mut last_warning: Option<Instant> = None;
+        while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            let (size, peer) = match received {
+                Ok(received) => received,
+                Err(e) => {
+                    // E.g. ICMP port unreachable from a host without a Z21, the other
+                    // stations may still answer.
+                    if last_warning.is_none_or(|at| at.elapsed() >= RECEIVE_ERROR_WARNING_INTERVAL)
+                    {
+                        log::warning!("Error receiving discovery reply: {}", e);
+                        last_warning = Some(Instant::now());
+                    }
+                    time::sleep_until((Instant::now() + RECEIVE_ERROR_BACKOFF).min(deadline)).await;
+                    continue;
+                }
+            };
+            for packet in DatagramIter::new(&buf[..size]).map_while(|packet| packet.ok()) {
+                match Reply::decode(&packet) {
+                    Ok(Reply::SerialNumber(serial)) => {
+                        serials.insert(peer, serial);
+                    }
+                    Ok(Reply::HardwareInfo(info)) => {
+                        hardware.insert(peer, info);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(serials
+            .into_iter()
+            .map(|(address, serial_number)| DiscoveredStation {
+                address,
+                serial_number,
+                hardware_info: hardware.remove(&address),
+            })
+            .collect())
+    }
+}
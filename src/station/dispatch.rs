@@ -0,0 +1,221 @@
+//! Correlation of requests with the replies of the Z21 station.
+//!
+//! A request is registered with the [`Dispatcher`] *before* it is sent, so a reply can
+//! never arrive before somebody is listening for it. The receiver task offers every
+//! decoded [`Reply`] to the dispatcher, which hands it to the oldest pending request
+//! it answers (see [`Request::matches_reply`]).
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use tokio::sync::oneshot;
+
+use crate::error::{Error, Result};
+use crate::messages::{Reply, Request};
+
+/// A request waiting for its reply.
+///
+/// Several callers can wait for the same reply when their requests were coalesced into
+/// one by the command queue.
+struct Pending {
+    request: Request,
+    waiters: Vec<(u64, oneshot::Sender<Result<Reply>>)>,
+}
+
+/// Registry of pending requests, shared between the station and its receiver task.
+#[derive(Default)]
+pub(crate) struct Dispatcher {
+    pending: Mutex<Vec<Pending>>,
+    next_id: AtomicU64,
+}
+
+impl Dispatcher {
+    /// Registers `request` as waiting for a reply.
+    ///
+    /// Returns the registration, which cancels itself when dropped, and the receiver the
+    /// reply is delivered to.
+    pub(crate) fn register(
+        &self,
+        request: Request,
+    ) -> (Registration<'_>, oneshot::Receiver<Result<Reply>>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (reply, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().push(Pending {
+            request,
+            waiters: vec![(id, reply)],
+        });
+        (
+            Registration {
+                dispatcher: self,
+                id,
+            },
+            receiver,
+        )
+    }
+
+    /// Removes a waiter, e.g. after its timeout elapsed.
+    fn cancel(&self, id: u64) {
+        let mut pending = self.pending.lock().unwrap();
+        for entry in pending.iter_mut() {
+            entry.waiters.retain(|(waiter, _)| *waiter != id);
+        }
+        pending.retain(|entry| !entry.waiters.is_empty());
+    }
+
+    /// Lets the waiter `from` wait for the same reply as the waiter `into`.
+    ///
+    /// Used when the command queue replaces the request of `into` by the request of
+    /// `from`. Returns `false` if `into` is no longer waiting, in which case `from` keeps
+    /// its own registration.
+    pub(crate) fn merge(&self, into: u64, from: u64) -> bool {
+        if into == from {
+            return true;
+        }
+        let mut pending = self.pending.lock().unwrap();
+        let Some(into_index) = pending.iter().position(|entry| entry.has_waiter(into)) else {
+            return false;
+        };
+        let Some(from_index) = pending.iter().position(|entry| entry.has_waiter(from)) else {
+            return true;
+        };
+        if into_index != from_index {
+            let from_entry = pending.remove(from_index);
+            let into_index = if from_index < into_index {
+                into_index - 1
+            } else {
+                into_index
+            };
+            let into_entry = &mut pending[into_index];
+            // The request sent last is the one the reply answers.
+            into_entry.request = from_entry.request;
+            into_entry.waiters.extend(from_entry.waiters);
+        }
+        true
+    }
+
+    /// Delivers `reply` to the oldest pending request it answers.
+    ///
+    /// Negative answers of the station are delivered as the corresponding [`Error`].
+    pub(crate) fn dispatch(&self, reply: &Reply) {
+        let mut pending = self.pending.lock().unwrap();
+        // Requests whose callers all gave up must not swallow the reply.
+        pending.retain(|entry| entry.waiters.iter().any(|(_, waiter)| !waiter.is_closed()));
+        let Some(index) = pending
+            .iter()
+            .position(|pending| pending.request.matches_reply(reply))
+        else {
+            return;
+        };
+        for (_, waiter) in pending.remove(index).waiters {
+            let result = match reply {
+                Reply::CvNack => Err(Error::CvNack),
+                Reply::CvNackShortCircuit => Err(Error::ShortCircuit),
+                Reply::UnknownCommand => Err(Error::UnknownCommand),
+                reply => Ok(reply.clone()),
+            };
+            // The requester may have given up in the meantime.
+            let _ = waiter.send(result);
+        }
+    }
+}
+
+/// Registration of a request with the [`Dispatcher`], cancelled when dropped.
+///
+/// Dropping the future of a request (e.g. in `select!` or an outer timeout) thereby
+/// removes its registration, so it cannot take the reply of a later request.
+pub(crate) struct Registration<'a> {
+    dispatcher: &'a Dispatcher,
+    id: u64,
+}
+
+impl Registration<'_> {
+    /// Id of the waiter, used by the command queue to merge registrations.
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.dispatcher.cancel(self.id);
+    }
+}
+
+impl Pending {
+    fn has_waiter(&self, id: u64) -> bool {
+        self.waiters.iter().any(|(waiter, _)| *waiter == id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::LocoState;
+
+    fn loco_info(address: u16) -> Reply {
+        Reply::LocoInfo(LocoState {
+            address,
+            is_busy: None,
+            stepping: None,
+            speed_percentage: None,
+            double_traction: None,
+            smart_search: None,
+            functions: None,
+        })
+    }
+
+    #[test]
+    fn test_dispatch_matches_address() {
+        let dispatcher = Dispatcher::default();
+        let (_registration, mut reply) = dispatcher.register(Request::GetLocoInfo { address: 3 });
+
+        dispatcher.dispatch(&loco_info(4));
+        assert!(reply.try_recv().is_err());
+
+        dispatcher.dispatch(&loco_info(3));
+        match reply.try_recv() {
+            Ok(Ok(Reply::LocoInfo(state))) => assert_eq!(state.address, 3),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_dispatch_oldest_first_and_cancel() {
+        let dispatcher = Dispatcher::default();
+        let (first_registration, mut first) = dispatcher.register(Request::CvRead { cv: 1 });
+        let (_second_registration, mut second) = dispatcher.register(Request::CvRead { cv: 1 });
+
+        drop(first_registration);
+        dispatcher.dispatch(&Reply::CvNack);
+        assert!(first.try_recv().is_err());
+        assert!(matches!(second.try_recv(), Ok(Err(Error::CvNack))));
+    }
+
+    #[test]
+    fn test_dispatch_skips_abandoned_requests() {
+        let dispatcher = Dispatcher::default();
+        let (_first_registration, first) = dispatcher.register(Request::CvRead { cv: 1 });
+        let (_second_registration, mut second) = dispatcher.register(Request::CvRead { cv: 1 });
+
+        drop(first);
+        dispatcher.dispatch(&Reply::CvNack);
+        assert!(matches!(second.try_recv(), Ok(Err(Error::CvNack))));
+    }
+
+    #[test]
+    fn test_merge_delivers_to_all_waiters() {
+        let dispatcher = Dispatcher::default();
+        let drive = |speed| Request::SetLocoDrive {
+            address: 3,
+            steps: Default::default(),
+            speed,
+        };
+        let (old_registration, mut old) = dispatcher.register(drive(10));
+        let (new_registration, mut new) = dispatcher.register(drive(20));
+
+        assert!(dispatcher.merge(old_registration.id(), new_registration.id()));
+        dispatcher.dispatch(&loco_info(3));
+        assert!(matches!(old.try_recv(), Ok(Ok(Reply::LocoInfo(_)))));
+        assert!(matches!(new.try_recv(), Ok(Ok(Reply::LocoInfo(_)))));
+    }
+}
@@ -0,0 +1,152 @@
+//! Typed events emitted by a [`Z21Station`](crate::Z21Station).
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::Stream;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
+
+use super::metrics::MetricsRecorder;
+use super::ConnectionState;
+use crate::log;
+use crate::messages::{
+    AccessoryInfo, CanBoosterState, CanDetector, FastClockTime, LocoNetDetector, LocoState,
+    RBusFeedback, RailComData, Reply, SystemState, TurnoutInfo,
+};
+
+/// An event received from the Z21 station.
+///
+/// Every message the station sends, whether a reply to a request or an unsolicited
+/// broadcast, is turned into one `Z21Event`. Messages without a dedicated variant are
+/// delivered as [`Z21Event::Reply`].
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Z21Event {
+    /// State of a locomotive changed (LAN_X_LOCO_INFO).
+    LocoInfo(LocoState),
+    /// Position of a turnout changed (LAN_X_TURNOUT_INFO).
+    TurnoutInfo(TurnoutInfo),
+    /// State of an extended accessory decoder changed (LAN_X_EXT_ACCESSORY_INFO).
+    AccessoryInfo(AccessoryInfo),
+    /// Track power was switched off.
+    TrackPowerOff,
+    /// Track power was switched on.
+    TrackPowerOn,
+    /// The station entered CV programming mode.
+    ProgrammingMode,
+    /// A short circuit was detected on the track.
+    ShortCircuit,
+    /// All locomotives were stopped (LAN_X_BC_STOPPED).
+    EmergencyStop,
+    /// Central state bitmask reported by LAN_X_STATUS_CHANGED.
+    Status(u8),
+    /// System state changed (LAN_SYSTEMSTATE_DATACHANGED).
+    SystemState(SystemState),
+    /// R-BUS feedback changed (LAN_RMBUS_DATACHANGED).
+    Feedback(RBusFeedback),
+    /// RailCom data of a locomotive changed (LAN_RAILCOM_DATACHANGED).
+    RailCom(RailComData),
+    /// LocoNet message received by the station (LAN_LOCONET_Z21_RX).
+    LocoNetRx(Vec<u8>),
+    /// LocoNet message sent by the station (LAN_LOCONET_Z21_TX).
+    LocoNetTx(Vec<u8>),
+    /// LocoNet message sent by another LAN client (LAN_LOCONET_FROM_LAN).
+    LocoNetFromLan(Vec<u8>),
+    /// LocoNet occupancy detector message (LAN_LOCONET_DETECTOR).
+    LocoNetDetector(LocoNetDetector),
+    /// CAN occupancy detector message (LAN_CAN_DETECTOR).
+    CanDetector(CanDetector),
+    /// State of a CAN booster changed (LAN_CAN_BOOSTER_SYSTEMSTATE_CHGD).
+    CanBooster(CanBoosterState),
+    /// Fast clock time (LAN_FAST_CLOCK_DATA).
+    FastClock(FastClockTime),
+    /// The state of the connection to the station changed.
+    ConnectionState(ConnectionState),
+    /// The [`EventStream`] fell behind and skipped this many events. The stream
+    /// continues with the oldest event still buffered; raise
+    /// [`channel_capacity`](crate::Z21StationBuilder::channel_capacity) if this happens
+    /// regularly.
+    Lagged(u64),
+    /// Any other message from the station.
+    Reply(Reply),
+}
+
+impl From<Reply> for Z21Event {
+    fn from(reply: Reply) -> Self {
+        match reply {
+            Reply::LocoInfo(state) => Z21Event::LocoInfo(state),
+            Reply::TurnoutInfo(info) => Z21Event::TurnoutInfo(info),
+            Reply::ExtAccessoryInfo(info) => Z21Event::AccessoryInfo(info),
+            Reply::TrackPowerOff => Z21Event::TrackPowerOff,
+            Reply::TrackPowerOn => Z21Event::TrackPowerOn,
+            Reply::ProgrammingMode => Z21Event::ProgrammingMode,
+            Reply::TrackShortCircuit => Z21Event::ShortCircuit,
+            Reply::Stopped => Z21Event::EmergencyStop,
+            Reply::Status(status) => Z21Event::Status(status),
+            Reply::SystemStateDataChanged(state) => Z21Event::SystemState(state),
+            Reply::RBusDataChanged(feedback) => Z21Event::Feedback(feedback),
+            Reply::RailComDataChanged(data) => Z21Event::RailCom(data),
+            Reply::LocoNetRx(message) => Z21Event::LocoNetRx(message),
+            Reply::LocoNetTx(message) => Z21Event::LocoNetTx(message),
+            Reply::LocoNetFromLan(message) => Z21Event::LocoNetFromLan(message),
+            Reply::LocoNetDetector(detector) => Z21Event::LocoNetDetector(detector),
+            Reply::CanDetector(detector) => Z21Event::CanDetector(detector),
+            Reply::CanBoosterSystemState(state) => Z21Event::CanBooster(state),
+            Reply::FastClockData(time) => Z21Event::FastClock(time),
+            other => Z21Event::Reply(other),
+        }
+    }
+}
+
+/// A [`Stream`] of [`Z21Event`]s, created by [`Z21Station::events`](crate::Z21Station::events).
+///
+/// The stream ends when the station is shut down or dropped. Dropping the stream
+/// unsubscribes from the station. A consumer which falls more than the
+/// [`channel_capacity`](crate::Z21StationBuilder::channel_capacity) behind skips the
+/// events it missed, which is reported by a [`Z21Event::Lagged`] in their place.
+pub struct EventStream {
+    inner: BroadcastStream<Z21Event>,
+    shutdown: Pin<Box<WaitForCancellationFutureOwned>>,
+    metrics: Arc<MetricsRecorder>,
+}
+
+impl EventStream {
+    pub(crate) fn new(
+        receiver: broadcast::Receiver<Z21Event>,
+        token: CancellationToken,
+        metrics: Arc<MetricsRecorder>,
+    ) -> Self {
+        EventStream {
+            inner: BroadcastStream::new(receiver),
+            shutdown: Box::pin(token.cancelled_owned()),
+            metrics,
+        }
+    }
+}
+
+impl Stream for EventStream {
+    type Item = Z21Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Z21Event>> {
+        if self.shutdown.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(None);
+        }
+        match Pin::new(&mut self.inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(event))) => Poll::Ready(Some(event)),
+            // Missed events are skipped, the stream continues with the oldest event
+            // still buffered.
+            Poll::Ready(Some(Err(BroadcastStreamRecvError::Lagged(skipped)))) => {
+                MetricsRecorder::add(&self.metrics.lag_drops, skipped);
+                log::warning!("Event stream lagging, skipped {} events", skipped);
+                Poll::Ready(Some(Z21Event::Lagged(skipped)))
+            }
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! Handing every packet of a station to a user callback, e.g. for logging.
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::capture::Direction;
+use crate::packet::{DatagramIter, Packet};
+use crate::transport::{BoxFuture, Transport};
+
+type HookFn = dyn Fn(Direction, &Packet) + Send + Sync;
+
+/// Callback installed with [`Z21StationBuilder::on_packet`](super::Z21StationBuilder::on_packet).
+#[derive(Clone)]
+pub(crate) struct PacketHook(Arc<HookFn>);
+
+impl PacketHook {
+    pub(crate) fn new(hook: impl Fn(Direction, &Packet) + Send + Sync + 'static) -> Self {
+        PacketHook(Arc::new(hook))
+    }
+
+    /// Calls the hook with every well-formed packet of `datagram`.
+    fn call(&self, direction: Direction, datagram: &[u8]) {
+        for packet in DatagramIter::new(datagram).map_while(|packet| packet.ok()) {
+            (self.0)(direction, &packet);
+        }
+    }
+}
+
+impl fmt::Debug for PacketHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PacketHook")
+    }
+}
+
+/// A [`Transport`] calling a [`PacketHook`] with everything sent and received over it.
+pub(crate) struct InspectTransport<T> {
+    transport: T,
+    hook: PacketHook,
+}
+
+impl<T: Transport> InspectTransport<T> {
+    pub(crate) fn new(transport: T, hook: PacketHook) -> Self {
+        InspectTransport { transport, hook }
+    }
+}
+
+impl<T: Transport> Transport for InspectTransport<T> {
+    fn send<'a>(&'a self, datagram: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            self.transport.send(datagram).await?;
+            self.hook.call(Direction::Tx, datagram);
+            Ok(())
+        })
+    }
+
+    fn recv<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>> {
+        Box::pin(async move {
+            let len = self.transport.recv(buf).await?;
+            self.hook.call(Direction::Rx, &buf[..len]);
+            Ok(len)
+        })
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.transport.local_addr()
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.transport.peer_addr()
+    }
+}
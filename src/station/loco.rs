@@ -15,10 +15,10 @@
 //!
 //! # Examples
 //!
-//! ```rust
-//! # use tokio;
+//! ```rust,no_run
+//! # use roco_z21_driver::{Loco, Z21Station};
 //! # use std::sync::Arc;
-//! # async fn example() -> std::io::Result<()> {
+//! # async fn example() -> roco_z21_driver::Result<()> {
 //! let station = Arc::new(Z21Station::new("192.168.0.111:21105").await?);
 //!
 //! // Control a locomotive with address 3
@@ -39,27 +39,14 @@
 //! # }
 //! ```
 
-use std::time::Duration;
-use std::{ops::Deref, sync::Arc, vec};
+use std::sync::Arc;
 
-use tokio::{io, time};
-
-use crate::messages::{DccThrottleSteps, LocoState};
-use crate::{messages::XBusMessage, Z21Station};
-
-const XBUS_LOCO_GET_INFO: u8 = 0xE3;
-const XBUS_LOCO_DRIVE: u8 = 0xE4;
-const XBUS_LOCO_INFO: u8 = 0xEF;
-const XBUS_LOCO_FUNCTION: u8 = 0xE4;
-const FUNC_OFF: u8 = 0x00;
-const FUNC_ON: u8 = 0x01;
-const FUNC_TOGGLE: u8 = 0x02;
-
-impl Default for DccThrottleSteps {
-    fn default() -> Self {
-        Self::Steps128
-    }
-}
+use super::unexpected_reply;
+use crate::error::Result;
+use crate::messages::{DccThrottleSteps, FunctionAction, LocoState, Reply, Request};
+use crate::{Subscription, Z21Event, Z21Station};
+use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
 
 /// Represents a DCC Locomotive that can be controlled via a Z21 station.
 ///
@@ -73,6 +60,8 @@ pub struct Loco {
     addr: u16,
     /// DCC throttle steps configuration (14, 28, or 128 steps)
     steps: DccThrottleSteps,
+    /// Cancelled when the locomotive is dropped, ends its subscriptions.
+    cancel: CancellationToken,
 }
 
 impl Loco {
@@ -93,19 +82,21 @@ impl Loco {
     ///
     /// # Errors
     ///
-    /// Returns an `io::Error` if:
+    /// Returns an [`Error`] if:
     /// - Communication with the Z21 station fails
     /// - The locomotive does not respond
     ///
     /// # Example
     ///
-    /// ```rust
-    /// # async fn example(station: Arc<Z21Station>) -> std::io::Result<()> {
+    /// ```rust,no_run
+    /// # use roco_z21_driver::{messages::DccThrottleSteps, Loco, Z21Station};
+    /// # use std::sync::Arc;
+    /// # async fn example(station: Arc<Z21Station>) -> roco_z21_driver::Result<()> {
     /// let loco = Loco::control(station.clone(), 3).await?;
     /// # Ok(())
     /// # }
     /// ```
-    pub async fn control(station: Arc<Z21Station>, address: u16) -> io::Result<Loco> {
+    pub async fn control(station: Arc<Z21Station>, address: u16) -> Result<Loco> {
         Self::control_with_steps(station, address, DccThrottleSteps::default()).await
     }
 
@@ -127,14 +118,16 @@ impl Loco {
     ///
     /// # Errors
     ///
-    /// Returns an `io::Error` if:
+    /// Returns an [`Error`] if:
     /// - Communication with the Z21 station fails
     /// - The locomotive does not respond
     ///
     /// # Example
     ///
-    /// ```rust
-    /// # async fn example(station: Arc<Z21Station>) -> std::io::Result<()> {
+    /// ```rust,no_run
+    /// # use roco_z21_driver::{messages::DccThrottleSteps, Loco, Z21Station};
+    /// # use std::sync::Arc;
+    /// # async fn example(station: Arc<Z21Station>) -> roco_z21_driver::Result<()> {
     /// let loco = Loco::control_with_steps(
     ///     station.clone(),
     ///     3,
@@ -143,16 +136,22 @@ impl Loco {
     /// # Ok(())
     /// # }
     /// ```
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(station))
+    )]
     pub async fn control_with_steps(
         station: Arc<Z21Station>,
         address: u16,
         steps: DccThrottleSteps,
-    ) -> io::Result<Loco> {
+    ) -> Result<Loco> {
         let loco = Loco {
+            cancel: station.child_token(),
             station: station.clone(),
             steps,
             addr: address,
         };
+        loco.station.control_loco(address);
 
         Self::poll_state_info(address, &loco.station).await?;
         Ok(loco)
@@ -168,14 +167,14 @@ impl Loco {
     ///
     /// # Errors
     ///
-    /// Returns an `io::Error` if the packet fails to send, or Z21 does not respond.
-    async fn send_drive(&self, drive_byte: u8) -> io::Result<()> {
-        let addr_bytes = self.addr.to_be_bytes();
-        let dbs = vec![self.steps as u8, addr_bytes[0], addr_bytes[1], drive_byte];
-        let drive_msg = XBusMessage::new_dbs_vec(XBUS_LOCO_DRIVE, dbs);
-        self.station
-            .send_xbus_command(drive_msg, Some(XBUS_LOCO_INFO))
-            .await?;
+    /// Returns an [`Error`] if the packet fails to send, or Z21 does not respond.
+    async fn send_drive(&self, drive_byte: u8) -> Result<()> {
+        let request = Request::SetLocoDrive {
+            address: self.addr,
+            steps: self.steps,
+            speed: drive_byte,
+        };
+        self.station.request(&request).await?;
         Ok(())
     }
 
@@ -186,18 +185,23 @@ impl Loco {
     ///
     /// # Errors
     ///
-    /// Returns an `io::Error` if the packet fails to send, or Z21 does not respond.
+    /// Returns an [`Error`] if the packet fails to send, or Z21 does not respond.
     ///
     /// # Example
     ///
-    /// ```rust
-    /// # async fn example(loco: &Loco) -> std::io::Result<()> {
+    /// ```rust,no_run
+    /// # use roco_z21_driver::Loco;
+    /// # async fn example(loco: &Loco) -> roco_z21_driver::Result<()> {
     /// // Gradually stop the locomotive
     /// loco.stop().await?;
     /// # Ok(())
     /// # }
     /// ```
-    pub async fn stop(&self) -> io::Result<()> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self), fields(address = self.addr))
+    )]
+    pub async fn stop(&self) -> Result<()> {
         self.send_drive(0x0).await
     }
 
@@ -209,18 +213,23 @@ impl Loco {
     ///
     /// # Errors
     ///
-    /// Returns an `io::Error` if the packet fails to send, or Z21 does not respond.
+    /// Returns an [`Error`] if the packet fails to send, or Z21 does not respond.
     ///
     /// # Example
     ///
-    /// ```rust
-    /// # async fn example(loco: &Loco) -> std::io::Result<()> {
+    /// ```rust,no_run
+    /// # use roco_z21_driver::Loco;
+    /// # async fn example(loco: &Loco) -> roco_z21_driver::Result<()> {
     /// // Emergency stop the locomotive
     /// loco.halt().await?;
     /// # Ok(())
     /// # }
     /// ```
-    pub async fn halt(&self) -> io::Result<()> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self), fields(address = self.addr))
+    )]
+    pub async fn halt(&self) -> Result<()> {
         self.send_drive(0x1).await
     }
 
@@ -267,16 +276,13 @@ impl Loco {
     ///
     /// # Errors
     ///
-    /// Returns an `io::Error` if the request fails or the response is invalid.
-    async fn poll_state_info(addr: u16, station: &Arc<Z21Station>) -> io::Result<LocoState> {
-        let addr_bytes = addr.to_be_bytes();
-        let init_xbus =
-            XBusMessage::new_dbs_vec(XBUS_LOCO_GET_INFO, vec![0xf0, addr_bytes[0], addr_bytes[1]]);
-        let info = station
-            .send_xbus_command(init_xbus, Some(XBUS_LOCO_INFO))
-            .await?;
-
-        Ok(LocoState::try_from(&info)?)
+    /// Returns an [`Error`] if the request fails or the response is invalid.
+    async fn poll_state_info(addr: u16, station: &Arc<Z21Station>) -> Result<LocoState> {
+        let request = Request::GetLocoInfo { address: addr };
+        match station.request(&request).await? {
+            Reply::LocoInfo(state) => Ok(state),
+            reply => Err(unexpected_reply(reply)),
+        }
     }
 
     /// Sets the speed of the locomotive in percent.
@@ -294,12 +300,13 @@ impl Loco {
     ///
     /// # Errors
     ///
-    /// Returns an `io::Error` if the packet fails to send, or Z21 does not respond.
+    /// Returns an [`Error`] if the packet fails to send, or Z21 does not respond.
     ///
     /// # Example
     ///
-    /// ```rust
-    /// # async fn example(loco: &Loco) -> std::io::Result<()> {
+    /// ```rust,no_run
+    /// # use roco_z21_driver::Loco;
+    /// # async fn example(loco: &Loco) -> roco_z21_driver::Result<()> {
     /// // Drive forward at 50% speed
     /// loco.drive(50.0).await?;
     ///
@@ -311,7 +318,11 @@ impl Loco {
     /// # Ok(())
     /// # }
     /// ```
-    pub async fn drive(&self, speed_percent: f64) -> io::Result<()> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self), fields(address = self.addr))
+    )]
+    pub async fn drive(&self, speed_percent: f64) -> Result<()> {
         let calced = Self::calc_speed(self.steps, speed_percent);
         self.send_drive(calced).await?;
         Ok(())
@@ -321,35 +332,51 @@ impl Loco {
     ///
     /// This method sets up a background task that listens for locomotive state
     /// events from the Z21 station and calls the provided callback function
-    /// whenever the state changes.
+    /// whenever the state changes. The task stops when the returned [`Subscription`]
+    /// or the `Loco` is dropped. If the subscription falls behind and skips events, the
+    /// state of the loco is polled again, so the latest state still reaches `subscriber`.
     ///
     /// # Arguments
     ///
     /// * `subscriber` - Callback function that receives locomotive state updates
     ///
+    /// # Returns
+    ///
+    /// The guard of the subscription.
+    ///
     /// # Example
     ///
-    /// ```rust
+    /// ```rust,no_run
+    /// # use roco_z21_driver::Loco;
     /// # fn example(loco: &Loco) {
-    /// loco.subscribe_loco_state(Box::new(|state| {
-    ///     println!("Locomotive speed: {}, direction: {}",
-    ///              state.speed,
-    ///              if state.direction { "forward" } else { "backward" });
+    /// let _subscription = loco.subscribe_loco_state(Box::new(|state| {
+    ///     println!("Locomotive speed: {:?}%", state.speed_percentage);
     /// }));
     /// # }
     /// ```
-    pub fn subscribe_loco_state(&self, subscriber: Box<dyn Fn(LocoState) + Send + Sync>) {
+    pub fn subscribe_loco_state(
+        &self,
+        subscriber: Box<dyn Fn(LocoState) + Send + Sync>,
+    ) -> Subscription {
+        let mut events = self.station.events();
+        let addr = self.addr;
         let station = Arc::clone(&self.station);
-        tokio::spawn(async move {
-            loop {
-                let msg = station.receive_xbus_packet(XBUS_LOCO_INFO).await;
-                if let Ok(msg) = msg {
-                    if let Ok(loco_state) = LocoState::try_from(&msg) {
+        self.station.subscribe(&self.cancel, async move {
+            while let Some(event) = events.next().await {
+                match event {
+                    Z21Event::LocoInfo(loco_state) if loco_state.address == addr => {
                         subscriber(loco_state);
                     }
+                    // The skipped events may have changed the loco, poll its state.
+                    Z21Event::Lagged(_) => {
+                        let _ = station
+                            .send_request(&Request::GetLocoInfo { address: addr })
+                            .await;
+                    }
+                    _ => {}
                 }
             }
-        });
+        })
     }
 
     /// Controls a locomotive function (F0-F31).
@@ -368,7 +395,7 @@ impl Loco {
     ///
     /// # Errors
     ///
-    /// Returns an `io::Error` if:
+    /// Returns an [`Error`] if:
     /// - The function index is invalid (must be 0-31)
     /// - The action is invalid (must be 0-2)
     /// - The packet fails to send
@@ -376,8 +403,9 @@ impl Loco {
     ///
     /// # Example
     ///
-    /// ```rust
-    /// # async fn example(loco: &Loco) -> std::io::Result<()> {
+    /// ```rust,no_run
+    /// # use roco_z21_driver::Loco;
+    /// # async fn example(loco: &Loco) -> roco_z21_driver::Result<()> {
     /// // Turn on the locomotive lights (F0)
     /// loco.set_function(0, 1).await?;
     ///
@@ -386,38 +414,17 @@ impl Loco {
     /// # Ok(())
     /// # }
     /// ```
-    pub async fn set_function(&self, function_index: u8, action: u8) -> io::Result<()> {
-        if function_index > 31 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Function index must be between 0 and 31",
-            ));
-        }
-
-        if action > 2 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Action must be 0 (off), 1 (on), or 2 (toggle)",
-            ));
-        }
-
-        let addr_bytes = self.addr.to_be_bytes();
-        let addr_msb = if self.addr >= 128 {
-            0xC0 | addr_bytes[0]
-        } else {
-            addr_bytes[0]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self), fields(address = self.addr))
+    )]
+    pub async fn set_function(&self, function_index: u8, action: u8) -> Result<()> {
+        let request = Request::SetLocoFunction {
+            address: self.addr,
+            function: function_index,
+            action: FunctionAction::try_from(action)?,
         };
-
-        // Create the function byte (TTNNNNNN): TT is action type, NNNNNN is function index
-        let function_byte = (action << 6) | (function_index & 0x3F);
-
-        let dbs = vec![0xF8, addr_msb, addr_bytes[1], function_byte];
-        let function_msg = XBusMessage::new_dbs_vec(XBUS_LOCO_FUNCTION, dbs);
-
-        self.station
-            .send_xbus_command(function_msg, Some(XBUS_LOCO_INFO))
-            .await?;
-
+        self.station.request(&request).await?;
         Ok(())
     }
 
@@ -431,12 +438,13 @@ impl Loco {
     ///
     /// # Errors
     ///
-    /// Returns an `io::Error` if the packet fails to send, or Z21 does not respond.
+    /// Returns an [`Error`] if the packet fails to send, or Z21 does not respond.
     ///
     /// # Example
     ///
-    /// ```rust
-    /// # async fn example(loco: &Loco) -> std::io::Result<()> {
+    /// ```rust,no_run
+    /// # use roco_z21_driver::Loco;
+    /// # async fn example(loco: &Loco) -> roco_z21_driver::Result<()> {
     /// // Turn on the locomotive lights (F0)
     /// loco.function_on(0).await?;
     ///
@@ -445,8 +453,9 @@ impl Loco {
     /// # Ok(())
     /// # }
     /// ```
-    pub async fn function_on(&self, function_index: u8) -> io::Result<()> {
-        self.set_function(function_index, FUNC_ON).await
+    pub async fn function_on(&self, function_index: u8) -> Result<()> {
+        self.set_function(function_index, FunctionAction::On as u8)
+            .await
     }
 
     /// Turns off a specific locomotive function.
@@ -459,12 +468,13 @@ impl Loco {
     ///
     /// # Errors
     ///
-    /// Returns an `io::Error` if the packet fails to send, or Z21 does not respond.
+    /// Returns an [`Error`] if the packet fails to send, or Z21 does not respond.
     ///
     /// # Example
     ///
-    /// ```rust
-    /// # async fn example(loco: &Loco) -> std::io::Result<()> {
+    /// ```rust,no_run
+    /// # use roco_z21_driver::Loco;
+    /// # async fn example(loco: &Loco) -> roco_z21_driver::Result<()> {
     /// // Turn off the locomotive lights (F0)
     /// loco.function_off(0).await?;
     ///
@@ -473,8 +483,9 @@ impl Loco {
     /// # Ok(())
     /// # }
     /// ```
-    pub async fn function_off(&self, function_index: u8) -> io::Result<()> {
-        self.set_function(function_index, FUNC_OFF).await
+    pub async fn function_off(&self, function_index: u8) -> Result<()> {
+        self.set_function(function_index, FunctionAction::Off as u8)
+            .await
     }
 
     /// Toggles a specific locomotive function (if on, turns off; if off, turns on).
@@ -487,12 +498,13 @@ impl Loco {
     ///
     /// # Errors
     ///
-    /// Returns an `io::Error` if the packet fails to send, or Z21 does not respond.
+    /// Returns an [`Error`] if the packet fails to send, or Z21 does not respond.
     ///
     /// # Example
     ///
-    /// ```rust
-    /// # async fn example(loco: &Loco) -> std::io::Result<()> {
+    /// ```rust,no_run
+    /// # use roco_z21_driver::Loco;
+    /// # async fn example(loco: &Loco) -> roco_z21_driver::Result<()> {
     /// // Toggle the locomotive lights (F0)
     /// loco.function_toggle(0).await?;
     ///
@@ -501,8 +513,9 @@ impl Loco {
     /// # Ok(())
     /// # }
     /// ```
-    pub async fn function_toggle(&self, function_index: u8) -> io::Result<()> {
-        self.set_function(function_index, FUNC_TOGGLE).await
+    pub async fn function_toggle(&self, function_index: u8) -> Result<()> {
+        self.set_function(function_index, FunctionAction::Toggle as u8)
+            .await
     }
 
     /// Convenience method to control the locomotive's headlights (F0).
@@ -516,12 +529,13 @@ impl Loco {
     ///
     /// # Errors
     ///
-    /// Returns an `io::Error` if the packet fails to send, or Z21 does not respond.
+    /// Returns an [`Error`] if the packet fails to send, or Z21 does not respond.
     ///
     /// # Example
     ///
-    /// ```rust
-    /// # async fn example(loco: &Loco) -> std::io::Result<()> {
+    /// ```rust,no_run
+    /// # use roco_z21_driver::Loco;
+    /// # async fn example(loco: &Loco) -> roco_z21_driver::Result<()> {
     /// // Turn on the locomotive headlights
     /// loco.set_headlights(true).await?;
     ///
@@ -530,7 +544,7 @@ impl Loco {
     /// # Ok(())
     /// # }
     /// ```
-    pub async fn set_headlights(&self, on: bool) -> io::Result<()> {
+    pub async fn set_headlights(&self, on: bool) -> Result<()> {
         if on {
             self.function_on(0).await
         } else {
@@ -538,3 +552,10 @@ impl Loco {
         }
     }
 }
+
+impl Drop for Loco {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+        self.station.release_loco(self.addr);
+    }
+}
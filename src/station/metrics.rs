@@ -0,0 +1,376 @@
+//! Health metrics of the link to a [`Z21Station`](crate::Z21Station).
+//!
+//! Every station counts the commands it sends, the replies it receives, lost and resent
+//! commands, corrupted packets and events skipped by slow consumers, and records the
+//! round-trip time of every answered request per [`CommandClass`]. A snapshot is
+//! returned by [`Z21Station::metrics`](crate::Z21Station::metrics).
+//!
+//! With the `prometheus` feature the snapshot can be rendered in the Prometheus text
+//! exposition format and served over HTTP with
+//! [`Z21Station::serve_metrics`](crate::Z21Station::serve_metrics).
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # use roco_z21_driver::{CommandClass, Z21Station};
+//! # async fn example(station: &Z21Station) -> roco_z21_driver::Result<()> {
+//! let metrics = station.metrics();
+//! println!(
+//!     "{} of {} commands timed out",
+//!     metrics.timeouts, metrics.commands_sent
+//! );
+//! if let Some(rtt) = metrics.rtt.get(&CommandClass::Drive) {
+//!     println!("Mean drive round trip: {:?}", rtt.mean());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::CommandClass;
+
+/// Upper bounds of the buckets of an [`RttHistogram`].
+pub const RTT_BUCKETS: [Duration; 11] = [
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_millis(1000),
+    Duration::from_millis(2500),
+    Duration::from_millis(5000),
+    Duration::from_millis(10000),
+];
+
+/// Snapshot of the metrics of a station, counted since it connected.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    /// Commands sent to the station, including resent ones.
+    pub commands_sent: u64,
+    /// Replies and broadcasts received from the station.
+    pub replies_received: u64,
+    /// Requests which stayed unanswered after all retries.
+    pub timeouts: u64,
+    /// Requests resent because their reply timed out.
+    pub retries: u64,
+    /// Received X-Bus messages with a wrong checksum.
+    pub checksum_failures: u64,
+    /// Events skipped because an event stream fell behind.
+    pub lag_drops: u64,
+    /// Round-trip times of requests answered without a retry, per [`CommandClass`].
+    pub rtt: HashMap<CommandClass, RttHistogram>,
+}
+
+/// Histogram of round-trip times.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RttHistogram {
+    /// Number of round trips of at most the matching bound of [`RTT_BUCKETS`] and more
+    /// than the previous one. Round trips above the last bound are only counted in
+    /// `count`.
+    pub buckets: [u64; RTT_BUCKETS.len()],
+    /// Number of round trips.
+    pub count: u64,
+    /// Sum of all round-trip times.
+    pub sum: Duration,
+}
+
+impl RttHistogram {
+    /// Adds a round trip of `rtt`.
+    pub fn record(&mut self, rtt: Duration) {
+        if let Some(bucket) = RTT_BUCKETS.iter().position(|bound| rtt <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += rtt;
+    }
+
+    /// Returns the mean round-trip time, or `None` if no round trip was recorded.
+    pub fn mean(&self) -> Option<Duration> {
+        // The sum in nanoseconds, a count beyond `u32::MAX` must not be truncated.
+        (self.count > 0)
+            .then(|| Duration::from_nanos((self.sum.as_nanos() / u128::from(self.count)) as u64))
+    }
+}
+
+/// Counters of a station, updated by the station and its background tasks.
+#[derive(Debug, Default)]
+pub(crate) struct MetricsRecorder {
+    pub(crate) commands_sent: AtomicU64,
+    pub(crate) replies_received: AtomicU64,
+    pub(crate) timeouts: AtomicU64,
+    pub(crate) retries: AtomicU64,
+    pub(crate) checksum_failures: AtomicU64,
+    pub(crate) lag_drops: AtomicU64,
+    rtt: Mutex<HashMap<CommandClass, RttHistogram>>,
+}
+
+impl MetricsRecorder {
+    /// Increments `counter` by `n`.
+    pub(crate) fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Records the round-trip time of an answered request of `class`.
+    pub(crate) fn record_rtt(&self, class: CommandClass, rtt: Duration) {
+        self.rtt
+            .lock()
+            .unwrap()
+            .entry(class)
+            .or_default()
+            .record(rtt);
+    }
+
+    pub(crate) fn snapshot(&self) -> Metrics {
+        Metrics {
+            commands_sent: self.commands_sent.load(Ordering::Relaxed),
+            replies_received: self.replies_received.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            checksum_failures: self.checksum_failures.load(Ordering::Relaxed),
+            lag_drops: self.lag_drops.load(Ordering::Relaxed),
+            rtt: self.rtt.lock().unwrap().clone(),
+        }
+    }
+}
+
+#[cfg(feature = "prometheus")]
+mod prometheus {
+    use std::fmt::Write;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::time::timeout;
+
+    use super::{Metrics, RTT_BUCKETS};
+    use crate::error::Result;
+    use crate::{CommandClass, Subscription, Z21Station};
+
+    /// Time a client has to send its request before it is answered anyway.
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn class_label(class: CommandClass) -> &'static str {
+        match class {
+            CommandClass::Query => "query",
+            CommandClass::Drive => "drive",
+            CommandClass::Accessory => "accessory",
+            CommandClass::Programming => "programming",
+            CommandClass::System => "system",
+        }
+    }
+
+    impl Metrics {
+        /// Renders the metrics in the Prometheus text exposition format.
+        pub fn to_prometheus(&self) -> String {
+            let mut out = String::new();
+            let counters = [
+                (
+                    "z21_commands_sent_total",
+                    "Commands sent to the station.",
+                    self.commands_sent,
+                ),
+                (
+                    "z21_replies_received_total",
+                    "Replies and broadcasts received from the station.",
+                    self.replies_received,
+                ),
+                (
+                    "z21_timeouts_total",
+                    "Requests unanswered after all retries.",
+                    self.timeouts,
+                ),
+                (
+                    "z21_retries_total",
+                    "Requests resent after a timeout.",
+                    self.retries,
+                ),
+                (
+                    "z21_checksum_failures_total",
+                    "Received X-Bus messages with a wrong checksum.",
+                    self.checksum_failures,
+                ),
+                (
+                    "z21_lag_drops_total",
+                    "Events skipped by lagging event streams.",
+                    self.lag_drops,
+                ),
+            ];
+            for (name, help, value) in counters {
+                let _ = writeln!(
+                    out,
+                    "# HELP {} {}\n# TYPE {} counter\n{} {}",
+                    name, help, name, name, value
+                );
+            }
+
+            let _ = writeln!(
+                out,
+                "# HELP z21_rtt_seconds Round-trip time of answered requests.\n# TYPE z21_rtt_seconds histogram"
+            );
+            let mut classes: Vec<_> = self.rtt.iter().collect();
+            classes.sort_by_key(|(class, _)| class_label(**class));
+            for (class, histogram) in classes {
+                let label = class_label(*class);
+                let mut cumulative = 0;
+                for (bound, count) in RTT_BUCKETS.iter().zip(histogram.buckets) {
+                    cumulative += count;
+                    let _ = writeln!(
+                        out,
+                        "z21_rtt_seconds_bucket{{class=\"{}\",le=\"{}\"}} {}",
+                        label,
+                        bound.as_secs_f64(),
+                        cumulative
+                    );
+                }
+                let _ = writeln!(
+                    out,
+                    "z21_rtt_seconds_bucket{{class=\"{}\",le=\"+Inf\"}} {}",
+                    label, histogram.count
+                );
+                let _ = writeln!(
+                    out,
+                    "z21_rtt_seconds_sum{{class=\"{}\"}} {}",
+                    label,
+                    histogram.sum.as_secs_f64()
+                );
+                let _ = writeln!(
+                    out,
+                    "z21_rtt_seconds_count{{class=\"{}\"}} {}",
+                    label, histogram.count
+                );
+            }
+            out
+        }
+    }
+
+    /// HTTP endpoint serving the metrics of a station, created by
+    /// [`Z21Station::serve_metrics`].
+    ///
+    /// The endpoint stops when it is dropped or the station shuts down.
+    #[derive(Debug)]
+    pub struct MetricsServer {
+        local_addr: SocketAddr,
+        _subscription: Subscription,
+    }
+
+    impl MetricsServer {
+        /// Returns the address the endpoint listens on.
+        pub fn local_addr(&self) -> SocketAddr {
+            self.local_addr
+        }
+    }
+
+    impl Z21Station {
+        /// Serves the [`metrics`](Z21Station::metrics) of the station in the Prometheus
+        /// text format over HTTP on `addr`.
+        ///
+        /// Every request is answered with the current metrics, regardless of its path.
+        ///
+        /// # Errors
+        ///
+        /// Returns an [`Error`](crate::Error) if the TCP listener cannot be bound.
+        ///
+        /// # Example
+        ///
+        /// ```rust,no_run
+        /// # use roco_z21_driver::Z21Station;
+        /// # async fn example(station: &Z21Station) -> roco_z21_driver::Result<()> {
+        /// let server = station.serve_metrics("0.0.0.0:9121").await?;
+        /// println!("Metrics on http://{}/metrics", server.local_addr());
+        /// # Ok(())
+        /// # }
+        /// ```
+        pub async fn serve_metrics(&self, addr: &str) -> Result<MetricsServer> {
+            let listener = TcpListener::bind(addr).await?;
+            let local_addr = listener.local_addr()?;
+            let recorder = Arc::clone(&self.inner.metrics);
+            let token = self.inner.cancel.child_token();
+            let connections = token.clone();
+            self.inner.spawn(token.clone(), async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    let body = recorder.snapshot().to_prometheus();
+                    let token = connections.clone();
+                    tokio::spawn(token.run_until_cancelled_owned(async move {
+                        let mut request = [0u8; 1024];
+                        // The request itself does not matter, only wait for it.
+                        let _ = timeout(REQUEST_TIMEOUT, stream.read(&mut request)).await;
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            body.len(),
+                            body
+                        );
+                        let _: io::Result<()> = stream.write_all(response.as_bytes()).await;
+                    }));
+                }
+            });
+            Ok(MetricsServer {
+                local_addr,
+                _subscription: Subscription::new(token),
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::time::Duration;
+
+        #[test]
+        fn test_prometheus_histogram_is_cumulative() {
+            let mut metrics = Metrics {
+                commands_sent: 3,
+                ..Metrics::default()
+            };
+            let rtt = metrics.rtt.entry(CommandClass::Drive).or_default();
+            rtt.record(Duration::from_millis(3));
+            rtt.record(Duration::from_millis(40));
+            rtt.record(Duration::from_secs(60));
+
+            let text = metrics.to_prometheus();
+            assert!(text.contains("z21_commands_sent_total 3\n"));
+            assert!(text.contains("z21_rtt_seconds_bucket{class=\"drive\",le=\"0.005\"} 1\n"));
+            assert!(text.contains("z21_rtt_seconds_bucket{class=\"drive\",le=\"0.05\"} 2\n"));
+            assert!(text.contains("z21_rtt_seconds_bucket{class=\"drive\",le=\"10\"} 2\n"));
+            assert!(text.contains("z21_rtt_seconds_bucket{class=\"drive\",le=\"+Inf\"} 3\n"));
+            assert!(text.contains("z21_rtt_seconds_count{class=\"drive\"} 3\n"));
+        }
+    }
+}
+
+#[cfg(feature = "prometheus")]
+pub use prometheus::MetricsServer;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtt_histogram_buckets() {
+        let mut histogram = RttHistogram::default();
+        assert_eq!(histogram.mean(), None);
+        histogram.record(Duration::from_millis(5));
+        histogram.record(Duration::from_millis(6));
+        histogram.record(Duration::from_secs(11));
+        assert_eq!(histogram.buckets[0], 1);
+        assert_eq!(histogram.buckets[1], 1);
+        assert_eq!(histogram.buckets.iter().sum::<u64>(), 2);
+        assert_eq!(histogram.count, 3);
+        assert_eq!(histogram.mean(), Some(Duration::from_millis(11011) / 3));
+
+        let many = RttHistogram {
+            count: u64::from(u32::MAX) + 1,
+            sum: Duration::from_millis(u64::from(u32::MAX) + 1),
+            ..RttHistogram::default()
+        };
+        assert_eq!(many.mean(), Some(Duration::from_millis(1)));
+    }
+}
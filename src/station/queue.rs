@@ -0,0 +1,211 @@
+//! Outgoing command queue of a [`Z21Station`](crate::Z21Station).
+//!
+//! The Z21 drops commands when it receives too many of them in a short burst. All
+//! requests are therefore put into a queue which a background task sends one by one,
+//! waiting [`QueueConfig::pacing`] between two datagrams. While a drive command for a
+//! loco is still waiting in the queue, a newer drive command for the same loco replaces
+//! it, so only the latest speed is sent.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::Notify;
+
+use super::dispatch::Dispatcher;
+use super::metrics::MetricsRecorder;
+use crate::error::Result;
+use crate::log;
+use crate::messages::Request;
+use crate::transport::Transport;
+
+/// Configuration of the command queue of a [`Z21Station`](crate::Z21Station).
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # use roco_z21_driver::{QueueConfig, Z21Station};
+/// # use std::time::Duration;
+/// # async fn example() -> roco_z21_driver::Result<()> {
+/// let config = QueueConfig {
+///     pacing: Duration::from_millis(20),
+///     retries: 3,
+///     ..QueueConfig::default()
+/// };
+/// let station = Z21Station::builder("192.168.0.111:21105")
+///     .queue_config(config)
+///     .connect()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct QueueConfig {
+    /// Minimum delay between two datagrams sent to the station.
+    pub pacing: Duration,
+    /// How often an idempotent request is resent when its reply times out
+    /// (see [`Request::is_idempotent`]).
+    pub retries: u32,
+    /// Whether a queued drive command is replaced by a newer one for the same loco.
+    pub coalesce_drive: bool,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            pacing: Duration::from_millis(10),
+            retries: 2,
+            coalesce_drive: true,
+        }
+    }
+}
+
+/// A datagram waiting to be sent.
+struct Queued {
+    /// The request encoded in `datagram`, `None` for a batch of packets.
+    request: Option<Request>,
+    datagram: Vec<u8>,
+    /// Dispatcher id of the caller waiting for the reply, if any.
+    waiter: Option<u64>,
+}
+
+/// Queue of requests, shared between the station and its sender task.
+pub(crate) struct CommandQueue {
+    entries: Mutex<VecDeque<Queued>>,
+    notify: Notify,
+    config: QueueConfig,
+}
+
+impl CommandQueue {
+    pub(crate) fn new(config: QueueConfig) -> Self {
+        CommandQueue {
+            entries: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            config,
+        }
+    }
+
+    pub(crate) fn config(&self) -> &QueueConfig {
+        &self.config
+    }
+
+    /// Appends `request` to the queue.
+    ///
+    /// A drive command replaces a queued drive command for the same loco; the callers
+    /// waiting for either of them are then answered by the same reply.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`Error`](crate::Error) if the request cannot be encoded.
+    pub(crate) fn push(
+        &self,
+        request: &Request,
+        waiter: Option<u64>,
+        dispatcher: &Dispatcher,
+    ) -> Result<()> {
+        let datagram: Vec<u8> = request.encode()?.into();
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(queued) = self.coalescable(&mut entries, request) {
+            queued.waiter = match (queued.waiter, waiter) {
+                (Some(into), Some(from)) if dispatcher.merge(into, from) => Some(into),
+                (into, from) => from.or(into),
+            };
+            queued.request = Some(request.clone());
+            queued.datagram = datagram;
+        } else {
+            entries.push_back(Queued {
+                request: Some(request.clone()),
+                datagram,
+                waiter,
+            });
+        }
+        drop(entries);
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Appends a datagram of several packets to the queue, it is never coalesced.
+    pub(crate) fn push_datagram(&self, datagram: Vec<u8>) {
+        self.entries.lock().unwrap().push_back(Queued {
+            request: None,
+            datagram,
+            waiter: None,
+        });
+        self.notify.notify_one();
+    }
+
+    /// Returns the queued request `request` may replace.
+    fn coalescable<'a>(
+        &self,
+        entries: &'a mut VecDeque<Queued>,
+        request: &Request,
+    ) -> Option<&'a mut Queued> {
+        let Request::SetLocoDrive { address, .. } = request else {
+            return None;
+        };
+        if !self.config.coalesce_drive {
+            return None;
+        }
+        entries.iter_mut().find(|queued| {
+            matches!(queued.request, Some(Request::SetLocoDrive { address: queued_address, .. })
+                if queued_address == *address)
+        })
+    }
+
+    /// Sends the queued requests through `transport`, until the sender task is cancelled.
+    pub(crate) async fn run_sender(&self, transport: &dyn Transport, metrics: &MetricsRecorder) {
+        loop {
+            let next = self.entries.lock().unwrap().pop_front();
+            let Some(queued) = next else {
+                self.notify.notified().await;
+                continue;
+            };
+            match transport.send(&queued.datagram).await {
+                Ok(()) => MetricsRecorder::add(&metrics.commands_sent, 1),
+                Err(e) => log::error!("Failed to send queued packet: {}", e),
+            }
+            tokio::time::sleep(self.config.pacing).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::DccThrottleSteps;
+
+    fn drive(address: u16, speed: u8) -> Request {
+        Request::SetLocoDrive {
+            address,
+            steps: DccThrottleSteps::Steps128,
+            speed,
+        }
+    }
+
+    #[test]
+    fn test_coalesce_drive() {
+        let queue = CommandQueue::new(QueueConfig::default());
+        let dispatcher = Dispatcher::default();
+        queue.push(&drive(3, 10), None, &dispatcher).unwrap();
+        queue.push(&drive(4, 10), None, &dispatcher).unwrap();
+        queue.push(&drive(3, 20), None, &dispatcher).unwrap();
+
+        let entries = queue.entries.lock().unwrap();
+        assert_eq!(entries.len(), 2);
+        let datagram: Vec<u8> = drive(3, 20).encode().unwrap().into();
+        assert_eq!(entries[0].datagram, datagram);
+    }
+
+    #[test]
+    fn test_no_coalesce_when_disabled() {
+        let config = QueueConfig {
+            coalesce_drive: false,
+            ..QueueConfig::default()
+        };
+        let queue = CommandQueue::new(config);
+        let dispatcher = Dispatcher::default();
+        queue.push(&drive(3, 10), None, &dispatcher).unwrap();
+        queue.push(&drive(3, 20), None, &dispatcher).unwrap();
+        assert_eq!(queue.entries.lock().unwrap().len(), 2);
+    }
+}
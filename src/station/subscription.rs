@@ -0,0 +1,59 @@
+//! Guards of the callback subscriptions of a [`Z21Station`](crate::Z21Station).
+
+use tokio_util::sync::CancellationToken;
+
+/// Guard of a callback subscription, e.g. created by
+/// [`Z21Station::subscribe_system_state`](crate::Z21Station::subscribe_system_state).
+///
+/// The callback is called until the guard is dropped, the [`Loco`](crate::Loco) it was
+/// created for is dropped, or the station is shut down.
+#[must_use = "the subscription ends when the guard is dropped"]
+#[derive(Debug)]
+pub struct Subscription {
+    token: CancellationToken,
+}
+
+impl Subscription {
+    pub(crate) fn new(token: CancellationToken) -> Self {
+        Subscription { token }
+    }
+
+    /// Ends the subscription. Same as dropping the guard.
+    pub fn unsubscribe(self) {}
+
+    /// Returns `true` until the subscription ended.
+    pub fn is_active(&self) -> bool {
+        !self.token.is_cancelled()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.token.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drop_ends_subscription() {
+        let station = CancellationToken::new();
+        let token = station.child_token();
+        let subscription = Subscription::new(token.clone());
+        assert!(subscription.is_active());
+
+        drop(subscription);
+        assert!(token.is_cancelled());
+        assert!(!station.is_cancelled());
+    }
+
+    #[test]
+    fn test_parent_cancel_ends_subscription() {
+        let station = CancellationToken::new();
+        let subscription = Subscription::new(station.child_token());
+        station.cancel();
+        assert!(!subscription.is_active());
+    }
+}
@@ -0,0 +1,82 @@
+//! Keep-alive and reconnection of a [`Z21Station`](crate::Z21Station).
+//!
+//! The supervisor task periodically re-sends the broadcast flags, which also keeps the
+//! client registered at the Z21, and probes the station with a request. When the probe
+//! stays unanswered the connection is reported as [`ConnectionState::Disconnected`].
+//! As soon as the station answers again, the session is restored: the handshake is
+//! repeated, the broadcast flags are sent again and the state of every loco controlled
+//! through a [`Loco`](crate::Loco) is polled, so subscribers receive fresh loco info.
+
+use std::sync::Arc;
+
+use super::{Inner, Z21Event};
+use crate::log;
+use crate::messages::Request;
+
+/// State of the connection to the Z21 station.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ConnectionState {
+    /// The station answers requests.
+    Connected,
+    /// The station stopped answering, e.g. because it reboots or Wi-Fi dropped.
+    Disconnected,
+    /// The station answers again and the session is being restored.
+    Reconnecting,
+}
+
+impl Inner {
+    /// Updates the connection state and reports a change as [`Z21Event::ConnectionState`].
+    pub(super) fn set_connection_state(&self, state: ConnectionState) {
+        let mut current = self.connection_state.lock().unwrap();
+        if *current != state {
+            *current = state;
+            drop(current);
+            self.emit(Z21Event::ConnectionState(state));
+        }
+    }
+
+    /// Re-establishes the session after the station was unreachable.
+    async fn restore_session(&self) {
+        self.set_connection_state(ConnectionState::Reconnecting);
+        if let Err(e) = self.handshake().await {
+            log::warning!("Failed to restore session with the Z21 station: {}", e);
+            self.set_connection_state(ConnectionState::Disconnected);
+            return;
+        }
+        let flags = *self.broadcast_flags.lock().unwrap();
+        let _ = self.send_request(&Request::SetBroadcastFlags(flags));
+
+        let locos: Vec<u16> = self
+            .controlled_locos
+            .lock()
+            .unwrap()
+            .keys()
+            .copied()
+            .collect();
+        for address in locos {
+            // The replies arrive as events, like any other loco info.
+            let _ = self.send_request(&Request::GetLocoInfo { address });
+        }
+        self.set_connection_state(ConnectionState::Connected);
+    }
+}
+
+/// Starts the supervisor task of the station sharing `station`.
+pub(super) fn start(station: &Arc<Inner>) {
+    let inner = Arc::clone(station);
+    station.spawn(station.cancel.clone(), async move {
+        loop {
+            let flags = *inner.broadcast_flags.lock().unwrap();
+            let _ = inner.send_request(&Request::SetBroadcastFlags(flags));
+            let reachable = inner.request(&Request::GetSerialNumber).await.is_ok();
+            let state = *inner.connection_state.lock().unwrap();
+            match (reachable, state) {
+                (true, ConnectionState::Connected) => {}
+                (true, _) => inner.restore_session().await,
+                (false, _) => inner.set_connection_state(ConnectionState::Disconnected),
+            }
+            tokio::time::sleep(inner.keep_alive_interval).await;
+        }
+    });
+}
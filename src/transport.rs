@@ -0,0 +1,496 @@
+//! Datagram transports a [`Z21Station`](crate::Z21Station) can talk over.
+//!
+//! The Z21 LAN protocol is datagram based. By default the station is reached over UDP
+//! with [`UdpTransport`]. Other transports carry the same datagrams elsewhere:
+//!
+//! - [`ChannelTransport`] connects two endpoints in memory, e.g. a station and a
+//!   simulated Z21 in a unit test.
+//! - [`TcpTransport`] tunnels the datagrams through a TCP stream, e.g. to reach a Z21
+//!   behind NAT through a relay. Every datagram is prefixed with its length as a
+//!   little-endian `u16`.
+//! - [`ReplayTransport`](crate::capture::ReplayTransport) and
+//!   [`CaptureTransport`](crate::capture::CaptureTransport) replay and record captures.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # use roco_z21_driver::{transport::TcpTransport, Z21Station};
+//! # async fn example() -> roco_z21_driver::Result<()> {
+//! let tunnel = TcpTransport::connect("relay.example.org:21105").await?;
+//! let station = Z21Station::builder("relay.example.org:21105")
+//!     .connect_with_transport(tunnel)
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use tokio::sync::{mpsc, Mutex};
+
+/// A boxed future returned by the methods of [`Transport`].
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A bidirectional transport of datagrams between a client and a Z21.
+///
+/// Both methods take `&self`, as sending and receiving happen concurrently from
+/// different tasks.
+pub trait Transport: Send + Sync + 'static {
+    /// Sends one datagram.
+    ///
+    /// The future must be cancel safe as well: a datagram which was partially sent
+    /// must either be completed later or not reach the other end at all.
+    fn send<'a>(&'a self, datagram: &'a [u8]) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Receives one datagram into `buf` and returns its length.
+    ///
+    /// A datagram longer than `buf` is truncated, like with UDP. A transport which is
+    /// closed for good reports [`io::ErrorKind::UnexpectedEof`],
+    /// [`io::ErrorKind::ConnectionReset`] or [`io::ErrorKind::BrokenPipe`], after which
+    /// the station stops receiving. Any other error is considered transient.
+    ///
+    /// The future must be cancel safe: the station drops it e.g. when it shuts down,
+    /// and a datagram which was partially received must then be completed by the next
+    /// call instead of being lost.
+    fn recv<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>>;
+
+    /// Returns the local address of the transport, if it has one.
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Returns the address of the other end of the transport, if it has one.
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Arc<T> {
+    fn send<'a>(&'a self, datagram: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        (**self).send(datagram)
+    }
+
+    fn recv<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>> {
+        (**self).recv(buf)
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        (**self).local_addr()
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        (**self).peer_addr()
+    }
+}
+
+/// Returns `true` if `error` reports a transport which is closed for good, see
+/// [`Transport::recv`].
+pub(crate) fn is_closed(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe
+    )
+}
+
+/// Transport over a connected UDP socket, the default.
+#[derive(Debug)]
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    /// Binds a UDP socket to `bind_addr` and connects it to the Z21 at `station_addr`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`io::Error`] if the socket cannot be bound or connected.
+    pub async fn connect(
+        bind_addr: SocketAddr,
+        station_addr: impl ToSocketAddrs,
+    ) -> io::Result<Self> {
+        let socket = UdpSocket::bind(bind_addr).await?;
+        // Enable broadcast on the socket to allow sending messages to a broadcast address.
+        socket.set_broadcast(true)?;
+        socket.connect(station_addr).await?;
+        Ok(UdpTransport { socket })
+    }
+
+    /// Wraps an already connected UDP socket.
+    pub fn from_socket(socket: UdpSocket) -> Self {
+        UdpTransport { socket }
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send<'a>(&'a self, datagram: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            self.socket.send(datagram).await?;
+            Ok(())
+        })
+    }
+
+    fn recv<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>> {
+        Box::pin(async move {
+            self.socket.recv(buf).await.map_err(|e| {
+                // Windows reports an ICMP port unreachable as a reset, but a UDP socket
+                // is never closed by its peer.
+                if e.kind() == io::ErrorKind::ConnectionReset {
+                    io::Error::new(io::ErrorKind::ConnectionRefused, e)
+                } else {
+                    e
+                }
+            })
+        })
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.socket.local_addr().ok()
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.socket.peer_addr().ok()
+    }
+}
+
+/// One end of an in-memory transport, created by [`ChannelTransport::pair`].
+///
+/// Datagrams sent on one end are received on the other end.
+#[derive(Debug)]
+pub struct ChannelTransport {
+    sender: mpsc::UnboundedSender<Vec<u8>>,
+    receiver: Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
+}
+
+impl ChannelTransport {
+    /// Creates two connected ends.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use roco_z21_driver::transport::{ChannelTransport, Transport};
+    /// # async fn example() -> std::io::Result<()> {
+    /// let (client, z21) = ChannelTransport::pair();
+    /// client.send(&[0x04, 0x00, 0x10, 0x00]).await?;
+    ///
+    /// let mut buf = [0u8; 1472];
+    /// let len = z21.recv(&mut buf).await?;
+    /// assert_eq!(&buf[..len], &[0x04, 0x00, 0x10, 0x00]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn pair() -> (ChannelTransport, ChannelTransport) {
+        let (a_sender, b_receiver) = mpsc::unbounded_channel();
+        let (b_sender, a_receiver) = mpsc::unbounded_channel();
+        (
+            ChannelTransport {
+                sender: a_sender,
+                receiver: Mutex::new(a_receiver),
+            },
+            ChannelTransport {
+                sender: b_sender,
+                receiver: Mutex::new(b_receiver),
+            },
+        )
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn send<'a>(&'a self, datagram: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            self.sender
+                .send(datagram.to_vec())
+                .map_err(|_| io::Error::from(io::ErrorKind::ConnectionReset))
+        })
+    }
+
+    fn recv<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>> {
+        Box::pin(async move {
+            let datagram = self
+                .receiver
+                .lock()
+                .await
+                .recv()
+                .await
+                .ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionReset))?;
+            let len = datagram.len().min(buf.len());
+            buf[..len].copy_from_slice(&datagram[..len]);
+            Ok(len)
+        })
+    }
+}
+
+/// Transport tunnelling the datagrams through a TCP stream.
+///
+/// Every datagram is sent as its length (little-endian `u16`) followed by its bytes.
+/// The other end of the stream, e.g. a relay next to the Z21, forwards the datagrams
+/// over UDP.
+#[derive(Debug)]
+pub struct TcpTransport {
+    reader: Mutex<FrameReader>,
+    writer: Mutex<FrameWriter>,
+    local_addr: Option<SocketAddr>,
+    peer_addr: Option<SocketAddr>,
+}
+
+impl TcpTransport {
+    /// Connects to the tunnel endpoint at `addr`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`io::Error`] if the connection cannot be established.
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        Ok(Self::from_stream(stream))
+    }
+
+    /// Wraps an established TCP stream, e.g. one accepted by a relay.
+    pub fn from_stream(stream: TcpStream) -> Self {
+        // Datagrams are small, waiting to fill a segment would only add latency.
+        let _ = stream.set_nodelay(true);
+        let local_addr = stream.local_addr().ok();
+        let peer_addr = stream.peer_addr().ok();
+        let (reader, writer) = stream.into_split();
+        TcpTransport {
+            reader: Mutex::new(FrameReader {
+                stream: reader,
+                buffer: Vec::new(),
+            }),
+            writer: Mutex::new(FrameWriter {
+                stream: writer,
+                unsent: Vec::new(),
+            }),
+            local_addr,
+            peer_addr,
+        }
+    }
+}
+
+/// Read half of a [`TcpTransport`] with the bytes of a frame received so far.
+///
+/// Bytes are kept in `buffer` until their frame is complete, so a cancelled
+/// [`Transport::recv`] loses nothing.
+#[derive(Debug)]
+struct FrameReader {
+    stream: OwnedReadHalf,
+    buffer: Vec<u8>,
+}
+
+impl FrameReader {
+    /// Removes the first frame from the buffer and returns its datagram, if complete.
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        let header = self.buffer.get(..2)?;
+        let len = u16::from_le_bytes([header[0], header[1]]) as usize;
+        if self.buffer.len() < 2 + len {
+            return None;
+        }
+        let datagram = self.buffer[2..2 + len].to_vec();
+        self.buffer.drain(..2 + len);
+        Some(datagram)
+    }
+
+    /// Receives the next datagram, reading the stream until its frame is complete.
+    async fn recv(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(datagram) = self.next_frame() {
+                return Ok(datagram);
+            }
+            // A single read is cancel safe, the bytes land in the buffer or not at all.
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+}
+
+/// Write half of a [`TcpTransport`] with the bytes of the frames not written yet.
+///
+/// A cancelled [`Transport::send`] leaves the rest of its frame in `unsent`, which the
+/// next send writes first, so a frame is never cut in half on the stream.
+#[derive(Debug)]
+struct FrameWriter {
+    stream: OwnedWriteHalf,
+    unsent: Vec<u8>,
+}
+
+impl FrameWriter {
+    /// Writes the unsent bytes and then `frame`.
+    async fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.write_unsent().await?;
+        self.unsent.extend_from_slice(frame);
+        self.write_unsent().await
+    }
+
+    async fn write_unsent(&mut self) -> io::Result<()> {
+        while !self.unsent.is_empty() {
+            // A single write is cancel safe, the written bytes are removed right away.
+            let written = self.stream.write(&self.unsent).await?;
+            if written == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.unsent.drain(..written);
+        }
+        Ok(())
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send<'a>(&'a self, datagram: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let len = u16::try_from(datagram.len())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Datagram too long"))?;
+            let mut frame = Vec::with_capacity(datagram.len() + 2);
+            frame.extend_from_slice(&len.to_le_bytes());
+            frame.extend_from_slice(datagram);
+            self.writer.lock().await.send(&frame).await
+        })
+    }
+
+    fn recv<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>> {
+        Box::pin(async move {
+            let datagram = self.reader.lock().await.recv().await?;
+            let len = datagram.len().min(buf.len());
+            buf[..len].copy_from_slice(&datagram[..len]);
+            Ok(len)
+        })
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ConnectionState, Z21Event, Z21Station};
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn test_station_over_channel() {
+        let (client, z21) = ChannelTransport::pair();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            while let Ok(len) = z21.recv(&mut buf).await {
+                // Answer LAN_GET_SERIAL_NUMBER, ignore everything else.
+                if buf[..len] == [0x04, 0x00, 0x10, 0x00] {
+                    let reply = [0x08, 0x00, 0x10, 0x00, 0x2A, 0x00, 0x00, 0x00];
+                    z21.send(&reply).await.unwrap();
+                }
+            }
+        });
+
+        let station = Z21Station::builder("in-memory")
+            .handshake(false)
+            .connect_with_transport(client)
+            .await
+            .unwrap();
+        assert_eq!(station.get_serial_number().await.unwrap(), 42);
+        station.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_closed_transport_disconnects() {
+        let (client, z21) = ChannelTransport::pair();
+        let station = Z21Station::builder("in-memory")
+            .handshake(false)
+            .timeout(Duration::from_secs(5))
+            .connect_with_transport(client)
+            .await
+            .unwrap();
+        let mut events = station.events();
+
+        drop(z21);
+        let event = tokio::time::timeout(Duration::from_secs(1), events.next())
+            .await
+            .unwrap();
+        assert!(matches!(
+            event,
+            Some(Z21Event::ConnectionState(ConnectionState::Disconnected))
+        ));
+        assert_eq!(station.connection_state(), ConnectionState::Disconnected);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_tunnel_keeps_datagram_boundaries() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = TcpTransport::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let relay = TcpTransport::from_stream(stream);
+
+        client.send(&[0x04, 0x00, 0x10, 0x00]).await.unwrap();
+        client.send(&[0x04, 0x00, 0x1A, 0x00]).await.unwrap();
+
+        let mut buf = [0u8; 16];
+        let len = relay.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], &[0x04, 0x00, 0x10, 0x00]);
+        let len = relay.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], &[0x04, 0x00, 0x1A, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_recv_is_cancel_safe() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = TcpTransport::connect(addr).await.unwrap();
+        let (mut relay, _) = listener.accept().await.unwrap();
+
+        // Half a frame arrives, then the receiver gives up waiting for the rest.
+        relay.write_all(&[0x04, 0x00, 0x04, 0x00]).await.unwrap();
+        let mut buf = [0u8; 16];
+        let cancelled = tokio::time::timeout(Duration::from_millis(100), client.recv(&mut buf));
+        assert!(cancelled.await.is_err());
+
+        relay.write_all(&[0x10, 0x00]).await.unwrap();
+        let len = client.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], &[0x04, 0x00, 0x10, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_send_is_cancel_safe() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = TcpTransport::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        // Nobody reads yet: fill the stream until a send gives up in the middle.
+        let datagram = [0x5A; 1400];
+        let mut sent = 0;
+        while tokio::time::timeout(Duration::from_millis(50), client.send(&datagram))
+            .await
+            .is_ok()
+        {
+            sent += 1;
+        }
+
+        let relay = TcpTransport::from_stream(stream);
+        let marker = [0x04, 0x00, 0x10, 0x00];
+        let (_, received) = tokio::join!(client.send(&marker), async {
+            let mut buf = [0u8; 1472];
+            let mut received = 0;
+            loop {
+                let len = relay.recv(&mut buf).await.unwrap();
+                if buf[..len] == marker {
+                    return received;
+                }
+                assert_eq!(&buf[..len], &datagram[..]);
+                received += 1;
+            }
+        });
+        // The frame of the cancelled send was completed before the marker.
+        assert_eq!(received, sent + 1);
+    }
+}
@@ -0,0 +1,74 @@
+//! Tests of the blocking API against the simulated Z21.
+#![cfg(feature = "blocking")]
+
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+
+use roco_z21_driver::blocking::{Loco, Z21Station};
+use roco_z21_driver::{Error, Z21Event, Z21Simulator};
+
+mod common;
+
+fn connect(simulator: &Z21Simulator) -> Z21Station {
+    Z21Station::connect(common::builder(simulator.local_addr())).unwrap()
+}
+
+#[test]
+fn test_blocking_station_and_loco() {
+    let (_runtime, simulator) = common::simulator_on_runtime();
+    simulator.set_serial_number(4711);
+    let station = connect(&simulator);
+    assert_eq!(station.get_serial_number().unwrap(), 4711);
+
+    let mut events = station.events();
+    let loco = Loco::control(&station, 3).unwrap();
+    loco.drive(50.0).unwrap();
+    loop {
+        match events.recv_timeout(Duration::from_secs(5)) {
+            Ok(Z21Event::LocoInfo(state)) if state.speed_percentage.unwrap_or(0.) > 0. => break,
+            Ok(_) => {}
+            Err(e) => panic!("No loco info: {:?}", e),
+        }
+    }
+
+    station.shutdown().unwrap();
+    assert_eq!(
+        events.recv_timeout(Duration::from_secs(1)).unwrap_err(),
+        RecvTimeoutError::Disconnected
+    );
+}
+
+#[test]
+fn test_blocking_event_callback() {
+    let (_runtime, simulator) = common::simulator_on_runtime();
+    let station = connect(&simulator);
+
+    let (sender, receiver) = mpsc::channel();
+    let sender = std::sync::Mutex::new(sender);
+    let _subscription = station.on_event(move |event| {
+        if let Z21Event::TrackPowerOff = event {
+            let _ = sender.lock().unwrap().send(());
+        }
+    });
+    station.voltage_off().unwrap();
+    receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+}
+
+#[test]
+fn test_blocking_call_from_callback_fails() {
+    let (_runtime, simulator) = common::simulator_on_runtime();
+    let station = std::sync::Arc::new(connect(&simulator));
+
+    let (sender, receiver) = mpsc::channel();
+    let sender = std::sync::Mutex::new(sender);
+    let weak = std::sync::Arc::downgrade(&station);
+    let _subscription = station.on_event(move |event| {
+        if let (Z21Event::TrackPowerOff, Some(station)) = (event, weak.upgrade()) {
+            let result = station.get_serial_number();
+            let _ = sender.lock().unwrap().send(result);
+        }
+    });
+    station.voltage_off().unwrap();
+    let result = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(matches!(result, Err(Error::InsideRuntime)));
+}
@@ -0,0 +1,53 @@
+//! Recording a session with the simulated Z21 and replaying it without the simulator.
+
+use std::time::Duration;
+
+use roco_z21_driver::capture::{
+    read_capture, CaptureFormat, CaptureWriter, Direction, ReplayTransport,
+};
+use roco_z21_driver::Z21Station;
+
+mod common;
+
+async fn record(path: &std::path::Path, format: CaptureFormat) {
+    let simulator = common::simulator().await;
+    simulator.set_serial_number(31337);
+    let writer = CaptureWriter::create(path, format).unwrap();
+    let station = common::builder(simulator.local_addr())
+        .capture(writer.clone())
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(station.get_serial_number().await.unwrap(), 31337);
+    station.shutdown().await.unwrap();
+    writer.flush().unwrap();
+}
+
+async fn record_and_replay(format: CaptureFormat, name: &str) {
+    let path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+    record(&path, format).await;
+
+    let records = read_capture(&path).unwrap();
+    assert!(records.iter().any(|r| r.direction == Direction::Tx));
+    assert!(records.iter().any(|r| r.direction == Direction::Rx));
+    assert_eq!(records[0].peer.port(), records[1].peer.port());
+
+    let replay = ReplayTransport::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let station = Z21Station::builder("replay")
+        .timeout(Duration::from_millis(500))
+        .connect_with_transport(replay)
+        .await
+        .unwrap();
+    assert_eq!(station.get_serial_number().await.unwrap(), 31337);
+}
+
+#[tokio::test]
+async fn test_replay_native_capture() {
+    record_and_replay(CaptureFormat::Native, "z21.txt").await;
+}
+
+#[tokio::test]
+async fn test_replay_pcap_capture() {
+    record_and_replay(CaptureFormat::Pcap, "z21.pcap").await;
+}
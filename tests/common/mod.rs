@@ -0,0 +1,34 @@
+//! Fixtures shared by the integration tests.
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use roco_z21_driver::{Z21Simulator, Z21Station, Z21StationBuilder};
+use tokio::runtime::Runtime;
+
+/// Reply timeout of the stations under test, the simulator answers right away.
+pub const TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Starts a simulated Z21 on a free local port.
+pub async fn simulator() -> Z21Simulator {
+    Z21Simulator::bind("127.0.0.1:0").await.unwrap()
+}
+
+/// Starts a simulated Z21 on its own runtime, as a blocking program would talk to a
+/// real Z21.
+pub fn simulator_on_runtime() -> (Runtime, Z21Simulator) {
+    let runtime = Runtime::new().unwrap();
+    let simulator = runtime.block_on(simulator());
+    (runtime, simulator)
+}
+
+/// Returns a builder of a station talking to `addr` with the short [`TIMEOUT`].
+pub fn builder(addr: SocketAddr) -> Z21StationBuilder {
+    Z21Station::builder(&addr.to_string()).timeout(TIMEOUT)
+}
+
+/// Connects a station to `addr` with the short [`TIMEOUT`].
+pub async fn connect(addr: SocketAddr) -> Z21Station {
+    builder(addr).connect().await.unwrap()
+}
@@ -0,0 +1,79 @@
+//! Tests of `Z21Proxy` between clients and the simulated Z21.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use roco_z21_driver::messages::{BroadcastFlags, Request};
+use roco_z21_driver::{Loco, Z21Event, Z21Proxy, Z21Simulator, Z21Station};
+use tokio::time::timeout;
+use tokio_stream::StreamExt;
+
+mod common;
+
+/// Starts a simulator and a proxy in front of it.
+async fn proxy() -> (Z21Simulator, Z21Proxy) {
+    let simulator = common::simulator().await;
+    let upstream = Arc::new(common::connect(simulator.local_addr()).await);
+    let proxy = Z21Proxy::bind("127.0.0.1:0", upstream).await.unwrap();
+    (simulator, proxy)
+}
+
+/// Subscribes `station` to the usual broadcasts and waits until the proxy knows.
+async fn subscribe(station: &Z21Station) {
+    station
+        .set_broadcast_flags(BroadcastFlags::DRIVING_SWITCHING)
+        .await
+        .unwrap();
+    station.get_serial_number().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_replies_reach_requester() {
+    let (simulator, proxy) = proxy().await;
+    simulator.set_serial_number(4711);
+    let first = common::connect(proxy.local_addr()).await;
+    let second = common::connect(proxy.local_addr()).await;
+
+    assert_eq!(first.get_serial_number().await.unwrap(), 4711);
+    assert_eq!(second.get_serial_number().await.unwrap(), 4711);
+    // The Z21 only sees the proxy.
+    assert_eq!(simulator.clients().len(), 1);
+    assert_eq!(proxy.clients().len(), 2);
+}
+
+#[tokio::test]
+async fn test_broadcasts_fan_out() {
+    let (simulator, proxy) = proxy().await;
+    let first = common::connect(proxy.local_addr()).await;
+    let second = common::connect(proxy.local_addr()).await;
+    subscribe(&first).await;
+    subscribe(&second).await;
+    let mut events = second.events();
+
+    first.voltage_off().await.unwrap();
+    assert!(!simulator.track_power());
+    let event = timeout(Duration::from_secs(2), async {
+        loop {
+            if let Some(Z21Event::TrackPowerOff) = events.next().await {
+                return;
+            }
+        }
+    })
+    .await;
+    assert!(event.is_ok());
+}
+
+#[tokio::test]
+async fn test_loco_control_through_proxy() {
+    let (simulator, proxy) = proxy().await;
+    let station = Arc::new(common::connect(proxy.local_addr()).await);
+    subscribe(&station).await;
+    let loco = Loco::control(Arc::clone(&station), 3).await.unwrap();
+
+    loco.drive(50.0).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(simulator.loco(3).unwrap().speed_percentage, Some(50.0));
+
+    let result = station.request(&Request::CvRead { cv: 999 }).await;
+    assert!(matches!(result, Err(roco_z21_driver::Error::CvNack)));
+}
@@ -0,0 +1,94 @@
+//! Tests of the Python bindings, running asyncio scripts against the simulated Z21.
+#![cfg(feature = "python")]
+
+use std::ffi::CString;
+use std::sync::Once;
+
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use roco_z21_driver::python::python_module;
+
+mod common;
+
+/// Runs `script` with `ADDRESS` set to the address of a fresh simulator.
+fn run_script(script: &str) -> PyResult<()> {
+    let (_runtime, simulator) = common::simulator_on_runtime();
+    simulator.set_serial_number(4711);
+
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        pyo3::append_to_inittab!(python_module);
+        pyo3::prepare_freethreaded_python();
+    });
+    Python::with_gil(|py| {
+        let globals = PyDict::new(py);
+        globals.set_item("ADDRESS", simulator.local_addr().to_string())?;
+        py.run(&CString::new(script).unwrap(), Some(&globals), None)
+    })
+}
+
+#[test]
+fn test_python_station_loco_and_events() {
+    run_script(
+        r#"
+import asyncio
+from roco_z21_driver import Loco, LocoState, SystemState, Z21Station
+
+async def main():
+    station = await Z21Station.connect(ADDRESS, timeout=0.3)
+    assert await station.get_serial_number() == 4711
+    assert station.connection_state == "Connected"
+    assert isinstance(await station.get_system_state(), SystemState)
+
+    events = station.events()
+    loco = await Loco.control(station, 3)
+    await loco.drive(50.0)
+    await loco.function_on(0)
+    async for event in events:
+        if event.kind == "LocoInfo" and event.loco_state.functions[0]:
+            break
+    assert event.loco_state.address == 3
+    assert event.loco_state.speed_percentage > 0
+
+    state = await loco.get_state()
+    assert isinstance(state, LocoState)
+    assert state.steps == 128
+
+    await station.shutdown()
+    # The iteration ends once the station shut down.
+    async for event in events:
+        pass
+
+asyncio.run(main())
+"#,
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_python_errors() {
+    run_script(
+        r#"
+import asyncio
+from roco_z21_driver import Loco, Z21Station
+
+async def main():
+    station = await Z21Station.connect(ADDRESS, timeout=0.3)
+    try:
+        await Loco.control(station, 3, steps=7)
+        raise AssertionError("invalid steps accepted")
+    except ValueError:
+        pass
+    loco = await Loco.control(station, 3)
+    try:
+        await loco.set_function(40, 1)
+        raise AssertionError("invalid function accepted")
+    except ValueError:
+        pass
+    await station.shutdown()
+
+asyncio.run(main())
+"#,
+    )
+    .unwrap();
+}
@@ -0,0 +1,56 @@
+//! JSON representation of states, messages and packets.
+#![cfg(feature = "serde")]
+
+use roco_z21_driver::messages::{
+    BroadcastFlags, DccThrottleSteps, LocoState, Request, XBusMessage,
+};
+use roco_z21_driver::Packet;
+use serde_json::json;
+
+#[test]
+fn test_loco_state_json() {
+    let state = LocoState {
+        address: 3,
+        is_busy: Some(false),
+        stepping: Some(DccThrottleSteps::Steps128),
+        speed_percentage: Some(-50.0),
+        double_traction: None,
+        smart_search: None,
+        functions: None,
+    };
+    let value = serde_json::to_value(&state).unwrap();
+    assert_eq!(value["address"], 3);
+    assert_eq!(value["stepping"], "Steps128");
+    assert_eq!(value["speed_percentage"], -50.0);
+
+    let decoded: LocoState = serde_json::from_value(value).unwrap();
+    assert_eq!(decoded.address, 3);
+    assert_eq!(decoded.speed_percentage, Some(-50.0));
+}
+
+#[test]
+fn test_request_and_packet_json() {
+    let request = Request::SetBroadcastFlags(BroadcastFlags::DRIVING_SWITCHING);
+    let value = serde_json::to_value(&request).unwrap();
+    assert_eq!(value, json!({ "SetBroadcastFlags": 1 }));
+
+    let packet = request.encode().unwrap();
+    let value = serde_json::to_value(&packet).unwrap();
+    assert_eq!(value, json!({ "header": 0x50, "data": [1, 0, 0, 0] }));
+    let decoded: Packet = serde_json::from_value(value).unwrap();
+    assert_eq!(decoded, packet);
+}
+
+#[test]
+fn test_xbus_message_checksum_is_verified() {
+    let message = XBusMessage::new_single(0x21, 0x24);
+    let value = serde_json::to_value(&message).unwrap();
+    assert_eq!(
+        value,
+        json!({ "x_header": 0x21, "dbs": [0x24], "xor": 0x05 })
+    );
+    assert!(serde_json::from_value::<XBusMessage>(value).is_ok());
+
+    let corrupt = json!({ "x_header": 0x21, "dbs": [0x24], "xor": 0x00 });
+    assert!(serde_json::from_value::<XBusMessage>(corrupt).is_err());
+}
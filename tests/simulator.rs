@@ -0,0 +1,389 @@
+//! Tests of `Z21Station` and `Loco` against the simulated Z21.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use roco_z21_driver::messages::{
+    BroadcastFlags, FunctionAction, HardwareType, Request, TurnoutPosition,
+};
+use roco_z21_driver::{
+    CommandClass, ConnectionState, Error, Loco, QueueConfig, Z21Event, Z21Station,
+};
+use tokio::time::timeout;
+use tokio_stream::StreamExt;
+
+mod common;
+
+/// Waits for the first event matching `predicate`.
+async fn wait_for(station: &Z21Station, predicate: impl Fn(&Z21Event) -> bool) -> Z21Event {
+    let mut events = station.events();
+    timeout(Duration::from_secs(5), async {
+        loop {
+            let event = events.next().await.unwrap();
+            if predicate(&event) {
+                return event;
+            }
+        }
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_station_info() {
+    let simulator = common::simulator().await;
+    simulator.set_serial_number(98765);
+    let station = common::connect(simulator.local_addr()).await;
+
+    assert_eq!(station.get_serial_number().await.unwrap(), 98765);
+    let info = station.get_hardware_info().await.unwrap();
+    assert_eq!(info.hardware_type, HardwareType::Z21New);
+    assert_eq!(simulator.clients().len(), 1);
+}
+
+#[tokio::test]
+async fn test_discover_from_local_address() {
+    let simulator = common::simulator().await;
+    simulator.set_serial_number(24680);
+    let bind = "127.0.0.1:0".parse().unwrap();
+    let stations =
+        Z21Station::discover_from(bind, simulator.local_addr(), Duration::from_millis(300))
+            .await
+            .unwrap();
+
+    assert_eq!(stations.len(), 1);
+    assert_eq!(stations[0].address, simulator.local_addr());
+    assert_eq!(stations[0].serial_number, 24680);
+    let info = stations[0].hardware_info.as_ref().unwrap();
+    assert_eq!(info.hardware_type, HardwareType::Z21New);
+}
+
+#[tokio::test]
+async fn test_track_power_broadcast() {
+    let simulator = common::simulator().await;
+    let station = common::connect(simulator.local_addr()).await;
+    let mut events = station.events();
+
+    station.voltage_off().await.unwrap();
+    assert!(!simulator.track_power());
+    let event = timeout(Duration::from_secs(1), events.next())
+        .await
+        .unwrap();
+    assert!(matches!(event, Some(Z21Event::TrackPowerOff)));
+
+    station.voltage_on().await.unwrap();
+    assert!(simulator.track_power());
+}
+
+#[tokio::test]
+async fn test_loco_control() {
+    let simulator = common::simulator().await;
+    let station = Arc::new(common::connect(simulator.local_addr()).await);
+    let loco = Loco::control(Arc::clone(&station), 3).await.unwrap();
+
+    loco.drive(50.0).await.unwrap();
+    loco.set_headlights(true).await.unwrap();
+
+    let state = simulator.loco(3).unwrap();
+    assert_eq!(state.speed_percentage, Some(50.0));
+    assert!(state.functions.unwrap()[0]);
+}
+
+#[tokio::test]
+async fn test_loco_info_reaches_other_clients() {
+    let simulator = common::simulator().await;
+    let observer = Arc::new(common::connect(simulator.local_addr()).await);
+    let driver = Arc::new(common::connect(simulator.local_addr()).await);
+    let _watched = Loco::control(Arc::clone(&observer), 5).await.unwrap();
+    let loco = Loco::control(Arc::clone(&driver), 5).await.unwrap();
+
+    let event = tokio::join!(
+        wait_for(&observer, |event| matches!(event, Z21Event::LocoInfo(state)
+            if state.speed_percentage.unwrap_or(0.) < 0.)),
+        loco.drive(-25.0),
+    )
+    .0;
+    assert!(matches!(event, Z21Event::LocoInfo(state) if state.address == 5));
+}
+
+#[tokio::test]
+async fn test_turnout() {
+    let simulator = common::simulator().await;
+    let station = common::connect(simulator.local_addr()).await;
+    let request = Request::SetTurnout {
+        address: 7,
+        output: true,
+        activate: true,
+        queue: false,
+    };
+
+    station.request(&request).await.unwrap();
+    assert_eq!(simulator.turnout(7), TurnoutPosition::P1);
+}
+
+#[tokio::test]
+async fn test_cv_programming() {
+    let simulator = common::simulator().await;
+    let station = common::connect(simulator.local_addr()).await;
+
+    station
+        .request(&Request::CvWrite { cv: 3, value: 10 })
+        .await
+        .unwrap_err();
+    simulator.set_cv(3, 5);
+    station
+        .request(&Request::CvWrite { cv: 3, value: 10 })
+        .await
+        .unwrap();
+    assert_eq!(simulator.cv(3), Some(10));
+
+    simulator.remove_decoder();
+    let result = station.request(&Request::CvRead { cv: 1 }).await;
+    assert!(matches!(result, Err(Error::CvNack)));
+}
+
+#[tokio::test]
+async fn test_short_circuit() {
+    let simulator = common::simulator().await;
+    let station = common::connect(simulator.local_addr()).await;
+    // Make sure the flags are registered before the broadcast, the queue keeps the order.
+    station
+        .send_request(&Request::SetBroadcastFlags(
+            BroadcastFlags::DRIVING_SWITCHING,
+        ))
+        .await
+        .unwrap();
+    station.get_serial_number().await.unwrap();
+
+    tokio::join!(
+        wait_for(&station, |event| matches!(event, Z21Event::ShortCircuit)),
+        simulator.short_circuit(),
+    );
+    assert!(!simulator.track_power());
+
+    let result = station.request(&Request::CvRead { cv: 1 }).await;
+    assert!(matches!(result, Err(Error::ShortCircuit)));
+}
+
+#[tokio::test]
+async fn test_retries_on_lossy_network() {
+    let simulator = common::simulator().await;
+    let station = common::builder(simulator.local_addr())
+        .timeout(Duration::from_millis(100))
+        .broadcast_flags(BroadcastFlags::NONE)
+        .queue_config(QueueConfig {
+            retries: 5,
+            ..QueueConfig::default()
+        })
+        .connect()
+        .await
+        .unwrap();
+
+    simulator.set_drop_rate(0.3);
+    for _ in 0..10 {
+        // Serial number requests are idempotent and resent when lost.
+        station.get_serial_number().await.unwrap();
+    }
+    assert!(station.metrics().retries > 0);
+}
+
+#[tokio::test]
+async fn test_dropped_request_does_not_take_later_reply() {
+    let simulator = common::simulator().await;
+    let station = common::connect(simulator.local_addr()).await;
+    // Toggling is not resent, so only the reply to the first send can answer it.
+    let toggle = Request::SetLocoFunction {
+        address: 3,
+        function: 1,
+        action: FunctionAction::Toggle,
+    };
+
+    simulator.set_drop_rate(1.);
+    assert!(timeout(Duration::from_millis(50), station.request(&toggle))
+        .await
+        .is_err());
+    simulator.set_drop_rate(0.);
+    station.request(&toggle).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_lagging_event_stream_continues() {
+    let simulator = common::simulator().await;
+    let station = common::builder(simulator.local_addr())
+        .channel_capacity(1)
+        .connect()
+        .await
+        .unwrap();
+    let mut events = station.events();
+
+    station.voltage_off().await.unwrap();
+    station.voltage_on().await.unwrap();
+    // Each call returns once its broadcast was received, only the last stays buffered.
+    station.voltage_off().await.unwrap();
+
+    let Some(Z21Event::Lagged(skipped)) = events.next().await else {
+        panic!("Expected the stream to report lag");
+    };
+    // Also skipped: replies to the keep-alive probe of the supervisor.
+    assert!(skipped >= 2);
+    assert!(matches!(events.next().await, Some(Z21Event::TrackPowerOff)));
+    assert_eq!(station.metrics().lag_drops, skipped);
+}
+
+#[tokio::test]
+async fn test_metrics() {
+    let simulator = common::simulator().await;
+    let station = common::connect(simulator.local_addr()).await;
+    // Let the first keep-alive probe of the supervisor finish.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let before = station.metrics();
+    assert_eq!(before.timeouts, 0);
+
+    simulator.set_drop_rate(1.);
+    assert!(station.get_serial_number().await.is_err());
+    simulator.set_drop_rate(0.);
+    station.get_serial_number().await.unwrap();
+
+    let metrics = station.metrics();
+    assert_eq!(metrics.timeouts, 1);
+    assert_eq!(metrics.retries, 2);
+    assert_eq!(metrics.commands_sent - before.commands_sent, 4);
+    assert!(metrics.replies_received > before.replies_received);
+    assert_eq!(metrics.checksum_failures, 0);
+    assert_eq!(
+        metrics.rtt[&CommandClass::Query].count - before.rtt[&CommandClass::Query].count,
+        1
+    );
+}
+
+#[tokio::test]
+async fn test_late_reply_is_no_round_trip() {
+    let simulator = common::simulator().await;
+    let station = common::builder(simulator.local_addr())
+        .timeout(Duration::from_millis(100))
+        .connect()
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let before = station.metrics();
+
+    // The reply to the first send arrives shortly after the resend.
+    simulator.set_reply_delay(Duration::from_millis(150));
+    station.get_serial_number().await.unwrap();
+
+    let metrics = station.metrics();
+    assert_eq!(metrics.retries - before.retries, 1);
+    assert_eq!(
+        metrics.rtt[&CommandClass::Query].count,
+        before.rtt[&CommandClass::Query].count
+    );
+}
+
+#[tokio::test]
+async fn test_send_packets_through_queue() {
+    let simulator = common::simulator().await;
+    let station = common::connect(simulator.local_addr()).await;
+    let mut events = station.events();
+    let before = station.metrics();
+
+    let packets = vec![
+        Request::GetSerialNumber.encode().unwrap(),
+        Request::SetTrackPowerOff.encode().unwrap(),
+    ];
+    station.send_packets(packets).await.unwrap();
+    timeout(Duration::from_secs(5), async {
+        while !matches!(events.next().await, Some(Z21Event::TrackPowerOff)) {}
+    })
+    .await
+    .unwrap();
+    assert!(!simulator.track_power());
+    assert!(station.metrics().commands_sent > before.commands_sent);
+
+    station.shutdown().await.unwrap();
+    assert!(matches!(
+        station
+            .send_packets(vec![Request::GetSerialNumber.encode().unwrap()])
+            .await,
+        Err(Error::NotConnected)
+    ));
+}
+
+#[cfg(feature = "prometheus")]
+#[tokio::test]
+async fn test_serve_metrics() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let simulator = common::simulator().await;
+    let station = common::connect(simulator.local_addr()).await;
+    let server = station.serve_metrics("127.0.0.1:0").await.unwrap();
+
+    let mut stream = tokio::net::TcpStream::connect(server.local_addr())
+        .await
+        .unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("z21_rtt_seconds_count{class=\"query\"}"));
+}
+
+#[tokio::test]
+async fn test_reply_delay() {
+    let simulator = common::simulator().await;
+    let station = common::connect(simulator.local_addr()).await;
+
+    simulator.set_reply_delay(Duration::from_millis(200));
+    let result = station
+        .request_with_timeout(&Request::GetSerialNumber, Duration::from_millis(50))
+        .await;
+    assert!(matches!(result, Err(Error::Timeout { .. })));
+}
+
+#[tokio::test]
+async fn test_reconnect_after_outage() {
+    let simulator = common::simulator().await;
+    let station = common::builder(simulator.local_addr())
+        .timeout(Duration::from_millis(100))
+        .keep_alive_interval(Duration::from_millis(100))
+        .connect()
+        .await
+        .unwrap();
+
+    simulator.set_offline(true);
+    wait_for(&station, |event| {
+        matches!(
+            event,
+            Z21Event::ConnectionState(ConnectionState::Disconnected)
+        )
+    })
+    .await;
+
+    simulator.set_offline(false);
+    wait_for(&station, |event| {
+        matches!(event, Z21Event::ConnectionState(ConnectionState::Connected))
+    })
+    .await;
+    assert_eq!(station.connection_state(), ConnectionState::Connected);
+}
+
+#[tokio::test]
+async fn test_shutdown_logs_out() {
+    let simulator = common::simulator().await;
+    let station = common::connect(simulator.local_addr()).await;
+    let mut events = station.events();
+    assert_eq!(simulator.clients().len(), 1);
+
+    station.shutdown().await.unwrap();
+    assert!(events.next().await.is_none());
+    assert!(matches!(
+        station.get_serial_number().await,
+        Err(Error::NotConnected)
+    ));
+
+    // The logoff is processed asynchronously by the simulator.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(simulator.clients().is_empty());
+}